@@ -0,0 +1,39 @@
+//! Whatever arrives, parsing must not panic, a probe's ACK must never be
+//! larger than the probe, and checksums must check out. Run with
+//! `cargo fuzz run packet`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use loss_lens::protocol::{self, Header, Request};
+
+fuzz_target!(|packet: &[u8]| {
+    let _ = protocol::reply(packet);
+    let Ok((Header { version, .. }, request)) = protocol::request(packet) else {
+        return;
+    };
+    if let Request::Probe {
+        seq,
+        client_id,
+        ack_size,
+    } = request
+    {
+        let mut probe = packet.to_vec();
+        protocol::write_probe(&mut probe, version, seq, client_id, ack_size);
+        assert_eq!(probe, packet);
+        let len = protocol::write_ack(
+            &mut probe,
+            version,
+            ack_size,
+            1,
+            packet[0] & 0b11,
+            Some(1),
+            Some(1),
+        );
+        assert!(len <= packet.len());
+        if packet.len() >= protocol::CHECKSUM_PROBE_MIN_SIZE {
+            protocol::write_checksum(&mut probe);
+            assert!(protocol::checksum_valid(&probe));
+        }
+    }
+});
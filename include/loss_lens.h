@@ -0,0 +1,42 @@
+/* C interface to loss_lens, built with `cargo build --features ffi`. */
+#ifndef LOSS_LENS_H
+#define LOSS_LENS_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct LossLensClient LossLensClient;
+typedef struct LossLensServer LossLensServer;
+
+typedef struct LossLensStats {
+    uint64_t client_sent;
+    uint64_t server_received;
+    uint64_t client_received;
+    double elapsed_secs;
+    double upstream_loss;
+    double downstream_loss;
+} LossLensStats;
+
+/* Start probing host ("addr:port"); recording may be NULL. NULL on error. */
+LossLensClient *loss_lens_start_client(const char *host, const char *recording);
+
+/* 0 and fills *out once a snapshot exists, -1 before that. */
+int loss_lens_get_stats(LossLensClient *client, LossLensStats *out);
+
+/* Stops the client and frees it. 0 on clean shutdown, -1 on failure. */
+int loss_lens_stop(LossLensClient *client);
+
+/* Start a reflector listening on host ("addr:port"). NULL on error. */
+LossLensServer *loss_lens_start_server(const char *host);
+
+/* Stops the server and frees it. 0 on clean shutdown, -1 on failure. */
+int loss_lens_stop_server(LossLensServer *server);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
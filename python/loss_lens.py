@@ -0,0 +1,122 @@
+"""Python bindings for loss_lens on top of its C ABI.
+
+Build the shared library with ``cargo build --release --features ffi`` and
+point ``LOSS_LENS_LIB`` at ``target/release/libloss_lens.so`` (or the
+``.dylib``/``.dll`` equivalent) if it is not on the default search path.
+
+    import loss_lens
+
+    with loss_lens.Client("reflector.example:13337") as client:
+        for stats in client.stats():
+            print(stats.upstream_loss, stats.downstream_loss)
+"""
+
+import ctypes
+import ctypes.util
+import os
+import time
+from typing import Iterator, NamedTuple, Optional
+
+__all__ = ["Client", "Server", "Stats"]
+
+
+class _CStats(ctypes.Structure):
+    _fields_ = [
+        ("client_sent", ctypes.c_uint64),
+        ("server_received", ctypes.c_uint64),
+        ("client_received", ctypes.c_uint64),
+        ("elapsed_secs", ctypes.c_double),
+        ("upstream_loss", ctypes.c_double),
+        ("downstream_loss", ctypes.c_double),
+    ]
+
+
+class Stats(NamedTuple):
+    client_sent: int
+    server_received: int
+    client_received: int
+    elapsed_secs: float
+    upstream_loss: float
+    downstream_loss: float
+
+
+def _load():
+    path = os.environ.get("LOSS_LENS_LIB") or ctypes.util.find_library("loss_lens")
+    if path is None:
+        raise ImportError("libloss_lens not found, set LOSS_LENS_LIB")
+    lib = ctypes.CDLL(path)
+    lib.loss_lens_start_client.argtypes = [ctypes.c_char_p, ctypes.c_char_p]
+    lib.loss_lens_start_client.restype = ctypes.c_void_p
+    lib.loss_lens_get_stats.argtypes = [ctypes.c_void_p, ctypes.POINTER(_CStats)]
+    lib.loss_lens_get_stats.restype = ctypes.c_int
+    lib.loss_lens_stop.argtypes = [ctypes.c_void_p]
+    lib.loss_lens_stop.restype = ctypes.c_int
+    lib.loss_lens_start_server.argtypes = [ctypes.c_char_p]
+    lib.loss_lens_start_server.restype = ctypes.c_void_p
+    lib.loss_lens_stop_server.argtypes = [ctypes.c_void_p]
+    lib.loss_lens_stop_server.restype = ctypes.c_int
+    return lib
+
+
+_lib = _load()
+
+
+class Client:
+    """A probe client running on a background thread inside the library."""
+
+    def __init__(self, host: str, recording: Optional[str] = None):
+        self._handle = _lib.loss_lens_start_client(
+            host.encode(), recording.encode() if recording else None
+        )
+        if not self._handle:
+            raise OSError(f"failed to start client for {host}")
+
+    def latest(self) -> Optional[Stats]:
+        """Most recent stats snapshot, or None before the first one."""
+        out = _CStats()
+        if _lib.loss_lens_get_stats(self._handle, ctypes.byref(out)) != 0:
+            return None
+        return Stats(*(getattr(out, name) for name, _ in _CStats._fields_))
+
+    def stats(self, poll_interval: float = 0.5) -> Iterator[Stats]:
+        """Yield each new snapshot until the client is stopped."""
+        last = None
+        while self._handle:
+            current = self.latest()
+            if current is not None and current != last:
+                last = current
+                yield current
+            time.sleep(poll_interval)
+
+    def stop(self) -> None:
+        if self._handle:
+            handle, self._handle = self._handle, None
+            if _lib.loss_lens_stop(handle) != 0:
+                raise OSError("client failed")
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.stop()
+
+
+class Server:
+    """A reflector running on a background thread inside the library."""
+
+    def __init__(self, host: str = "127.0.0.1:13337"):
+        self._handle = _lib.loss_lens_start_server(host.encode())
+        if not self._handle:
+            raise OSError(f"failed to listen on {host}")
+
+    def stop(self) -> None:
+        if self._handle:
+            handle, self._handle = self._handle, None
+            if _lib.loss_lens_stop_server(handle) != 0:
+                raise OSError("server failed")
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.stop()
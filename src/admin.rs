@@ -0,0 +1,159 @@
+//! Authenticated HTTP API for operating a running server:
+//!
+//! - `GET /status`: uptime, client count, clients served, packets reflected,
+//!   clients expired and evicted, and limits
+//! - `GET /clients`: every tracked client with its counters
+//! - `DELETE /clients/ID`: forget a client; its next probe starts over
+//! - `POST /limits?idle_timeout_secs=N&max_clients=M&max_rate=R&per_ip_rate=P`:
+//!   adjust limits, `per_ip_rate=0` lifting that one
+//!
+//! Every request needs an `Authorization: Bearer TOKEN` header.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use crate::{
+    http::{self, Request, Response},
+    json,
+    server::{AdminConfig, ClientEntry, ServerLimits, ServerState},
+};
+
+pub(crate) fn serve(config: &AdminConfig, state: Arc<ServerState>) -> eyre::Result<()> {
+    let token = config.token.clone();
+    http::serve(&config.addr, http::MAX_BODY, move |request, _stream| {
+        if !http::bearer_authorized(request, &token) {
+            return Response::text("401 Unauthorized", "missing or wrong bearer token").into();
+        }
+        handle(request, &state).into()
+    })?;
+    Ok(())
+}
+
+fn handle(request: &Request, state: &ServerState) -> Response {
+    let method = request.method.as_str();
+    let path = request.path.as_str();
+    match (method, path) {
+        ("GET", "/status") => Response::json(status_json(state)),
+        ("GET", "/clients") => Response::json(clients_json(state)),
+        ("POST", "/limits") => match update_limits(request, state) {
+            Ok(limits) => Response::json(limits_json(&limits)),
+            Err(e) => Response::text("400 Bad Request", e),
+        },
+        ("DELETE", _) if path.starts_with("/clients/") => {
+            let Ok(id) = path["/clients/".len()..].parse::<u32>() else {
+                return Response::text("400 Bad Request", "client id must be a u32");
+            };
+            let removed = state.shard(id).lock().unwrap().remove(id);
+            match removed {
+                Some(_) => Response::text("200 OK", "evicted"),
+                None => Response::not_found(),
+            }
+        }
+        (_, "/status" | "/clients" | "/limits") => Response::method_not_allowed(),
+        _ if path.starts_with("/clients/") => Response::method_not_allowed(),
+        _ => Response::not_found(),
+    }
+}
+
+fn update_limits(request: &Request, state: &ServerState) -> Result<ServerLimits, &'static str> {
+    let mut limits = *state.limits.lock().unwrap();
+    if let Some(v) = request.query_param("idle_timeout_secs") {
+        limits.idle_timeout = Duration::from_secs(
+            v.parse()
+                .map_err(|_| "idle_timeout_secs must be an integer")?,
+        );
+    }
+    if let Some(v) = request.query_param("max_clients") {
+        limits.max_clients = v.parse().map_err(|_| "max_clients must be an integer")?;
+    }
+    if let Some(v) = request.query_param("max_rate") {
+        limits.max_rate = v.parse().map_err(|_| "max_rate must be an integer")?;
+    }
+    if let Some(v) = request.query_param("per_ip_rate") {
+        let rate: u32 = v.parse().map_err(|_| "per_ip_rate must be an integer")?;
+        limits.per_ip_rate = (rate > 0).then_some(rate);
+    }
+    *state.limits.lock().unwrap() = limits;
+    Ok(limits)
+}
+
+fn limits_json(limits: &ServerLimits) -> String {
+    json::Object::new()
+        .u64("idle_timeout_secs", limits.idle_timeout.as_secs())
+        .u64("max_clients", limits.max_clients as u64)
+        .u64("max_rate", limits.max_rate.into())
+        .raw(
+            "per_ip_rate",
+            &limits
+                .per_ip_rate
+                .map_or_else(|| "null".to_string(), |rate| rate.to_string()),
+        )
+        .finish()
+}
+
+fn status_json(state: &ServerState) -> String {
+    json::Object::new()
+        .f64("uptime_secs", state.started.elapsed().as_secs_f64())
+        .u64("epoch", state.epoch.into())
+        .u64(
+            "clients",
+            state
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().clients.len() as u64)
+                .sum(),
+        )
+        .u64("packets_reflected", state.reflected.load(Ordering::Relaxed))
+        .u64(
+            "packets_rate_limited",
+            state.rate_limited.load(Ordering::Relaxed),
+        )
+        .u64("clients_served", state.served.load(Ordering::Relaxed))
+        .u64("clients_expired", state.expired.load(Ordering::Relaxed))
+        .u64("clients_evicted", state.evicted.load(Ordering::Relaxed))
+        .raw("limits", &limits_json(&state.limits.lock().unwrap()))
+        .finish()
+}
+
+fn clients_json(state: &ServerState) -> String {
+    // a shard at a time, so workers wait for one at most
+    json::array(state.shards.iter().flat_map(|shard| {
+        let shard = shard.lock().unwrap();
+        let clients = shard.clients.iter();
+        clients
+            .map(|(&id, client)| client_json(id, client))
+            .collect::<Vec<_>>()
+    }))
+}
+
+fn client_json(id: u32, client: &ClientEntry) -> String {
+    json::Object::new()
+        .u64("id", id.into())
+        .str("addr", &client.addr.to_string())
+        .u64("received", client.received)
+        .u64("duplicates", client.duplicates.into())
+        .u64("corrupted", client.corrupted.into())
+        .raw(
+            "packets_per_second",
+            &client
+                .packets_per_second
+                .map_or_else(|| "null".to_string(), |rate| rate.to_string()),
+        )
+        .raw(
+            "stream",
+            &client.stream.map_or_else(
+                || "null".to_string(),
+                |stream| {
+                    json::Object::new()
+                        .u64("sent", stream.sent)
+                        .u64("acked", stream.acked)
+                        .finish()
+                },
+            ),
+        )
+        .f64("connected_secs", client.first_seen.elapsed().as_secs_f64())
+        .f64("idle_secs", client.last_seen.elapsed().as_secs_f64())
+        .finish()
+}
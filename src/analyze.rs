@@ -0,0 +1,700 @@
+//! Offline analysis of measurement history, as kept in the JSON lines of
+//! `client --ndjson` or of a collector's store file: loss and outages by
+//! hour of day and day of week, which per-run numbers hide, e.g. congestion
+//! every evening.
+//!
+//! Both carry cumulative [`Stats`](crate::Stats) with a timestamp. What was
+//! sent and received between consecutive snapshots of a run counts for the
+//! hour of the later one; a run starts over where the counts drop. Hours
+//! are local time on Unix and UTC elsewhere.
+
+use std::{cmp::Reverse, fmt, time::Duration};
+
+use crate::{
+    client::labels_json,
+    json::{self, Value},
+    stats::Outage,
+};
+
+pub const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+/// What times are given in
+#[cfg(unix)]
+pub const TIME_ZONE: &str = "local time";
+#[cfg(not(unix))]
+pub const TIME_ZONE: &str = "UTC";
+/// Upper loss bounds in percent of the heatmap shades, lightest first
+const SHADES: [(f64, char); 4] = [(0.1, '░'), (1.0, '▒'), (5.0, '▓'), (f64::INFINITY, '█')];
+/// Hours listed as the worst ones
+const WORST_HOURS: usize = 5;
+
+/// A snapshot of a run's cumulative stats.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    /// Probed host, or probe and host for a collector's reports
+    pub source: String,
+    /// See [`ClientConfig::labels`](crate::ClientConfig::labels)
+    pub labels: Vec<(String, String)>,
+    pub unix_secs: f64,
+    /// Time into the run
+    pub elapsed: Duration,
+    pub sent: u64,
+    pub received: u64,
+    pub outages: Vec<Outage>,
+    /// Over the run so far, if any ACKs arrived
+    pub rtt: Option<Percentiles>,
+    /// See [`Stats::smoothed_rtt`](crate::Stats::smoothed_rtt), if known
+    pub smoothed_rtt: Option<Duration>,
+    /// Since the previous snapshot
+    pub max_gap: Duration,
+}
+
+/// Round-trip time percentiles as serialized by
+/// [`Histogram::to_json`](crate::Histogram::to_json).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    fn to_json(self) -> String {
+        json::Object::new()
+            .f64("p50_ms", self.p50.as_secs_f64() * 1000.0)
+            .f64("p95_ms", self.p95.as_secs_f64() * 1000.0)
+            .f64("p99_ms", self.p99.as_secs_f64() * 1000.0)
+            .f64("max_ms", self.max.as_secs_f64() * 1000.0)
+            .finish()
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        if value.get("count")?.as_f64()? == 0.0 {
+            return None;
+        }
+        let ms = |key| {
+            let ms = value.get(key)?.as_f64()?;
+            Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0))
+        };
+        Some(Self {
+            p50: ms("p50_ms")?,
+            p95: ms("p95_ms")?,
+            p99: ms("p99_ms")?,
+            max: ms("max_ms")?,
+        })
+    }
+}
+
+impl Sample {
+    /// A line of `client --ndjson` of kind `stats` or `summary`, or a line
+    /// of a collector's store; `None` for other lines, like events.
+    fn from_json(value: &Value) -> Option<Self> {
+        let (source, unix_secs, stats) = match value.get("received_unix") {
+            Some(at) => (
+                format!(
+                    "{} → {}",
+                    value.get("probe")?.as_str()?,
+                    value.get("target")?.as_str()?
+                ),
+                at.as_f64()?,
+                value.get("stats")?,
+            ),
+            None => {
+                let kind = value.get("kind")?.as_str()?;
+                if !matches!(kind, "stats" | "summary") {
+                    return None;
+                }
+                (
+                    value.get("host")?.as_str()?.to_string(),
+                    value.get("unix_secs")?.as_f64()?,
+                    value.get(kind)?,
+                )
+            }
+        };
+        let labels = match value.get("labels") {
+            Some(Value::Object(members)) => members
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let secs = |value: &Value, key| {
+            value
+                .get(key)
+                .and_then(Value::as_f64)
+                .map(|secs| Duration::from_secs_f64(secs.max(0.0)))
+        };
+        let outages = match stats.get("outages") {
+            Some(Value::Array(outages)) => outages
+                .iter()
+                .filter_map(|outage| {
+                    Some(Outage {
+                        start: secs(outage, "start_secs")?,
+                        duration: secs(outage, "duration_secs")?,
+                    })
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(Self {
+            source,
+            labels,
+            unix_secs,
+            elapsed: secs(stats, "elapsed_secs")?,
+            sent: stats.get("client_sent")?.as_f64()? as u64,
+            received: stats.get("client_received")?.as_f64()? as u64,
+            outages,
+            rtt: stats
+                .get("rtt_percentiles")
+                .and_then(Percentiles::from_json),
+            // NaN, so null, until the first second is over
+            smoothed_rtt: stats
+                .get("smoothed_loss")
+                .and_then(Value::as_f64)
+                .and_then(|_| {
+                    Some(Duration::from_secs_f64(
+                        stats.get("smoothed_rtt_ms")?.as_f64()?.max(0.0) / 1000.0,
+                    ))
+                }),
+            max_gap: stats
+                .get("max_gap_ms")
+                .and_then(Value::as_f64)
+                .map_or(Duration::ZERO, |ms| {
+                    Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+                }),
+        })
+    }
+}
+
+/// The samples in JSON lines `text`, skipping lines that aren't any and
+/// failing on ones that aren't JSON.
+pub fn read_samples(text: &str) -> eyre::Result<Vec<Sample>> {
+    let mut samples = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value = json::parse(line).map_err(|e| eyre::eyre!("line {}: {e}", i + 1))?;
+        samples.extend(Sample::from_json(&value));
+    }
+    Ok(samples)
+}
+
+/// Probes and outages of one hour of the week.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cell {
+    pub sent: u64,
+    pub received: u64,
+    pub outages: u64,
+}
+
+impl Cell {
+    /// Round-trip loss in percent, NaN without probes.
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            f64::NAN
+        } else {
+            100.0 * (1.0 - self.received as f64 / self.sent as f64).max(0.0)
+        }
+    }
+
+    fn add(&mut self, other: &Cell) {
+        self.sent += other.sent;
+        self.received += other.received;
+        self.outages += other.outages;
+    }
+
+    /// `object` with the cell's members added.
+    fn json(&self, object: json::Object) -> json::Object {
+        object
+            .u64("sent", self.sent)
+            .u64("received", self.received)
+            .f64("loss", self.loss())
+            .u64("outages", self.outages)
+    }
+}
+
+/// Probes sent between two snapshots of a run, or from its start to the
+/// first one.
+#[derive(Clone, Copy, Debug)]
+pub struct Interval {
+    /// Index into [`History::sources`]
+    pub source: usize,
+    pub start_unix: f64,
+    pub end_unix: f64,
+    pub sent: u64,
+    pub received: u64,
+    /// Smoothed round-trip time at its end, if known
+    pub rtt: Option<Duration>,
+    /// Largest gap between ACKs
+    pub max_gap: Duration,
+}
+
+/// An outage of a source, with when it started.
+#[derive(Clone, Copy, Debug)]
+pub struct TimedOutage {
+    /// Index into [`History::sources`]
+    pub source: usize,
+    pub start_unix: f64,
+    pub duration: Duration,
+}
+
+/// One probed host, or one probe's host in a collector's reports.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub name: String,
+    /// Of its latest sample
+    pub labels: Vec<(String, String)>,
+    pub runs: usize,
+    pub first_unix: f64,
+    pub last_unix: f64,
+    pub total: Cell,
+    /// Of its latest run
+    pub rtt: Option<Percentiles>,
+}
+
+/// The samples of any number of sources and runs, taken apart into the
+/// intervals between them.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    /// By name
+    pub sources: Vec<Source>,
+    /// By source, then time
+    pub intervals: Vec<Interval>,
+    /// By source, then time
+    pub outages: Vec<TimedOutage>,
+}
+
+impl History {
+    pub fn from_samples(samples: &[Sample]) -> Self {
+        let mut samples: Vec<&Sample> = samples.iter().collect();
+        samples.sort_by(|a, b| {
+            a.source
+                .cmp(&b.source)
+                .then(a.unix_secs.total_cmp(&b.unix_secs))
+        });
+        let mut history = Self::default();
+        let mut prev: Option<&Sample> = None;
+        for sample in samples {
+            let run_start = sample.unix_secs - sample.elapsed.as_secs_f64();
+            if prev.is_none_or(|prev| prev.source != sample.source) {
+                history.sources.push(Source {
+                    name: sample.source.clone(),
+                    labels: Vec::new(),
+                    runs: 0,
+                    first_unix: run_start,
+                    last_unix: sample.unix_secs,
+                    total: Cell::default(),
+                    rtt: None,
+                });
+            }
+            let same_run = prev.filter(|prev| {
+                prev.source == sample.source
+                    && prev.sent <= sample.sent
+                    && prev.elapsed <= sample.elapsed
+            });
+            let index = history.sources.len() - 1;
+            let source = &mut history.sources[index];
+            let interval = match same_run {
+                Some(prev) => Interval {
+                    source: index,
+                    start_unix: prev.unix_secs,
+                    end_unix: sample.unix_secs,
+                    sent: sample.sent - prev.sent,
+                    received: sample.received.saturating_sub(prev.received),
+                    rtt: sample.smoothed_rtt,
+                    max_gap: sample.max_gap,
+                },
+                None => {
+                    source.runs += 1;
+                    Interval {
+                        source: index,
+                        start_unix: run_start,
+                        end_unix: sample.unix_secs,
+                        sent: sample.sent,
+                        received: sample.received,
+                        rtt: sample.smoothed_rtt,
+                        max_gap: sample.max_gap,
+                    }
+                }
+            };
+            source.labels.clone_from(&sample.labels);
+            source.rtt = sample.rtt;
+            source.last_unix = sample.unix_secs;
+            source.total.sent += interval.sent;
+            source.total.received += interval.received;
+            let known_outages = same_run.map_or(0, |prev| prev.outages.len());
+            for outage in sample.outages.iter().skip(known_outages) {
+                source.total.outages += 1;
+                history.outages.push(TimedOutage {
+                    source: index,
+                    start_unix: run_start + outage.start.as_secs_f64(),
+                    duration: outage.duration,
+                });
+            }
+            history.intervals.push(interval);
+            prev = Some(sample);
+        }
+        history
+    }
+
+    pub fn total(&self) -> Cell {
+        let mut total = Cell::default();
+        self.sources
+            .iter()
+            .for_each(|source| total.add(&source.total));
+        total
+    }
+
+    /// The `n` longest outages, in the order they happened.
+    pub fn longest_outages(&self, n: usize) -> Vec<&TimedOutage> {
+        let mut outages: Vec<_> = self.outages.iter().collect();
+        outages.sort_by_key(|outage| Reverse(outage.duration));
+        outages.truncate(n);
+        outages.sort_by(|a, b| a.start_unix.total_cmp(&b.start_unix));
+        outages
+    }
+
+    /// When the earliest run started and the latest snapshot was taken.
+    pub fn span(&self) -> Option<(f64, f64)> {
+        let first = self
+            .sources
+            .iter()
+            .map(|s| s.first_unix)
+            .min_by(f64::total_cmp)?;
+        let last = self
+            .sources
+            .iter()
+            .map(|s| s.last_unix)
+            .max_by(f64::total_cmp)?;
+        Some((first, last))
+    }
+
+    /// Everything computed, as fed to report templates: the span as `from`
+    /// and `to` in [`TIME_ZONE`] and as `from_unix` and `to_unix`, the
+    /// `total`, the `sources`, loss by `hours` of the day and `days` of the
+    /// week, the `worst_hours` and all `outages`, with counts of probes as
+    /// `sent`, `received`, `loss` and `outages` throughout.
+    pub fn to_json(&self) -> String {
+        let (first, last) = self.span().unwrap_or_default();
+        let time_of_day = TimeOfDay::from_history(self);
+        let time = |unix_secs| LocalTime::at(unix_secs).to_string();
+        json::Object::new()
+            .str("from", &time(first))
+            .str("to", &time(last))
+            .f64("from_unix", first)
+            .f64("to_unix", last)
+            .str("time_zone", TIME_ZONE)
+            .raw("total", &self.total().json(json::Object::new()).finish())
+            .raw(
+                "sources",
+                &json::array(self.sources.iter().map(|source| {
+                    source
+                        .total
+                        .json(
+                            json::Object::new()
+                                .str("name", &source.name)
+                                .raw("labels", &labels_json(&source.labels))
+                                .u64("runs", source.runs as u64)
+                                .str("from", &time(source.first_unix))
+                                .str("to", &time(source.last_unix)),
+                        )
+                        .raw(
+                            "rtt",
+                            &source
+                                .rtt
+                                .map_or_else(|| "null".to_string(), Percentiles::to_json),
+                        )
+                        .finish()
+                })),
+            )
+            .raw(
+                "hours",
+                &json::array(
+                    time_of_day
+                        .by_hour()
+                        .iter()
+                        .enumerate()
+                        .map(|(hour, cell)| {
+                            cell.json(json::Object::new().u64("hour", hour as u64))
+                                .finish()
+                        }),
+                ),
+            )
+            .raw(
+                "days",
+                &json::array(
+                    DAYS.iter()
+                        .zip(time_of_day.by_day())
+                        .map(|(day, cell)| cell.json(json::Object::new().str("day", day)).finish()),
+                ),
+            )
+            .raw(
+                "worst_hours",
+                &json::array(
+                    time_of_day
+                        .worst_hours()
+                        .into_iter()
+                        .map(|(day, hour, cell)| {
+                            cell.json(
+                                json::Object::new()
+                                    .str("day", DAYS[day])
+                                    .u64("hour", hour as u64),
+                            )
+                            .finish()
+                        }),
+                ),
+            )
+            .raw(
+                "outages",
+                &json::array(self.outages.iter().map(|outage| {
+                    json::Object::new()
+                        .str("start", &time(outage.start_unix))
+                        .f64("start_unix", outage.start_unix)
+                        .f64("duration_secs", outage.duration.as_secs_f64())
+                        .str("source", &self.sources[outage.source].name)
+                        .finish()
+                })),
+            )
+            .finish()
+    }
+}
+
+/// Loss and outages by day of week, Monday first, and hour of day.
+#[derive(Clone, Debug, Default)]
+pub struct TimeOfDay {
+    pub cells: [[Cell; 24]; 7],
+}
+
+impl TimeOfDay {
+    /// Probes count for the hour their interval ended in, outages for the
+    /// one they started in.
+    pub fn from_history(history: &History) -> Self {
+        let mut report = Self::default();
+        for interval in &history.intervals {
+            let cell = report.cell_mut(interval.end_unix);
+            cell.sent += interval.sent;
+            cell.received += interval.received;
+        }
+        for outage in &history.outages {
+            report.cell_mut(outage.start_unix).outages += 1;
+        }
+        report
+    }
+
+    fn cell_mut(&mut self, unix_secs: f64) -> &mut Cell {
+        let time = LocalTime::at(unix_secs);
+        &mut self.cells[time.weekday][time.hour]
+    }
+
+    pub fn total(&self) -> Cell {
+        let mut total = Cell::default();
+        self.cells.iter().flatten().for_each(|cell| total.add(cell));
+        total
+    }
+
+    pub fn by_hour(&self) -> [Cell; 24] {
+        let mut hours = [Cell::default(); 24];
+        for day in &self.cells {
+            for (total, cell) in hours.iter_mut().zip(day) {
+                total.add(cell);
+            }
+        }
+        hours
+    }
+
+    pub fn by_day(&self) -> [Cell; 7] {
+        let mut days = [Cell::default(); 7];
+        for (total, day) in days.iter_mut().zip(&self.cells) {
+            day.iter().for_each(|cell| total.add(cell));
+        }
+        days
+    }
+
+    /// The hours of the week with the most loss, as day, hour and cell.
+    pub fn worst_hours(&self) -> Vec<(usize, usize, Cell)> {
+        let mut hours: Vec<(usize, usize, Cell)> = (0..7)
+            .flat_map(|day| (0..24).map(move |hour| (day, hour)))
+            .map(|(day, hour)| (day, hour, self.cells[day][hour]))
+            .filter(|(.., cell)| cell.sent > 0 && (cell.loss() > 0.0 || cell.outages > 0))
+            .collect();
+        hours.sort_by(|a, b| {
+            b.2.loss()
+                .total_cmp(&a.2.loss())
+                .then(b.2.outages.cmp(&a.2.outages))
+        });
+        hours.truncate(WORST_HOURS);
+        hours
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        writeln!(
+            f,
+            "{} probes, {:.3}% round-trip loss, {} outages",
+            total.sent,
+            total.loss(),
+            total.outages
+        )?;
+        writeln!(
+            f,
+            "Loss by day and hour ({TIME_ZONE}): · none, ░ <0.1%, ▒ <1%, ▓ <5%, █ 5% or more"
+        )?;
+        write!(f, "   ")?;
+        for hour in 0..24 {
+            write!(f, " {hour:02}")?;
+        }
+        writeln!(f)?;
+        for (day, hours) in DAYS.iter().zip(&self.cells) {
+            write!(f, "{day}")?;
+            for cell in hours {
+                let shade = match cell.loss() {
+                    loss if loss.is_nan() => ' ',
+                    0.0 => '·',
+                    loss => SHADES.iter().find(|(max, _)| loss < *max).unwrap().1,
+                };
+                write!(f, "  {shade}")?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "Outages")?;
+        for cell in self.by_hour() {
+            match cell.outages {
+                0 => write!(f, "   ")?,
+                n => write!(f, "{n:>3}")?,
+            }
+        }
+        writeln!(f)?;
+        write!(f, "By day:")?;
+        for (day, cell) in DAYS.iter().zip(self.by_day()) {
+            if cell.sent > 0 {
+                write!(f, "  {day} {:.2}%", cell.loss())?;
+            }
+        }
+        writeln!(f)?;
+        let worst = self.worst_hours();
+        if !worst.is_empty() {
+            writeln!(f, "Worst hours:")?;
+            for (day, hour, cell) in worst {
+                writeln!(
+                    f,
+                    "  {} {hour:02}:00  {:.2}% of {} probes, outages: {}",
+                    DAYS[day],
+                    cell.loss(),
+                    cell.sent,
+                    cell.outages
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A point in time in [`TIME_ZONE`], to the second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    /// Monday first
+    pub weekday: usize,
+    pub hour: usize,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl LocalTime {
+    #[cfg(unix)]
+    pub fn at(unix_secs: f64) -> Self {
+        let time = unix_secs.floor() as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return Self::utc(unix_secs.floor() as i64);
+        }
+        Self {
+            year: i64::from(tm.tm_year) + 1900,
+            month: tm.tm_mon as u32 + 1,
+            day: tm.tm_mday as u32,
+            weekday: ((tm.tm_wday + 6) % 7) as usize,
+            hour: tm.tm_hour as usize,
+            minute: tm.tm_min as u32,
+            second: tm.tm_sec as u32,
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn at(unix_secs: f64) -> Self {
+        Self::utc(unix_secs.floor() as i64)
+    }
+
+    /// The same in UTC, whatever [`TIME_ZONE`] is.
+    pub fn utc(unix_secs: i64) -> Self {
+        let days = unix_secs.div_euclid(86_400);
+        let secs = unix_secs.rem_euclid(86_400);
+        // civil from days, after Howard Hinnant's algorithm
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        Self {
+            year: yoe + era * 400 + i64::from(month <= 2),
+            month: month as u32,
+            day: (doy - (153 * mp + 2) / 5 + 1) as u32,
+            // 1970-01-01 was a Thursday
+            weekday: (days + 3).rem_euclid(7) as usize,
+            hour: (secs / 3600) as usize,
+            minute: (secs / 60 % 60) as u32,
+            second: (secs % 60) as u32,
+        }
+    }
+}
+
+impl fmt::Display for LocalTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_count_their_differences_and_outages_once() {
+        let lines = [
+            r#"{"kind":"stats","host":"a","unix_secs":1000,"stats":{"client_sent":100,"client_received":100,"elapsed_secs":10,"outages":[]}}"#,
+            r#"{"kind":"event","host":"a","unix_secs":1001,"event":{"type":"outage_start"}}"#,
+            r#"{"kind":"stats","host":"a","unix_secs":1010,"stats":{"client_sent":200,"client_received":150,"elapsed_secs":20,"outages":[{"start_secs":12,"duration_secs":5}]}}"#,
+            r#"{"kind":"summary","host":"a","unix_secs":1011,"summary":{"client_sent":200,"client_received":150,"elapsed_secs":21,"outages":[{"start_secs":12,"duration_secs":5}]}}"#,
+            // a new run
+            r#"{"kind":"stats","host":"a","unix_secs":5000,"stats":{"client_sent":50,"client_received":50,"elapsed_secs":5,"outages":[]}}"#,
+            r#"{"received_unix":1005,"probe":"p","target":"b","stats":{"client_sent":10,"client_received":9,"elapsed_secs":1,"outages":[{"start_secs":0.5,"duration_secs":2}]}}"#,
+        ];
+        let samples = read_samples(&lines.join("\n")).unwrap();
+        assert_eq!(samples.len(), 5);
+        let history = History::from_samples(&samples);
+        assert_eq!(history.sources.len(), 2);
+        assert_eq!(history.sources[0].runs, 2);
+        assert_eq!(
+            TimeOfDay::from_history(&history).total(),
+            Cell {
+                sent: 260,
+                received: 209,
+                outages: 2
+            }
+        );
+        assert_eq!(LocalTime::utc(0).weekday, 3);
+        let time = LocalTime::utc(1_792_094_459);
+        assert_eq!(time.to_string(), "2026-10-15 20:00:59");
+        assert_eq!(time.weekday, 3);
+    }
+}
@@ -0,0 +1,99 @@
+//! Pre-shared key authentication: with a key configured, every packet ends
+//! in a truncated HMAC-SHA-256 of the rest of it, and packets without a valid
+//! one are dropped. Servers with a key only answer clients that have it, and
+//! clients only count ACKs that really came from the server.
+//!
+//! Packets aren't hidden and replays aren't detected. Multicast streams and
+//! mDNS are left unauthenticated.
+
+use std::{borrow::Cow, fmt};
+
+use crate::hash::{hmac_sha256, sha256};
+
+/// Bytes the tag adds to every packet
+pub const TAG_SIZE: usize = 8;
+
+/// Key shared by client and server, derived from a passphrase.
+#[derive(Clone)]
+pub struct Psk([u8; 32]);
+
+impl Psk {
+    pub fn new(passphrase: &str) -> Self {
+        Self(sha256(passphrase.as_bytes()))
+    }
+
+    fn tag(&self, data: &[u8]) -> [u8; TAG_SIZE] {
+        hmac_sha256(&self.0, data)[..TAG_SIZE].try_into().unwrap()
+    }
+}
+
+// never print the key
+impl fmt::Debug for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Psk(..)")
+    }
+}
+
+/// `packet` as sent, with the tag appended if there's a key.
+pub(crate) fn seal<'a>(psk: Option<&Psk>, packet: &'a [u8]) -> Cow<'a, [u8]> {
+    match psk {
+        Some(psk) => {
+            let mut sealed = Vec::with_capacity(packet.len() + TAG_SIZE);
+            sealed.extend_from_slice(packet);
+            sealed.extend_from_slice(&psk.tag(packet));
+            Cow::Owned(sealed)
+        }
+        None => Cow::Borrowed(packet),
+    }
+}
+
+/// Length of a received `packet` without its tag, `None` if there's a key
+/// and the tag is missing or wrong.
+pub(crate) fn open(psk: Option<&Psk>, packet: &[u8]) -> Option<usize> {
+    let Some(psk) = psk else {
+        return Some(packet.len());
+    };
+    let len = packet.len().checked_sub(TAG_SIZE)?;
+    let (data, tag) = packet.split_at(len);
+    // constant time, so the tag can't be guessed byte by byte
+    let diff = psk
+        .tag(data)
+        .iter()
+        .zip(tag)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    (diff == 0).then_some(len)
+}
+
+/// Size of the tag `psk` adds.
+pub(crate) fn overhead(psk: Option<&Psk>) -> usize {
+    if psk.is_some() {
+        TAG_SIZE
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_intact_packets_under_the_same_key_open() {
+        let psk = Psk::new("correct horse");
+        let packet = b"probe 42";
+        let sealed = seal(Some(&psk), packet);
+        assert_eq!(sealed.len(), packet.len() + TAG_SIZE);
+        assert_eq!(open(Some(&psk), &sealed), Some(packet.len()));
+        assert_eq!(open(Some(&Psk::new("battery staple")), &sealed), None);
+        for i in 0..sealed.len() {
+            let mut flipped = sealed.to_vec();
+            flipped[i] ^= 1;
+            assert_eq!(open(Some(&psk), &flipped), None, "byte {i} flipped");
+        }
+        assert_eq!(open(Some(&psk), &sealed[..TAG_SIZE - 1]), None);
+        assert_eq!(open(Some(&psk), &[]), None);
+        // without a key nothing is added or checked
+        assert_eq!(seal(None, packet), &packet[..]);
+        assert_eq!(open(None, packet), Some(packet.len()));
+    }
+}
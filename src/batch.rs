@@ -0,0 +1,252 @@
+//! Batched UDP I/O: on Linux up to [`BATCH_SIZE`] packets per `recvmmsg` or
+//! `sendmmsg` call, so per-packet syscalls don't dominate at high probe
+//! rates or with many clients. Elsewhere packets go one call each, behind
+//! the same interface.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, UdpSocket},
+    ops::Range,
+};
+
+#[cfg(not(target_os = "linux"))]
+use crate::ecn;
+#[cfg(target_os = "linux")]
+use crate::BUF_SIZE;
+
+/// Packets moved per syscall at most
+pub(crate) const BATCH_SIZE: usize = 16;
+
+/// Receives like `ecn::recv_from`, reading
+/// whatever else has arrived along with a packet in the same call and
+/// handing it out on the next ones.
+pub(crate) struct BatchReceiver {
+    /// A buffer of `BUF_SIZE` per packet of a batch
+    #[cfg(target_os = "linux")]
+    bufs: Vec<u8>,
+    /// Packets read but not yet handed out: buffer index, length, source
+    /// and TOS byte
+    pending: VecDeque<(usize, usize, SocketAddr, Option<u8>)>,
+}
+
+impl BatchReceiver {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(target_os = "linux")]
+            bufs: vec![0; BATCH_SIZE * BUF_SIZE],
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Whether the next call reads from the socket, and so may block.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// The next packet into `buf`, truncated to fit, with its length, source
+    /// and TOS byte if reported.
+    #[cfg(target_os = "linux")]
+    pub fn recv_from(
+        &mut self,
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+        if self.pending.is_empty() {
+            self.fill(socket)?;
+        }
+        let (index, len, addr, tos) = self.pending.pop_front().unwrap();
+        let n = len.min(buf.len());
+        buf[..n].copy_from_slice(&self.packet(index)[..n]);
+        Ok((n, addr, tos))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn packet(&self, index: usize) -> &[u8] {
+        &self.bufs[index * BUF_SIZE..(index + 1) * BUF_SIZE]
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fill(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        use std::{mem, os::fd::AsRawFd, ptr};
+
+        let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        // u64 for the alignment of cmsghdr
+        let mut controls = [[0u64; 8]; BATCH_SIZE];
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (iov, buf) in iovs.iter_mut().zip(self.bufs.chunks_exact_mut(BUF_SIZE)) {
+            iov.iov_base = buf.as_mut_ptr().cast();
+            iov.iov_len = buf.len();
+        }
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (i, msg) in msgs.iter_mut().enumerate() {
+            let hdr = &mut msg.msg_hdr;
+            hdr.msg_name = (&mut addrs[i] as *mut libc::sockaddr_storage).cast();
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = controls[i].as_mut_ptr().cast();
+            hdr.msg_controllen = mem::size_of_val(&controls[i]) as _;
+        }
+        // blocks, up to the read timeout, only for the first
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                BATCH_SIZE as _,
+                libc::MSG_WAITFORONE as _,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (i, msg) in msgs.iter().take(n as usize).enumerate() {
+            // skipped like any packet of an unexpected family
+            if let Ok(addr) = crate::ecn::socket_addr(&addrs[i]) {
+                let tos = crate::ecn::received_tos(&msg.msg_hdr);
+                self.pending.push_back((i, msg.msg_len as usize, addr, tos));
+            }
+        }
+        if self.pending.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+
+    /// Without `recvmmsg` a packet per call, straight into `buf`.
+    #[cfg(not(target_os = "linux"))]
+    pub fn recv_from(
+        &mut self,
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+        ecn::recv_from(socket, buf)
+    }
+}
+
+/// Answers queued while a batch is handled, sent together with
+/// [`BatchSender::flush`].
+#[derive(Default)]
+pub(crate) struct BatchSender {
+    data: Vec<u8>,
+    packets: Vec<(Range<usize>, SocketAddr)>,
+}
+
+impl BatchSender {
+    pub fn push(&mut self, packet: &[u8], addr: SocketAddr) {
+        let start = self.data.len();
+        self.data.extend_from_slice(packet);
+        self.packets.push((start..self.data.len(), addr));
+    }
+
+    /// Send the queued packets, passing failed ones to `failed`.
+    pub fn flush(&mut self, socket: &UdpSocket, mut failed: impl FnMut(SocketAddr, &io::Error)) {
+        let mut sent = 0;
+        while sent < self.packets.len() {
+            let end = self.packets.len().min(sent + BATCH_SIZE);
+            match self.send(socket, sent..end) {
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // the first one failed, the rest may yet go
+                Err(e) => {
+                    failed(self.packets[sent].1, &e);
+                    sent += 1;
+                }
+            }
+        }
+        self.data.clear();
+        self.packets.clear();
+    }
+
+    /// Send some of `packets`, returning how many, at least one.
+    #[cfg(target_os = "linux")]
+    fn send(&self, socket: &UdpSocket, packets: Range<usize>) -> io::Result<usize> {
+        use std::{mem, os::fd::AsRawFd};
+
+        let packets = &self.packets[packets];
+        let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (i, (range, addr)) in packets.iter().enumerate() {
+            let data = &self.data[range.clone()];
+            iovs[i] = libc::iovec {
+                iov_base: data.as_ptr().cast_mut().cast(),
+                iov_len: data.len(),
+            };
+            let hdr = &mut msgs[i].msg_hdr;
+            hdr.msg_name = (&mut addrs[i] as *mut libc::sockaddr_storage).cast();
+            hdr.msg_namelen = write_sockaddr(*addr, &mut addrs[i]);
+            hdr.msg_iov = &mut iovs[i];
+            hdr.msg_iovlen = 1;
+        }
+        let n =
+            unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), packets.len() as _, 0) };
+        match n {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            0 => Err(io::ErrorKind::WriteZero.into()),
+            n => Ok(n as usize),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send(&self, socket: &UdpSocket, packets: Range<usize>) -> io::Result<usize> {
+        let (range, addr) = &self.packets[packets.start];
+        socket.send_to(&self.data[range.clone()], addr)?;
+        Ok(1)
+    }
+}
+
+/// Write `addr` to `storage` as the system's socket address, returning its
+/// length.
+#[cfg(target_os = "linux")]
+pub(crate) fn write_sockaddr(
+    addr: SocketAddr,
+    storage: &mut libc::sockaddr_storage,
+) -> libc::socklen_t {
+    use std::mem;
+
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = addr.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = addr.port().to_be();
+            sockaddr.sin6_flowinfo = addr.flowinfo();
+            sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+            sockaddr.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_arrive_in_order_and_whole() {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = rx.local_addr().unwrap();
+        let mut sender = BatchSender::default();
+        for i in 0..BATCH_SIZE as u8 + 3 {
+            sender.push(&vec![i; usize::from(i) + 1], to);
+        }
+        sender.flush(&tx, |addr, e| panic!("sending to {addr}: {e}"));
+        let mut receiver = BatchReceiver::new();
+        let mut buf = [0; 64];
+        for i in 0..BATCH_SIZE as u8 + 3 {
+            let (n, from, _) = receiver.recv_from(&rx, &mut buf).unwrap();
+            assert_eq!(from, tx.local_addr().unwrap());
+            assert_eq!(&buf[..n], &vec![i; usize::from(i) + 1][..]);
+        }
+        assert!(receiver.is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+//! Bottleneck capacity estimation from packet trains: a few packets sent back
+//! to back leave the narrowest link spaced by its serialization time, so the
+//! dispersion of the server's receive timestamps gives its capacity.
+//!
+//! Train packets are `[TRAIN, train_id, client_id, index, length]` followed by
+//! padding. The server answers each with `[TRAIN_ACK, train_id, index,
+//! rx_micros]`, where `rx_micros` is its own clock; only differences within a
+//! train are used, so the clocks needn't agree.
+
+use std::collections::VecDeque;
+
+use crate::stats::HEADER_OVERHEAD;
+
+pub(crate) const TRAIN_PACKET_CONST: u8 = 6;
+pub(crate) const TRAIN_ACK_PACKET_CONST: u8 = 7;
+pub(crate) const TRAIN_ACK_SIZE: usize = 1 + 4 + 1 + 8;
+
+/// Per-train estimates the reported median is taken over
+const ESTIMATES: usize = 9;
+
+/// Write the header of train packet `index` of `length` into `buf`.
+pub(crate) fn train_packet(buf: &mut [u8], train_id: u32, client_id: u32, index: u8, length: u8) {
+    buf[0] = TRAIN_PACKET_CONST;
+    buf[1..5].copy_from_slice(&train_id.to_be_bytes());
+    buf[5..9].copy_from_slice(&client_id.to_be_bytes());
+    buf[9] = index;
+    buf[10] = length;
+}
+
+/// Server reply to train packet `index` received at `rx_micros`.
+pub(crate) fn train_ack(train_id: u32, index: u8, rx_micros: u64) -> [u8; TRAIN_ACK_SIZE] {
+    let mut ack = [0u8; TRAIN_ACK_SIZE];
+    ack[0] = TRAIN_ACK_PACKET_CONST;
+    ack[1..5].copy_from_slice(&train_id.to_be_bytes());
+    ack[5] = index;
+    ack[6..14].copy_from_slice(&rx_micros.to_be_bytes());
+    ack
+}
+
+/// Collects train ACKs on the client and turns them into estimates.
+pub(crate) struct Estimator {
+    length: usize,
+    /// Bits on the wire per train packet
+    packet_bits: f64,
+    train_id: Option<u32>,
+    /// Server receive time of each packet of the current train
+    rx_micros: Vec<Option<u64>>,
+    /// Recent estimates in Mbit/s
+    estimates: VecDeque<f64>,
+}
+
+impl Estimator {
+    pub fn new(length: u8, packet_size: usize) -> Self {
+        Self {
+            length: length.into(),
+            packet_bits: ((packet_size as u64 + HEADER_OVERHEAD) * 8) as f64,
+            train_id: None,
+            rx_micros: vec![None; length.into()],
+            estimates: VecDeque::new(),
+        }
+    }
+
+    pub fn ack(&mut self, train_id: u32, index: u8, rx_micros: u64) {
+        let index = usize::from(index);
+        if index >= self.length {
+            return;
+        }
+        match self.train_id {
+            Some(current) if train_id == current => {}
+            // stragglers of a train that was already evaluated
+            Some(current) if train_id < current => return,
+            _ => {
+                self.finish();
+                self.train_id = Some(train_id);
+            }
+        }
+        self.rx_micros[index] = Some(rx_micros);
+        if self.rx_micros.iter().all(Option::is_some) {
+            self.finish();
+        }
+    }
+
+    /// Evaluate the current train with whatever arrived of it. Lost packets
+    /// in between don't matter as the spacing is per index.
+    fn finish(&mut self) {
+        let received: Vec<(usize, u64)> = self
+            .rx_micros
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, rx)| rx.take().map(|rx| (i, rx)))
+            .collect();
+        if let (Some(&(first, first_rx)), Some(&(last, last_rx))) =
+            (received.first(), received.last())
+        {
+            if last > first && last_rx > first_rx {
+                let bits = self.packet_bits * (last - first) as f64;
+                // bits per microsecond is Mbit/s
+                self.estimates.push_back(bits / (last_rx - first_rx) as f64);
+                while self.estimates.len() > ESTIMATES {
+                    self.estimates.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Median of the recent estimates in Mbit/s, which discards trains
+    /// squeezed or stretched by cross traffic.
+    pub fn mbps(&self) -> Option<f64> {
+        let mut sorted: Vec<f64> = self.estimates.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        sorted.get(sorted.len() / 2).copied()
+    }
+}
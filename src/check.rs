@@ -0,0 +1,149 @@
+//! A single bounded measurement judged against loss thresholds, reported
+//! the way Nagios, Icinga and compatible monitoring systems expect of a
+//! plugin: one line of status and performance data, and the status as exit
+//! code.
+//!
+//! ```text
+//! LOSS_LENS WARNING - 1.33% loss of 300 probes, RTT p50 12.1ms | loss=1.33%;1;5;0;100 ...
+//! ```
+
+use std::fmt;
+
+use crate::Stats;
+
+/// A plugin's status, with its exit code as value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl Status {
+    pub fn exit_code(self) -> i32 {
+        self as i32
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ok => "OK",
+            Self::Warning => "WARNING",
+            Self::Critical => "CRITICAL",
+            Self::Unknown => "UNKNOWN",
+        })
+    }
+}
+
+/// Round-trip loss in percent at which a check warns, and at which it's
+/// critical.
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    pub warn_loss: f64,
+    pub crit_loss: f64,
+}
+
+/// The outcome of a check.
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub status: Status,
+    thresholds: Thresholds,
+    stats: Stats,
+}
+
+impl Check {
+    /// Judge the final `stats` of a measurement; unknown if no probe went
+    /// out.
+    pub fn new(stats: Stats, thresholds: Thresholds) -> Self {
+        let loss = stats.round_trip_loss();
+        let status = if stats.client_sent == 0 {
+            Status::Unknown
+        } else if loss >= thresholds.crit_loss {
+            Status::Critical
+        } else if loss >= thresholds.warn_loss {
+            Status::Warning
+        } else {
+            Status::Ok
+        };
+        Self {
+            status,
+            thresholds,
+            stats,
+        }
+    }
+
+    /// The plugin output for a measurement that failed with `error`.
+    pub fn failed(error: &eyre::Report) -> String {
+        format!("LOSS_LENS {} - {error:#}", Status::Unknown)
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = &self.stats;
+        if self.status == Status::Unknown {
+            return write!(f, "LOSS_LENS {} - no probes sent", self.status);
+        }
+        let loss = stats.round_trip_loss().max(0.0);
+        let ms = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "LOSS_LENS {} - {loss:.2}% loss of {} probes",
+            self.status, stats.client_sent
+        )?;
+        if stats.rtts.count() > 0 {
+            write!(f, ", RTT p50 {:.1}ms", ms(stats.rtts.percentile(50.0)))?;
+        }
+        write!(
+            f,
+            " | loss={loss:.2}%;{};{};0;100 sent={}c received={}c outages={}",
+            self.thresholds.warn_loss,
+            self.thresholds.crit_loss,
+            stats.client_sent,
+            stats.client_received,
+            stats.outages.len()
+        )?;
+        if stats.rtts.count() > 0 {
+            write!(
+                f,
+                " rtt_p50={:.3}ms;;;0 rtt_p99={:.3}ms;;;0 jitter={:.3}ms;;;0",
+                ms(stats.rtts.percentile(50.0)),
+                ms(stats.rtts.percentile(99.0)),
+                ms(stats.jitter)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loss_picks_status_and_perfdata() {
+        let thresholds = Thresholds {
+            warn_loss: 1.0,
+            crit_loss: 5.0,
+        };
+        let stats = |received| Stats {
+            client_sent: 300,
+            client_received: received,
+            ..Stats::default()
+        };
+        assert_eq!(Check::new(stats(300), thresholds).status, Status::Ok);
+        assert_eq!(Check::new(stats(297), thresholds).status, Status::Warning);
+        let check = Check::new(stats(285), thresholds);
+        assert_eq!(check.status.exit_code(), 2);
+        assert_eq!(
+            check.to_string(),
+            "LOSS_LENS CRITICAL - 5.00% loss of 300 probes | loss=5.00%;1;5;0;100 sent=300c received=285c outages=0"
+        );
+        assert_eq!(
+            Check::new(Stats::default(), thresholds).status,
+            Status::Unknown
+        );
+    }
+}
@@ -0,0 +1,2917 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::OptionExt;
+
+use crate::{
+    auth::{self, Psk},
+    batch::BatchReceiver,
+    capacity::{self, TRAIN_ACK_SIZE},
+    ecn::{CE, ECN_MASK, ECT_0},
+    event::{Event, BURST_MIN},
+    icmp, json,
+    noise::{self, Initiator, PublicKey, Transport},
+    periodicity::LossSeries,
+    protocol::{
+        self, Header, Kind, Reply, Request, CHECKSUM_PROBE_MIN_SIZE, PADDED_PROBE_MIN_SIZE,
+    },
+    recording::{self, Recorder},
+    replay::ReplayWindow,
+    resolve, resume,
+    session::{
+        self, Resumption, Session, COOKIE_SIZE, FEATURE_CHECKSUMS, FEATURE_ECN, FEATURE_EPOCH,
+        FEATURE_HOPS, FEATURE_PAD_ACKS, FEATURE_RESUME, FEATURE_SYMMETRIC, FEATURE_TRAINS,
+        PROTOCOL_VERSION,
+    },
+    stats::{
+        EcnStats, Ewma, Histogram, HopStats, Outage, PathStats, RecentLoss, SizeStats, Stats,
+        SymmetricStats, OUTAGE_THRESHOLD,
+    },
+    stun::{self, NatReport},
+    tcp, twamp, with_version, IpVersion, LocalBind, StopHandle, BUF_SIZE,
+    CLIENT_TO_SERVER_PACKET_SIZE, DEFAULT_PACKETS_PER_SECOND, LATE_WINDOW_SECS, MAX_PACKET_SIZE,
+    NOISE_DATA_PACKET_CONST, NOISE_RESP_PACKET_CONST, SERVER_TO_CLIENT_PACKET_SIZE,
+};
+
+/// Settings for a [`ProbeClient`].
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Host to connect to
+    pub host: String,
+    /// Only connect over this IP version
+    pub ip_version: Option<IpVersion>,
+    /// Local address to probe from, e.g. to pick an uplink
+    pub source_addr: Option<IpAddr>,
+    /// Network interface to probe through regardless of the routing table;
+    /// Linux only
+    pub interface: Option<String>,
+    /// File to write the zstd-compressed receive record to
+    pub recording: Option<PathBuf>,
+    /// Probe rate, announced to the server
+    pub packets_per_second: u32,
+    /// Probe UDP payload size; anything beyond the header is padding
+    pub probe_size: usize,
+    /// Ask the server to pad ACKs to the probe size as well
+    pub pad_acks: bool,
+    /// `(size, weight)` pairs to cycle probe sizes through instead of using
+    /// `probe_size`, e.g. IMIX-style `[(64, 7), (576, 4), (1400, 1)]`. Loss is
+    /// then also reported per size.
+    pub size_mix: Vec<(usize, u32)>,
+    /// Exponentially distributed gaps between probes at the same mean rate
+    /// (RFC 2330 Poisson sampling), so probes can't alias with periodic
+    /// network behavior
+    pub poisson: bool,
+    /// Stop sending after this long
+    pub duration: Option<Duration>,
+    /// Stop sending after this many probes
+    pub count: Option<u32>,
+    /// Windows to report recent round-trip loss over, see
+    /// [`Stats::recent_loss`]
+    pub loss_windows: Vec<Duration>,
+    /// Half-life of the moving averages of loss and round-trip time, see
+    /// [`Stats::smoothed_loss`]; adaptive rate control goes by them too
+    pub half_life: Duration,
+    /// How often [`ProbeClient::run`] reports stats, ACKs or not
+    pub report_interval: Duration,
+    /// Only probe part of the time instead of continuously
+    pub duty_cycle: Option<DutyCycle>,
+    /// DiffServ code point to mark probes with
+    pub dscp: Option<u8>,
+    /// Send probes ECN-capable (ECT(0)) and count the CE marks the server
+    /// saw; needs probes of at least 10 bytes
+    pub ecn: bool,
+    /// End probes in a checksum the server verifies, so probes corrupted on
+    /// the way are counted apart from lost ones, see [`Stats::corrupted`];
+    /// needs probes of at least [`protocol::CHECKSUM_PROBE_MIN_SIZE`] bytes
+    pub checksums: bool,
+    /// Fill probes beyond the header with random bytes, different for every
+    /// probe, so links compressing payloads and middleboxes inspecting them
+    /// don't treat the otherwise repetitive probes specially
+    pub random_payload: bool,
+    /// Have the server stream probes of its own back at the same rate, which
+    /// the client answers, so downstream loss and jitter are measured on
+    /// their own rather than inferred from ACKs, whose loss would count
+    /// against both directions, see [`Stats::symmetric`]. Servers grant it
+    /// only when configured to
+    pub symmetric: bool,
+    /// Lower the rate while the link is lossy, up to `packets_per_second`
+    pub adaptive: Option<AdaptiveRate>,
+    /// Periodically estimate the upstream bottleneck capacity
+    pub packet_trains: Option<PacketTrains>,
+    /// Rotate through several source ports, reporting each separately
+    pub port_hopping: Option<PortHopping>,
+    /// Alongside the probes, trace the route and report loss and latency
+    /// per hop; Linux only
+    pub hops: Option<HopTrace>,
+    /// Store the route to the server in the recording at the start and
+    /// whenever it changes; Linux only
+    pub record_route: bool,
+    /// Before probing, ask these STUN servers, `HOST:PORT` each, for the
+    /// probe socket's external address, and tell from their answers how
+    /// NATs on the way map it, see [`crate::stun`]; the result is stored in
+    /// the recording
+    pub stun: Vec<String>,
+    /// Look up `host` again this often, and whenever the server has been
+    /// silent for a few seconds, switching over if its address changed
+    pub reresolve_every: Option<Duration>,
+    /// Servers to switch to, in order, once the one probed has been silent
+    /// for a few seconds, e.g. while `host` reboots. Earlier ones are
+    /// switched back to as soon as they answer a handshake again. Probes the
+    /// silent server left unanswered count as neither sent nor lost, see
+    /// [`Stats::failover_unanswered`]
+    pub failover: Vec<String>,
+    /// Refuse servers speaking another protocol version, or predating the
+    /// handshake, instead of probing with what both understand
+    pub strict_version: bool,
+    /// What answers the probes; anything but a loss_lens server is probed
+    /// without a handshake, and features needing the server's cooperation
+    /// are unavailable
+    pub protocol: Protocol,
+    /// Authenticate every packet with this key and drop ACKs that aren't,
+    /// for servers that require it; adds [`auth::TAG_SIZE`] bytes to each
+    pub psk: Option<Psk>,
+    /// Encrypt the session for the server with this static key, see
+    /// [`crate::noise`]; adds [`noise::OVERHEAD`] bytes to each packet
+    pub noise: Option<PublicKey>,
+    /// File to keep the session token in, so a restarted client resumes the
+    /// session if the server still knows it: the server's counts carry on,
+    /// sequence numbers continue after the last it received, and the
+    /// recording is appended to with the gap marked, see
+    /// [`Event::SessionResume`]. Stats cover the current run only
+    pub resume: Option<PathBuf>,
+    /// `key=value` tags such as `site=office`, stored in the recording and
+    /// sent along with results, so results of many probes can be told
+    /// apart and grouped. Keys are letters, digits, `.`, `_` and `-`
+    pub labels: Vec<(String, String)>,
+}
+
+/// Label keys end up in JSON and query strings, so they're kept to
+/// characters that need no escaping.
+pub(crate) fn valid_label_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 64
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Labels as a JSON object of strings.
+pub(crate) fn labels_json(labels: &[(String, String)]) -> String {
+    labels
+        .iter()
+        .fold(json::Object::new(), |object, (key, value)| {
+            object.str(key, value)
+        })
+        .finish()
+}
+
+/// Rounds of TTL-limited probes like mtr, see [`Stats::hops`].
+#[derive(Clone, Copy, Debug)]
+pub struct HopTrace {
+    /// Give up on reaching the server beyond this many hops
+    pub max_hops: u8,
+    /// Time between rounds
+    pub every: Duration,
+}
+
+impl Default for HopTrace {
+    fn default() -> Self {
+        Self {
+            max_hops: 30,
+            every: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Send from a pool of sockets in turn, each probing for `every` before the
+/// next takes over, within one session on the server. As ECMP routers pick
+/// a path per 5-tuple, per-port results show whether one path is broken.
+#[derive(Clone, Copy, Debug)]
+pub struct PortHopping {
+    /// Number of source ports, at most [`MAX_HOP_PORTS`]
+    pub ports: usize,
+    pub every: Duration,
+}
+
+/// Upper bound on [`PortHopping::ports`]
+pub const MAX_HOP_PORTS: usize = 256;
+
+/// Back-to-back packets sent every so often, whose spacing on arrival at the
+/// server gives the bottleneck capacity, see [`Stats::capacity_mbps`].
+#[derive(Clone, Copy, Debug)]
+pub struct PacketTrains {
+    pub every: Duration,
+    /// Packets per train, at least 2
+    pub length: u8,
+    /// UDP payload size of each packet; larger packets give more accurate
+    /// estimates on fast links
+    pub size: usize,
+}
+
+impl Default for PacketTrains {
+    fn default() -> Self {
+        Self {
+            every: Duration::from_secs(10),
+            length: 8,
+            size: 1200,
+        }
+    }
+}
+
+/// Back off the probe rate when loss stays above a threshold, so measuring
+/// doesn't add to congestion, and ramp it back up once loss clears.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveRate {
+    /// Round-trip loss percentage above which to back off
+    pub loss_threshold: f64,
+    /// Never go below this rate
+    pub min_rate: u32,
+}
+
+/// What a client probes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// A loss_lens server, which counts the probes it receives
+    #[default]
+    LossLens,
+    /// A TWAMP-Light reflector, see [`crate::twamp`]
+    Twamp,
+    /// A plain UDP echo service (RFC 862), which sends probes back as they
+    /// are. Without a count of the server's, loss is only known for the
+    /// round trip, see [`Stats::round_trip_only`]
+    Echo,
+    /// ICMP echo, answered by most hosts without any server, see
+    /// [`crate::icmp`]; round trip only as well
+    Icmp,
+    /// TCP connects to any listening port, for paths dropping UDP, see
+    /// [`crate::tcp`]; round trip only as well
+    Tcp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::LossLens => "loss-lens",
+            Protocol::Twamp => "twamp",
+            Protocol::Echo => "echo",
+            Protocol::Icmp => "icmp",
+            Protocol::Tcp => "tcp",
+        })
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        match s {
+            "loss-lens" => Ok(Protocol::LossLens),
+            "twamp" => Ok(Protocol::Twamp),
+            "echo" => Ok(Protocol::Echo),
+            "icmp" => Ok(Protocol::Icmp),
+            "tcp" => Ok(Protocol::Tcp),
+            _ => {
+                eyre::bail!("unknown protocol {s:?}, expected loss-lens, twamp, echo, icmp or tcp")
+            }
+        }
+    }
+}
+
+/// Probe for `active` at the start of every `period` and stay silent for the
+/// rest of it. Sequence numbers, stats and the recording simply continue
+/// across the pauses.
+#[derive(Clone, Copy, Debug)]
+pub struct DutyCycle {
+    pub active: Duration,
+    pub period: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1:13337".to_string(),
+            ip_version: None,
+            source_addr: None,
+            interface: None,
+            recording: None,
+            packets_per_second: DEFAULT_PACKETS_PER_SECOND,
+            probe_size: CLIENT_TO_SERVER_PACKET_SIZE,
+            pad_acks: false,
+            size_mix: Vec::new(),
+            poisson: false,
+            duration: None,
+            count: None,
+            loss_windows: vec![
+                Duration::from_secs(10),
+                Duration::from_secs(60),
+                Duration::from_secs(300),
+            ],
+            half_life: Duration::from_secs(5),
+            report_interval: Duration::from_secs(1),
+            duty_cycle: None,
+            dscp: None,
+            ecn: false,
+            checksums: false,
+            random_payload: false,
+            symmetric: false,
+            adaptive: None,
+            packet_trains: None,
+            port_hopping: None,
+            hops: None,
+            record_route: false,
+            stun: Vec::new(),
+            reresolve_every: None,
+            failover: Vec::new(),
+            labels: Vec::new(),
+            strict_version: false,
+            protocol: Protocol::LossLens,
+            psk: None,
+            noise: None,
+            resume: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Number of probes tracked for late arrival before counting as lost
+    pub fn late_window(&self) -> usize {
+        late_window(self.packets_per_second)
+    }
+
+    /// Size of the ACKs the server will send back for fixed-size probes.
+    pub fn ack_size(&self) -> usize {
+        // reflected packets are as long as test packets, and echoes anyway
+        if self.pad_acks || self.protocol != Protocol::LossLens {
+            self.probe_size
+        } else {
+            SERVER_TO_CLIENT_PACKET_SIZE
+        }
+    }
+
+    /// Bytes authentication and encryption add to every packet.
+    fn overhead(&self) -> usize {
+        auth::overhead(self.psk.as_ref()) + self.noise.map_or(0, |_| noise::OVERHEAD)
+    }
+
+    fn validate(&self) -> eyre::Result<()> {
+        eyre::ensure!(
+            self.packets_per_second > 0,
+            "packets per second must be positive"
+        );
+        eyre::ensure!(
+            self.dscp.is_none_or(|dscp| dscp < 64),
+            "DSCP must be below 64"
+        );
+        if let Some(adaptive) = self.adaptive {
+            eyre::ensure!(
+                (1..=self.packets_per_second).contains(&adaptive.min_rate),
+                "minimum rate must be between 1 and the probe rate"
+            );
+        }
+        if let Some(trains) = self.packet_trains {
+            eyre::ensure!(trains.length >= 2, "packet trains need at least 2 packets");
+            eyre::ensure!(
+                (TRAIN_ACK_SIZE..=MAX_PACKET_SIZE).contains(&trains.size),
+                "train packet size must be between {TRAIN_ACK_SIZE} and {MAX_PACKET_SIZE} bytes"
+            );
+            eyre::ensure!(!trains.every.is_zero(), "train interval must be positive");
+        }
+        if let Some(hopping) = self.port_hopping {
+            eyre::ensure!(
+                (2..=MAX_HOP_PORTS).contains(&hopping.ports),
+                "port hopping needs between 2 and {MAX_HOP_PORTS} ports"
+            );
+            eyre::ensure!(!hopping.every.is_zero(), "hop interval must be positive");
+        }
+        if let Some(hops) = self.hops {
+            eyre::ensure!(
+                cfg!(target_os = "linux"),
+                "tracing hops is only supported on Linux"
+            );
+            eyre::ensure!(hops.max_hops > 0, "maximum hops must be positive");
+            eyre::ensure!(!hops.every.is_zero(), "hop trace interval must be positive");
+        }
+        eyre::ensure!(
+            self.reresolve_every.is_none_or(|every| !every.is_zero()),
+            "re-resolve interval must be positive"
+        );
+        for (i, (key, _)) in self.labels.iter().enumerate() {
+            eyre::ensure!(
+                valid_label_key(key),
+                "label key {key:?} must be up to 64 letters, digits, `.`, `_` and `-`"
+            );
+            eyre::ensure!(
+                self.labels[..i].iter().all(|(other, _)| other != key),
+                "label {key:?} given twice"
+            );
+        }
+        eyre::ensure!(
+            self.protocol != Protocol::Icmp || cfg!(unix),
+            "ICMP probing is only supported on Unix"
+        );
+        if self.protocol != Protocol::LossLens {
+            for (enabled, feature) in [
+                (self.pad_acks, "padded ACKs"),
+                (self.ecn, "ECN"),
+                (self.checksums, "checksums"),
+                (self.symmetric, "symmetric mode"),
+                (self.packet_trains.is_some(), "packet trains"),
+                (self.port_hopping.is_some(), "port hopping"),
+                (self.hops.is_some(), "tracing hops"),
+                (self.record_route, "recording the route"),
+                (!self.failover.is_empty(), "failing over"),
+                (self.strict_version, "a strict version"),
+                (self.psk.is_some(), "a pre-shared key"),
+                (self.noise.is_some(), "encryption"),
+                (self.resume.is_some(), "resuming sessions"),
+                // connects are made by the OS, not from the probe socket
+                (
+                    self.protocol == Protocol::Tcp && self.source_addr.is_some(),
+                    "a source address",
+                ),
+                (
+                    self.protocol == Protocol::Tcp && self.interface.is_some(),
+                    "an interface",
+                ),
+                (
+                    self.protocol == Protocol::Tcp && self.dscp.is_some(),
+                    "DSCP marking",
+                ),
+            ] {
+                eyre::ensure!(
+                    !enabled,
+                    "probing {} doesn't support {feature}",
+                    self.protocol
+                );
+            }
+        }
+        if self.record_route {
+            eyre::ensure!(
+                cfg!(target_os = "linux"),
+                "recording the route is only supported on Linux"
+            );
+            eyre::ensure!(
+                self.recording.is_some(),
+                "recording the route needs a recording"
+            );
+        }
+        eyre::ensure!(
+            self.loss_windows.iter().all(|window| !window.is_zero()),
+            "loss windows must be positive"
+        );
+        eyre::ensure!(!self.half_life.is_zero(), "half-life must be positive");
+        eyre::ensure!(
+            !self.report_interval.is_zero(),
+            "report interval must be positive"
+        );
+        if let Some(cycle) = self.duty_cycle {
+            eyre::ensure!(
+                !cycle.active.is_zero() && cycle.active <= cycle.period,
+                "duty cycle must be active for part of its period"
+            );
+            // its pauses would look like a dead server
+            eyre::ensure!(
+                self.failover.is_empty(),
+                "failing over doesn't work with a duty cycle"
+            );
+        }
+        let fixed = [(self.probe_size, 1)];
+        let mix = if self.size_mix.is_empty() {
+            &fixed[..]
+        } else {
+            &self.size_mix
+        };
+        for &(size, weight) in mix {
+            eyre::ensure!(
+                (CLIENT_TO_SERVER_PACKET_SIZE..=MAX_PACKET_SIZE).contains(&size),
+                "probe size must be between {CLIENT_TO_SERVER_PACKET_SIZE} and {MAX_PACKET_SIZE} bytes"
+            );
+            eyre::ensure!(
+                self.protocol != Protocol::Twamp || size >= twamp::REFLECTED_SIZE,
+                "TWAMP-Light test packets must be at least {} bytes",
+                twamp::REFLECTED_SIZE
+            );
+            eyre::ensure!(
+                !self.ecn || size > SERVER_TO_CLIENT_PACKET_SIZE,
+                "ECN needs probes of at least {} bytes",
+                SERVER_TO_CLIENT_PACKET_SIZE + 1
+            );
+            eyre::ensure!(
+                !self.pad_acks || size >= PADDED_PROBE_MIN_SIZE,
+                "padded ACKs need probes of at least {PADDED_PROBE_MIN_SIZE} bytes"
+            );
+            eyre::ensure!(
+                !self.checksums || size >= CHECKSUM_PROBE_MIN_SIZE,
+                "checksums need probes of at least {CHECKSUM_PROBE_MIN_SIZE} bytes"
+            );
+            eyre::ensure!(
+                size + self.overhead() <= MAX_PACKET_SIZE,
+                "probes with a pre-shared key or encryption must be at most {} bytes",
+                MAX_PACKET_SIZE - self.overhead()
+            );
+            eyre::ensure!(weight > 0, "size mix weights must be positive");
+        }
+        eyre::ensure!(
+            mix.iter().map(|&(_, weight)| weight as u64).sum::<u64>() <= MAX_MIX_WEIGHT,
+            "size mix weights must add up to at most {MAX_MIX_WEIGHT}"
+        );
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .str("host", &self.host)
+            .raw(
+                "ip_version",
+                &self.ip_version.map_or_else(
+                    || "null".to_string(),
+                    |version| json::string(&version.to_string()),
+                ),
+            )
+            .raw(
+                "source_addr",
+                &self.source_addr.map_or_else(
+                    || "null".to_string(),
+                    |addr| json::string(&addr.to_string()),
+                ),
+            )
+            .raw(
+                "interface",
+                &self
+                    .interface
+                    .as_deref()
+                    .map_or_else(|| "null".to_string(), json::string),
+            )
+            .raw(
+                "recording",
+                &self.recording.as_ref().map_or_else(
+                    || "null".to_string(),
+                    |p| json::string(&p.to_string_lossy()),
+                ),
+            )
+            .u64("packets_per_second", self.packets_per_second.into())
+            .raw("poisson", if self.poisson { "true" } else { "false" })
+            .raw(
+                "duration_secs",
+                &self
+                    .duration
+                    .map_or_else(|| "null".to_string(), |d| json::number(d.as_secs_f64())),
+            )
+            .raw(
+                "dscp",
+                &self
+                    .dscp
+                    .map_or_else(|| "null".to_string(), |dscp| dscp.to_string()),
+            )
+            .raw("ecn", if self.ecn { "true" } else { "false" })
+            .raw("checksums", if self.checksums { "true" } else { "false" })
+            .raw(
+                "random_payload",
+                if self.random_payload { "true" } else { "false" },
+            )
+            .raw("symmetric", if self.symmetric { "true" } else { "false" })
+            .raw(
+                "adaptive",
+                &self.adaptive.map_or_else(
+                    || "null".to_string(),
+                    |adaptive| {
+                        json::Object::new()
+                            .f64("loss_threshold", adaptive.loss_threshold)
+                            .u64("min_rate", adaptive.min_rate.into())
+                            .finish()
+                    },
+                ),
+            )
+            .raw(
+                "packet_trains",
+                &self.packet_trains.map_or_else(
+                    || "null".to_string(),
+                    |trains| {
+                        json::Object::new()
+                            .f64("every_secs", trains.every.as_secs_f64())
+                            .u64("length", trains.length.into())
+                            .u64("size", trains.size as u64)
+                            .finish()
+                    },
+                ),
+            )
+            .raw(
+                "port_hopping",
+                &self.port_hopping.map_or_else(
+                    || "null".to_string(),
+                    |hopping| {
+                        json::Object::new()
+                            .u64("ports", hopping.ports as u64)
+                            .f64("every_secs", hopping.every.as_secs_f64())
+                            .finish()
+                    },
+                ),
+            )
+            .raw(
+                "hops",
+                &self.hops.map_or_else(
+                    || "null".to_string(),
+                    |hops| {
+                        json::Object::new()
+                            .u64("max_hops", hops.max_hops.into())
+                            .f64("every_secs", hops.every.as_secs_f64())
+                            .finish()
+                    },
+                ),
+            )
+            .raw(
+                "record_route",
+                if self.record_route { "true" } else { "false" },
+            )
+            .raw(
+                "stun",
+                &json::array(self.stun.iter().map(|host| json::string(host))),
+            )
+            .raw(
+                "reresolve_every_secs",
+                &self.reresolve_every.map_or_else(
+                    || "null".to_string(),
+                    |every| json::number(every.as_secs_f64()),
+                ),
+            )
+            .raw(
+                "failover",
+                &json::array(self.failover.iter().map(|host| json::string(host))),
+            )
+            .raw("labels", &labels_json(&self.labels))
+            .raw(
+                "strict_version",
+                if self.strict_version { "true" } else { "false" },
+            )
+            .str("protocol", &self.protocol.to_string())
+            // whether one was used, never the key
+            .raw("psk", if self.psk.is_some() { "true" } else { "false" })
+            .raw(
+                "noise",
+                &self
+                    .noise
+                    .map_or_else(|| "null".to_string(), |key| json::string(&key.to_string())),
+            )
+            .raw(
+                "resume",
+                &self.resume.as_ref().map_or_else(
+                    || "null".to_string(),
+                    |p| json::string(&p.to_string_lossy()),
+                ),
+            )
+            .raw(
+                "duty_cycle",
+                &self.duty_cycle.map_or_else(
+                    || "null".to_string(),
+                    |cycle| {
+                        json::Object::new()
+                            .f64("active_secs", cycle.active.as_secs_f64())
+                            .f64("period_secs", cycle.period.as_secs_f64())
+                            .finish()
+                    },
+                ),
+            )
+            .raw(
+                "count",
+                &self
+                    .count
+                    .map_or_else(|| "null".to_string(), |count| count.to_string()),
+            )
+            .u64("late_window", self.late_window() as u64)
+            .u64("probe_size", self.probe_size as u64)
+            .u64("ack_size", self.ack_size() as u64)
+            .raw(
+                "size_mix",
+                &json::array(self.size_mix.iter().map(|&(size, weight)| {
+                    json::Object::new()
+                        .u64("size", size as u64)
+                        .u64("weight", weight.into())
+                        .finish()
+                })),
+            )
+            .finish()
+    }
+}
+
+/// Encrypted handshake renewing a lost session.
+struct Renewal {
+    initiator: Initiator,
+    /// First message, repeated as is so answers to any copy count
+    message: Vec<u8>,
+    /// Cookie it echoes
+    cookie: Option<[u8; COOKIE_SIZE]>,
+}
+
+struct ClientSharedState {
+    host: String,
+    /// Configured rate, the upper bound for adaptive rate control
+    packets_per_second: u32,
+    /// Rate currently in use
+    rate: AtomicU32,
+    adaptive: Option<AdaptiveRate>,
+    packet_trains: Option<PacketTrains>,
+    ecn: bool,
+    checksums: bool,
+    /// Whether ACKs carry the server's epoch
+    epochs: bool,
+    /// Whether the server streams probes of its own
+    symmetric: bool,
+    protocol: Protocol,
+    random_payload: bool,
+    mix: SizeMix,
+    pad_acks: bool,
+    /// Probes sent per size of `mix`
+    sent_by_size: Vec<AtomicU64>,
+    poisson: bool,
+    /// Scheduled wait in microseconds before each probe, indexed by sequence
+    /// number modulo the length
+    send_gaps: Vec<AtomicU32>,
+    duration: Option<Duration>,
+    count: Option<u32>,
+    loss_windows: Vec<Duration>,
+    half_life: Duration,
+    report_interval: Duration,
+    duty_cycle: Option<DutyCycle>,
+    /// Set once the last probe has been sent
+    sending_done: AtomicBool,
+    /// Makes the connects when probing TCP
+    connector: Option<tcp::Connector>,
+    /// One socket per source port; just one unless hopping
+    sockets: Vec<UdpSocket>,
+    /// What the sockets are bound to, for other sockets to the server
+    local: LocalBind,
+    hop_every: Duration,
+    /// Probes sent per socket
+    sent_by_path: Vec<AtomicU64>,
+    /// Send time in microseconds since the start shifted left by 8, ORed
+    /// with the socket index, indexed like `send_gaps`, for round-trip times
+    /// and to attribute ACKs to paths while hopping
+    send_paths: Vec<AtomicU64>,
+    /// When the client was created, the epoch of `send_paths`
+    epoch: Instant,
+    hop_trace: Option<HopTrace>,
+    /// Hops to trace the route over for the recording, if recording it
+    route_max_hops: Option<u8>,
+    /// Latest results of the hop tracer
+    hops: Mutex<Vec<HopStats>>,
+    /// Current target, changed when re-resolving `host` or failing over
+    addr: Mutex<SocketAddr>,
+    /// `host` and the servers to fail over to, and which of them is probed
+    targets: Vec<String>,
+    active: AtomicUsize,
+    /// Probes sent by the time the latest ACK arrived, to tell how many a
+    /// server failed over from left unanswered
+    sent_at_last_ack: AtomicU64,
+    /// Probes left unanswered by servers failed over from, which don't
+    /// count as sent
+    failover_unanswered: AtomicU64,
+    reresolve_every: Option<Duration>,
+    /// Protocol version packets are marked with, 0 without a session
+    version: u8,
+    /// Session token, or an id of our own choosing without a session
+    client_id: AtomicU32,
+    /// Sent again to open a new session when the server forgot ours
+    handshake: Option<Session>,
+    /// State file to store the token of a new one in
+    resume: Option<PathBuf>,
+    /// First sequence number, after those of the run a resumed session
+    /// continues
+    first_seq: u64,
+    /// Where the server's counts stood when the session was resumed
+    resumption: Option<Resumption>,
+    session_lost: AtomicBool,
+    /// Cookie to echo in that handshake, if the server asked for one
+    cookie: Mutex<Option<[u8; COOKIE_SIZE]>>,
+    psk: Option<Psk>,
+    /// Server key of an encrypted session
+    noise: Option<PublicKey>,
+    /// Keys of the encrypted session, replaced when it's renewed
+    transport: Mutex<Option<Arc<Transport>>>,
+    /// Renewal handshake waiting for an answer
+    renewal: Mutex<Option<Renewal>>,
+    /// Time of the latest ACK in microseconds since `epoch`, to re-resolve
+    /// the host when the server stops answering
+    last_ack: AtomicU64,
+    /// Events from other threads, for the receive loop to deliver
+    pending_events: Mutex<Vec<Event>>,
+    client_sent: AtomicU64,
+    done: StopHandle,
+    latest: Mutex<Option<Stats>>,
+}
+
+impl ClientSharedState {
+    fn reresolve(&self) -> eyre::Result<Option<SocketAddr>> {
+        // the sockets are bound for one family
+        let version = IpVersion::of(*self.addr.lock().unwrap());
+        let host = &self.targets[self.active.load(Ordering::SeqCst)];
+        let new = resolve(host, Some(version))?;
+        let mut addr = self.addr.lock().unwrap();
+        if *addr == new {
+            crate::log::debug!("{host} still resolves to {new}");
+            return Ok(None);
+        }
+        for socket in &self.sockets {
+            socket.connect(new)?;
+        }
+        self.pending_events
+            .lock()
+            .unwrap()
+            .push(Event::AddressChange {
+                from: *addr,
+                to: new,
+            });
+        *addr = new;
+        Ok(Some(new))
+    }
+
+    /// Send from the socket of `path`, within the encrypted session if any.
+    fn send(&self, path: usize, buf: &[u8], addr: SocketAddr) -> eyre::Result<()> {
+        let transport = self.transport.lock().unwrap().clone();
+        let client_id = self.client_id.load(Ordering::SeqCst);
+        let packet = noise::seal(transport.as_deref().map(|t| (t, client_id)), buf);
+        self.send_raw(path, &packet, addr)
+    }
+
+    fn send_raw(&self, path: usize, buf: &[u8], addr: SocketAddr) -> eyre::Result<()> {
+        let packet = auth::seal(self.psk.as_ref(), buf);
+        match self.sockets[path].send_to(&packet, addr) {
+            // counts as sent, the probe is lost like any other
+            Err(e) if is_unreachable(&e) => Ok(()),
+            rv => {
+                rv?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Probe `targets[to]` from now on. Probes sent since the latest ACK
+    /// count as left unanswered if the current one `failed`.
+    fn switch_to(&self, to: usize, failed: bool) -> eyre::Result<()> {
+        let version = IpVersion::of(*self.addr.lock().unwrap());
+        let new = resolve(&self.targets[to], Some(version))?;
+        let mut addr = self.addr.lock().unwrap();
+        for socket in &self.sockets {
+            socket.connect(new)?;
+        }
+        *addr = new;
+        let from = self.active.swap(to, Ordering::SeqCst);
+        let sent = self.client_sent.load(Ordering::SeqCst);
+        let since_ack = sent - self.sent_at_last_ack.swap(sent, Ordering::SeqCst);
+        let unanswered = if failed { since_ack } else { 0 };
+        self.failover_unanswered
+            .fetch_add(unanswered, Ordering::SeqCst);
+        // the new server doesn't know the session and asks for a new one
+        self.pending_events.lock().unwrap().push(Event::Failover {
+            from: self.targets[from].clone(),
+            to: self.targets[to].clone(),
+            unanswered,
+        });
+        Ok(())
+    }
+
+    /// Fail over to the next server once the current one has been silent
+    /// for a while, and back to an earlier one once it answers again, until
+    /// done.
+    fn failover_loop(&self) {
+        let mut switched = Instant::now();
+        let mut last_check = Instant::now();
+        while !self.done.is_stopped() && !self.sending_done.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+            let since_ack = self
+                .epoch
+                .elapsed()
+                .saturating_sub(Duration::from_micros(self.last_ack.load(Ordering::SeqCst)));
+            let active = self.active.load(Ordering::SeqCst);
+            if since_ack >= FAILOVER_WHEN_SILENT && switched.elapsed() >= FAILOVER_WHEN_SILENT {
+                switched = Instant::now();
+                crate::log::debug!(
+                    "No ACKs from {} for {since_ack:.1?}, failing over",
+                    self.targets[active]
+                );
+                // the first that resolves, wrapping around to `host`
+                let n = self.targets.len();
+                for to in (1..n).map(|i| (active + i) % n) {
+                    match self.switch_to(to, true) {
+                        Ok(()) => break,
+                        Err(e) => {
+                            crate::log::debug!("Failing over to {} failed: {e}", self.targets[to])
+                        }
+                    }
+                }
+            } else if active > 0 && last_check.elapsed() >= FAILBACK_EVERY {
+                last_check = Instant::now();
+                let version = Some(IpVersion::of(*self.addr.lock().unwrap()));
+                let back = (0..active)
+                    .find(|&i| answers(&self.local, &self.targets[i], version, self.psk.as_ref()));
+                if let Some(to) = back {
+                    switched = Instant::now();
+                    crate::log::debug!("{} answers again, switching back", self.targets[to]);
+                    if let Err(e) = self.switch_to(to, false) {
+                        crate::log::debug!("Switching back to {} failed: {e}", self.targets[to]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Re-resolve the host every `every`, and every few seconds while the
+    /// server doesn't answer, until done. Failed lookups keep the current
+    /// address.
+    fn reresolve_loop(&self, every: Duration) {
+        let mut last_attempt = Instant::now();
+        while !self.done.is_stopped() {
+            thread::sleep(Duration::from_millis(100));
+            let since_ack = self
+                .epoch
+                .elapsed()
+                .saturating_sub(Duration::from_micros(self.last_ack.load(Ordering::SeqCst)));
+            let silent = since_ack >= RERESOLVE_WHEN_SILENT;
+            let due = last_attempt.elapsed() >= every
+                || (silent && last_attempt.elapsed() >= RERESOLVE_WHEN_SILENT);
+            if due {
+                last_attempt = Instant::now();
+                if let Err(e) = self.reresolve() {
+                    let host = &self.targets[self.active.load(Ordering::SeqCst)];
+                    crate::log::debug!("Re-resolving {host} failed: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Whether the server at `host` answers a handshake, asked from a socket of
+/// its own.
+fn answers(local: &LocalBind, host: &str, version: Option<IpVersion>, psk: Option<&Psk>) -> bool {
+    let hello = Session {
+        version: PROTOCOL_VERSION,
+        packets_per_second: 1,
+        probe_size: CLIENT_TO_SERVER_PACKET_SIZE as u16,
+        features: 0,
+        token: rand::random(),
+    };
+    let answered = || -> eyre::Result<bool> {
+        let addr = resolve(host, version)?;
+        let socket = local.socket_for(addr)?;
+        socket.connect(addr)?;
+        Ok(session::handshake(&socket, hello, None, psk)?.is_some())
+    };
+    answered().unwrap_or(false)
+}
+
+/// Silence after which the host is re-resolved, and how often while it lasts
+const RERESOLVE_WHEN_SILENT: Duration = Duration::from_secs(5);
+/// Silence after which the next server is failed over to, and how long each
+/// gets to answer
+const FAILOVER_WHEN_SILENT: Duration = Duration::from_secs(5);
+/// How often earlier servers are asked whether they're back while failed
+/// over
+const FAILBACK_EVERY: Duration = Duration::from_secs(30);
+
+/// Runtime control of a client from other threads, e.g. a control socket.
+#[derive(Clone)]
+pub struct ClientControl {
+    state: Arc<ClientSharedState>,
+    recorder: Option<Sender<recording::Message>>,
+}
+
+impl ClientControl {
+    pub fn stop(&self) {
+        self.state.done.stop();
+    }
+
+    /// Most recent stats snapshot, if one has been produced yet.
+    pub fn latest_stats(&self) -> Option<Stats> {
+        self.state.latest.lock().unwrap().clone()
+    }
+
+    /// Make everything recorded so far durable on disk.
+    pub fn flush(&self) -> eyre::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        self.recorder_send(recording::Message::Flush(tx))?;
+        rx.recv()?
+    }
+
+    /// Move the recording aside and continue in a fresh file, returning
+    /// where the previous data now lives.
+    pub fn rotate(&self) -> eyre::Result<PathBuf> {
+        let (tx, rx) = mpsc::channel();
+        self.recorder_send(recording::Message::Rotate(tx))?;
+        rx.recv()?
+    }
+
+    /// Resolve the host name again and switch to the new address if it
+    /// changed. Returns the new address in that case.
+    pub fn reresolve(&self) -> eyre::Result<Option<SocketAddr>> {
+        self.state.reresolve()
+    }
+
+    fn recorder_send(&self, message: recording::Message) -> eyre::Result<()> {
+        self.recorder
+            .as_ref()
+            .ok_or_eyre("recording is disabled")?
+            .send(message)
+            .map_err(|_| eyre::eyre!("recorder stopped"))
+    }
+}
+
+type EventCallback = Box<dyn FnMut(&Event) + Send>;
+
+/// Sends sequenced probes to a server and measures loss in both directions.
+pub struct ProbeClient {
+    session: Option<Session>,
+    nat: Option<NatReport>,
+    recorder: Option<Recorder>,
+    state: Arc<ClientSharedState>,
+    on_event: Option<EventCallback>,
+}
+
+impl ProbeClient {
+    pub fn new(mut config: ClientConfig) -> eyre::Result<Self> {
+        config.validate()?;
+        if config.protocol == Protocol::Icmp {
+            config.host = icmp::with_port(&config.host);
+        }
+        let mix = if config.size_mix.is_empty() {
+            SizeMix::new(&[(config.probe_size, 1)])
+        } else {
+            SizeMix::new(&config.size_mix)
+        };
+        let local = LocalBind {
+            source_addr: config.source_addr,
+            interface: config.interface.clone(),
+        };
+        let version = local.ip_version(config.ip_version);
+        // the first server that answers, where there are several
+        let active = if config.failover.is_empty() {
+            0
+        } else {
+            [&config.host]
+                .into_iter()
+                .chain(&config.failover)
+                .position(|host| answers(&local, host, version, config.psk.as_ref()))
+                .unwrap_or(0)
+        };
+        let host = match active {
+            0 => &config.host,
+            i => &config.failover[i - 1],
+        };
+        let addr = resolve(host, version)?;
+        let sockets = (0..config.port_hopping.map_or(1, |hopping| hopping.ports))
+            .map(|_| match config.protocol {
+                Protocol::Icmp => icmp::socket_for(&local, addr),
+                _ => local.socket_for(addr),
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        Self::with_sockets(config, mix, local, addr, sockets, active)
+    }
+
+    /// Probe from an already bound socket, e.g. one NATs on the way let the
+    /// other client's packets through to, see [`crate::rendezvous`].
+    /// `config.source_addr` and `config.interface` are ignored.
+    pub fn from_socket(socket: UdpSocket, config: ClientConfig) -> eyre::Result<Self> {
+        config.validate()?;
+        eyre::ensure!(
+            config.port_hopping.is_none(),
+            "port hopping needs sockets of its own"
+        );
+        eyre::ensure!(
+            config.protocol != Protocol::Icmp,
+            "ICMP probes need a socket of their own"
+        );
+        let mix = if config.size_mix.is_empty() {
+            SizeMix::new(&[(config.probe_size, 1)])
+        } else {
+            SizeMix::new(&config.size_mix)
+        };
+        let local = socket.local_addr()?;
+        let addr = resolve(&config.host, Some(IpVersion::of(local)))?;
+        let local = LocalBind {
+            source_addr: Some(local.ip()).filter(|ip| !ip.is_unspecified()),
+            interface: None,
+        };
+        Self::with_sockets(config, mix, local, addr, vec![socket], 0)
+    }
+
+    fn with_sockets(
+        config: ClientConfig,
+        mix: SizeMix,
+        local: LocalBind,
+        addr: SocketAddr,
+        sockets: Vec<UdpSocket>,
+        active: usize,
+    ) -> eyre::Result<Self> {
+        let hopping = config.port_hopping;
+        // before connecting, which would drop answers from anywhere else
+        let nat = if config.stun.is_empty() {
+            None
+        } else {
+            let servers = config
+                .stun
+                .iter()
+                .map(|host| resolve(host, Some(IpVersion::of(addr))))
+                .collect::<eyre::Result<Vec<_>>>()?;
+            Some(stun::query(&sockets[0], &servers)?)
+        };
+        for socket in &sockets {
+            if config.dscp.is_some() || config.ecn {
+                let ecn = if config.ecn { ECT_0 } else { 0 };
+                set_tos(socket, config.dscp.unwrap_or(0) << 2 | ecn)?;
+            }
+            socket.connect(addr)?;
+        }
+
+        let resumed = config
+            .resume
+            .as_deref()
+            .map(|path| resume::load(path, &config.host))
+            .transpose()?
+            .flatten();
+        if let Some((token, _)) = resumed {
+            crate::log::debug!("Resuming session {token:08x}");
+        }
+        // to notice counts from another server instance, so always
+        let mut features = FEATURE_EPOCH;
+        for (wanted, feature) in [
+            (resumed.is_some(), FEATURE_RESUME),
+            (config.ecn, FEATURE_ECN),
+            (config.pad_acks, FEATURE_PAD_ACKS),
+            (config.checksums, FEATURE_CHECKSUMS),
+            (config.symmetric, FEATURE_SYMMETRIC),
+            (config.packet_trains.is_some(), FEATURE_TRAINS),
+            (config.hops.is_some(), FEATURE_HOPS),
+        ] {
+            if wanted {
+                features |= feature;
+            }
+        }
+        let proposal = Session {
+            version: PROTOCOL_VERSION,
+            packets_per_second: config.packets_per_second,
+            probe_size: mix.sizes.iter().copied().max().unwrap() as u16,
+            features,
+            token: resumed.map_or_else(rand::random, |(token, _)| token),
+        };
+        let resume_secret = resumed.as_ref().map(|(_, secret)| secret);
+        let welcome = match &config.noise {
+            // reflectors answer test packets only
+            _ if config.protocol != Protocol::LossLens => None,
+            Some(server) => {
+                let Some(welcome) = session::encrypted_handshake(
+                    &sockets[0],
+                    proposal,
+                    resume_secret,
+                    config.psk.as_ref(),
+                    server,
+                )?
+                else {
+                    eyre::bail!(
+                        "no answer to the encrypted handshake; the server needs a --noise-key with this public key"
+                    );
+                };
+                Some(welcome)
+            }
+            None => session::handshake(&sockets[0], proposal, resume_secret, config.psk.as_ref())?,
+        };
+        let session = welcome.as_ref().map(|welcome| welcome.session);
+        let resumption = welcome.as_ref().and_then(|welcome| welcome.resumption);
+        let resume_secret = welcome.as_ref().and_then(|welcome| welcome.resume_secret);
+        let transport = welcome.and_then(|welcome| welcome.transport).map(Arc::new);
+        if config.strict_version {
+            match session {
+                None => eyre::bail!("the server predates protocol versions"),
+                Some(session) => eyre::ensure!(
+                    session.version == PROTOCOL_VERSION,
+                    "the server speaks protocol version {}, this client {PROTOCOL_VERSION}",
+                    session.version
+                ),
+            }
+        }
+        // servers predating sessions wouldn't check them
+        eyre::ensure!(
+            !config.checksums || session.is_some(),
+            "the server doesn't support checksums"
+        );
+        eyre::ensure!(
+            !config.symmetric || session.is_some(),
+            "the server doesn't support symmetric mode"
+        );
+        if let Some(session) = session {
+            let refused = session.refused(features);
+            eyre::ensure!(
+                refused.is_empty(),
+                "the server doesn't support {}",
+                refused.join(", ")
+            );
+            eyre::ensure!(
+                session.probe_size >= proposal.probe_size,
+                "the server only accepts probes up to {} bytes",
+                session.probe_size
+            );
+        }
+        // the server may grant less than asked for
+        let packets_per_second = session.map_or(config.packets_per_second, |session| {
+            session.packets_per_second
+        });
+
+        if let (Some(path), Some(session)) = (&config.resume, session) {
+            match resumption {
+                Some(resumption) => crate::log::debug!(
+                    "The server resumed the session after probe {}",
+                    resumption.highest_seq
+                ),
+                None => crate::log::debug!("Starting session {:08x}", session.token),
+            }
+            resume::save(path, &config.host, session.token, resume_secret.as_ref())?;
+        }
+        // from a fresh slot, so the recording's slots still line up with
+        // sequence numbers
+        let first_seq = resumption.map_or(1, |resumption| {
+            resumption.highest_seq.div_ceil(SLOT_SIZE as u64) * SLOT_SIZE as u64 + 1
+        });
+
+        let recorder = config
+            .recording
+            .as_deref()
+            .map(|path| match config.resume {
+                Some(_) => Recorder::resume(path, (first_seq - 1) / SLOT_SIZE as u64),
+                None => Recorder::create(path),
+            })
+            .transpose()?;
+        if let Some(recorder) = &recorder {
+            // tells apart recordings of runs side by side, e.g. per interface
+            let mut settings = json::Object::new().raw("config", &config.to_json());
+            if config.resume.is_some() {
+                let resumed = if resumption.is_some() {
+                    "true"
+                } else {
+                    "false"
+                };
+                settings = settings.raw("resumed", resumed);
+            }
+            if let Some(nat) = &nat {
+                settings = settings.raw("nat", &nat.to_json());
+            }
+            recorder
+                .sender()
+                .send(recording::Message::Metadata(settings))
+                .map_err(|_| eyre::eyre!("recorder stopped"))?;
+        }
+        let targets = [config.host.clone()]
+            .into_iter()
+            .chain(config.failover)
+            .collect();
+        Ok(Self {
+            session,
+            nat,
+            recorder,
+            state: Arc::new(ClientSharedState {
+                host: config.host,
+                packets_per_second,
+                rate: AtomicU32::new(packets_per_second),
+                adaptive: config.adaptive,
+                packet_trains: config.packet_trains,
+                ecn: config.ecn,
+                checksums: config.checksums,
+                epochs: session.is_some_and(|session| session.features & FEATURE_EPOCH != 0),
+                symmetric: config.symmetric,
+                protocol: config.protocol,
+                random_payload: config.random_payload,
+                sent_by_size: mix.sizes.iter().map(|_| AtomicU64::new(0)).collect(),
+                mix,
+                pad_acks: config.pad_acks || config.protocol != Protocol::LossLens,
+                poisson: config.poisson,
+                send_gaps: (0..late_window(packets_per_second))
+                    .map(|_| AtomicU32::new(0))
+                    .collect(),
+                duration: config.duration,
+                count: config.count,
+                loss_windows: config.loss_windows,
+                half_life: config.half_life,
+                report_interval: config.report_interval,
+                duty_cycle: config.duty_cycle,
+                sending_done: AtomicBool::new(false),
+                connector: (config.protocol == Protocol::Tcp).then(tcp::Connector::new),
+                hop_every: hopping.map_or(Duration::MAX, |hopping| hopping.every),
+                sent_by_path: sockets.iter().map(|_| AtomicU64::new(0)).collect(),
+                send_paths: (0..late_window(packets_per_second))
+                    .map(|_| AtomicU64::new(0))
+                    .collect(),
+                sockets,
+                local,
+                epoch: Instant::now(),
+                hop_trace: config.hops,
+                route_max_hops: config.record_route.then(|| {
+                    config
+                        .hops
+                        .map_or(HopTrace::default().max_hops, |hops| hops.max_hops)
+                }),
+                hops: Mutex::new(Vec::new()),
+                addr: Mutex::new(addr),
+                targets,
+                active: AtomicUsize::new(active),
+                sent_at_last_ack: AtomicU64::new(0),
+                failover_unanswered: AtomicU64::new(0),
+                reresolve_every: config.reresolve_every,
+                version: session.map_or(0, |session| session.negotiated_version()),
+                client_id: AtomicU32::new(session.map_or(proposal.token, |session| session.token)),
+                // a forgotten session can't be resumed
+                handshake: session.is_some().then_some(Session {
+                    features: proposal.features & !FEATURE_RESUME,
+                    ..proposal
+                }),
+                resume: config.resume,
+                first_seq,
+                resumption,
+                session_lost: AtomicBool::new(false),
+                cookie: Mutex::new(None),
+                psk: config.psk,
+                noise: config.noise,
+                transport: Mutex::new(transport),
+                renewal: Mutex::new(None),
+                last_ack: AtomicU64::new(0),
+                pending_events: Mutex::new(
+                    resumption
+                        .map(|_| Event::SessionResume { first_seq })
+                        .into_iter()
+                        .collect(),
+                ),
+                client_sent: AtomicU64::new(0),
+                done: StopHandle::default(),
+                latest: Mutex::new(None),
+            }),
+            on_event: None,
+        })
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.state.done.clone()
+    }
+
+    /// What the server accepted in the handshake, `None` for servers that
+    /// predate it.
+    pub fn session(&self) -> Option<Session> {
+        self.session
+    }
+
+    /// What the STUN servers in [`ClientConfig::stun`] saw of the probe
+    /// socket, `None` without any.
+    pub fn nat(&self) -> Option<&NatReport> {
+        self.nat.as_ref()
+    }
+
+    pub fn control(&self) -> ClientControl {
+        ClientControl {
+            state: Arc::clone(&self.state),
+            recorder: self.recorder.as_ref().map(Recorder::sender),
+        }
+    }
+
+    /// Call `on_event` for each [`Event`] as soon as it is detected.
+    pub fn on_event(mut self, on_event: impl FnMut(&Event) + Send + 'static) -> Self {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    /// Probe until stopped or the configured duration or count is reached,
+    /// calling `on_stats` every [`ClientConfig::report_interval`]. The
+    /// final snapshot is available from [`ClientControl::latest_stats`].
+    pub fn run(mut self, on_stats: impl FnMut(&Stats) + Send + 'static) -> eyre::Result<()> {
+        let recorder = self.recorder.take();
+
+        let t = thread::spawn({
+            let state = Arc::clone(&self.state);
+            let slots = recorder.as_ref().map(Recorder::sender);
+            let mut on_event = self.on_event.take();
+            move || -> eyre::Result<()> {
+                for socket in &state.sockets {
+                    socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+                }
+                let on_event = |event: &Event| {
+                    if let Some(on_event) = &mut on_event {
+                        on_event(event);
+                    }
+                };
+                let connects = state.connector.as_ref().and_then(tcp::Connector::acks);
+                let rv = match (connects, &state.sockets[..]) {
+                    (Some(rx), _) => {
+                        receive_loop(&mut AckSource::Ports(rx), &state, slots, on_stats, on_event)
+                    }
+                    (None, [socket]) => receive_loop(
+                        &mut AckSource::Socket(socket, BatchReceiver::new()),
+                        &state,
+                        slots,
+                        on_stats,
+                        on_event,
+                    ),
+                    (None, sockets) => thread::scope(|scope| {
+                        let (tx, rx) = mpsc::channel();
+                        let readers_done = StopHandle::default();
+                        for socket in sockets {
+                            let tx = tx.clone();
+                            let readers_done = readers_done.clone();
+                            scope.spawn(move || forward_acks(socket, &tx, &readers_done));
+                        }
+                        let rv = receive_loop(
+                            &mut AckSource::Ports(rx),
+                            &state,
+                            slots,
+                            on_stats,
+                            on_event,
+                        );
+                        readers_done.stop();
+                        rv
+                    }),
+                };
+                if rv.is_err() {
+                    state.done.stop();
+                }
+                rv
+            }
+        });
+
+        #[cfg(target_os = "linux")]
+        let tracer = self.state.hop_trace.map(|config| {
+            let state = Arc::clone(&self.state);
+            let addr = *state.addr.lock().unwrap();
+            thread::spawn(move || {
+                let rv = crate::hops::trace(
+                    addr,
+                    &state.client_id,
+                    config,
+                    &state.local,
+                    state.psk.as_ref(),
+                    &state.done,
+                    &state.hops,
+                );
+                if rv.is_err() {
+                    state.done.stop();
+                }
+                rv
+            })
+        });
+
+        #[cfg(target_os = "linux")]
+        let route_recorder = self
+            .state
+            .route_max_hops
+            .zip(recorder.as_ref().map(Recorder::sender))
+            .map(|(max_hops, recorder)| {
+                let state = Arc::clone(&self.state);
+                thread::spawn(move || {
+                    crate::hops::record_route(
+                        &state.addr,
+                        &state.client_id,
+                        max_hops,
+                        &state.local,
+                        state.psk.as_ref(),
+                        &state.done,
+                        &recorder,
+                        &state.pending_events,
+                    )
+                })
+            });
+
+        let resolver = self.state.reresolve_every.map(|every| {
+            let state = Arc::clone(&self.state);
+            thread::spawn(move || state.reresolve_loop(every))
+        });
+        let failover = (self.state.targets.len() > 1).then(|| {
+            let state = Arc::clone(&self.state);
+            thread::spawn(move || state.failover_loop())
+        });
+
+        let rv = self.send_loop();
+        self.state.sending_done.store(true, Ordering::SeqCst);
+        if rv.is_ok() {
+            // give ACKs for the last probes time to arrive; the receiver ends
+            // early once it has them all
+            let deadline = Instant::now() + Duration::from_secs(LATE_WINDOW_SECS as u64);
+            while !t.is_finished() && !self.state.done.is_stopped() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+        self.state.done.stop();
+        let received = t.join().unwrap();
+        if let Some(failover) = failover {
+            failover.join().unwrap();
+        }
+        if let Some(resolver) = resolver {
+            resolver.join().unwrap();
+        }
+        #[cfg(target_os = "linux")]
+        let traced = tracer.map_or(Ok(()), |tracer| tracer.join().unwrap());
+        #[cfg(target_os = "linux")]
+        let routed = route_recorder.map_or(Ok(()), |thread| thread.join().unwrap());
+        if let Some(recorder) = recorder {
+            recorder.finish()?;
+        }
+        received?;
+        #[cfg(target_os = "linux")]
+        traced?;
+        #[cfg(target_os = "linux")]
+        routed?;
+        rv
+    }
+
+    /// Run the client on a background thread, delivering stats and events
+    /// over channels.
+    pub fn spawn(self) -> ClientHandle {
+        let stop = self.stop_handle();
+        let (tx, stats) = mpsc::channel();
+        let (event_tx, events) = mpsc::channel();
+        let client = self.on_event(move |e| {
+            let _ = event_tx.send(e.clone());
+        });
+        let thread = thread::spawn(move || {
+            client.run(move |s| {
+                let _ = tx.send(s.clone());
+            })
+        });
+        ClientHandle {
+            stop,
+            stats,
+            events,
+            thread,
+        }
+    }
+
+    fn send_loop(&self) -> eyre::Result<()> {
+        // absolute schedule so time spent sending doesn't accumulate as drift
+        let start = Instant::now();
+        let mut next_send = start;
+        let mut cycle_start = start;
+        let mix = &self.state.mix;
+        let mut buf = vec![0u8; icmp::HEADER_SIZE + mix.sizes.iter().copied().max().unwrap()];
+        let mut train = self
+            .state
+            .packet_trains
+            .map(|trains| (vec![0u8; trains.size], start + trains.every));
+        let mut train_id = 0u32;
+        // 64 bits so they never wrap; only the low 32 go on the wire, see
+        // protocol::widen
+        for seq in self.state.first_seq.. {
+            if self.state.done.is_stopped()
+                || self
+                    .state
+                    .count
+                    .is_some_and(|count| seq - self.state.first_seq >= count.into())
+                || self.state.duration.is_some_and(|d| start.elapsed() >= d)
+            {
+                break;
+            }
+            let rate = self.state.rate.load(Ordering::SeqCst);
+            let interval = Duration::from_nanos(1_000_000_000 / u64::from(rate));
+            let addr = *self.state.addr.lock().unwrap();
+            let client_id = self.state.client_id.load(Ordering::SeqCst);
+            let since_start = start.elapsed();
+            let path = (since_start.as_nanos() / self.state.hop_every.as_nanos()) as usize
+                % self.state.sockets.len();
+            let micros = self.state.epoch.elapsed().as_micros() as u64;
+            self.state.send_paths[seq as usize % self.state.send_paths.len()]
+                .store(micros << 8 | path as u64, Ordering::SeqCst);
+            let renewing = self
+                .state
+                .handshake
+                .filter(|_| self.state.session_lost.load(Ordering::SeqCst));
+            if let Some(handshake) = renewing {
+                // about ten times a second until the server answers, as
+                // probes are lost meanwhile
+                if seq % u64::from((rate / 10).max(1)) == 0 {
+                    let cookie = *self.state.cookie.lock().unwrap();
+                    match &self.state.noise {
+                        Some(server) => {
+                            let mut renewal = self.state.renewal.lock().unwrap();
+                            if renewal
+                                .as_ref()
+                                .is_none_or(|renewal| renewal.cookie != cookie)
+                            {
+                                let hello = handshake.hello(cookie);
+                                *renewal = session::encrypted_hello(server, &hello, cookie).map(
+                                    |(initiator, message)| Renewal {
+                                        initiator,
+                                        message,
+                                        cookie,
+                                    },
+                                );
+                            }
+                            if let Some(renewal) = &*renewal {
+                                self.state.send_raw(path, &renewal.message, addr)?;
+                            }
+                        }
+                        None => self.state.send(path, &handshake.hello(cookie), addr)?,
+                    }
+                }
+            } else if self.state.protocol == Protocol::LossLens
+                && (seq % u64::from(rate) == 1 || rate == 1)
+            {
+                // repeated about once a second since it may get lost
+                let hello = protocol::hello(self.state.version, rate, client_id);
+                self.state.send(path, &hello, addr)?;
+            }
+
+            let bucket = mix.bucket(seq);
+            let size = mix.sizes[bucket];
+            let ack_size = mix.ack_size(bucket, self.state.pad_acks) as u16;
+            let packet = match self.state.protocol {
+                Protocol::Twamp => {
+                    if self.state.random_payload {
+                        rand::fill(&mut buf[twamp::TEST_SIZE..size]);
+                    }
+                    twamp::write_test(&mut buf[..size], (seq - 1) as u32);
+                    Some(&buf[..size])
+                }
+                // the probe size is that of the data after the header
+                Protocol::Icmp => {
+                    let len = icmp::HEADER_SIZE + size;
+                    if self.state.random_payload {
+                        rand::fill(&mut buf[icmp::HEADER_SIZE + icmp::MIN_SIZE..len]);
+                    }
+                    icmp::write_request(&mut buf[..len], addr.is_ipv6(), seq as u32, client_id);
+                    Some(&buf[..len])
+                }
+                // the probe socket stays silent
+                Protocol::Tcp => {
+                    if let Some(connector) = &self.state.connector {
+                        connector.connect(addr, seq as u32);
+                    }
+                    None
+                }
+                Protocol::LossLens | Protocol::Echo => {
+                    protocol::write_probe(
+                        &mut buf[..size],
+                        self.state.version,
+                        seq as u32,
+                        client_id,
+                        Some(ack_size),
+                    );
+                    if self.state.random_payload {
+                        rand::fill(protocol::payload(&mut buf[..size]));
+                    } else if self.state.checksums {
+                        protocol::write_pattern(&mut buf[..size], seq as u32);
+                    }
+                    if self.state.checksums {
+                        protocol::write_checksum(&mut buf[..size]);
+                    }
+                    Some(&buf[..size])
+                }
+            };
+            if let Some(packet) = packet {
+                self.state.send(path, packet, addr)?;
+            }
+            self.state.sent_by_size[bucket].fetch_add(1, Ordering::SeqCst);
+            self.state.sent_by_path[path].fetch_add(1, Ordering::SeqCst);
+
+            self.state.client_sent.fetch_add(1, Ordering::SeqCst);
+
+            if let (Some(trains), Some((packet, next_train))) =
+                (self.state.packet_trains, &mut train)
+            {
+                if Instant::now() >= *next_train {
+                    *next_train += trains.every;
+                    train_id += 1;
+                    for index in 0..trains.length {
+                        capacity::train_packet(packet, train_id, client_id, index, trains.length);
+                        packet[0] = with_version(packet[0], self.state.version);
+                        self.state.send(path, packet, addr)?;
+                    }
+                }
+            }
+
+            let mut gap = if self.state.poisson {
+                // 1 - U avoids ln(0)
+                interval.mul_f64(-(1.0 - rand::random::<f64>()).ln())
+            } else {
+                interval
+            };
+            if let Some(cycle) = self.state.duty_cycle {
+                if next_send + gap >= cycle_start + cycle.active {
+                    // the pause counts as the wait before the next probe, so
+                    // the receiver doesn't take it for an outage
+                    cycle_start += cycle.period;
+                    gap = cycle_start.saturating_duration_since(next_send);
+                }
+            }
+            let next_seq = (seq + 1) as usize;
+            self.state.send_gaps[next_seq % self.state.send_gaps.len()].store(
+                gap.as_micros().min(u32::MAX.into()) as u32,
+                Ordering::SeqCst,
+            );
+            next_send += gap;
+            let now = Instant::now();
+            match next_send.checked_duration_since(now) {
+                Some(wait) => self.sleep(wait),
+                // fell behind by more than a slot (e.g. suspended): skip
+                // ahead instead of bursting to catch up
+                None if now - next_send > interval => next_send = now,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Sleep in short steps so long pauses still notice stop requests.
+    fn sleep(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while !self.state.done.is_stopped() {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            thread::sleep(left.min(Duration::from_millis(100)));
+        }
+    }
+}
+
+/// Set the TOS byte (DSCP and ECN) of outgoing packets. IPv6 sockets get the
+/// IPv4 option too, for IPv4-mapped destinations; IPv4 sockets don't accept
+/// the IPv6 one.
+#[cfg(unix)]
+fn set_tos(socket: &UdpSocket, tos: u8) -> eyre::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let tos = libc::c_int::from(tos);
+    let options: &[_] = if socket.local_addr()?.is_ipv6() {
+        &[
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+            (libc::IPPROTO_IP, libc::IP_TOS),
+        ]
+    } else {
+        &[(libc::IPPROTO_IP, libc::IP_TOS)]
+    };
+    for &(level, name) in options {
+        let rv = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                (&tos as *const libc::c_int).cast(),
+                std::mem::size_of_val(&tos) as libc::socklen_t,
+            )
+        };
+        if rv == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_tos(_socket: &UdpSocket, _tos: u8) -> eyre::Result<()> {
+    eyre::bail!("DSCP and ECN marking are only supported on Unix")
+}
+
+/// Where the receive loop reads ACKs from: the probe socket itself, or while
+/// hopping ports a channel fed by a reader thread per socket.
+enum AckSource<'a> {
+    Socket(&'a UdpSocket, BatchReceiver),
+    Ports(Receiver<Vec<u8>>),
+}
+
+impl AckSource<'_> {
+    /// Receive into `buf` like [`UdpSocket::recv`], timing out as the socket
+    /// would.
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            AckSource::Socket(socket, receiver) => Ok(receiver.recv_from(socket, buf)?.0),
+            AckSource::Ports(rx) => match rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(packet) => {
+                    let n = packet.len().min(buf.len());
+                    buf[..n].copy_from_slice(&packet[..n]);
+                    Ok(n)
+                }
+                Err(_) => Err(ErrorKind::WouldBlock.into()),
+            },
+        }
+    }
+}
+
+/// Pass everything received on `socket` on to the receive loop.
+fn forward_acks(socket: &UdpSocket, tx: &Sender<Vec<u8>>, done: &StopHandle) {
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut receiver = BatchReceiver::new();
+    while !done.is_stopped() {
+        match receiver.recv_from(socket, &mut buf) {
+            Ok((n, ..)) => {
+                if tx.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) if is_unreachable(&e) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+/// ICMP unreachable for an earlier probe, e.g. while the server restarts.
+/// Either socket operation may report it; Windows calls it a reset.
+fn is_unreachable(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+    )
+}
+
+/// Upper bound on the total weight of a size mix, which is also the length
+/// of its pattern
+const MAX_MIX_WEIGHT: u64 = 10_000;
+
+/// Windows in a row that must agree before the rate is changed
+const ADAPT_SUSTAIN: u32 = 3;
+const ADAPT_WINDOW: Duration = Duration::from_secs(1);
+/// How often the moving averages of [`Smoother`] are updated
+const SMOOTH_EVERY: Duration = Duration::from_secs(1);
+
+/// Adaptive rate control, comparing probes sent and ACKs received about once
+/// a second. Unlike the slot bitmaps this doesn't wait for the late window,
+/// so the rate reacts within a few seconds even at low rates.
+struct RateController {
+    config: AdaptiveRate,
+    max_rate: u32,
+    window_start: Instant,
+    sent: u64,
+    received: u64,
+    loss: Ewma,
+    /// Consecutive windows above the threshold, and well below it
+    over: u32,
+    under: u32,
+}
+
+impl RateController {
+    fn new(config: AdaptiveRate, state: &ClientSharedState) -> Self {
+        Self {
+            config,
+            max_rate: state.packets_per_second,
+            window_start: Instant::now(),
+            sent: 0,
+            received: 0,
+            loss: Ewma::new(state.half_life),
+            over: 0,
+            under: 0,
+        }
+    }
+
+    /// `received` is the number of ACKs received so far.
+    fn tick(&mut self, state: &ClientSharedState, received: u64) -> Option<Event> {
+        if self.window_start.elapsed() < ADAPT_WINDOW {
+            return None;
+        }
+        let sent = state.client_sent.load(Ordering::SeqCst);
+        let window_sent = sent - self.sent;
+        let window_received = received - self.received;
+        self.window_start = Instant::now();
+        self.sent = sent;
+        self.received = received;
+        if window_sent == 0 {
+            // e.g. paused by a duty cycle
+            return None;
+        }
+        // ACKs for the previous window's last probes arrive in this one
+        let loss = self.loss.update(
+            100.0 * (1.0 - window_received as f64 / window_sent as f64).max(0.0),
+            ADAPT_WINDOW,
+        );
+
+        let from = state.rate.load(Ordering::SeqCst);
+        let mut to = from;
+        if loss > self.config.loss_threshold {
+            self.under = 0;
+            self.over += 1;
+            if self.over >= ADAPT_SUSTAIN {
+                self.over = 0;
+                to = (from / 2).max(self.config.min_rate);
+            }
+        } else if loss < self.config.loss_threshold / 2.0 {
+            self.over = 0;
+            self.under += 1;
+            if self.under >= ADAPT_SUSTAIN {
+                self.under = 0;
+                to = (from + (from / 4).max(1)).min(self.max_rate);
+            }
+        } else {
+            self.over = 0;
+            self.under = 0;
+        }
+        if to == from {
+            return None;
+        }
+        state.rate.store(to, Ordering::SeqCst);
+        Some(Event::RateChange { from, to, loss })
+    }
+}
+
+/// Moving averages of the round-trip loss and time, updated every
+/// [`SMOOTH_EVERY`] from the ACKs that arrived meanwhile, ACKs or not.
+struct Smoother {
+    loss: Ewma,
+    rtt: Ewma,
+    last: Instant,
+    sent: u64,
+    received: u64,
+    rtt_sum: Duration,
+}
+
+impl Smoother {
+    fn new(half_life: Duration) -> Self {
+        Self {
+            loss: Ewma::new(half_life),
+            rtt: Ewma::new(half_life),
+            last: Instant::now(),
+            sent: 0,
+            received: 0,
+            rtt_sum: Duration::ZERO,
+        }
+    }
+
+    /// Update with the totals of probes sent, ACKs received and their
+    /// round-trip times, if it's time to.
+    fn tick(&mut self, sent: u64, received: u64, rtt_sum: Duration) {
+        let elapsed = self.last.elapsed();
+        if elapsed < SMOOTH_EVERY {
+            return;
+        }
+        let window_sent = sent - self.sent;
+        let window_received = received - self.received;
+        if window_sent > 0 {
+            // ACKs for the previous window's last probes arrive in this one
+            let loss = 1.0 - window_received as f64 / window_sent as f64;
+            self.loss.update(100.0 * loss.max(0.0), elapsed);
+        }
+        if window_received > 0 {
+            let rtt = (rtt_sum - self.rtt_sum).as_secs_f64() / window_received as f64;
+            self.rtt.update(rtt, elapsed);
+        }
+        self.last = Instant::now();
+        (self.sent, self.received, self.rtt_sum) = (sent, received, rtt_sum);
+    }
+
+    fn loss(&self) -> f64 {
+        self.loss.value().unwrap_or(f64::NAN)
+    }
+
+    fn rtt(&self) -> Duration {
+        Duration::from_secs_f64(self.rtt.value().unwrap_or(0.0))
+    }
+}
+
+/// Probe sizes cycled through in a fixed pattern, so the size of each probe
+/// follows from its sequence number.
+struct SizeMix {
+    sizes: Vec<usize>,
+    /// Index into `sizes` for each sequence number modulo the length
+    pattern: Vec<usize>,
+}
+
+impl SizeMix {
+    /// Interleave sizes by weight (smooth weighted round-robin) rather than
+    /// sending runs of the same size.
+    fn new(mix: &[(usize, u32)]) -> Self {
+        let total: i64 = mix.iter().map(|&(_, weight)| i64::from(weight)).sum();
+        let mut current = vec![0i64; mix.len()];
+        let pattern = (0..total)
+            .map(|_| {
+                for (c, &(_, weight)) in current.iter_mut().zip(mix) {
+                    *c += i64::from(weight);
+                }
+                let (pick, _) = current
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|&(i, c)| (*c, std::cmp::Reverse(i)))
+                    .unwrap();
+                current[pick] -= total;
+                pick
+            })
+            .collect();
+        Self {
+            sizes: mix.iter().map(|&(size, _)| size).collect(),
+            pattern,
+        }
+    }
+
+    fn bucket(&self, seq: u64) -> usize {
+        self.pattern[seq as usize % self.pattern.len()]
+    }
+
+    fn ack_size(&self, bucket: usize, pad_acks: bool) -> usize {
+        if pad_acks {
+            self.sizes[bucket]
+        } else {
+            SERVER_TO_CLIENT_PACKET_SIZE
+        }
+    }
+
+    /// Average probe and ACK sizes over the pattern
+    fn mean_sizes(&self, pad_acks: bool) -> (u32, u32) {
+        let n = self.pattern.len();
+        let probe: usize = self.pattern.iter().map(|&b| self.sizes[b]).sum();
+        let ack: usize = self
+            .pattern
+            .iter()
+            .map(|&b| self.ack_size(b, pad_acks))
+            .sum();
+        ((probe / n) as u32, (ack / n) as u32)
+    }
+}
+
+/// Probes per recorded slot, one bit each
+const SLOT_SIZE: usize = 64;
+
+/// At low rates a few seconds are less than a slot, but whole slots need to
+/// stay around until their probes can have arrived.
+fn late_window(packets_per_second: u32) -> usize {
+    (packets_per_second as usize * LATE_WINDOW_SECS).max(2 * SLOT_SIZE)
+}
+
+/// Round-trip loss over the probes before `end` sent within each of
+/// `windows` at the configured rate, from the `decided` slots before
+/// `seq_offset` and the `pending` ones from there on.
+fn recent_loss(
+    decided: &VecDeque<u64>,
+    pending: &VecDeque<u64>,
+    seq_offset: u64,
+    end: u64,
+    windows: &[Duration],
+    rate: u32,
+) -> Vec<RecentLoss> {
+    let slot = SLOT_SIZE as u64;
+    let first_known = seq_offset - decided.len() as u64 * slot;
+    let slots = decided
+        .iter()
+        .enumerate()
+        .map(|(i, &bits)| (first_known + i as u64 * slot, bits))
+        .chain(
+            pending
+                .iter()
+                .enumerate()
+                .map(|(i, &bits)| (seq_offset + i as u64 * slot, bits)),
+        );
+    windows
+        .iter()
+        .map(|&window| {
+            let probes = (window.as_secs_f64() * f64::from(rate)).ceil() as u64;
+            let start = end.saturating_sub(probes).max(first_known).min(end);
+            let received = slots
+                .clone()
+                .filter(|&(first, _)| first < end && first + slot > start)
+                .map(|(first, bits)| {
+                    // bit i is probe first + i
+                    let low = start.saturating_sub(first);
+                    let high = (end - first).min(slot);
+                    let mask = (u64::MAX >> (slot - (high - low))) << low;
+                    u64::from((bits & mask).count_ones())
+                })
+                .sum();
+            RecentLoss {
+                window,
+                sent: end - start,
+                received,
+            }
+        })
+        .collect()
+}
+
+/// Mean of `n` round trips summing to `sum`, zero if there were none.
+fn mean(sum: Duration, n: u64) -> Duration {
+    if n == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_secs_f64(sum.as_secs_f64() / n as f64)
+}
+
+/// A client running on a background thread, see [`ProbeClient::spawn`].
+pub struct ClientHandle {
+    stop: StopHandle,
+    stats: Receiver<Stats>,
+    events: Receiver<Event>,
+    thread: JoinHandle<eyre::Result<()>>,
+}
+
+impl ClientHandle {
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Stream of stats snapshots; iterate it to follow the measurement live.
+    pub fn stats(&self) -> &Receiver<Stats> {
+        &self.stats
+    }
+
+    /// Events as they are detected.
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+
+    /// Stop the client and wait for it to finish its recording.
+    pub fn stop(self) -> eyre::Result<()> {
+        self.stop.stop();
+        self.join()
+    }
+
+    pub fn join(self) -> eyre::Result<()> {
+        self.thread.join().unwrap()
+    }
+
+    /// Whether the client has stopped, so [`ClientHandle::join`] won't block.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+}
+
+/// Receiving end of the server's own probes in symmetric mode.
+#[derive(Default)]
+struct ServerProbes {
+    /// Sequence numbers received in the current session
+    seen: ReplayWindow<64>,
+    received: u64,
+    /// Counts of sessions the server has since forgotten
+    earlier: SymmetricStats,
+    /// Transit time of the latest in microseconds, against the server's
+    /// clock
+    last_transit: Option<i64>,
+    jitter_micros: f64,
+}
+
+impl ServerProbes {
+    /// Count probe `seq`, sent at `sent_micros` and arriving at
+    /// `now_micros`, returning how many arrived in this session if it's new.
+    fn receive(&mut self, seq: u32, sent_micros: u64, now_micros: u64) -> Option<u64> {
+        let seq = protocol::widen(seq, self.seen.highest());
+        if !self.seen.insert(seq) {
+            return None;
+        }
+        self.received += 1;
+        let transit = now_micros as i64 - sent_micros as i64;
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit - last_transit).abs() as f64;
+            self.jitter_micros += (d - self.jitter_micros) / 16.0;
+        }
+        self.last_transit = Some(transit);
+        Some(self.received)
+    }
+
+    /// The server forgot the session, and streams from scratch in the next.
+    fn restart(&mut self) {
+        self.earlier = self.stats();
+        self.seen = ReplayWindow::default();
+        self.received = 0;
+        self.last_transit = None;
+    }
+
+    fn stats(&self) -> SymmetricStats {
+        SymmetricStats {
+            // sequence numbers start at 1
+            server_sent: self.earlier.server_sent + self.seen.highest(),
+            client_received: self.earlier.client_received + self.received,
+            jitter: Duration::from_micros(self.jitter_micros as u64),
+        }
+    }
+}
+
+/// Count the first `probes` of a slot from `seq_offset` whose late window has
+/// passed as received or lost, reporting loss bursts that end in it and
+/// recording it.
+fn decide_slot(
+    packets_received: u64,
+    seq_offset: u64,
+    probes: usize,
+    loss_series: &mut LossSeries,
+    (lost_run, lost_run_start): (&mut u32, &mut u64),
+    slots: Option<&Sender<recording::Message>>,
+    on_event: &mut impl FnMut(&Event),
+) -> eyre::Result<()> {
+    for i in 0..probes {
+        let lost = packets_received & (1 << i) == 0;
+        loss_series.record(seq_offset + i as u64, lost);
+        if lost {
+            if *lost_run == 0 {
+                *lost_run_start = seq_offset + i as u64;
+            }
+            *lost_run += 1;
+        } else {
+            if *lost_run >= BURST_MIN {
+                on_event(&Event::LossBurst {
+                    first_seq: *lost_run_start,
+                    lost: *lost_run,
+                });
+            }
+            *lost_run = 0;
+        }
+    }
+    if let Some(slots) = slots {
+        slots
+            .send(recording::Message::Slot(packets_received.count_ones() as u8))
+            .map_err(|_| eyre::eyre!("recorder stopped"))?;
+    }
+    Ok(())
+}
+
+fn receive_loop(
+    acks: &mut AckSource,
+    state: &ClientSharedState,
+    slots: Option<Sender<recording::Message>>,
+    mut on_stats: impl FnMut(&Stats),
+    mut on_event: impl FnMut(&Event),
+) -> eyre::Result<()> {
+    let done = &state.done;
+    let client_sent = &state.client_sent;
+    let late_window = late_window(state.packets_per_second);
+    let mut controller = state
+        .adaptive
+        .map(|config| RateController::new(config, state));
+    let mut trains = state
+        .packet_trains
+        .map(|trains| capacity::Estimator::new(trains.length, trains.size));
+    let start_time = Instant::now();
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut time_slots = VecDeque::<u64>::new();
+    let mut seq_offset = state.first_seq;
+    // highest sequence number acknowledged, to widen the next one against
+    let mut highest_seq = state.first_seq - 1;
+    let mut client_received = 0u64;
+    let mut server_received = 0u64;
+    // received by the server in sessions it has since forgotten
+    let mut earlier_sessions_received = 0u64;
+    // as reported by the server when checking checksums, and likewise
+    let mut corrupted = state.checksums.then_some(0);
+    let mut earlier_sessions_corrupted = 0;
+    // the server's epoch in ACKs, and the one before it changed, whose late
+    // ACKs are then ignored
+    let mut epoch = None;
+    let mut previous_epoch = None;
+    // where the server's counts stood when this run took over the session
+    let (mut session_received, mut session_corrupted) =
+        state.resumption.map_or((0, 0), |resumption| {
+            (resumption.received, resumption.corrupted)
+        });
+    let mut last_report = Instant::now();
+    // an echo service doesn't count what it received
+    let round_trip_only = matches!(
+        state.protocol,
+        Protocol::Echo | Protocol::Icmp | Protocol::Tcp
+    );
+
+    let mut last_recv: Option<Instant> = None;
+    // whether packets of another protocol version were reported
+    let mut mismatched = false;
+    let mut lags = Histogram::default();
+    let mut rtts = Histogram::default();
+    // sequence number and round-trip time of the latest new ACK, for the
+    // delay variation to the next probe's
+    let mut last_probe: Option<(u64, Duration)> = None;
+    let mut ipdv = Histogram::default();
+    let mut loss_series = LossSeries::new(state.packets_per_second);
+    let mut smoother = Smoother::new(state.half_life);
+    // the latest slots past the late window, enough for the longest loss
+    // window
+    let mut recent_slots = VecDeque::new();
+    let max_recent_slots = state.loss_windows.iter().max().map_or(0, |window| {
+        (window.as_secs_f64() * f64::from(state.packets_per_second) / SLOT_SIZE as f64).ceil()
+            as usize
+            + 1
+    });
+    let mut max_gap = Duration::ZERO;
+    // round-trip time of the latest new ACK, and the jitter of them
+    let mut last_rtt: Option<Duration> = None;
+    let mut jitter_micros = 0.0;
+    let mut outages = Vec::new();
+    // whether the outage going on was reported as started
+    let mut in_outage = false;
+    // consecutive probes without ACK, carried across slots
+    let mut lost_run = 0;
+    let mut lost_run_start = 0;
+    let mix = &state.mix;
+    let mut received_by_size = vec![0u64; mix.sizes.len()];
+    let mut ecn = state.ecn.then(EcnStats::default);
+    let mut server_probes = state.symmetric.then(ServerProbes::default);
+    // (received, sum of round-trip times, largest round-trip time) per path
+    let mut received_by_path = vec![(0u64, Duration::ZERO, Duration::ZERO); state.sockets.len()];
+    let ports: Vec<u16> = state
+        .sockets
+        .iter()
+        .map(|socket| socket.local_addr().map_or(0, |addr| addr.port()))
+        .collect();
+    let (probe_size, ack_size) = mix.mean_sizes(state.pad_acks);
+    let snapshot = |server_received,
+                    client_received,
+                    lags: &Histogram,
+                    rtts: &Histogram,
+                    ipdv: &Histogram,
+                    recent_slots: &VecDeque<u64>,
+                    time_slots: &VecDeque<u64>,
+                    seq_offset: u64,
+                    holdback: u64,
+                    smoother: &Smoother,
+                    max_gap,
+                    outages: &[Outage],
+                    received_by_size: &[u64],
+                    received_by_path: &[(u64, Duration, Duration)],
+                    ecn,
+                    capacity_mbps,
+                    corrupted,
+                    symmetric,
+                    jitter| Stats {
+        client_sent: client_sent
+            .load(Ordering::SeqCst)
+            .saturating_sub(state.failover_unanswered.load(Ordering::SeqCst)),
+        failover_unanswered: state.failover_unanswered.load(Ordering::SeqCst),
+        server_received,
+        client_received,
+        elapsed: start_time.elapsed(),
+        lags: lags.clone(),
+        max_gap,
+        outages: outages.to_vec(),
+        probe_size,
+        ack_size,
+        sizes: if mix.sizes.len() > 1 {
+            mix.sizes
+                .iter()
+                .zip(&state.sent_by_size)
+                .zip(received_by_size)
+                .map(|((&size, sent), &received)| SizeStats {
+                    size: size as u32,
+                    sent: sent.load(Ordering::SeqCst),
+                    received,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        },
+        capacity_mbps,
+        ecn,
+        corrupted,
+        symmetric,
+        mean_rtt: {
+            let (received, rtt_sum) = received_by_path
+                .iter()
+                .fold((0, Duration::ZERO), |(n, sum), path| {
+                    (n + path.0, sum + path.1)
+                });
+            mean(rtt_sum, received)
+        },
+        max_rtt: received_by_path
+            .iter()
+            .map(|path| path.2)
+            .max()
+            .unwrap_or_default(),
+        rtts: rtts.clone(),
+        ipdv: ipdv.clone(),
+        recent_loss: recent_loss(
+            recent_slots,
+            time_slots,
+            seq_offset,
+            // probes still awaited don't count yet
+            (state.first_seq + client_sent.load(Ordering::SeqCst)).saturating_sub(holdback),
+            &state.loss_windows,
+            state.packets_per_second,
+        ),
+        smoothed_loss: smoother.loss(),
+        smoothed_rtt: smoother.rtt(),
+        // searched once at the end, being costly
+        loss_periods: Vec::new(),
+        jitter,
+        paths: if state.sockets.len() == 1 {
+            Vec::new()
+        } else {
+            ports
+                .iter()
+                .zip(&state.sent_by_path)
+                .zip(received_by_path)
+                .map(|((&port, sent), &(received, rtt_sum, max_rtt))| PathStats {
+                    port,
+                    sent: sent.load(Ordering::SeqCst),
+                    received,
+                    mean_rtt: mean(rtt_sum, received),
+                    max_rtt,
+                })
+                .collect()
+        },
+        hops: state.hops.lock().unwrap().clone(),
+        round_trip_only,
+    };
+    #[cfg(unix)]
+    let mut watchdog = crate::systemd::Watchdog::from_env();
+    while !done.is_stopped() {
+        #[cfg(unix)]
+        watchdog.ping();
+        if state.sending_done.load(Ordering::SeqCst)
+            && client_received >= client_sent.load(Ordering::SeqCst)
+        {
+            break;
+        }
+        let rate = state.rate.load(Ordering::SeqCst);
+        for event in state.pending_events.lock().unwrap().drain(..) {
+            // marks where in the recording the silent server's probes are
+            if let (Event::Failover { .. }, Some(slots)) = (&event, &slots) {
+                let metadata = json::Object::new()
+                    .u64(
+                        "unix_secs",
+                        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                    )
+                    .raw("event", &event.to_json());
+                slots
+                    .send(recording::Message::Metadata(metadata))
+                    .map_err(|_| eyre::eyre!("recorder stopped"))?;
+            }
+            on_event(&event);
+        }
+        smoother.tick(
+            client_sent.load(Ordering::SeqCst),
+            client_received,
+            received_by_path.iter().map(|path| path.1).sum(),
+        );
+        if let Some(controller) = &mut controller {
+            if let Some(event) = controller.tick(state, client_received) {
+                on_event(&event);
+            }
+        }
+        if let Some(last) = last_recv.filter(|_| !in_outage) {
+            // like lag, only time beyond the probe interval counts, and not
+            // once sending is done and everything was answered
+            let unanswered =
+                client_sent.load(Ordering::SeqCst) > state.sent_at_last_ack.load(Ordering::SeqCst);
+            if unanswered
+                && last.elapsed().saturating_sub(Duration::from_secs(1) / rate) >= OUTAGE_THRESHOLD
+            {
+                in_outage = true;
+                on_event(&Event::OutageStart {
+                    start: last.duration_since(start_time),
+                });
+            }
+        }
+        // on a timer, so outages get reported while they last
+        if last_report.elapsed() >= state.report_interval && client_sent.load(Ordering::SeqCst) > 0
+        {
+            last_report = Instant::now();
+            let stats = snapshot(
+                server_received,
+                client_received,
+                &lags,
+                &rtts,
+                &ipdv,
+                &recent_slots,
+                &time_slots,
+                seq_offset,
+                late_window as u64,
+                &smoother,
+                // including the gap going on
+                max_gap.max(last_recv.map_or(Duration::ZERO, |last| last.elapsed())),
+                &outages,
+                &received_by_size,
+                &received_by_path,
+                ecn,
+                trains.as_ref().and_then(capacity::Estimator::mbps),
+                corrupted,
+                server_probes.as_ref().map(ServerProbes::stats),
+                Duration::from_micros(jitter_micros as u64),
+            );
+            on_stats(&stats);
+            *state.latest.lock().unwrap() = Some(stats);
+            max_gap = Duration::ZERO;
+        }
+        let n = match acks.recv(&mut buf) {
+            Ok(x) => Ok(x),
+            // read timeouts are WouldBlock on Unix but TimedOut on Windows,
+            // and signals handled on this thread interrupt the wait
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) if is_unreachable(&e) => continue,
+            x => x,
+        }?;
+        // forged or from a server with another key
+        let Some(mut n) = auth::open(state.psk.as_ref(), &buf[..n]) else {
+            continue;
+        };
+        if state.noise.is_some() {
+            match buf[0] {
+                NOISE_DATA_PACKET_CONST => {
+                    let transport = state.transport.lock().unwrap().clone();
+                    let Some(len) = transport.and_then(|transport| transport.open(&mut buf[..n]))
+                    else {
+                        continue;
+                    };
+                    n = len;
+                }
+                // a renewed session, handled like a plaintext welcome
+                NOISE_RESP_PACKET_CONST if state.session_lost.load(Ordering::SeqCst) => {
+                    let answer = state
+                        .renewal
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .and_then(|renewal| renewal.initiator.finish(&buf[..n]));
+                    let Some((welcome, transport)) = answer else {
+                        continue;
+                    };
+                    if welcome.len() > buf.len() {
+                        continue;
+                    }
+                    *state.renewal.lock().unwrap() = None;
+                    *state.transport.lock().unwrap() = Some(Arc::new(transport));
+                    buf[..welcome.len()].copy_from_slice(&welcome);
+                    n = welcome.len();
+                }
+                // plaintext, as the server lost the keys or has none yet
+                _ if matches!(
+                    protocol::header(&buf[..n]),
+                    Ok(Header {
+                        kind: Kind::UnknownSession | Kind::Cookie,
+                        ..
+                    })
+                ) => {}
+                _ => continue,
+            }
+            if n == 0 {
+                continue;
+            }
+        }
+        let parsed = match state.protocol {
+            Protocol::LossLens => protocol::reply(&buf[..n]),
+            // anything else from the reflector's port is no concern of ours
+            Protocol::Twamp => match twamp::reply(&buf[..n]) {
+                Some(reply) => Ok((
+                    Header {
+                        kind: Kind::Ack,
+                        version: 0,
+                    },
+                    reply,
+                )),
+                None => continue,
+            },
+            // the probe itself, without a count of the server's
+            Protocol::Echo => match protocol::request(&buf[..n]) {
+                Ok((header, Request::Probe { seq, client_id, .. }))
+                    if client_id == state.client_id.load(Ordering::SeqCst) =>
+                {
+                    let reply = Reply::Ack {
+                        seq,
+                        received: 0,
+                        ecn: None,
+                    };
+                    Ok((header, reply))
+                }
+                _ => continue,
+            },
+            Protocol::Tcp => match tcp::reply(&buf[..n]) {
+                Some(reply) => Ok((
+                    Header {
+                        kind: Kind::Ack,
+                        version: 0,
+                    },
+                    reply,
+                )),
+                None => continue,
+            },
+            Protocol::Icmp => {
+                match icmp::reply(&buf[..n], state.client_id.load(Ordering::SeqCst)) {
+                    Some(reply) => Ok((
+                        Header {
+                            kind: Kind::Ack,
+                            version: 0,
+                        },
+                        reply,
+                    )),
+                    None => continue,
+                }
+            }
+        };
+        let (version, reply) = match parsed {
+            Ok((header, reply)) => (header.version, Some(reply)),
+            // reported as a mismatch below
+            Err(protocol::Error::Version(version)) => (version, None),
+            // malformed, or nothing the server would send
+            Err(_) => continue,
+        };
+        match reply {
+            // the server forgot the session; the send loop handshakes again
+            Some(Reply::UnknownSession { client_id })
+                if version == state.version
+                    && client_id == state.client_id.load(Ordering::SeqCst) =>
+            {
+                if !state.session_lost.swap(true, Ordering::SeqCst) {
+                    crate::log::debug!("The server forgot the session; handshaking again");
+                }
+                continue;
+            }
+            Some(Reply::Cookie(cookie)) => {
+                if state.cookie.lock().unwrap().replace(cookie) != Some(cookie) {
+                    crate::log::debug!("The server asked for a cookie; handshaking again with it");
+                }
+                continue;
+            }
+            Some(Reply::Welcome(welcome)) => {
+                if state.session_lost.swap(false, Ordering::SeqCst) {
+                    *state.cookie.lock().unwrap() = None;
+                    state.client_id.store(welcome.token, Ordering::SeqCst);
+                    if let Some(path) = &state.resume {
+                        let secret = session::decode_resume_secret(&buf[..n]);
+                        resume::save(path, &state.host, welcome.token, secret.as_ref())?;
+                    }
+                    earlier_sessions_received = server_received;
+                    earlier_sessions_corrupted = corrupted.unwrap_or(0);
+                    (session_received, session_corrupted) = (0, 0);
+                    previous_epoch = epoch.take();
+                    if let Some(server_probes) = &mut server_probes {
+                        server_probes.restart();
+                    }
+                    on_event(&Event::SessionRestart);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let Some(reply) = reply.filter(|_| version == state.version) else {
+            if !mismatched {
+                mismatched = true;
+                on_event(&Event::ProtocolMismatch {
+                    expected: state.version,
+                    got: version,
+                });
+            }
+            continue;
+        };
+        let ack = match reply {
+            // not part of the probe stream, and would hide lags
+            Reply::TrainAck {
+                train_id,
+                index,
+                rx_micros,
+            } => {
+                if let Some(trains) = &mut trains {
+                    trains.ack(train_id, index, rx_micros);
+                }
+                continue;
+            }
+            // the stream of an earlier session may still be arriving
+            Reply::ServerProbe {
+                seq,
+                client_id,
+                sent_micros,
+            } if client_id == state.client_id.load(Ordering::SeqCst) => {
+                let now_micros = state.epoch.elapsed().as_micros() as u64;
+                let received = server_probes
+                    .as_mut()
+                    .and_then(|probes| probes.receive(seq, sent_micros, now_micros));
+                if let Some(received) = received {
+                    // the count wraps on the wire like the server's
+                    let answer =
+                        protocol::server_probe_ack(state.version, seq, client_id, received as u32);
+                    let addr = *state.addr.lock().unwrap();
+                    state.send(0, &answer, addr)?;
+                }
+                continue;
+            }
+            Reply::ServerProbe { .. } => continue,
+            Reply::Ack { seq, received, ecn } => {
+                let seq = protocol::widen(seq, highest_seq);
+                highest_seq = highest_seq.max(seq);
+                Some((seq, received, ecn))
+            }
+            _ => None,
+        };
+        // the wait before the acknowledged probe was sent; with Poisson
+        // sending long waits are normal and must not count as lag
+        if ack.is_some() {
+            let micros = state.epoch.elapsed().as_micros() as u64;
+            state.last_ack.store(micros, Ordering::SeqCst);
+            state
+                .sent_at_last_ack
+                .store(client_sent.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+        let send_gap = if let Some((seq, ..)) = ack {
+            let gap = &state.send_gaps[seq as usize % state.send_gaps.len()];
+            Duration::from_micros(gap.load(Ordering::SeqCst).into())
+        } else {
+            Duration::from_secs(1) / rate
+        };
+        if let Some(last) = last_recv {
+            let gap = last.elapsed();
+            max_gap = max_gap.max(gap);
+            // only time beyond the probe interval counts as lag, which
+            // matters at low rates
+            let late = gap.saturating_sub(send_gap);
+            if late >= OUTAGE_THRESHOLD {
+                let outage = Outage {
+                    start: last.duration_since(start_time),
+                    duration: gap,
+                };
+                outages.push(outage);
+                on_event(&Event::Outage(outage));
+            }
+            lags.record(late);
+        }
+        last_recv = Some(Instant::now());
+        in_outage = false;
+        // ACKs may be padded, only the header matters
+        if let Some((received_seq, acked, ecn_field)) = ack {
+            let ack_epoch = protocol::epoch(&buf[..n]).filter(|_| state.epochs);
+            let stale = ack_epoch.is_some() && ack_epoch == previous_epoch;
+            if let Some(ack_epoch) = ack_epoch.filter(|_| !stale) {
+                if let Some(from) = epoch.filter(|&e| e != ack_epoch) {
+                    // a restarted or another server, counting from scratch
+                    earlier_sessions_received = server_received;
+                    earlier_sessions_corrupted = corrupted.unwrap_or(0);
+                    (session_received, session_corrupted) = (0, 0);
+                    previous_epoch = Some(from);
+                    on_event(&Event::ServerChange {
+                        from,
+                        to: ack_epoch,
+                    });
+                }
+                epoch = Some(ack_epoch);
+            }
+            // counts of the server left behind would be added up again
+            if !stale && !round_trip_only {
+                // the server's counter wraps on the wire too
+                let acked = protocol::widen(
+                    acked,
+                    server_received - earlier_sessions_received + session_received,
+                );
+                server_received = (earlier_sessions_received
+                    + acked.saturating_sub(session_received))
+                .max(server_received);
+                if let (Some(corrupted), Some(count)) =
+                    (&mut corrupted, protocol::corrupted(&buf[..n]))
+                {
+                    *corrupted = (earlier_sessions_corrupted
+                        + count.saturating_sub(session_corrupted))
+                    .max(*corrupted);
+                }
+            }
+            // account for reordering by keeping track of which sequence numbers have not been responded to yet
+            // remove overly late packets from the datastructure and count them as lost
+            while time_slots.len() * SLOT_SIZE > late_window {
+                if let Some(packets_received) = time_slots.pop_front() {
+                    recent_slots.push_back(packets_received);
+                    if recent_slots.len() > max_recent_slots {
+                        recent_slots.pop_front();
+                    }
+                    decide_slot(
+                        packets_received,
+                        seq_offset,
+                        SLOT_SIZE,
+                        &mut loss_series,
+                        (&mut lost_run, &mut lost_run_start),
+                        slots.as_ref(),
+                        &mut on_event,
+                    )?;
+                    seq_offset += SLOT_SIZE as u64;
+                }
+            }
+
+            // packet already counted as lost if it didn't arrive within this window
+            if received_seq >= seq_offset {
+                // make space for new sequence numbers
+                while received_seq >= (time_slots.len() * SLOT_SIZE) as u64 + seq_offset {
+                    time_slots.push_back(0u64);
+                }
+                let idx = (received_seq - seq_offset) as usize;
+                if time_slots[idx / SLOT_SIZE] & (1 << (idx % SLOT_SIZE)) == 0 {
+                    client_received += 1;
+                    if round_trip_only {
+                        // for the traffic estimate, and to report as often
+                        server_received = client_received;
+                    }
+                    received_by_size[mix.bucket(received_seq)] += 1;
+                    if let Some(ecn) = &mut ecn {
+                        match ecn_field {
+                            Some(field) if field & ECN_MASK == CE => ecn.ce += 1,
+                            Some(_) => ecn.ect += 1,
+                            None => ecn.not_ect += 1,
+                        }
+                    }
+                    let sent = state.send_paths[received_seq as usize % state.send_paths.len()]
+                        .load(Ordering::SeqCst);
+                    let rtt = state
+                        .epoch
+                        .elapsed()
+                        .saturating_sub(Duration::from_micros(sent >> 8));
+                    let path = &mut received_by_path[(sent & 0xff) as usize];
+                    path.0 += 1;
+                    path.1 += rtt;
+                    path.2 = path.2.max(rtt);
+                    rtts.record(rtt);
+                    if let Some((seq, last)) = last_probe {
+                        if received_seq == seq + 1 {
+                            ipdv.record(rtt.abs_diff(last));
+                        }
+                    }
+                    last_probe = Some((received_seq, rtt));
+                    if let Some(last_rtt) = last_rtt {
+                        let d = rtt.abs_diff(last_rtt).as_secs_f64() * 1e6;
+                        jitter_micros += (d - jitter_micros) / 16.0;
+                    }
+                    last_rtt = Some(rtt);
+                }
+                time_slots[idx / SLOT_SIZE] |= 1 << (idx % SLOT_SIZE);
+            }
+        }
+    }
+    // the probes of the last late window are as late as they get by now
+    let end = state.first_seq + client_sent.load(Ordering::SeqCst);
+    while seq_offset < end {
+        let packets_received = time_slots.pop_front().unwrap_or(0);
+        recent_slots.push_back(packets_received);
+        if recent_slots.len() > max_recent_slots {
+            recent_slots.pop_front();
+        }
+        decide_slot(
+            packets_received,
+            seq_offset,
+            ((end - seq_offset) as usize).min(SLOT_SIZE),
+            &mut loss_series,
+            (&mut lost_run, &mut lost_run_start),
+            slots.as_ref(),
+            &mut on_event,
+        )?;
+        seq_offset += SLOT_SIZE as u64;
+    }
+    if lost_run >= BURST_MIN {
+        on_event(&Event::LossBurst {
+            first_seq: lost_run_start,
+            lost: lost_run,
+        });
+    }
+    // final totals, including probes acknowledged after the last report
+    if client_sent.load(Ordering::SeqCst) > 0 {
+        let mut stats = snapshot(
+            server_received,
+            client_received,
+            &lags,
+            &rtts,
+            &ipdv,
+            &recent_slots,
+            &time_slots,
+            seq_offset,
+            0,
+            &smoother,
+            max_gap,
+            &outages,
+            &received_by_size,
+            &received_by_path,
+            ecn,
+            trains.as_ref().and_then(capacity::Estimator::mbps),
+            corrupted,
+            server_probes.as_ref().map(ServerProbes::stats),
+            Duration::from_micros(jitter_micros as u64),
+        );
+        stats.loss_periods = loss_series.periods();
+        *state.latest.lock().unwrap() = Some(stats);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process::Command};
+
+    use super::*;
+    use crate::{ProbeServer, ServerConfig};
+
+    #[test]
+    fn short_runs_record_every_slot() {
+        let path = env::temp_dir().join(format!("loss_lens-{}.zst", std::process::id()));
+        let server = ProbeServer::new(ServerConfig {
+            host: "127.0.0.1:0".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let server = server.spawn();
+        let client = ProbeClient::new(ClientConfig {
+            host: addr.to_string(),
+            recording: Some(path.clone()),
+            packets_per_second: 2000,
+            count: Some(200),
+            ..Default::default()
+        })
+        .unwrap();
+        client.run(|_| {}).unwrap();
+        server.stop().unwrap();
+        // the in-process writer is tested in `zstd`
+        let decompressed = Command::new("zstd").arg("-dc").arg(&path).output();
+        fs::remove_file(&path).unwrap();
+        let Ok(out) = decompressed else {
+            return;
+        };
+        // all within the late window when sending ended
+        assert_eq!(out.stdout, [64, 64, 64, 8]);
+    }
+}
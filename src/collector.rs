@@ -0,0 +1,592 @@
+//! Central collector for clients probing from many sites, which send it
+//! their latest summary every so often with a [`Reporter`]
+//! (`client --collector`), and an API and dashboard over all of them:
+//!
+//! - `POST /report`: a reporter's summary
+//! - `GET /probes`: every probe with its latest summary
+//! - `GET /probes/NAME`: a probe's recent summaries, oldest first
+//! - `GET /aggregate`: totals over the probes that reported recently
+//! - `GET /aggregate?by=KEY`: the same per value of label `KEY`, e.g. per
+//!   `site`
+//! - `GET /`: a dashboard of the above
+//!
+//! Where a token is configured, reports need an `Authorization: Bearer
+//! TOKEN` header; reading is open. With a store file, every report is
+//! appended to it as a line of JSON, and the file is read back on start.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    client::{labels_json, valid_label_key},
+    http::{self, Request, Response, Url},
+    json::{self, Value},
+    snapshots::Snapshots,
+    Stats,
+};
+
+/// Summaries kept per probe; at one a minute this is a day.
+const HISTORY: usize = 1440;
+/// Most probes tracked. With [`HISTORY`] reports of up to [`MAX_REPORT`]
+/// each, that still leaves room for gigabytes, so collectors reachable from
+/// untrusted hosts should require a token.
+const MAX_PROBES: usize = 1024;
+/// Largest report body accepted: [`COUNTERS`] and labels take a few
+/// hundred bytes, leaving the rest for a run's outages
+const MAX_REPORT: usize = 16 << 10;
+/// The numbers kept of a probe's [`Stats`], all the aggregate, dashboard
+/// and `analyze` read besides `rtt_percentiles` and `outages`
+const COUNTERS: [&str; 14] = [
+    "client_sent",
+    "server_received",
+    "client_received",
+    "elapsed_secs",
+    "upstream_loss",
+    "downstream_loss",
+    "round_trip_loss",
+    "mean_rtt_ms",
+    "max_rtt_ms",
+    "max_gap_ms",
+    "smoothed_loss",
+    "smoothed_rtt_ms",
+    "jitter_ms",
+    "mos",
+];
+/// Probes that haven't reported for this long are left out of the aggregate
+const STALE_AFTER: Duration = Duration::from_secs(600);
+
+const INDEX_HTML: &str = include_str!("web/collector.html");
+
+/// Where and how a collector serves.
+#[derive(Clone, Debug)]
+pub struct CollectorConfig {
+    /// Address to listen on for reports and the API
+    pub addr: String,
+    /// Bearer token reports must carry
+    pub token: Option<String>,
+    /// File to keep reports in across restarts
+    pub store: Option<PathBuf>,
+}
+
+/// One summary as received from a probe.
+#[derive(Clone, Debug)]
+struct Report {
+    /// When the collector received it, as a Unix timestamp
+    received_unix: f64,
+    probe: String,
+    /// Host the probe measures
+    target: String,
+    /// The client's labels, see [`ClientConfig::labels`](crate::ClientConfig::labels)
+    labels: Vec<(String, String)>,
+    /// The probe's [`Stats`] as serialized by [`Stats::to_json`], cut down
+    /// by [`counters`]
+    stats: Value,
+}
+
+impl Report {
+    /// A report as sent by a [`Reporter`], or as stored, where
+    /// `received_unix` comes with it.
+    fn from_json(value: &Value, received_unix: Option<f64>) -> Result<Self, &'static str> {
+        let probe = value
+            .get("probe")
+            .and_then(Value::as_str)
+            .filter(|probe| valid_probe_name(probe))
+            .ok_or("probe must be a name of letters, digits, `.`, `_` and `-`")?;
+        let target = value
+            .get("target")
+            .and_then(Value::as_str)
+            .ok_or("target must be a string")?;
+        let labels = match value.get("labels") {
+            None => Vec::new(),
+            Some(Value::Object(members)) => members
+                .iter()
+                .map(|(key, value)| match value.as_str() {
+                    Some(value) if valid_label_key(key) => Ok((key.clone(), value.to_string())),
+                    _ => {
+                        Err("labels must map keys of letters, digits, `.`, `_` and `-` to strings")
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err("labels must be an object"),
+        };
+        let stats = counters(value.get("stats").unwrap_or(&Value::Null))?;
+        let received_unix = match received_unix {
+            Some(at) => at,
+            None => value
+                .get("received_unix")
+                .and_then(Value::as_f64)
+                .ok_or("received_unix must be a number")?,
+        };
+        Ok(Self {
+            received_unix,
+            probe: probe.to_string(),
+            target: target.to_string(),
+            labels,
+            stats,
+        })
+    }
+
+    fn to_json(&self) -> String {
+        json::Object::new()
+            .f64("received_unix", self.received_unix)
+            .str("probe", &self.probe)
+            .str("target", &self.target)
+            .raw("labels", &labels_json(&self.labels))
+            .raw("stats", &self.stats.to_json())
+            .finish()
+    }
+
+    fn label(&self, key: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Counter `key` of the stats, 0 where missing.
+    fn counter(&self, key: &str) -> f64 {
+        self.stats.get(key).and_then(Value::as_f64).unwrap_or(0.0)
+    }
+
+    fn age(&self, now_unix: f64) -> Duration {
+        Duration::from_secs_f64((now_unix - self.received_unix).max(0.0))
+    }
+}
+
+/// The [`COUNTERS`], `rtt_percentiles` and `outages` of `stats`, checked to
+/// be numbers, or `null` for what a probe couldn't work out yet, all the way
+/// down, so what's served back is what the dashboard can show.
+fn counters(stats: &Value) -> Result<Value, &'static str> {
+    fn number(value: &Value) -> bool {
+        matches!(value, Value::Number(_) | Value::Null)
+    }
+    fn numbers(value: &Value) -> bool {
+        matches!(value, Value::Object(members) if members.iter().all(|(_, value)| number(value)))
+    }
+    let Value::Object(members) = stats else {
+        return Err("stats must be an object");
+    };
+    members
+        .iter()
+        .filter(|(key, _)| {
+            COUNTERS.contains(&key.as_str())
+                || matches!(key.as_str(), "rtt_percentiles" | "outages")
+        })
+        .map(|(key, value)| {
+            let valid = match key.as_str() {
+                "rtt_percentiles" => numbers(value),
+                "outages" => matches!(value, Value::Array(outages) if outages.iter().all(numbers)),
+                _ => number(value),
+            };
+            if !valid {
+                return Err("stats counters must be numbers");
+            }
+            Ok((key.clone(), value.clone()))
+        })
+        .collect::<Result<_, _>>()
+        .map(Value::Object)
+}
+
+/// Probe names end up in URL paths, so like label keys they're kept to
+/// characters that need no escaping.
+fn valid_probe_name(name: &str) -> bool {
+    valid_label_key(name)
+}
+
+fn now_unix() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[derive(Default)]
+struct CollectorState {
+    /// Recent reports by probe name
+    probes: BTreeMap<String, VecDeque<Report>>,
+    store: Option<File>,
+}
+
+impl CollectorState {
+    fn add(&mut self, report: Report) -> Result<(), &'static str> {
+        if !self.probes.contains_key(&report.probe) && self.probes.len() >= MAX_PROBES {
+            return Err("too many probes");
+        }
+        let history = self.probes.entry(report.probe.clone()).or_default();
+        history.push_back(report);
+        while history.len() > HISTORY {
+            history.pop_front();
+        }
+        Ok(())
+    }
+}
+
+/// HTTP server collecting reports, in the background for the life of the
+/// process like the dashboard.
+pub struct Collector {
+    state: Arc<Mutex<CollectorState>>,
+    local_addr: SocketAddr,
+}
+
+impl Collector {
+    /// Load the store, if any, and start serving in the background.
+    pub fn serve(config: CollectorConfig) -> eyre::Result<Self> {
+        let mut state = CollectorState::default();
+        if let Some(path) = &config.store {
+            if path.exists() {
+                for line in std::fs::read_to_string(path)?.lines() {
+                    // a line torn by a crash mid-write is skipped
+                    let Ok(value) = json::parse(line) else {
+                        continue;
+                    };
+                    if let Ok(report) = Report::from_json(&value, None) {
+                        let _ = state.add(report);
+                    }
+                }
+            }
+            state.store = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        let state = Arc::new(Mutex::new(state));
+        let token = config.token.clone();
+        let local_addr = http::serve(&config.addr, MAX_REPORT, {
+            let state = Arc::clone(&state);
+            move |request, _stream| handle(request, &state, token.as_deref()).into()
+        })?;
+        Ok(Self { state, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of probes that reported so far.
+    pub fn probes(&self) -> usize {
+        self.state.lock().unwrap().probes.len()
+    }
+}
+
+fn handle(request: &Request, state: &Mutex<CollectorState>, token: Option<&str>) -> Response {
+    let method = request.method.as_str();
+    let path = request.path.as_str();
+    match (method, path) {
+        ("GET", "/") => Response::ok("text/html; charset=utf-8", INDEX_HTML),
+        ("POST", "/report") => {
+            if token.is_some_and(|token| !http::bearer_authorized(request, token)) {
+                return Response::text("401 Unauthorized", "missing or wrong bearer token");
+            }
+            match receive(request, state) {
+                Ok(()) => Response::text("200 OK", "stored"),
+                Err(e) => Response::text("400 Bad Request", e),
+            }
+        }
+        ("GET", "/probes") => Response::json(probes_json(&state.lock().unwrap())),
+        ("GET", "/aggregate") => match request.query_param("by") {
+            Some(key) if !valid_label_key(key) => {
+                Response::text("400 Bad Request", "by must be a label key")
+            }
+            by => Response::json(aggregate_json(&state.lock().unwrap(), by)),
+        },
+        ("GET", _) if path.starts_with("/probes/") => {
+            let state = state.lock().unwrap();
+            match state.probes.get(&path["/probes/".len()..]) {
+                Some(history) => Response::json(json::array(history.iter().map(Report::to_json))),
+                None => Response::not_found(),
+            }
+        }
+        (_, "/" | "/report" | "/probes" | "/aggregate") => Response::method_not_allowed(),
+        _ if path.starts_with("/probes/") => Response::method_not_allowed(),
+        _ => Response::not_found(),
+    }
+}
+
+fn receive(request: &Request, state: &Mutex<CollectorState>) -> Result<(), &'static str> {
+    let body = std::str::from_utf8(&request.body).map_err(|_| "body must be UTF-8")?;
+    let value = json::parse(body).map_err(|_| "body must be JSON")?;
+    let report = Report::from_json(&value, Some(now_unix()))?;
+    let line = report.to_json();
+    let mut state = state.lock().unwrap();
+    state.add(report)?;
+    if let Some(store) = &mut state.store {
+        writeln!(store, "{line}").map_err(|_| "storing the report failed")?;
+    }
+    Ok(())
+}
+
+fn probes_json(state: &CollectorState) -> String {
+    let now = now_unix();
+    json::array(
+        state
+            .probes
+            .values()
+            .filter_map(VecDeque::back)
+            .map(|latest| {
+                json::Object::new()
+                    .str("probe", &latest.probe)
+                    .str("target", &latest.target)
+                    .raw("labels", &labels_json(&latest.labels))
+                    .f64("received_unix", latest.received_unix)
+                    .f64("age_secs", latest.age(now).as_secs_f64())
+                    .raw(
+                        "stale",
+                        if latest.age(now) >= STALE_AFTER {
+                            "true"
+                        } else {
+                            "false"
+                        },
+                    )
+                    .raw("stats", &latest.stats.to_json())
+                    .finish()
+            }),
+    )
+}
+
+/// Counters of the latest reports of fresh probes added up, with loss and
+/// round-trip time over all of them; with `by`, a list of such totals per
+/// value of that label, `null` for probes without it.
+fn aggregate_json(state: &CollectorState, by: Option<&str>) -> String {
+    let now = now_unix();
+    let latest = state.probes.values().filter_map(VecDeque::back);
+    let Some(key) = by else {
+        return totals(json::Object::new(), latest, now);
+    };
+    let mut groups = BTreeMap::<Option<&str>, Vec<&Report>>::new();
+    for report in latest {
+        groups.entry(report.label(key)).or_default().push(report);
+    }
+    json::array(groups.into_iter().map(|(value, reports)| {
+        let group = json::Object::new().raw(
+            "value",
+            &value.map_or_else(|| "null".to_string(), json::string),
+        );
+        totals(group, reports, now)
+    }))
+}
+
+/// `object` with the totals over `latest` added.
+fn totals<'a>(
+    object: json::Object,
+    latest: impl IntoIterator<Item = &'a Report>,
+    now: f64,
+) -> String {
+    let (fresh, stale): (Vec<_>, Vec<_>) = latest
+        .into_iter()
+        .partition(|latest| latest.age(now) < STALE_AFTER);
+    let sum = |key: &str| fresh.iter().map(|report| report.counter(key)).sum::<f64>();
+    let client_sent = sum("client_sent");
+    let server_received = sum("server_received");
+    let client_received = sum("client_received");
+    // weighted by how many round trips each mean is over
+    let mean_rtt_ms = fresh
+        .iter()
+        .map(|report| report.counter("mean_rtt_ms") * report.counter("client_received"))
+        .sum::<f64>()
+        / client_received;
+    let max_rtt_ms = fresh
+        .iter()
+        .map(|report| report.counter("max_rtt_ms"))
+        .fold(0.0, f64::max);
+    object
+        .u64("probes", fresh.len() as u64)
+        .u64("stale", stale.len() as u64)
+        .u64("client_sent", client_sent as u64)
+        .u64("server_received", server_received as u64)
+        .u64("client_received", client_received as u64)
+        .f64(
+            "upstream_loss",
+            100.0 * (1.0 - server_received / client_sent),
+        )
+        .f64(
+            "downstream_loss",
+            100.0 * (1.0 - client_received / server_received),
+        )
+        .f64("mean_rtt_ms", mean_rtt_ms)
+        .f64("max_rtt_ms", max_rtt_ms)
+        .finish()
+}
+
+/// Where and as what a client reports to a collector.
+#[derive(Clone, Debug)]
+pub struct ReporterConfig {
+    /// The collector, e.g. `http://collector.example:13380`
+    pub url: String,
+    /// Name the collector files reports under, e.g. the site probed from
+    pub probe: String,
+    /// Host being probed, shown alongside
+    pub target: String,
+    /// Sent along with every report, see [`ClientConfig::labels`](crate::ClientConfig::labels)
+    pub labels: Vec<(String, String)>,
+    /// Bearer token the collector requires
+    pub token: Option<String>,
+    /// How often to report
+    pub every: Duration,
+}
+
+/// Sends a client's latest [`Stats`] to a [`Collector`] every so often,
+/// from a thread of its own so a slow collector doesn't hold up probing.
+/// Cloning shares the thread, so one clone can be moved into the stats
+/// callback.
+#[derive(Clone)]
+pub struct Reporter {
+    snapshots: Snapshots,
+    sender: Arc<ReportSender>,
+}
+
+struct ReportSender {
+    url: Url,
+    probe: String,
+    target: String,
+    labels: Vec<(String, String)>,
+    authorization: Option<String>,
+}
+
+impl ReportSender {
+    fn send(&self, stats: &Stats) -> eyre::Result<()> {
+        // only what the collector keeps, so reports stay within its limit
+        let stats = counters(&json::parse(&stats.to_json())?).map_err(|e| eyre::eyre!(e))?;
+        let body = json::Object::new()
+            .str("probe", &self.probe)
+            .str("target", &self.target)
+            .raw("labels", &labels_json(&self.labels))
+            .raw("stats", &stats.to_json())
+            .finish();
+        let mut headers = vec![("Content-Type", "application/json")];
+        if let Some(authorization) = &self.authorization {
+            headers.push(("Authorization", authorization));
+        }
+        let (status, answer) = http::request(&self.url, "POST", &headers, &body)?;
+        eyre::ensure!(
+            status == 200,
+            "collector answered {status}: {}",
+            answer.trim()
+        );
+        Ok(())
+    }
+}
+
+impl Reporter {
+    pub fn start(config: ReporterConfig) -> eyre::Result<Self> {
+        eyre::ensure!(
+            valid_probe_name(&config.probe),
+            "probe names are up to 64 letters, digits, `.`, `_` and `-`"
+        );
+        eyre::ensure!(
+            config.labels.iter().all(|(key, _)| valid_label_key(key)),
+            "label keys are up to 64 letters, digits, `.`, `_` and `-`"
+        );
+        let url = if config.url.contains("://") {
+            config.url.clone()
+        } else {
+            format!("http://{}", config.url)
+        };
+        let mut url = Url::parse(&url)
+            .ok_or_else(|| eyre::eyre!("collector must be an http:// URL or HOST:PORT"))?;
+        url.path = format!("{}/report", url.path.trim_end_matches('/'));
+        let sender = Arc::new(ReportSender {
+            url,
+            probe: config.probe,
+            target: config.target,
+            labels: config.labels,
+            authorization: config.token.map(|token| format!("Bearer {token}")),
+        });
+        let snapshots = Snapshots::start(config.every, {
+            let sender = Arc::clone(&sender);
+            let mut failing = false;
+            move |stats, _| match sender.send(stats) {
+                Ok(()) if failing => {
+                    failing = false;
+                    crate::log::info!("Reporting to the collector works again");
+                }
+                Ok(()) => {}
+                // once per outage rather than every period
+                Err(e) if !failing => {
+                    failing = true;
+                    crate::log::warn!("Reporting to the collector failed: {e}");
+                }
+                Err(_) => {}
+            }
+        });
+        Ok(Self { snapshots, sender })
+    }
+
+    /// Keep `stats` to report when the next report is due.
+    pub fn update(&self, stats: &Stats) {
+        self.snapshots.update(stats);
+    }
+
+    /// Report `stats` right away, e.g. the final ones, and stop reporting
+    /// periodically.
+    pub fn finish(&self, stats: &Stats) -> eyre::Result<()> {
+        self.snapshots.stop();
+        self.sender.send(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(probe: &str, received_unix: f64, stats: &str) -> Result<Report, &'static str> {
+        let json = format!(r#"{{"probe":"{probe}","target":"t","stats":{stats}}}"#);
+        Report::from_json(&json::parse(&json).unwrap(), Some(received_unix))
+    }
+
+    #[test]
+    fn reports_keep_only_numeric_counters() {
+        let report = report(
+            "p",
+            0.0,
+            r#"{"client_sent":10,"upstream_loss":null,"outages":[{"start_secs":1}],"hops":[1]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            report.stats.to_json(),
+            r#"{"client_sent":10,"upstream_loss":null,"outages":[{"start_secs":1}]}"#
+        );
+        for stats in [
+            r#""x""#,
+            r#"{"upstream_loss":"x"}"#,
+            r#"{"mos":{}}"#,
+            r#"{"outages":2}"#,
+            r#"{"outages":[{"start_secs":"x"}]}"#,
+            r#"{"rtt_percentiles":{"p50_ms":[]}}"#,
+        ] {
+            assert!(self::report("p", 0.0, stats).is_err(), "{stats}");
+        }
+        assert!(
+            Report::from_json(&json::parse(r#"{"probe":"p","target":"t"}"#).unwrap(), None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn totals_without_fresh_probes_are_null() {
+        let stale = report("p", 0.0, r#"{"client_sent":10,"mean_rtt_ms":5}"#).unwrap();
+        let totals = json::parse(&totals(json::Object::new(), [&stale], 1e9)).unwrap();
+        assert_eq!(totals.get("probes").and_then(Value::as_f64), Some(0.0));
+        assert_eq!(totals.get("stale").and_then(Value::as_f64), Some(1.0));
+        for key in ["upstream_loss", "downstream_loss", "mean_rtt_ms"] {
+            assert!(matches!(totals.get(key), Some(Value::Null)), "{key}");
+        }
+    }
+
+    #[test]
+    fn new_probes_are_turned_away_past_the_cap() {
+        let mut state = CollectorState::default();
+        for i in 0..MAX_PROBES {
+            state
+                .add(report(&format!("p{i}"), 0.0, "{}").unwrap())
+                .unwrap();
+        }
+        assert!(state.add(report("new", 0.0, "{}").unwrap()).is_err());
+        // those already tracked still report
+        state.add(report("p0", 1.0, "{}").unwrap()).unwrap();
+        assert_eq!(state.probes["p0"].len(), 2);
+    }
+}
@@ -0,0 +1,260 @@
+//! A/B comparison of two hosts probed at once, at the same rate and with
+//! their probes taking turns, so both see the same moments of the local
+//! network. Whether they differ beyond chance tells trouble on the way to
+//! one server from trouble both share, e.g. in the access network.
+//!
+//! Round-trip loss is compared with a two-proportion z-test. Latency is
+//! compared with a paired t-test on the mean round-trip time of each second,
+//! pairing the seconds of both so swings of the shared part of the path
+//! cancel out. Both test at the 5% level.
+
+use std::fmt;
+
+use crate::{json, Stats};
+
+/// |z| beyond this is a significant difference at the 5% level
+const Z_CRITICAL: f64 = 1.96;
+
+/// Two-sided 5% critical values of Student's t for 1 to 30 degrees of
+/// freedom
+const T_CRITICAL: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// Critical value of t for `df` degrees of freedom, rounding down to the
+/// next tabulated one, which errs on the side of no difference.
+fn t_critical(df: usize) -> f64 {
+    match df {
+        0 => f64::INFINITY,
+        1..=30 => T_CRITICAL[df - 1],
+        31..=40 => 2.042,
+        41..=60 => 2.021,
+        61..=120 => 2.000,
+        _ => 1.980,
+    }
+}
+
+/// z statistic for the difference between `lost_a` of `sent_a` and `lost_b`
+/// of `sent_b`; 0 when neither lost anything.
+pub fn loss_z(lost_a: u64, sent_a: u64, lost_b: u64, sent_b: u64) -> f64 {
+    let (n_a, n_b) = (sent_a as f64, sent_b as f64);
+    if n_a == 0.0 || n_b == 0.0 {
+        return 0.0;
+    }
+    let pooled = (lost_a + lost_b) as f64 / (n_a + n_b);
+    let se = (pooled * (1.0 - pooled) * (1.0 / n_a + 1.0 / n_b)).sqrt();
+    if se == 0.0 {
+        return 0.0;
+    }
+    (lost_a as f64 / n_a - lost_b as f64 / n_b) / se
+}
+
+/// t statistic of a paired test on `diffs`, `None` for fewer than two.
+pub fn paired_t(diffs: &[f64]) -> Option<f64> {
+    if diffs.len() < 2 {
+        return None;
+    }
+    let n = diffs.len() as f64;
+    let mean = diffs.iter().sum::<f64>() / n;
+    let variance = diffs.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    if variance == 0.0 {
+        // every second differed by the same, which is as clear as it gets,
+        // unless by nothing
+        return Some(if mean == 0.0 {
+            0.0
+        } else {
+            mean.signum() * f64::INFINITY
+        });
+    }
+    Some(mean / (variance / n).sqrt())
+}
+
+/// Mean round-trip time in ms of the probes acknowledged between two
+/// snapshots of the same client, `None` if none were.
+pub fn interval_rtt_ms(before: &Stats, after: &Stats) -> Option<f64> {
+    let acked = after.client_received.checked_sub(before.client_received)?;
+    if acked == 0 {
+        return None;
+    }
+    let total = after.mean_rtt.as_secs_f64() * after.client_received as f64
+        - before.mean_rtt.as_secs_f64() * before.client_received as f64;
+    Some(total / acked as f64 * 1000.0)
+}
+
+/// Result of comparing two hosts.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub names: [String; 2],
+    pub stats: [Stats; 2],
+    /// Mean round-trip time of the first host minus the second's, in ms, for
+    /// each second both had probes acknowledged in
+    pub rtt_diffs_ms: Vec<f64>,
+}
+
+impl Comparison {
+    fn lost(stats: &Stats) -> u64 {
+        stats.client_sent.saturating_sub(stats.client_received)
+    }
+
+    pub fn loss_z(&self) -> f64 {
+        let [a, b] = &self.stats;
+        loss_z(Self::lost(a), a.client_sent, Self::lost(b), b.client_sent)
+    }
+
+    /// Whether round-trip loss differs beyond chance.
+    pub fn loss_differs(&self) -> bool {
+        self.loss_z().abs() > Z_CRITICAL
+    }
+
+    pub fn rtt_t(&self) -> Option<f64> {
+        paired_t(&self.rtt_diffs_ms)
+    }
+
+    /// Whether latency differs beyond chance.
+    pub fn rtt_differs(&self) -> bool {
+        self.rtt_t()
+            .is_some_and(|t| t.abs() > t_critical(self.rtt_diffs_ms.len() - 1))
+    }
+
+    /// Mean of the per-second differences, the first host's minus the
+    /// second's.
+    pub fn rtt_diff_ms(&self) -> Option<f64> {
+        (!self.rtt_diffs_ms.is_empty())
+            .then(|| self.rtt_diffs_ms.iter().sum::<f64>() / self.rtt_diffs_ms.len() as f64)
+    }
+
+    /// Index of the host that came out worse, if either did significantly.
+    pub fn worse(&self) -> Option<usize> {
+        let [a, b] = &self.stats;
+        if self.loss_differs() {
+            Some(usize::from(b.round_trip_loss() > a.round_trip_loss()))
+        } else if self.rtt_differs() {
+            self.rtt_diff_ms().map(|diff| usize::from(diff < 0.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let number = |value: Option<f64>| {
+            value
+                .filter(|value| value.is_finite())
+                .map_or_else(|| "null".to_string(), |value| value.to_string())
+        };
+        let bool = |value| if value { "true" } else { "false" };
+        json::Object::new()
+            .raw(
+                "hosts",
+                &json::array(self.names.iter().zip(&self.stats).map(|(name, stats)| {
+                    json::Object::new()
+                        .str("host", name)
+                        .u64("sent", stats.client_sent)
+                        .f64("round_trip_loss", stats.round_trip_loss())
+                        .f64("mean_rtt_ms", stats.mean_rtt.as_secs_f64() * 1000.0)
+                        .finish()
+                })),
+            )
+            .f64("loss_z", self.loss_z())
+            .raw("loss_differs", bool(self.loss_differs()))
+            .raw("rtt_diff_ms", &number(self.rtt_diff_ms()))
+            .raw("rtt_t", &number(self.rtt_t()))
+            .u64("rtt_seconds", self.rtt_diffs_ms.len() as u64)
+            .raw("rtt_differs", bool(self.rtt_differs()))
+            .finish()
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b] = &self.names;
+        let width = a.len().max(b.len()).max(8);
+        writeln!(f, "{:<16} {a:>width$} {b:>width$}", "")?;
+        let [sa, sb] = &self.stats;
+        writeln!(
+            f,
+            "{:<16} {:>width$} {:>width$}",
+            "Sent", sa.client_sent, sb.client_sent
+        )?;
+        writeln!(
+            f,
+            "{:<16} {:>w$.2}% {:>w$.2}%",
+            "Round-trip loss",
+            sa.round_trip_loss(),
+            sb.round_trip_loss(),
+            w = width - 1
+        )?;
+        writeln!(
+            f,
+            "{:<16} {:>w$.1}ms {:>w$.1}ms",
+            "Mean RTT",
+            sa.mean_rtt.as_secs_f64() * 1000.0,
+            sb.mean_rtt.as_secs_f64() * 1000.0,
+            w = width - 2
+        )?;
+        writeln!(f)?;
+        let z = self.loss_z();
+        if self.loss_differs() {
+            let worse = if z > 0.0 { a } else { b };
+            writeln!(f, "Loss           : {worse} loses more (z = {z:.2})")?;
+        } else {
+            writeln!(f, "Loss           : no significant difference (z = {z:.2})")?;
+        }
+        match (self.rtt_t(), self.rtt_diff_ms()) {
+            (Some(t), Some(diff)) if self.rtt_differs() => {
+                let slower = if diff > 0.0 { a } else { b };
+                writeln!(
+                    f,
+                    "Latency        : {slower} is {:.1}ms slower (t = {t:.2} over {}s)",
+                    diff.abs(),
+                    self.rtt_diffs_ms.len()
+                )?;
+            }
+            (Some(t), _) => writeln!(
+                f,
+                "Latency        : no significant difference (t = {t:.2} over {}s)",
+                self.rtt_diffs_ms.len()
+            )?,
+            (None, _) => writeln!(f, "Latency        : too few seconds with ACKs from both")?,
+        }
+        match self.worse() {
+            Some(i) => writeln!(
+                f,
+                "{} is worse, so the trouble lies on the way to it rather than in the shared part of the path",
+                self.names[i]
+            ),
+            None => writeln!(
+                f,
+                "Both are alike, so any trouble is shared, e.g. in the local network or the ISP"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loss_z_matches_a_worked_example() {
+        // 30 of 1000 against 10 of 1000: pooled 2%, z = 0.02 / sqrt(0.02 *
+        // 0.98 * 0.002)
+        let z = loss_z(30, 1000, 10, 1000);
+        assert!((z - 3.194).abs() < 0.001, "{z}");
+        assert_eq!(loss_z(10, 1000, 30, 1000), -z);
+        assert_eq!(loss_z(0, 1000, 0, 1000), 0.0);
+        assert_eq!(loss_z(0, 0, 5, 100), 0.0);
+    }
+
+    #[test]
+    fn paired_t_matches_a_worked_example() {
+        // mean 2, sample standard deviation 1.5811 over 5
+        let t = paired_t(&[0.0, 1.0, 2.0, 3.0, 4.0]).unwrap();
+        assert!((t - 2.828).abs() < 0.001, "{t}");
+        assert_eq!(paired_t(&[1.0]), None);
+        assert_eq!(paired_t(&[0.0, 0.0]), Some(0.0));
+        assert!(t > t_critical(4));
+        assert!(paired_t(&[-1.0, 1.0, -0.5, 0.5]).unwrap().abs() < t_critical(3));
+    }
+}
@@ -0,0 +1,97 @@
+//! Unix domain control socket for a running client. Each connection sends a
+//! single command line and receives a textual reply:
+//!
+//! - `stats`: the latest stats block
+//! - `flush`: make the recording durable on disk
+//! - `rotate`: move the recording aside and start a new file
+//! - `stop`: finish the measurement and exit
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use crate::ClientControl;
+
+/// `$XDG_RUNTIME_DIR/loss_lens.sock`, or a per-user path in `/tmp`.
+pub fn default_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("loss_lens.sock"),
+        None => PathBuf::from(format!("/tmp/loss_lens-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+/// Listening control socket; the socket file is removed on drop.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path, control: ClientControl) -> eyre::Result<Self> {
+        if UnixStream::connect(path).is_ok() {
+            eyre::bail!("{} is in use by another client", path.display());
+        }
+        // left behind by a client that didn't shut down cleanly
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = handle(stream, &control);
+            }
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn handle(mut stream: UnixStream, control: &ClientControl) -> std::io::Result<()> {
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+    let reply = match command.trim() {
+        "stats" => match control.latest_stats() {
+            Some(stats) => stats.to_string(),
+            None => "no stats yet\n".to_string(),
+        },
+        "flush" => match control.flush() {
+            Ok(()) => "ok\n".to_string(),
+            Err(e) => format!("error: {e}\n"),
+        },
+        "rotate" => match control.rotate() {
+            Ok(rotated) => format!("ok, previous recording moved to {}\n", rotated.display()),
+            Err(e) => format!("error: {e}\n"),
+        },
+        "stop" => {
+            control.stop();
+            "ok\n".to_string()
+        }
+        other => {
+            format!("error: unknown command {other:?}, expected stats, flush, rotate or stop\n")
+        }
+    };
+    stream.write_all(reply.as_bytes())
+}
+
+/// Send `command` to the client listening on `path` and return its reply.
+pub fn request(path: &Path, command: &str) -> eyre::Result<String> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| eyre::eyre!("connecting to {}: {e}", path.display()))?;
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
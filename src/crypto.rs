@@ -0,0 +1,462 @@
+//! X25519 (RFC 7748) and ChaCha20-Poly1305 (RFC 8439) for encrypted
+//! sessions, implemented in-tree like the hash functions. Both run in
+//! constant time with respect to keys and data.
+
+/// Field element mod 2^255 - 19 in five 51-bit limbs
+type Fe = [u64; 5];
+
+const MASK51: u64 = (1 << 51) - 1;
+
+fn fe_from_bytes(b: &[u8; 32]) -> Fe {
+    let load = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+    [
+        load(0) & MASK51,
+        (load(6) >> 3) & MASK51,
+        (load(12) >> 6) & MASK51,
+        (load(19) >> 1) & MASK51,
+        // drops the top bit, as RFC 7748 asks for u-coordinates
+        (load(24) >> 12) & MASK51,
+    ]
+}
+
+/// Carry limbs down to about 51 bits.
+fn fe_carry(mut h: Fe) -> Fe {
+    let c = h.map(|limb| limb >> 51);
+    for limb in &mut h {
+        *limb &= MASK51;
+    }
+    h[0] += c[4] * 19;
+    h[1] += c[0];
+    h[2] += c[1];
+    h[3] += c[2];
+    h[4] += c[3];
+    h
+}
+
+fn fe_to_bytes(h: Fe) -> [u8; 32] {
+    let mut h = fe_carry(fe_carry(h));
+    // subtract p once if h >= p
+    let mut q = (h[0] + 19) >> 51;
+    for limb in &h[1..] {
+        q = (limb + q) >> 51;
+    }
+    h[0] += 19 * q;
+    for i in 0..4 {
+        h[i + 1] += h[i] >> 51;
+        h[i] &= MASK51;
+    }
+    h[4] &= MASK51;
+    let words = [
+        h[0] | h[1] << 51,
+        h[1] >> 13 | h[2] << 38,
+        h[2] >> 26 | h[3] << 25,
+        h[3] >> 39 | h[4] << 12,
+    ];
+    let mut out = [0u8; 32];
+    for (chunk, word) in out.chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+fn fe_add(a: &Fe, b: &Fe) -> Fe {
+    std::array::from_fn(|i| a[i] + b[i])
+}
+
+fn fe_sub(a: &Fe, b: &Fe) -> Fe {
+    // add 4p first so limbs can't underflow
+    const FOUR_P: Fe = [
+        0x1f_ffff_ffff_ffb4,
+        0x1f_ffff_ffff_fffc,
+        0x1f_ffff_ffff_fffc,
+        0x1f_ffff_ffff_fffc,
+        0x1f_ffff_ffff_fffc,
+    ];
+    fe_carry(std::array::from_fn(|i| a[i] + FOUR_P[i] - b[i]))
+}
+
+fn fe_mul(a: &Fe, b: &Fe) -> Fe {
+    let m = |x: u64, y: u64| u128::from(x) * u128::from(y);
+    let b19 = b.map(|limb| limb * 19);
+    let c = [
+        m(a[0], b[0]) + m(a[4], b19[1]) + m(a[3], b19[2]) + m(a[2], b19[3]) + m(a[1], b19[4]),
+        m(a[1], b[0]) + m(a[0], b[1]) + m(a[4], b19[2]) + m(a[3], b19[3]) + m(a[2], b19[4]),
+        m(a[2], b[0]) + m(a[1], b[1]) + m(a[0], b[2]) + m(a[4], b19[3]) + m(a[3], b19[4]),
+        m(a[3], b[0]) + m(a[2], b[1]) + m(a[1], b[2]) + m(a[0], b[3]) + m(a[4], b19[4]),
+        m(a[4], b[0]) + m(a[3], b[1]) + m(a[2], b[2]) + m(a[1], b[3]) + m(a[0], b[4]),
+    ];
+    fe_carry_wide(c)
+}
+
+fn fe_carry_wide(mut c: [u128; 5]) -> Fe {
+    let mut h = [0u64; 5];
+    for i in 0..4 {
+        c[i + 1] += c[i] >> 51;
+        h[i] = c[i] as u64 & MASK51;
+    }
+    h[4] = c[4] as u64 & MASK51;
+    h[0] += (c[4] >> 51) as u64 * 19;
+    h[1] += h[0] >> 51;
+    h[0] &= MASK51;
+    h
+}
+
+fn fe_mul_small(a: &Fe, b: u64) -> Fe {
+    fe_carry_wide(a.map(|limb| u128::from(limb) * u128::from(b)))
+}
+
+fn fe_invert(a: &Fe) -> Fe {
+    // a^(p - 2), p - 2 = 2^255 - 21
+    let mut exponent = [0xff; 32];
+    exponent[0] = 0xeb;
+    exponent[31] = 0x7f;
+    let mut result: Fe = [1, 0, 0, 0, 0];
+    for bit in (0..255).rev() {
+        result = fe_mul(&result, &result);
+        if exponent[bit / 8] >> (bit % 8) & 1 == 1 {
+            result = fe_mul(&result, a);
+        }
+    }
+    result
+}
+
+fn fe_cswap(a: &mut Fe, b: &mut Fe, swap: u64) {
+    let mask = 0u64.wrapping_sub(swap);
+    for (a, b) in a.iter_mut().zip(b) {
+        let t = mask & (*a ^ *b);
+        *a ^= t;
+        *b ^= t;
+    }
+}
+
+/// X25519 of `scalar` and the u-coordinate `point`.
+pub(crate) fn x25519(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+    let x1 = fe_from_bytes(point);
+    let (mut x2, mut z2): (Fe, Fe) = ([1, 0, 0, 0, 0], [0; 5]);
+    let (mut x3, mut z3) = (x1, [1, 0, 0, 0, 0]);
+    let mut swap = 0;
+    for t in (0..255).rev() {
+        let bit = u64::from(k[t / 8] >> (t % 8) & 1);
+        swap ^= bit;
+        fe_cswap(&mut x2, &mut x3, swap);
+        fe_cswap(&mut z2, &mut z3, swap);
+        swap = bit;
+
+        let a = fe_add(&x2, &z2);
+        let aa = fe_mul(&a, &a);
+        let b = fe_sub(&x2, &z2);
+        let bb = fe_mul(&b, &b);
+        let e = fe_sub(&aa, &bb);
+        let c = fe_add(&x3, &z3);
+        let d = fe_sub(&x3, &z3);
+        let da = fe_mul(&d, &a);
+        let cb = fe_mul(&c, &b);
+        let sum = fe_add(&da, &cb);
+        x3 = fe_mul(&sum, &sum);
+        let difference = fe_sub(&da, &cb);
+        z3 = fe_mul(&x1, &fe_mul(&difference, &difference));
+        x2 = fe_mul(&aa, &bb);
+        z2 = fe_mul(&e, &fe_add(&aa, &fe_mul_small(&e, 121_665)));
+    }
+    fe_cswap(&mut x2, &mut x3, swap);
+    fe_cswap(&mut z2, &mut z3, swap);
+    fe_to_bytes(fe_mul(&x2, &fe_invert(&z2)))
+}
+
+/// Public key of the X25519 `secret`.
+pub(crate) fn x25519_base(secret: &[u8; 32]) -> [u8; 32] {
+    let mut base = [0u8; 32];
+    base[0] = 9;
+    x25519(secret, &base)
+}
+
+fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for (word, chunk) in state[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    state[12] = counter;
+    for (word, chunk) in state[13..].iter_mut().zip(nonce.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    let mut x = state;
+    fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[a] = x[a].wrapping_add(x[b]);
+        x[d] = (x[d] ^ x[a]).rotate_left(16);
+        x[c] = x[c].wrapping_add(x[d]);
+        x[b] = (x[b] ^ x[c]).rotate_left(12);
+        x[a] = x[a].wrapping_add(x[b]);
+        x[d] = (x[d] ^ x[a]).rotate_left(8);
+        x[c] = x[c].wrapping_add(x[d]);
+        x[b] = (x[b] ^ x[c]).rotate_left(7);
+    }
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; 64];
+    for ((chunk, x), s) in out.chunks_exact_mut(4).zip(x).zip(state) {
+        chunk.copy_from_slice(&x.wrapping_add(s).to_le_bytes());
+    }
+    out
+}
+
+/// XOR `data` with the key stream from block `counter` on.
+fn chacha20(key: &[u8; 32], counter: u32, nonce: &[u8; 12], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, k) in chunk.iter_mut().zip(block) {
+            *byte ^= k;
+        }
+    }
+}
+
+fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; 16] {
+    let le32 = |b: &[u8], i: usize| u64::from(u32::from_le_bytes(b[i..i + 4].try_into().unwrap()));
+    const MASK26: u64 = (1 << 26) - 1;
+    let r = [
+        le32(key, 0) & 0x3ff_ffff,
+        (le32(key, 3) >> 2) & 0x3ff_ff03,
+        (le32(key, 6) >> 4) & 0x3ff_c0ff,
+        (le32(key, 9) >> 6) & 0x3f0_3fff,
+        (le32(key, 12) >> 8) & 0x00f_ffff,
+    ];
+    let s = [r[1] * 5, r[2] * 5, r[3] * 5, r[4] * 5];
+    let mut h = [0u64; 5];
+    for chunk in message.chunks(16) {
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        // full blocks get the 2^128 bit above them, the last one right
+        // after its bytes
+        let high = if chunk.len() == 16 {
+            1 << 24
+        } else {
+            block[chunk.len()] = 1;
+            0
+        };
+        h[0] += le32(&block, 0) & MASK26;
+        h[1] += (le32(&block, 3) >> 2) & MASK26;
+        h[2] += (le32(&block, 6) >> 4) & MASK26;
+        h[3] += (le32(&block, 9) >> 6) & MASK26;
+        h[4] += (le32(&block, 12) >> 8) | high;
+
+        let d = [
+            h[0] * r[0] + h[1] * s[3] + h[2] * s[2] + h[3] * s[1] + h[4] * s[0],
+            h[0] * r[1] + h[1] * r[0] + h[2] * s[3] + h[3] * s[2] + h[4] * s[1],
+            h[0] * r[2] + h[1] * r[1] + h[2] * r[0] + h[3] * s[3] + h[4] * s[2],
+            h[0] * r[3] + h[1] * r[2] + h[2] * r[1] + h[3] * r[0] + h[4] * s[3],
+            h[0] * r[4] + h[1] * r[3] + h[2] * r[2] + h[3] * r[1] + h[4] * r[0],
+        ];
+        let mut carry = 0;
+        for i in 0..5 {
+            let di = d[i] + carry;
+            carry = di >> 26;
+            h[i] = di & MASK26;
+        }
+        h[0] += carry * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= MASK26;
+    }
+
+    // fully carry, then subtract p = 2^130 - 5 if h >= p
+    let mut carry = 0;
+    for limb in &mut h[1..] {
+        *limb += carry;
+        carry = *limb >> 26;
+        *limb &= MASK26;
+    }
+    h[0] += carry * 5;
+    carry = h[0] >> 26;
+    h[0] &= MASK26;
+    h[1] += carry;
+
+    let mut g = [0u64; 5];
+    carry = 5;
+    for i in 0..4 {
+        g[i] = h[i] + carry;
+        carry = g[i] >> 26;
+        g[i] &= MASK26;
+    }
+    g[4] = (h[4] + carry).wrapping_sub(1 << 26);
+    // all ones if g didn't borrow, i.e. h >= p
+    let use_g = (g[4] >> 63).wrapping_sub(1);
+    for (h, g) in h.iter_mut().zip(g) {
+        *h = (*h & !use_g) | (g & use_g & MASK26);
+    }
+
+    let words = [
+        (h[0] | h[1] << 26) as u32,
+        (h[1] >> 6 | h[2] << 20) as u32,
+        (h[2] >> 12 | h[3] << 14) as u32,
+        (h[3] >> 18 | h[4] << 8) as u32,
+    ];
+    let mut tag = [0u8; 16];
+    let mut carry = 0;
+    for (i, (chunk, word)) in tag.chunks_exact_mut(4).zip(words).enumerate() {
+        let f = u64::from(word) + le32(key, 16 + 4 * i) + carry;
+        chunk.copy_from_slice(&(f as u32).to_le_bytes());
+        carry = f >> 32;
+    }
+    tag
+}
+
+/// Bytes a ChaCha20-Poly1305 tag adds
+pub(crate) const AEAD_TAG_SIZE: usize = 16;
+
+fn aead_tag(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let mut poly_key = [0u8; 32];
+    poly_key.copy_from_slice(&chacha20_block(key, 0, nonce)[..32]);
+    let pad = |len: usize| (16 - len % 16) % 16;
+    let mut data = Vec::with_capacity(aad.len() + ciphertext.len() + 48);
+    data.extend_from_slice(aad);
+    data.resize(data.len() + pad(aad.len()), 0);
+    data.extend_from_slice(ciphertext);
+    data.resize(data.len() + pad(ciphertext.len()), 0);
+    data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly1305(&poly_key, &data)
+}
+
+/// ChaCha20-Poly1305 encryption of `plaintext`, with the tag appended.
+pub(crate) fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(plaintext.len() + AEAD_TAG_SIZE);
+    out.extend_from_slice(plaintext);
+    chacha20(key, 1, nonce, &mut out);
+    let tag = aead_tag(key, nonce, aad, &out);
+    out.extend_from_slice(&tag);
+    out
+}
+
+/// Decrypt `data`, ciphertext followed by its tag, in place. Returns the
+/// plaintext length, `None` if the tag is wrong.
+pub(crate) fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &mut [u8]) -> Option<usize> {
+    let len = data.len().checked_sub(AEAD_TAG_SIZE)?;
+    let (ciphertext, tag) = data.split_at_mut(len);
+    let diff = aead_tag(key, nonce, aad, ciphertext)
+        .iter()
+        .zip(tag.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    if diff != 0 {
+        return None;
+    }
+    chacha20(key, 1, nonce, ciphertext);
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        let bytes: Vec<u8> = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    // RFC 7748, section 5.2
+    #[test]
+    fn x25519_vectors() {
+        assert_eq!(
+            x25519(
+                &hex("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+                &hex("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c"),
+            ),
+            hex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"),
+        );
+        assert_eq!(
+            x25519(
+                &hex("4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d"),
+                &hex("e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493"),
+            ),
+            hex("95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957"),
+        );
+    }
+
+    // RFC 7748, section 5.2, iterating k, u = x25519(k, u), k
+    #[test]
+    fn x25519_iterated() {
+        let mut k = [0u8; 32];
+        k[0] = 9;
+        let mut u = k;
+        for i in 1..=1000 {
+            (k, u) = (x25519(&k, &u), k);
+            if i == 1 {
+                assert_eq!(
+                    k,
+                    hex("422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079")
+                );
+            }
+        }
+        assert_eq!(
+            k,
+            hex("684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51")
+        );
+    }
+
+    // RFC 7748, section 6.1
+    #[test]
+    fn x25519_key_agreement() {
+        let alice = hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = hex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let alice_public = x25519_base(&alice);
+        let bob_public = x25519_base(&bob);
+        assert_eq!(
+            alice_public,
+            hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+        );
+        assert_eq!(
+            bob_public,
+            hex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f")
+        );
+        let shared = hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(x25519(&alice, &bob_public), shared);
+        assert_eq!(x25519(&bob, &alice_public), shared);
+    }
+
+    // RFC 8439, section 2.8.2
+    #[test]
+    fn aead_vector() {
+        let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+        let nonce = hex("070000004041424344454647");
+        let aad = hex::<12>("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only \
+            one tip for the future, sunscreen would be it.";
+        let ciphertext = hex::<114>(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+             3ff4def08e4b7a9de576d26586cec64b6116",
+        );
+        let tag = hex::<16>("1ae10b594f09e26a7e902ecbd0600691");
+
+        let mut sealed = seal(&key, &nonce, &aad, plaintext);
+        assert_eq!(sealed[..plaintext.len()], ciphertext);
+        assert_eq!(sealed[plaintext.len()..], tag);
+
+        assert_eq!(
+            open(&key, &nonce, &aad, &mut sealed.clone()),
+            Some(plaintext.len())
+        );
+        let len = open(&key, &nonce, &aad, &mut sealed).unwrap();
+        assert_eq!(&sealed[..len], plaintext);
+
+        let mut tampered = seal(&key, &nonce, &aad, plaintext);
+        tampered[0] ^= 1;
+        assert_eq!(open(&key, &nonce, &aad, &mut tampered), None);
+        let mut sealed = seal(&key, &nonce, &aad, plaintext);
+        assert_eq!(open(&key, &nonce, b"other", &mut sealed), None);
+    }
+}
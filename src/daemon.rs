@@ -0,0 +1,87 @@
+//! Detaching into the background for init scripts: double fork, new session,
+//! stdio redirected to a log file and an optional PID file.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+/// Removes the PID file when the daemon shuts down cleanly.
+pub struct PidFile(Option<PathBuf>);
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn check(rv: libc::c_int) -> io::Result<libc::c_int> {
+    if rv == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(rv)
+    }
+}
+
+/// Detach from the terminal. Must be called before any threads are spawned.
+///
+/// The original process only exits once the daemon has written its PID file,
+/// with a non-zero status if that failed, so init scripts can rely on it.
+/// The working directory is kept so relative paths such as the recording
+/// still resolve.
+pub fn daemonize(pid_file: Option<&Path>, log_file: Option<&Path>) -> eyre::Result<PidFile> {
+    // open everything up front so mistakes are still reported on the terminal
+    let log = match log_file {
+        Some(path) => OpenOptions::new().append(true).create(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = File::open("/dev/null")?;
+    let pid_file = pid_file.map(std::path::absolute).transpose()?;
+
+    let mut fds = [0; 2];
+    check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let (mut ready_rx, mut ready_tx) = unsafe {
+        use std::os::fd::FromRawFd;
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+
+    if check(unsafe { libc::fork() })? != 0 {
+        drop(ready_tx);
+        let mut status = [1];
+        let _ = ready_rx.read_exact(&mut status);
+        unsafe { libc::_exit(status[0].into()) };
+    }
+    drop(ready_rx);
+    check(unsafe { libc::setsid() })?;
+    // the session leader exits so the daemon can never reacquire a terminal
+    if check(unsafe { libc::fork() })? != 0 {
+        unsafe { libc::_exit(0) };
+    }
+
+    let written = pid_file.as_deref().map_or(Ok(()), write_pid_file);
+    if let Err(e) = &written {
+        warn!("Writing PID file failed: {e}");
+    }
+    unsafe {
+        check(libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO))?;
+        check(libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO))?;
+        check(libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO))?;
+    }
+    let _ = ready_tx.write_all(&[u8::from(written.is_err())]);
+    written?;
+    Ok(PidFile(pid_file))
+}
+
+fn write_pid_file(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(path)?;
+    writeln!(file, "{}", std::process::id())
+}
@@ -0,0 +1,130 @@
+//! Explicit Congestion Notification: probes can be sent ECN-capable, and the
+//! server reads the ECN field each probe arrived with so it can report CE
+//! (congestion experienced) marks from routers that mark instead of drop.
+
+#[cfg(unix)]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+/// ECN-capable transport, codepoint 0
+pub(crate) const ECT_0: u8 = 0b10;
+/// Congestion experienced
+pub(crate) const CE: u8 = 0b11;
+/// Mask of the ECN field in the TOS byte or traffic class
+pub(crate) const ECN_MASK: u8 = 0b11;
+
+/// Ask for the TOS byte or traffic class of received packets. IPv4-only
+/// sockets reject the IPv6 option, so failures are ignored; the server then
+/// simply doesn't report ECN.
+#[cfg(unix)]
+pub(crate) fn enable_receive(socket: &UdpSocket) {
+    use std::os::fd::AsRawFd;
+
+    let on: libc::c_int = 1;
+    for (level, name) in [
+        (libc::IPPROTO_IP, libc::IP_RECVTOS),
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS),
+    ] {
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                (&on as *const libc::c_int).cast(),
+                std::mem::size_of_val(&on) as libc::socklen_t,
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn enable_receive(_socket: &UdpSocket) {}
+
+/// Like [`UdpSocket::recv_from`], but also returning the TOS byte or traffic
+/// class the packet arrived with, if the system reported it. Linux reads
+/// batches instead, see [`crate::batch`].
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(crate) fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    use std::{mem, os::fd::AsRawFd};
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // u64 for the alignment of cmsghdr
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = (&mut addr as *mut libc::sockaddr_storage).cast();
+    msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((n as usize, socket_addr(&addr)?, received_tos(&msg)))
+}
+
+/// The TOS byte or traffic class in the control messages of a received
+/// `msg`, if any.
+#[cfg(unix)]
+pub(crate) fn received_tos(msg: &libc::msghdr) -> Option<u8> {
+    let mut tos = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+        // Linux reports IPv4 as IP_TOS, the BSDs as IP_RECVTOS
+        if level == libc::IPPROTO_IP && (kind == libc::IP_TOS || kind == libc::IP_RECVTOS) {
+            tos = Some(unsafe { *data });
+        } else if level == libc::IPPROTO_IPV6 && kind == libc::IPV6_TCLASS {
+            tos = Some(unsafe { data.cast::<libc::c_int>().read_unaligned() } as u8);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+    tos
+}
+
+#[cfg(not(unix))]
+pub(crate) fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    let (n, addr) = socket.recv_from(buf)?;
+    Ok((n, addr, None))
+}
+
+#[cfg(unix)]
+pub(crate) fn socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match libc::c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::from((
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected address family",
+        )),
+    }
+}
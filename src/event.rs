@@ -0,0 +1,127 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use crate::{json, stats::Outage};
+
+/// Consecutive lost probes from which on a gap is reported as a burst.
+pub const BURST_MIN: u32 = 3;
+
+/// Something noteworthy that happened during a measurement, delivered as it
+/// is detected rather than with the periodic [`Stats`](crate::Stats).
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// ACKs stopped arriving for at least
+    /// [`OUTAGE_THRESHOLD`](crate::stats::OUTAGE_THRESHOLD); reported once
+    /// they resume.
+    Outage(Outage),
+    /// ACKs stopped arriving for
+    /// [`OUTAGE_THRESHOLD`](crate::stats::OUTAGE_THRESHOLD), `start` into the
+    /// run; reported again as [`Event::Outage`] once they resume.
+    OutageStart { start: Duration },
+    /// At least [`BURST_MIN`] consecutive probes got no ACK within the late
+    /// window.
+    LossBurst { first_seq: u64, lost: u32 },
+    /// Adaptive rate control changed the probe rate after sustained loss or
+    /// once it cleared; `loss` is the percentage that triggered it.
+    RateChange { from: u32, to: u32, loss: f64 },
+    /// Re-resolving the host gave a new address, which probes now go to.
+    AddressChange { from: SocketAddr, to: SocketAddr },
+    /// Packets arrived marked with another protocol version than the one
+    /// agreed on, e.g. after the server was replaced; they are ignored.
+    /// Reported for the first such packet.
+    ProtocolMismatch { expected: u8, got: u8 },
+    /// The server no longer knew the session, e.g. after a restart, and a
+    /// new one was opened. Its counts start over.
+    SessionRestart,
+    /// The session of an earlier run was resumed, see
+    /// [`ClientConfig::resume`](crate::ClientConfig::resume). Sequence
+    /// numbers continue from `first_seq`, past any the earlier run sent.
+    SessionResume { first_seq: u64 },
+    /// ACKs started carrying another server epoch, so the counts in them
+    /// come from a restarted or another server instance, e.g. after an
+    /// anycast failover to one that also knows the session. They are
+    /// counted from scratch instead of compared with the old ones.
+    ServerChange { from: u32, to: u32 },
+    /// Probes now go to another of the configured servers, see
+    /// [`ClientConfig::failover`](crate::ClientConfig::failover): the next
+    /// one after `from` went silent, or back to an earlier one answering
+    /// again. `unanswered` probes sent to a silent `from` count as neither
+    /// sent nor lost.
+    Failover {
+        from: String,
+        to: String,
+        unanswered: u64,
+    },
+    /// The route to the target changed, as traced for
+    /// [`ClientConfig::record_route`](crate::ClientConfig::record_route).
+    /// Hops that didn't answer are `None`.
+    RouteChange { route: Vec<Option<IpAddr>> },
+}
+
+impl Event {
+    pub fn to_json(&self) -> String {
+        match self {
+            Event::Outage(outage) => json::Object::new()
+                .str("type", "outage")
+                .f64("start_secs", outage.start.as_secs_f64())
+                .f64("duration_secs", outage.duration.as_secs_f64())
+                .finish(),
+            Event::OutageStart { start } => json::Object::new()
+                .str("type", "outage_start")
+                .f64("start_secs", start.as_secs_f64())
+                .finish(),
+            Event::LossBurst { first_seq, lost } => json::Object::new()
+                .str("type", "loss_burst")
+                .u64("first_seq", *first_seq)
+                .u64("lost", (*lost).into())
+                .finish(),
+            Event::RateChange { from, to, loss } => json::Object::new()
+                .str("type", "rate_change")
+                .u64("from", (*from).into())
+                .u64("to", (*to).into())
+                .f64("loss", *loss)
+                .finish(),
+            Event::AddressChange { from, to } => json::Object::new()
+                .str("type", "address_change")
+                .str("from", &from.to_string())
+                .str("to", &to.to_string())
+                .finish(),
+            Event::ProtocolMismatch { expected, got } => json::Object::new()
+                .str("type", "protocol_mismatch")
+                .u64("expected", (*expected).into())
+                .u64("got", (*got).into())
+                .finish(),
+            Event::SessionRestart => json::Object::new().str("type", "session_restart").finish(),
+            Event::SessionResume { first_seq } => json::Object::new()
+                .str("type", "session_resume")
+                .u64("first_seq", *first_seq)
+                .finish(),
+            Event::ServerChange { from, to } => json::Object::new()
+                .str("type", "server_change")
+                .u64("from", (*from).into())
+                .u64("to", (*to).into())
+                .finish(),
+            Event::Failover {
+                from,
+                to,
+                unanswered,
+            } => json::Object::new()
+                .str("type", "failover")
+                .str("from", from)
+                .str("to", to)
+                .u64("unanswered", *unanswered)
+                .finish(),
+            Event::RouteChange { route } => json::Object::new()
+                .str("type", "route_change")
+                .raw(
+                    "route",
+                    &json::array(route.iter().map(|hop| {
+                        hop.map_or_else(|| "null".to_string(), |hop| json::string(&hop.to_string()))
+                    })),
+                )
+                .finish(),
+        }
+    }
+}
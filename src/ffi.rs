@@ -0,0 +1,163 @@
+//! C ABI for embedding the probe client and server, see `include/loss_lens.h`.
+
+use std::{
+    ffi::{c_char, c_int, CStr},
+    path::PathBuf,
+    ptr,
+};
+
+use crate::{
+    ClientConfig, ClientHandle, ProbeClient, ProbeServer, ServerConfig, ServerHandle, Stats,
+};
+
+/// Opaque client handle returned by [`loss_lens_start_client`].
+pub struct LossLensClient {
+    handle: ClientHandle,
+    latest: Option<Stats>,
+}
+
+/// Opaque server handle returned by [`loss_lens_start_server`].
+pub struct LossLensServer {
+    handle: ServerHandle,
+}
+
+/// Plain-data copy of [`Stats`] for C callers.
+#[repr(C)]
+pub struct LossLensStats {
+    pub client_sent: u64,
+    pub server_received: u64,
+    pub client_received: u64,
+    pub elapsed_secs: f64,
+    pub upstream_loss: f64,
+    pub downstream_loss: f64,
+}
+
+impl From<&Stats> for LossLensStats {
+    fn from(stats: &Stats) -> Self {
+        Self {
+            client_sent: stats.client_sent,
+            server_received: stats.server_received,
+            client_received: stats.client_received,
+            elapsed_secs: stats.elapsed.as_secs_f64(),
+            upstream_loss: stats.upstream_loss(),
+            downstream_loss: stats.downstream_loss(),
+        }
+    }
+}
+
+unsafe fn opt_str(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
+
+/// Start probing `host` ("addr:port") on a background thread. `recording` is
+/// the zstd output path, or NULL to disable recording. Returns NULL on error.
+///
+/// # Safety
+///
+/// `host` must be a valid NUL-terminated string, `recording` must be NULL or
+/// one.
+#[no_mangle]
+pub unsafe extern "C" fn loss_lens_start_client(
+    host: *const c_char,
+    recording: *const c_char,
+) -> *mut LossLensClient {
+    let Some(host) = opt_str(host) else {
+        return ptr::null_mut();
+    };
+    let config = ClientConfig {
+        host,
+        recording: opt_str(recording).map(PathBuf::from),
+        ..Default::default()
+    };
+    match ProbeClient::new(config) {
+        Ok(client) => Box::into_raw(Box::new(LossLensClient {
+            handle: client.spawn(),
+            latest: None,
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Copy the most recent stats snapshot into `out`. Returns 0 on success and
+/// -1 if no snapshot has been produced yet.
+///
+/// # Safety
+///
+/// `client` must come from [`loss_lens_start_client`] and not have been
+/// stopped; `out` must point to writable memory. Not safe to call
+/// concurrently on the same client.
+#[no_mangle]
+pub unsafe extern "C" fn loss_lens_get_stats(
+    client: *mut LossLensClient,
+    out: *mut LossLensStats,
+) -> c_int {
+    let client = &mut *client;
+    if let Some(stats) = client.handle.stats().try_iter().last() {
+        client.latest = Some(stats);
+    }
+    match &client.latest {
+        Some(stats) => {
+            out.write(stats.into());
+            0
+        }
+        None => -1,
+    }
+}
+
+/// Stop the client, finalize its recording and free the handle. Returns 0 if
+/// the client shut down cleanly and -1 if it had failed.
+///
+/// # Safety
+///
+/// `client` must come from [`loss_lens_start_client`] and must not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn loss_lens_stop(client: *mut LossLensClient) -> c_int {
+    let client = Box::from_raw(client);
+    match client.handle.stop() {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Start a reflector listening on `host` ("addr:port") on a background
+/// thread. Returns NULL on error.
+///
+/// # Safety
+///
+/// `host` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn loss_lens_start_server(host: *const c_char) -> *mut LossLensServer {
+    let Some(host) = opt_str(host) else {
+        return ptr::null_mut();
+    };
+    match ProbeServer::new(ServerConfig {
+        host,
+        ..Default::default()
+    }) {
+        Ok(server) => Box::into_raw(Box::new(LossLensServer {
+            handle: server.spawn(),
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Stop the server and free the handle. Returns 0 if the server shut down
+/// cleanly and -1 if it had failed.
+///
+/// # Safety
+///
+/// `server` must come from [`loss_lens_start_server`] and must not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn loss_lens_stop_server(server: *mut LossLensServer) -> c_int {
+    let server = Box::from_raw(server);
+    match server.handle.stop() {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
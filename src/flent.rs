@@ -0,0 +1,196 @@
+//! Export of an analyzed [`History`] in the data format of flent, the
+//! FLExible Network Tester, to compare with RRUL and other bufferbloat test
+//! archives and plot along with them in tools reading flent's files.
+//!
+//! Flent keeps a test's series in `results`, sampled at common `x_values`
+//! in seconds from its start, `null` where a series has no value, and the
+//! same values with their Unix times in `raw_values`. Each source gets
+//! these series, with ` - SOURCE` appended if there are several:
+//!
+//! - `Ping (ms) UDP`: the smoothed round-trip time at each snapshot
+//! - `Packet loss`: round-trip loss in percent since the previous snapshot
+//! - `ACK gap (ms)`: the largest gap between ACKs since then
+//!
+//! Flent reads the JSON as is; gzip it to `.flent.gz` like its own files
+//! if wanted.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    analyze::{History, Interval, LocalTime},
+    json,
+};
+
+/// Version of the data format written
+const DATA_VERSION: u64 = 4;
+/// What flent knows the test as
+const TEST_NAME: &str = "loss_lens";
+
+/// A series' name, units and value in an interval.
+type Series = (&'static str, &'static str, fn(&Interval) -> Option<f64>);
+
+const SERIES: [Series; 3] = [
+    ("Ping (ms) UDP", "ms", |interval| {
+        interval.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0)
+    }),
+    ("Packet loss", "%", |interval| {
+        (interval.sent > 0)
+            .then(|| 100.0 * (1.0 - interval.received as f64 / interval.sent as f64).max(0.0))
+    }),
+    ("ACK gap (ms)", "ms", |interval| {
+        Some(interval.max_gap.as_secs_f64() * 1000.0)
+    }),
+];
+
+/// `history` as a flent data file.
+pub fn export(history: &History) -> String {
+    let (first, last) = history.span().unwrap_or_default();
+    // snapshots of different sources at the same millisecond share an x value
+    let mut xs = BTreeMap::new();
+    for interval in &history.intervals {
+        xs.insert(millis(interval.end_unix - first), 0);
+    }
+    for (i, index) in xs.values_mut().enumerate() {
+        *index = i;
+    }
+    let mut results = json::Object::new();
+    let mut raw_values = json::Object::new();
+    let mut series_meta = json::Object::new();
+    for (source, meta) in history.sources.iter().enumerate() {
+        let intervals: Vec<&Interval> = history
+            .intervals
+            .iter()
+            .filter(|interval| interval.source == source)
+            .collect();
+        for (name, units, value) in SERIES {
+            let name = match history.sources.len() {
+                1 => name.to_string(),
+                _ => format!("{name} - {}", meta.name),
+            };
+            let mut values = vec![None; xs.len()];
+            for interval in &intervals {
+                values[xs[&millis(interval.end_unix - first)]] = value(interval);
+            }
+            results = results.raw(
+                &name,
+                &json::array(
+                    values
+                        .iter()
+                        .map(|value| value.map_or_else(|| "null".to_string(), json::number)),
+                ),
+            );
+            raw_values = raw_values.raw(
+                &name,
+                &json::array(intervals.iter().filter_map(|interval| {
+                    Some(
+                        json::Object::new()
+                            .f64("t", interval.end_unix)
+                            .f64("val", value(interval)?)
+                            .finish(),
+                    )
+                })),
+            );
+            series_meta = series_meta.raw(&name, &json::Object::new().str("UNITS", units).finish());
+        }
+    }
+    let steps: Vec<f64> = xs
+        .keys()
+        .zip(xs.keys().skip(1))
+        .map(|(a, b)| (b - a) as f64 / 1000.0)
+        .collect();
+    let metadata = json::Object::new()
+        .str("NAME", TEST_NAME)
+        .str("TITLE", "")
+        .str(
+            "HOST",
+            history.sources.first().map_or("", |source| &source.name),
+        )
+        .raw(
+            "HOSTS",
+            &json::array(
+                history
+                    .sources
+                    .iter()
+                    .map(|source| json::string(&source.name)),
+            ),
+        )
+        .str("TIME", &iso_utc(first))
+        .str("T0", &iso_utc(first))
+        .f64("LENGTH", last - first)
+        .f64("TOTAL_LENGTH", last - first)
+        .f64("STEP_SIZE", median(steps).unwrap_or(1.0))
+        .str("LOSS_LENS_VERSION", env!("CARGO_PKG_VERSION"))
+        .raw("SERIES_META", &series_meta.finish())
+        .finish();
+    json::Object::new()
+        .raw("metadata", &metadata)
+        .u64("version", DATA_VERSION)
+        .raw(
+            "x_values",
+            &json::array(xs.keys().map(|&ms| json::number(ms as f64 / 1000.0))),
+        )
+        .raw("results", &results.finish())
+        .raw("raw_values", &raw_values.finish())
+        .finish()
+}
+
+fn millis(secs: f64) -> i64 {
+    (secs * 1000.0).round() as i64
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    values.get(values.len() / 2).copied()
+}
+
+/// Like Python's `datetime.isoformat()` of a naive UTC time, which flent
+/// writes and parses.
+fn iso_utc(unix_secs: f64) -> String {
+    let time = LocalTime::utc(unix_secs.floor() as i64);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}",
+        time.year,
+        time.month,
+        time.day,
+        time.hour,
+        time.minute,
+        time.second,
+        ((unix_secs - unix_secs.floor()) * 1e6) as u32
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::read_samples;
+
+    #[test]
+    fn series_share_x_values() {
+        let lines = [
+            r#"{"kind":"stats","host":"a","unix_secs":1000,"stats":{"client_sent":100,"client_received":100,"elapsed_secs":1,"outages":[],"smoothed_loss":0,"smoothed_rtt_ms":20,"max_gap_ms":15}}"#,
+            r#"{"kind":"stats","host":"a","unix_secs":1001,"stats":{"client_sent":200,"client_received":150,"elapsed_secs":2,"outages":[],"smoothed_loss":null,"smoothed_rtt_ms":0,"max_gap_ms":500}}"#,
+            r#"{"kind":"stats","host":"b","unix_secs":1000.5,"stats":{"client_sent":10,"client_received":10,"elapsed_secs":1.5,"outages":[]}}"#,
+        ];
+        let history = History::from_samples(&read_samples(&lines.join("\n")).unwrap());
+        let data = json::parse(&export(&history)).unwrap();
+        assert_eq!(data.get("x_values").unwrap().to_json(), "[1,1.5,2]");
+        let results = data.get("results").unwrap();
+        assert_eq!(
+            results.get("Ping (ms) UDP - a").unwrap().to_json(),
+            "[20,null,null]"
+        );
+        assert_eq!(
+            results.get("Packet loss - a").unwrap().to_json(),
+            "[0,null,50]"
+        );
+        assert_eq!(
+            results.get("Packet loss - b").unwrap().to_json(),
+            "[null,0,null]"
+        );
+        let metadata = data.get("metadata").unwrap();
+        assert_eq!(
+            metadata.get("T0").unwrap().as_str(),
+            Some("1970-01-01T00:16:39.000000")
+        );
+    }
+}
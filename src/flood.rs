@@ -0,0 +1,325 @@
+//! Downstream flood test: the client asks the server to stream packets at a
+//! given rate and counts what arrives. Downstream capacity problems can't be
+//! provoked with probes alone, since ACKs are never sent faster than probes.
+//!
+//! The request is `[FLOOD_REQUEST, packets_per_second, client_id,
+//! duration_ms, packet_size]`, with a rate of 0 cancelling a running flood.
+//! Flood packets are `[FLOOD, seq, total, packets_per_second]` followed by
+//! padding, carrying the total and rate the server granted after applying its
+//! limits.
+
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use eyre::OptionExt;
+
+use crate::{
+    auth::{self, Psk},
+    gso, json, resolve,
+    session::{self, Session, FEATURE_FLOOD, PROTOCOL_VERSION},
+    split_kind, with_version, IpVersion, LocalBind, StopHandle, BUF_SIZE, LATE_WINDOW_SECS,
+};
+
+pub(crate) const FLOOD_REQUEST_PACKET_CONST: u8 = 4;
+pub(crate) const FLOOD_PACKET_CONST: u8 = 5;
+pub(crate) const FLOOD_REQUEST_SIZE: usize = 1 + 4 + 4 + 4 + 2;
+/// Smallest flood packet; larger ones are padded
+pub const FLOOD_PACKET_MIN_SIZE: usize = 1 + 4 + 4 + 4;
+
+/// Requests sent before giving up on a server that doesn't answer
+const REQUEST_ATTEMPTS: u32 = 3;
+
+/// Settings for a [`FloodTest`].
+#[derive(Clone, Debug)]
+pub struct FloodConfig {
+    /// Server to request the flood from
+    pub host: String,
+    /// Only connect over this IP version
+    pub ip_version: Option<IpVersion>,
+    /// Local address to receive the flood on
+    pub source_addr: Option<IpAddr>,
+    /// Network interface to use, Linux only
+    pub interface: Option<String>,
+    /// Requested rate; the server may grant less
+    pub packets_per_second: u32,
+    /// Requested UDP payload size of flood packets
+    pub packet_size: usize,
+    /// Requested length of the flood
+    pub duration: Duration,
+    /// Key for servers that require one, see [`crate::auth`]
+    pub psk: Option<Psk>,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1:13337".to_string(),
+            ip_version: None,
+            source_addr: None,
+            interface: None,
+            packets_per_second: 1000,
+            packet_size: 1200,
+            duration: Duration::from_secs(10),
+            psk: None,
+        }
+    }
+}
+
+/// Result of a [`FloodTest`].
+#[derive(Clone, Debug)]
+pub struct FloodStats {
+    pub requested_rate: u32,
+    /// Rate granted by the server
+    pub packets_per_second: u32,
+    pub packet_size: u32,
+    /// Packets the server announced it would send
+    pub expected: u32,
+    /// Distinct packets received
+    pub received: u32,
+    /// Packets that arrived after one with a higher sequence number
+    pub reordered: u32,
+    pub max_gap: Duration,
+    /// Time from the first to the last packet received
+    pub elapsed: Duration,
+}
+
+impl FloodStats {
+    /// Percentage of announced packets that never arrived.
+    pub fn loss(&self) -> f64 {
+        100.0 * (1.0 - (self.received as f64 / self.expected as f64))
+    }
+
+    /// Payload throughput actually received.
+    pub fn received_kib_per_sec(&self) -> f64 {
+        (self.received as f64 * self.packet_size as f64 / (1 << 10) as f64)
+            / self.elapsed.as_secs_f64()
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("requested_rate", self.requested_rate.into())
+            .u64("packets_per_second", self.packets_per_second.into())
+            .u64("packet_size", self.packet_size.into())
+            .u64("expected", self.expected.into())
+            .u64("received", self.received.into())
+            .u64("reordered", self.reordered.into())
+            .f64("loss", self.loss())
+            .f64("max_gap_ms", self.max_gap.as_secs_f64() * 1000.0)
+            .f64("elapsed_secs", self.elapsed.as_secs_f64())
+            .finish()
+    }
+}
+
+impl fmt::Display for FloodStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Flood rate     : {} packets/s of {}B (requested {})",
+            self.packets_per_second, self.packet_size, self.requested_rate
+        )?;
+        writeln!(f, "Server sent    : {}", self.expected)?;
+        writeln!(f, "Client received: {}", self.received)?;
+        writeln!(f, "Downstream loss: {:.2}%", self.loss())?;
+        writeln!(f, "Reordered      : {}", self.reordered)?;
+        writeln!(
+            f,
+            "Received       : {:.02} KiB/s",
+            self.received_kib_per_sec()
+        )?;
+        writeln!(
+            f,
+            "Max gap        : {:.1}ms",
+            self.max_gap.as_secs_f64() * 1000.0
+        )?;
+        writeln!(f, "Time elapsed: {:.2} seconds", self.elapsed.as_secs_f64())
+    }
+}
+
+/// Client side of a downstream flood test.
+pub struct FloodTest {
+    config: FloodConfig,
+    socket: UdpSocket,
+    client_id: u32,
+    /// Protocol version packets are marked with, 0 without a session
+    version: u8,
+    done: StopHandle,
+}
+
+impl FloodTest {
+    pub fn new(config: FloodConfig) -> eyre::Result<Self> {
+        eyre::ensure!(config.packets_per_second > 0, "flood rate must be positive");
+        eyre::ensure!(
+            (FLOOD_PACKET_MIN_SIZE..=BUF_SIZE).contains(&config.packet_size),
+            "flood packet size must be between {FLOOD_PACKET_MIN_SIZE} and {BUF_SIZE}"
+        );
+        eyre::ensure!(
+            config.duration.as_millis() <= u32::MAX.into(),
+            "flood duration is too long"
+        );
+        let local = LocalBind {
+            source_addr: config.source_addr,
+            interface: config.interface.clone(),
+        };
+        let addr = resolve(&config.host, local.ip_version(config.ip_version))?;
+        let socket = local.socket_for(addr)?;
+        socket.connect(addr)?;
+        let proposal = Session {
+            version: PROTOCOL_VERSION,
+            packets_per_second: config.packets_per_second,
+            probe_size: FLOOD_REQUEST_SIZE as u16,
+            features: FEATURE_FLOOD,
+            token: rand::random(),
+        };
+        let session = session::handshake(&socket, proposal, None, config.psk.as_ref())?
+            .map(|welcome| welcome.session);
+        if let Some(session) = session {
+            eyre::ensure!(
+                session.features & FEATURE_FLOOD != 0,
+                "the server doesn't allow floods; start it with --allow-flood"
+            );
+        }
+        Ok(Self {
+            config,
+            socket,
+            client_id: session.map_or(proposal.token, |session| session.token),
+            version: session.map_or(0, |session| session.negotiated_version()),
+            done: StopHandle::default(),
+        })
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.done.clone()
+    }
+
+    fn request(&self, packets_per_second: u32) -> eyre::Result<()> {
+        let mut request = [0u8; FLOOD_REQUEST_SIZE];
+        request[0] = with_version(FLOOD_REQUEST_PACKET_CONST, self.version);
+        request[1..5].copy_from_slice(&packets_per_second.to_be_bytes());
+        request[5..9].copy_from_slice(&self.client_id.to_be_bytes());
+        request[9..13].copy_from_slice(&(self.config.duration.as_millis() as u32).to_be_bytes());
+        let size = self.config.packet_size.min(u16::MAX.into()) as u16;
+        request[13..15].copy_from_slice(&size.to_be_bytes());
+        self.socket
+            .send(&auth::seal(self.config.psk.as_ref(), &request))?;
+        Ok(())
+    }
+
+    /// Request the flood and receive it until the server is done or the test
+    /// is stopped, which also cancels the flood on the server.
+    pub fn run(self) -> eyre::Result<FloodStats> {
+        self.socket
+            .set_read_timeout(Some(Duration::from_millis(100)))?;
+        gso::enable_gro(&self.socket);
+        let mut buf = vec![0u8; BUF_SIZE];
+        let mut seen: Vec<u64> = Vec::new();
+        let mut stats = FloodStats {
+            requested_rate: self.config.packets_per_second,
+            packets_per_second: 0,
+            packet_size: 0,
+            expected: 0,
+            received: 0,
+            reordered: 0,
+            max_gap: Duration::ZERO,
+            elapsed: Duration::ZERO,
+        };
+        let mut highest_seq = None;
+        let mut first: Option<Instant> = None;
+        let mut last = Instant::now();
+        let mut attempts = 0;
+        let mut last_request = None::<Instant>;
+
+        while !self.done.is_stopped() {
+            if first.is_none() {
+                if last_request.is_none_or(|at| at.elapsed() >= Duration::from_secs(1)) {
+                    eyre::ensure!(
+                        attempts < REQUEST_ATTEMPTS,
+                        "no flood from the server; it needs to run with --allow-flood"
+                    );
+                    attempts += 1;
+                    self.request(self.config.packets_per_second)?;
+                    last_request = Some(Instant::now());
+                }
+            } else if stats.received >= stats.expected
+                || last.elapsed() >= Duration::from_secs(LATE_WINDOW_SECS as u64)
+            {
+                break;
+            }
+            let (n, segment) = match gso::recv(&self.socket, &mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                // the request may have been refused before the server is up
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let now = Instant::now();
+            // several if they arrived back to back, see `gso`
+            for packet in buf[..n].chunks(segment) {
+                let Some(n) = auth::open(self.config.psk.as_ref(), packet) else {
+                    continue;
+                };
+                if n < FLOOD_PACKET_MIN_SIZE
+                    || split_kind(packet[0]) != (FLOOD_PACKET_CONST, self.version)
+                {
+                    continue;
+                }
+                let seq = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+                let total = u32::from_be_bytes(packet[5..9].try_into().unwrap());
+                if seq >= total {
+                    continue;
+                }
+                if first.is_none() {
+                    first = Some(now);
+                    stats.expected = total;
+                    stats.packets_per_second =
+                        u32::from_be_bytes(packet[9..13].try_into().unwrap());
+                    stats.packet_size = n as u32;
+                    seen = vec![0; (total as usize).div_ceil(64)];
+                } else {
+                    stats.max_gap = stats.max_gap.max(now - last);
+                }
+                last = now;
+                let Some(word) = seen.get_mut(seq as usize / 64) else {
+                    continue;
+                };
+                if *word & (1 << (seq % 64)) != 0 {
+                    continue;
+                }
+                *word |= 1 << (seq % 64);
+                stats.received += 1;
+                if highest_seq.is_some_and(|highest| seq < highest) {
+                    stats.reordered += 1;
+                }
+                highest_seq = highest_seq.max(Some(seq));
+            }
+        }
+        if self.done.is_stopped() {
+            // best effort, the server ends the flood on its own anyway
+            let _ = self.request(0);
+            // only count what should have arrived so far
+            if let Some(highest) = highest_seq {
+                stats.expected = highest + 1;
+            }
+        }
+        let first = first.ok_or_eyre("stopped before the flood started")?;
+        stats.elapsed = last - first;
+        Ok(stats)
+    }
+}
@@ -0,0 +1,209 @@
+//! UDP segmentation offload for floods: the server hands the kernel up to
+//! [`MAX_SEGMENTS`] equal-size packets in one `UDP_SEGMENT` send, and the
+//! client takes what arrived back to back in one `UDP_GRO` receive, so
+//! hundreds of Mbps don't take a syscall per packet. Linux only; elsewhere,
+//! and where the kernel or the interface refuses, packets go one at a time.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+use crate::MAX_PACKET_SIZE;
+
+/// Segments per send at most, the kernel's `UDP_MAX_SEGMENTS`
+const MAX_SEGMENTS: usize = 64;
+
+/// Sends runs of equal-size packets to one address, segmented by the kernel
+/// while it accepts that.
+pub(crate) struct SegmentSender {
+    offload: bool,
+}
+
+impl SegmentSender {
+    pub fn new() -> Self {
+        Self {
+            offload: cfg!(target_os = "linux"),
+        }
+    }
+
+    /// How many packets of `size` bytes go in one send.
+    pub fn capacity(&self, size: usize) -> usize {
+        if self.offload {
+            (MAX_PACKET_SIZE / size.max(1)).clamp(1, MAX_SEGMENTS)
+        } else {
+            1
+        }
+    }
+
+    /// Send `packets`, back to back `size` bytes each, to `addr`.
+    pub fn send(
+        &mut self,
+        socket: &UdpSocket,
+        packets: &[u8],
+        size: usize,
+        addr: SocketAddr,
+    ) -> io::Result<()> {
+        if self.offload && packets.len() > size {
+            match send_segmented(socket, packets, size, addr) {
+                Ok(()) => return Ok(()),
+                // one at a time from now on
+                Err(e) if is_unsupported(&e) => self.offload = false,
+                Err(e) => return Err(e),
+            }
+        }
+        for packet in packets.chunks(size) {
+            socket.send_to(packet, addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_segmented(
+    socket: &UdpSocket,
+    packets: &[u8],
+    size: usize,
+    addr: SocketAddr,
+) -> io::Result<()> {
+    use std::{mem, os::fd::AsRawFd};
+
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let namelen = crate::batch::write_sockaddr(addr, &mut name);
+    let mut iov = libc::iovec {
+        iov_base: packets.as_ptr().cast_mut().cast(),
+        iov_len: packets.len(),
+    };
+    // u64 for the alignment of cmsghdr
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = (&mut name as *mut libc::sockaddr_storage).cast();
+    msg.msg_namelen = namelen;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+        libc::CMSG_DATA(cmsg)
+            .cast::<u16>()
+            .write_unaligned(size as u16);
+    }
+    let n = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_segmented(
+    _socket: &UdpSocket,
+    _packets: &[u8],
+    _size: usize,
+    _addr: SocketAddr,
+) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Whether a segmented send failed for want of offload, by a kernel
+/// predating it or an interface without checksum offload.
+fn is_unsupported(e: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    if matches!(
+        e.raw_os_error(),
+        Some(libc::EIO | libc::EINVAL | libc::EOPNOTSUPP | libc::ENOPROTOOPT)
+    ) {
+        return true;
+    }
+    e.kind() == io::ErrorKind::Unsupported
+}
+
+/// Have packets arriving back to back on `socket` coalesced, see [`recv`].
+/// Kernels without `UDP_GRO` just deliver them one by one.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_gro(socket: &UdpSocket) {
+    use std::os::fd::AsRawFd;
+
+    let on: libc::c_int = 1;
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            (&on as *const libc::c_int).cast(),
+            std::mem::size_of_val(&on) as libc::socklen_t,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_gro(_socket: &UdpSocket) {}
+
+/// Receive like [`UdpSocket::recv`], returning with the length the size of
+/// the packets coalesced into `buf`, the last of which may be shorter.
+#[cfg(target_os = "linux")]
+pub(crate) fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, usize)> {
+    use std::{mem, os::fd::AsRawFd};
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let n = n as usize;
+    let mut segment = n;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if level == libc::SOL_UDP && kind == libc::UDP_GRO {
+            let size = unsafe { libc::CMSG_DATA(cmsg).cast::<libc::c_int>().read_unaligned() };
+            segment = usize::try_from(size).unwrap_or(n);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok((n, segment.max(1)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, usize)> {
+    let n = socket.recv(buf)?;
+    Ok((n, n.max(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segmented_sends_arrive_as_their_packets() {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        rx.connect(tx.local_addr().unwrap()).unwrap();
+        enable_gro(&rx);
+        let mut sender = SegmentSender::new();
+        let packets: Vec<u8> = (0..5u8).flat_map(|i| [i; 100]).collect();
+        sender
+            .send(&tx, &packets, 100, rx.local_addr().unwrap())
+            .unwrap();
+        let mut received = Vec::new();
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        while received.len() < 5 {
+            let (n, segment) = recv(&rx, &mut buf).unwrap();
+            received.extend(buf[..n].chunks(segment).map(<[u8]>::to_vec));
+        }
+        assert_eq!(received, packets.chunks(100).collect::<Vec<_>>());
+    }
+}
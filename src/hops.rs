@@ -0,0 +1,456 @@
+//! Per-hop loss and latency, like mtr: rounds of probes with TTLs from 1 up
+//! to the server, each answered by ICMP Time Exceeded from the router where
+//! it expired or by the server once it gets through. Linux delivers the ICMP
+//! errors to unprivileged UDP sockets through `IP_RECVERR`, so no raw socket
+//! is needed.
+//!
+//! Every TTL has its own socket, and so its own source port, which matches
+//! errors to hops even when a router quotes too little of the probe to see
+//! its payload. Probes are `[HOP, round, client_id]`, the minimum probe size,
+//! and the server echoes them back as `HOP_REPLY` if it knows the session,
+//! like it only answers probes of known sessions. With a pre-shared key they
+//! carry a tag like every other packet.
+//!
+//! The same probes also give a one-off traceroute, which is stored in the
+//! recording so later analysis knows which path the measurements took.
+//!
+//! Routers often rate-limit ICMP, so loss at an intermediate hop that doesn't
+//! carry on to later hops is usually not loss on the path.
+
+use std::{
+    collections::VecDeque,
+    io, mem,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::Sender,
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    auth::{self, Psk},
+    client::HopTrace,
+    ecn,
+    event::Event,
+    json, recording,
+    stats::HopStats,
+    LocalBind, StopHandle, CLIENT_TO_SERVER_PACKET_SIZE, HOP_PACKET_CONST, HOP_REPLY_PACKET_CONST,
+};
+
+/// How long to wait for an answer before counting a probe as lost
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Hop {
+    ttl: u8,
+    socket: UdpSocket,
+    /// Unanswered probes by round, oldest first
+    outstanding: VecDeque<(u32, Instant)>,
+    addr: Option<IpAddr>,
+    sent: u32,
+    received: u32,
+    rtt_sum: Duration,
+    max_rtt: Duration,
+}
+
+impl Hop {
+    fn new(ttl: u8, target: SocketAddr, local: &LocalBind) -> eyre::Result<Self> {
+        let socket = local.socket_for(target)?;
+        let (level, ttl_name, recverr_name) = match target {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TTL, libc::IP_RECVERR),
+            SocketAddr::V6(_) => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_UNICAST_HOPS,
+                libc::IPV6_RECVERR,
+            ),
+        };
+        setsockopt(&socket, level, ttl_name, ttl.into())?;
+        setsockopt(&socket, level, recverr_name, 1)?;
+        socket.connect(target)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            ttl,
+            socket,
+            outstanding: VecDeque::new(),
+            addr: None,
+            sent: 0,
+            received: 0,
+            rtt_sum: Duration::ZERO,
+            max_rtt: Duration::ZERO,
+        })
+    }
+
+    fn send(&mut self, round: u32, client_id: u32, psk: Option<&Psk>) {
+        let mut probe = [0u8; CLIENT_TO_SERVER_PACKET_SIZE];
+        probe[0] = HOP_PACKET_CONST;
+        probe[1..5].copy_from_slice(&round.to_be_bytes());
+        probe[5..9].copy_from_slice(&client_id.to_be_bytes());
+        // a failed send is just a lost probe
+        let _ = self.socket.send(&auth::seal(psk, &probe));
+        self.outstanding.push_back((round, Instant::now()));
+    }
+
+    /// Count an answer from `from` to the probe quoted in `payload`.
+    fn answered(&mut self, payload: &[u8], from: IpAddr) {
+        let index = if payload.len() >= 5 {
+            let round = u32::from_be_bytes(payload[1..5].try_into().unwrap());
+            self.outstanding.iter().position(|&(r, _)| r == round)
+        } else if self.outstanding.is_empty() {
+            None
+        } else {
+            // too little quoted to tell, so assume the oldest
+            Some(0)
+        };
+        let Some((_, sent_at)) = index.and_then(|i| self.outstanding.remove(i)) else {
+            return;
+        };
+        let rtt = sent_at.elapsed();
+        self.addr = Some(from);
+        self.sent += 1;
+        self.received += 1;
+        self.rtt_sum += rtt;
+        self.max_rtt = self.max_rtt.max(rtt);
+    }
+
+    fn expire(&mut self) {
+        while self
+            .outstanding
+            .front()
+            .is_some_and(|(_, sent_at)| sent_at.elapsed() >= TIMEOUT)
+        {
+            self.outstanding.pop_front();
+            self.sent += 1;
+        }
+    }
+
+    /// Read the server's replies and the ICMP errors queued on the socket.
+    /// Returns whether the server was reached.
+    fn receive(&mut self, target: SocketAddr, psk: Option<&Psk>) -> bool {
+        let mut reached = false;
+        let mut buf = [0u8; 64];
+        while let Ok(n) = self.socket.recv(&mut buf) {
+            let Some(n) = auth::open(psk, &buf[..n]) else {
+                continue;
+            };
+            if n >= 5 && buf[0] == HOP_REPLY_PACKET_CONST {
+                self.answered(&buf[..n], target.ip());
+                reached = true;
+            }
+        }
+        while let Ok(Some((n, from))) = recv_error(&self.socket, &mut buf) {
+            if from == target.ip() {
+                // e.g. port unreachable from the server's host
+                reached = true;
+            }
+            self.answered(&buf[..n], from);
+        }
+        reached
+    }
+
+    fn stats(&self) -> HopStats {
+        HopStats {
+            ttl: self.ttl,
+            addr: self.addr,
+            sent: self.sent,
+            received: self.received,
+            mean_rtt: self.rtt_sum.checked_div(self.received).unwrap_or_default(),
+            max_rtt: self.max_rtt,
+        }
+    }
+}
+
+fn setsockopt(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if rv == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Take one ICMP error off the socket's error queue, returning how much of
+/// the offending probe is in `buf` and which router sent the error.
+fn recv_error(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Option<(usize, IpAddr)>> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // u64 for the alignment of cmsghdr
+    let mut control = [0u64; 64];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let n = unsafe {
+        libc::recvmsg(
+            socket.as_raw_fd(),
+            &mut msg,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if (level == libc::IPPROTO_IP && kind == libc::IP_RECVERR)
+            || (level == libc::IPPROTO_IPV6 && kind == libc::IPV6_RECVERR)
+        {
+            let err = unsafe { libc::CMSG_DATA(cmsg) }.cast::<libc::sock_extended_err>();
+            let origin = unsafe { (*err).ee_origin };
+            if origin == libc::SO_EE_ORIGIN_ICMP || origin == libc::SO_EE_ORIGIN_ICMP6 {
+                let offender = unsafe { libc::SO_EE_OFFENDER(err) };
+                let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+                // the offender is a sockaddr_in or sockaddr_in6, which both
+                // start with the family
+                let len = match libc::c_int::from(unsafe { (*offender).sa_family }) {
+                    libc::AF_INET => mem::size_of::<libc::sockaddr_in>(),
+                    libc::AF_INET6 => mem::size_of::<libc::sockaddr_in6>(),
+                    _ => return Ok(None),
+                };
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        offender.cast::<u8>(),
+                        (&mut storage as *mut libc::sockaddr_storage).cast::<u8>(),
+                        len,
+                    );
+                }
+                let from = ecn::socket_addr(&storage)?.ip();
+                return Ok(Some((n as usize, from)));
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok(None)
+}
+
+/// Probe sockets for every TTL up to where the server answers.
+struct Tracer {
+    target: SocketAddr,
+    hops: Vec<Hop>,
+    /// TTL at which the server answers; no need to probe beyond it
+    reached_at: usize,
+    round: u32,
+    psk: Option<Psk>,
+}
+
+impl Tracer {
+    fn new(
+        target: SocketAddr,
+        max_hops: u8,
+        local: &LocalBind,
+        psk: Option<&Psk>,
+    ) -> eyre::Result<Self> {
+        let hops = (1..=max_hops)
+            .map(|ttl| Hop::new(ttl, target, local))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        Ok(Self {
+            target,
+            reached_at: hops.len(),
+            hops,
+            round: 0,
+            psk: psk.cloned(),
+        })
+    }
+
+    /// Probe every TTL for the session `client_id`.
+    fn send_round(&mut self, client_id: u32) {
+        self.round = self.round.wrapping_add(1);
+        for hop in &mut self.hops[..self.reached_at] {
+            hop.send(self.round, client_id, self.psk.as_ref());
+        }
+    }
+
+    /// Wait up to 50ms for answers and account for them.
+    fn poll(&mut self) -> eyre::Result<()> {
+        let mut fds: Vec<libc::pollfd> = self
+            .hops
+            .iter()
+            .map(|hop| libc::pollfd {
+                fd: hop.socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 50) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+        for (i, (hop, fd)) in self.hops.iter_mut().zip(&fds).enumerate() {
+            if fd.revents != 0 && hop.receive(self.target, self.psk.as_ref()) {
+                self.reached_at = self.reached_at.min(i + 1);
+            }
+            hop.expire();
+        }
+        Ok(())
+    }
+
+    fn outstanding(&self) -> bool {
+        self.hops[..self.reached_at]
+            .iter()
+            .any(|hop| !hop.outstanding.is_empty())
+    }
+
+    fn stats(&self) -> Vec<HopStats> {
+        self.hops[..self.reached_at]
+            .iter()
+            .filter(|hop| hop.sent > 0)
+            .map(Hop::stats)
+            .collect()
+    }
+}
+
+/// Trace hops to `target` for the session `client_id`, which changes when
+/// it's renewed, until `done`, publishing results to `out`.
+pub(crate) fn trace(
+    target: SocketAddr,
+    client_id: &AtomicU32,
+    config: HopTrace,
+    local: &LocalBind,
+    psk: Option<&Psk>,
+    done: &StopHandle,
+    out: &Mutex<Vec<HopStats>>,
+) -> eyre::Result<()> {
+    let mut tracer = Tracer::new(target, config.max_hops, local, psk)?;
+    let mut next_round = Instant::now();
+    while !done.is_stopped() {
+        if Instant::now() >= next_round {
+            tracer.send_round(client_id.load(Ordering::SeqCst));
+            next_round += config.every;
+        }
+        tracer.poll()?;
+        *out.lock().unwrap() = tracer.stats();
+    }
+    Ok(())
+}
+
+/// Rounds of a one-off traceroute; each hop only needs to answer one
+const ROUTE_ROUNDS: u32 = 3;
+
+/// One-off traceroute to `target`: the address answering at each TTL, up to
+/// the server or `max_hops`, `None` for hops that never answered.
+pub(crate) fn route(
+    target: SocketAddr,
+    client_id: &AtomicU32,
+    max_hops: u8,
+    local: &LocalBind,
+    psk: Option<&Psk>,
+    done: &StopHandle,
+) -> eyre::Result<Vec<Option<IpAddr>>> {
+    let mut tracer = Tracer::new(target, max_hops, local, psk)?;
+    for _ in 0..ROUTE_ROUNDS {
+        tracer.send_round(client_id.load(Ordering::SeqCst));
+        let round_start = Instant::now();
+        // spaced a little, as bursts are more likely to be dropped
+        while round_start.elapsed() < Duration::from_millis(100) {
+            tracer.poll()?;
+        }
+    }
+    while tracer.outstanding() && !done.is_stopped() {
+        tracer.poll()?;
+    }
+    Ok(tracer.hops[..tracer.reached_at]
+        .iter()
+        .map(|hop| hop.addr)
+        .collect())
+}
+
+/// How often [`record_route`] checks whether the route changed
+const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether `new` is a different route than `old`. Hops that didn't answer
+/// one of the traces don't count, as routers drop ICMP all the time.
+fn route_changed(old: &[Option<IpAddr>], new: &[Option<IpAddr>]) -> bool {
+    old.len() != new.len()
+        || old
+            .iter()
+            .zip(new)
+            .any(|pair| matches!(pair, (Some(a), Some(b)) if a != b))
+}
+
+/// Trace the route to the current target at the start and whenever it may
+/// have changed, storing it in the recording until `done`. Changes to the
+/// route of the same target are queued as [`Event::RouteChange`].
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn record_route(
+    addr: &Mutex<SocketAddr>,
+    client_id: &AtomicU32,
+    max_hops: u8,
+    local: &LocalBind,
+    psk: Option<&Psk>,
+    done: &StopHandle,
+    recorder: &Sender<recording::Message>,
+    events: &Mutex<Vec<Event>>,
+) -> eyre::Result<()> {
+    let mut recorded: Option<(SocketAddr, Vec<Option<IpAddr>>)> = None;
+    let mut last_check = None::<Instant>;
+    while !done.is_stopped() {
+        let target = *addr.lock().unwrap();
+        let retargeted = recorded.as_ref().is_some_and(|(addr, _)| *addr != target);
+        if retargeted || last_check.is_none_or(|at| at.elapsed() >= ROUTE_CHECK_INTERVAL) {
+            last_check = Some(Instant::now());
+            let route = route(target, client_id, max_hops, local, psk, done)?;
+            match &mut recorded {
+                Some((addr, old)) if *addr == target && !route_changed(old, &route) => {
+                    // learn hops that didn't answer before
+                    for (old, new) in old.iter_mut().zip(route) {
+                        *old = old.or(new);
+                    }
+                }
+                _ => {
+                    if recorded.as_ref().is_some_and(|(addr, _)| *addr == target) {
+                        events.lock().unwrap().push(Event::RouteChange {
+                            route: route.clone(),
+                        });
+                    }
+                    let metadata = json::Object::new()
+                        .u64(
+                            "unix_secs",
+                            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                        )
+                        .str("target", &target.to_string())
+                        .raw(
+                            "route",
+                            &json::array(route.iter().map(|hop| {
+                                hop.map_or_else(
+                                    || "null".to_string(),
+                                    |hop| json::string(&hop.to_string()),
+                                )
+                            })),
+                        );
+                    if recorder
+                        .send(recording::Message::Metadata(metadata))
+                        .is_err()
+                    {
+                        // the recording is already finished
+                        return Ok(());
+                    }
+                    recorded = Some((target, route));
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
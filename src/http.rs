@@ -0,0 +1,386 @@
+//! Minimal HTTP/1.1 server plumbing for the dashboard and APIs. Every
+//! connection gets its own thread, up to [`MAX_CONNECTIONS`] at once, and is
+//! closed after one response. Also a client for the few requests made to
+//! other servers, such as UPnP gateways and collectors.
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Longest request head accepted before giving up on a client.
+const MAX_HEAD: usize = 16 << 10;
+/// Largest request body accepted unless a server asks for less.
+pub(crate) const MAX_BODY: usize = 1 << 20;
+/// Connections served at once; further ones are answered 503 right away.
+const MAX_CONNECTIONS: usize = 64;
+/// How long a client may take to send its whole request, however it
+/// trickles in, and then each write of the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long requests made to other servers may take to connect, and then
+/// to send or answer
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Value of `name` in the query string. No percent-decoding is done, which
+    /// is fine for the numeric parameters used here.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+}
+
+pub(crate) struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn json(body: String) -> Self {
+        Self::ok("application/json", body)
+    }
+
+    pub fn text(status: &'static str, body: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: format!("{body}\n").into_bytes(),
+        }
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Self::text("405 Method Not Allowed", "method not allowed")
+    }
+
+    pub fn not_found() -> Self {
+        Self::text("404 Not Found", "not found")
+    }
+
+    pub fn write_to(&self, stream: &mut TcpStream) -> io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+/// What a handler wants done with a connection.
+pub(crate) enum Reply {
+    Respond(Response),
+    /// The handler took over the stream (e.g. after a protocol upgrade).
+    Handled,
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Self {
+        Reply::Respond(response)
+    }
+}
+
+/// Whether `request` carries `Authorization: Bearer TOKEN` with `token`.
+pub(crate) fn bearer_authorized(request: &Request, token: &str) -> bool {
+    let Some(presented) = request
+        .header("Authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // compare in constant time so the token can't be guessed byte by byte
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Accept connections on `addr` in the background, passing each parsed
+/// request with a body of up to `max_body` bytes to `handler`.
+pub(crate) fn serve(
+    addr: impl ToSocketAddrs,
+    max_body: usize,
+    handler: impl Fn(&Request, &mut TcpStream) -> Reply + Send + Sync + 'static,
+) -> eyre::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let handler = Arc::new(handler);
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                let busy = Response::text("503 Service Unavailable", "too many connections");
+                let _ = busy.write_to(&mut stream);
+                continue;
+            }
+            let handler = Arc::clone(&handler);
+            let connections = Arc::clone(&connections);
+            thread::spawn(move || {
+                if let Ok(request) = read_request(&mut stream, max_body) {
+                    if let Reply::Respond(response) = handler(&request, &mut stream) {
+                        let _ = response.write_to(&mut stream);
+                    }
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Reads from a stream until a deadline, rather than waiting up to a
+/// timeout for every read.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn read_request(stream: &mut TcpStream, max_body: usize) -> io::Result<Request> {
+    let deadline = Deadline {
+        stream,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    };
+    let mut reader = BufReader::new(deadline.take((MAX_HEAD + max_body) as u64));
+    let mut line = String::new();
+    let mut total = reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad request"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+    loop {
+        line.clear();
+        total += reader.read_line(&mut line)?;
+        if total > MAX_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "head too long"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            request
+                .headers
+                .push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    if let Some(len) = request.header("Content-Length") {
+        let len: usize = len
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length"))?;
+        if len > max_body {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "body too long"));
+        }
+        request.body.resize(len, 0);
+        reader.read_exact(&mut request.body)?;
+    }
+    Ok(request)
+}
+
+/// Status and body of a request to `url`. HTTP/1.0, so answers aren't
+/// chunked and the connection closes after them.
+pub(crate) fn request(
+    url: &Url,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> eyre::Result<(u16, String)> {
+    let mut stream = TcpStream::connect_timeout(&url.addr()?, CLIENT_TIMEOUT)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut request = format!(
+        "{method} {} HTTP/1.0\r\nHost: {}:{}\r\nContent-Length: {}\r\n",
+        url.path,
+        url.host,
+        url.port,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer)?;
+    let answer = String::from_utf8_lossy(&answer);
+    let (head, body) = answer
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| eyre::eyre!("malformed answer from {}", url.host))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| eyre::eyre!("malformed answer from {}", url.host))?;
+    Ok((status, body.to_string()))
+}
+
+/// An `http://` URL.
+#[derive(Clone, Debug)]
+pub(crate) struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, 80),
+        };
+        Some(Self {
+            host: host.to_string(),
+            port,
+            path: if path.is_empty() { "/" } else { path }.to_string(),
+        })
+    }
+
+    /// `reference` taken relative to this URL, as control URLs often are.
+    pub fn join(&self, reference: &str) -> Url {
+        if let Some(url) = Url::parse(reference) {
+            return url;
+        }
+        let path = if reference.starts_with('/') {
+            reference.to_string()
+        } else {
+            format!("/{reference}")
+        };
+        Url {
+            path,
+            ..self.clone()
+        }
+    }
+
+    /// The host's first IPv4 address, or else its first address.
+    pub fn addr(&self) -> eyre::Result<SocketAddr> {
+        // brackets of IPv6 literals stay in the Host header only
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<_> = (host, self.port).to_socket_addrs()?.collect();
+        addrs
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .or(addrs.first())
+            .copied()
+            .ok_or_else(|| eyre::eyre!("{} did not resolve to any address", self.host))
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_beyond_the_cap_are_turned_away() {
+        let addr = serve("127.0.0.1:0", MAX_BODY, |_, _| {
+            Response::text("200 OK", "hi").into()
+        })
+        .unwrap();
+        // silent, so each holds its slot until the request timeout
+        let held: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        let mut extra = TcpStream::connect(addr).unwrap();
+        let mut answer = String::new();
+        extra.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.1 503 "), "{answer}");
+        drop(held);
+    }
+
+    #[test]
+    fn trickling_requests_are_cut_off_at_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let trickle = thread::spawn(move || {
+            // each byte well within any per-read timeout
+            while client.write_all(b"x").is_ok() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let start = Instant::now();
+        let mut reader = Deadline {
+            stream: &server,
+            deadline: start + Duration::from_millis(200),
+        };
+        let err = io::copy(&mut reader, &mut io::sink()).unwrap_err();
+        // or the socket's own for a last read shorter than the trickle
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(server);
+        trickle.join().unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+//! ICMP echo probing, for targets where no server can be installed, and to
+//! compare how a path treats ICMP with how it treats UDP.
+//!
+//! Probes are echo requests whose data is `[client_id, seq]` followed by
+//! padding, so the probe size is that of the data like it's the UDP payload
+//! otherwise, and the ICMP header takes the place of the UDP one. The
+//! sequence number field of the header only has 16 bits, so replies are
+//! matched by the data the target echoes. Like with an echo service, loss is
+//! only known for the round trip.
+//!
+//! Linux and macOS let unprivileged users open ICMP datagram sockets, on
+//! Linux only for groups in `net.ipv4.ping_group_range`; otherwise a raw
+//! socket is used, which needs root or `CAP_NET_RAW`. Either is wrapped in a
+//! [`UdpSocket`], as both send and receive whole messages the same way.
+
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::{protocol::Reply, LocalBind};
+
+/// Type, code, checksum, identifier and sequence number
+pub(crate) const HEADER_SIZE: usize = 8;
+/// Probe data needed to match replies: `[client_id, seq]`
+pub const MIN_SIZE: usize = 4 + 4;
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+/// Write echo request `seq` into `packet`, the ICMP header followed by the
+/// probe's data, whose padding is already written.
+pub(crate) fn write_request(packet: &mut [u8], ipv6: bool, seq: u32, client_id: u32) {
+    packet[0] = if ipv6 {
+        ECHO_REQUEST_V6
+    } else {
+        ECHO_REQUEST_V4
+    };
+    packet[1] = 0;
+    packet[2..4].fill(0);
+    // datagram sockets replace the identifier with their own
+    packet[4..6].copy_from_slice(&(client_id as u16).to_be_bytes());
+    packet[6..8].copy_from_slice(&(seq as u16).to_be_bytes());
+    packet[8..12].copy_from_slice(&client_id.to_be_bytes());
+    packet[12..16].copy_from_slice(&seq.to_be_bytes());
+    // the kernel fills it in for ICMPv6, over a pseudo-header
+    if !ipv6 {
+        let checksum = checksum(packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// An echo reply to one of `client_id`'s requests as an ACK, without a
+/// count of the target's. Raw IPv4 sockets deliver the IP header too, which
+/// is skipped.
+pub(crate) fn reply(packet: &[u8], client_id: u32) -> Option<Reply> {
+    let packet = match packet.first()? >> 4 {
+        // no ICMP message type starts like that
+        4 => packet.get(usize::from(packet[0] & 0x0f) * 4..)?,
+        _ => packet,
+    };
+    if packet.len() < HEADER_SIZE + MIN_SIZE
+        || !matches!(packet[0], ECHO_REPLY_V4 | ECHO_REPLY_V6)
+        || packet[1] != 0
+        || packet[8..12] != client_id.to_be_bytes()
+    {
+        return None;
+    }
+    Some(Reply::Ack {
+        seq: u32::from_be_bytes(packet[12..16].try_into().unwrap()),
+        received: 0,
+        ecn: None,
+    })
+}
+
+/// The Internet checksum (RFC 1071) of `data`.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|pair| u32::from(pair[0]) << 8 | pair.get(1).copied().map_or(0, u32::from))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// ICMP socket for probing `addr`, bound like [`LocalBind::socket_for`].
+#[cfg(unix)]
+pub(crate) fn socket_for(local: &LocalBind, addr: SocketAddr) -> eyre::Result<UdpSocket> {
+    use std::{
+        io, mem,
+        net::IpAddr,
+        os::fd::{AsRawFd, FromRawFd},
+    };
+
+    let (family, protocol) = match addr {
+        SocketAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+        SocketAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+    };
+    let mut fd = unsafe { libc::socket(family, libc::SOCK_DGRAM, protocol) };
+    if fd == -1 {
+        fd = unsafe { libc::socket(family, libc::SOCK_RAW, protocol) };
+    }
+    if fd == -1 {
+        return Err(eyre::eyre!(
+            "opening an ICMP socket: {}; it needs root, CAP_NET_RAW or, on Linux, a group in net.ipv4.ping_group_range",
+            io::Error::last_os_error()
+        ));
+    }
+    // owned right away so it's closed on errors
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let rv = match local.source_addr {
+        None => 0,
+        Some(IpAddr::V4(ip)) if addr.is_ipv4() => {
+            let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_addr.s_addr = u32::from(ip).to_be();
+            unsafe {
+                libc::bind(
+                    socket.as_raw_fd(),
+                    (&sockaddr as *const libc::sockaddr_in).cast(),
+                    mem::size_of_val(&sockaddr) as libc::socklen_t,
+                )
+            }
+        }
+        Some(IpAddr::V6(ip)) if addr.is_ipv6() => {
+            let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_addr.s6_addr = ip.octets();
+            unsafe {
+                libc::bind(
+                    socket.as_raw_fd(),
+                    (&sockaddr as *const libc::sockaddr_in6).cast(),
+                    mem::size_of_val(&sockaddr) as libc::socklen_t,
+                )
+            }
+        }
+        Some(source) => eyre::bail!("source address {source} can't reach {addr}"),
+    };
+    if rv == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if let Some(interface) = &local.interface {
+        crate::bind_to_device(&socket, interface)
+            .map_err(|e| eyre::eyre!("binding to interface {interface}: {e}"))?;
+    }
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn socket_for(_local: &LocalBind, _addr: SocketAddr) -> eyre::Result<UdpSocket> {
+    eyre::bail!("ICMP probing is only supported on Unix")
+}
+
+/// `host` with a port, which ICMP doesn't use but resolving needs, unless it
+/// has one already.
+pub(crate) fn with_port(host: &str) -> String {
+    match host.parse::<std::net::IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 0).to_string(),
+        Err(_) if host.contains(':') => host.to_string(),
+        Err(_) => format!("{host}:0"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_behind_an_ip_header_acks_the_probe() {
+        let mut request = [0u8; HEADER_SIZE + 12];
+        write_request(&mut request, false, 70_000, 0xdead_beef);
+        assert_eq!(checksum(&request), 0);
+        let mut reply_packet = [0x45u8; 20].to_vec();
+        reply_packet.extend_from_slice(&request);
+        reply_packet[20] = ECHO_REPLY_V4;
+        assert_eq!(
+            reply(&reply_packet, 0xdead_beef),
+            Some(Reply::Ack {
+                seq: 70_000,
+                received: 0,
+                ecn: None
+            })
+        );
+        assert!(reply(&reply_packet[20..], 0xdead_beef).is_some());
+        assert_eq!(reply(&reply_packet, 1), None);
+    }
+}
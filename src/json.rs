@@ -0,0 +1,405 @@
+//! Just enough JSON writing for the HTTP endpoints and machine-readable
+//! output, and reading for what clients report to a collector, without
+//! pulling in a serialization framework.
+
+use std::fmt::Write;
+
+/// Incrementally built JSON object.
+pub(crate) struct Object {
+    buf: String,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Self {
+            buf: String::from("{"),
+        }
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.buf.len() > 1 {
+            self.buf.push(',');
+        }
+        string_into(&mut self.buf, key);
+        self.buf.push(':');
+    }
+
+    pub fn u64(mut self, key: &str, value: u64) -> Self {
+        self.key(key);
+        let _ = write!(self.buf, "{value}");
+        self
+    }
+
+    /// Non-finite values (e.g. loss before anything was sent) become `null`.
+    pub fn f64(mut self, key: &str, value: f64) -> Self {
+        self.key(key);
+        number_into(&mut self.buf, value);
+        self
+    }
+
+    pub fn str(mut self, key: &str, value: &str) -> Self {
+        self.key(key);
+        string_into(&mut self.buf, value);
+        self
+    }
+
+    /// Insert an already serialized JSON value.
+    pub fn raw(mut self, key: &str, json: &str) -> Self {
+        self.key(key);
+        self.buf.push_str(json);
+        self
+    }
+
+    pub fn finish(mut self) -> String {
+        self.buf.push('}');
+        self.buf
+    }
+}
+
+/// Join already serialized values into a JSON array.
+pub(crate) fn array(items: impl IntoIterator<Item = String>) -> String {
+    let mut buf = String::from("[");
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            buf.push(',');
+        }
+        buf.push_str(&item);
+    }
+    buf.push(']');
+    buf
+}
+
+pub(crate) fn number(value: f64) -> String {
+    let mut buf = String::new();
+    number_into(&mut buf, value);
+    buf
+}
+
+fn number_into(buf: &mut String, value: f64) {
+    if value.is_finite() {
+        let _ = write!(buf, "{value}");
+    } else {
+        buf.push_str("null");
+    }
+}
+
+pub(crate) fn string(value: &str) -> String {
+    let mut buf = String::new();
+    string_into(&mut buf, value);
+    buf
+}
+
+fn string_into(buf: &mut String, value: &str) {
+    buf.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+/// Deepest nesting [`parse`] accepts, so hostile input can't overflow the
+/// stack.
+const MAX_DEPTH: usize = 32;
+
+/// A parsed value. Numbers are kept as `f64`, exact for counters below
+/// 2^53.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Members in document order
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Serialize again, e.g. to pass a parsed value on.
+    pub fn to_json(&self) -> String {
+        match self {
+            Value::Null => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => number(*n),
+            Value::String(s) => string(s),
+            Value::Array(items) => array(items.iter().map(Value::to_json)),
+            Value::Object(members) => members
+                .iter()
+                .fold(Object::new(), |object, (k, v)| object.raw(k, &v.to_json()))
+                .finish(),
+        }
+    }
+}
+
+/// Parse a complete JSON document.
+pub(crate) fn parse(input: &str) -> eyre::Result<Value> {
+    let mut parser = Parser {
+        input: input.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    eyre::ensure!(
+        parser.pos == parser.input.len(),
+        "trailing characters at offset {}",
+        parser.pos
+    );
+    Ok(value)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.input.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> eyre::Result<()> {
+        eyre::ensure!(
+            self.peek() == Some(byte),
+            "expected `{}` at offset {}",
+            byte as char,
+            self.pos
+        );
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> eyre::Result<Value> {
+        eyre::ensure!(
+            self.input[self.pos..].starts_with(word.as_bytes()),
+            "unexpected character at offset {}",
+            self.pos
+        );
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> eyre::Result<Value> {
+        eyre::ensure!(depth < MAX_DEPTH, "nested too deeply");
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value(depth + 1)?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Value::Object(members))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Value::Array(items))
+            }
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') =
+                    self.input.get(self.pos)
+                {
+                    self.pos += 1;
+                }
+                let text = std::str::from_utf8(&self.input[start..self.pos])?;
+                let number = text
+                    .parse()
+                    .map_err(|_| eyre::eyre!("bad number {text:?} at offset {start}"))?;
+                Ok(Value::Number(number))
+            }
+            Some(_) => eyre::bail!("unexpected character at offset {}", self.pos),
+            None => eyre::bail!("unexpected end of input"),
+        }
+    }
+
+    fn string(&mut self) -> eyre::Result<String> {
+        self.expect(b'"')?;
+        let mut buf = Vec::new();
+        loop {
+            let Some(&byte) = self.input.get(self.pos) else {
+                eyre::bail!("unterminated string");
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.input.get(self.pos) else {
+                        eyre::bail!("unterminated string");
+                    };
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => eyre::bail!("bad escape at offset {}", self.pos - 1),
+                    };
+                    buf.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                _ => buf.push(byte),
+            }
+        }
+        Ok(String::from_utf8(buf)?)
+    }
+
+    /// The character of a `\uXXXX` escape, after the `u`, combining
+    /// surrogate pairs.
+    fn unicode_escape(&mut self) -> eyre::Result<char> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            eyre::ensure!(
+                self.input[self.pos..].starts_with(b"\\u"),
+                "unpaired surrogate at offset {}",
+                self.pos
+            );
+            self.pos += 2;
+            let low = self.hex4()?;
+            eyre::ensure!(
+                (0xdc00..0xe000).contains(&low),
+                "unpaired surrogate at offset {}",
+                self.pos
+            );
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| eyre::eyre!("bad escape at offset {}", self.pos))
+    }
+
+    fn hex4(&mut self) -> eyre::Result<u32> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| eyre::eyre!("bad escape at offset {}", self.pos))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let json = Object::new()
+            .u64("sent", 1234)
+            .f64("loss", 0.5)
+            .str("name", "a \"quoted\"\n\u{1}name")
+            .raw("list", &array(["1".to_string(), "null".to_string()]))
+            .raw("nested", &Object::new().raw("ok", "true").finish())
+            .finish();
+        let value = parse(&json).unwrap();
+        assert_eq!(value.get("sent").and_then(Value::as_f64), Some(1234.0));
+        assert_eq!(value.get("loss").and_then(Value::as_f64), Some(0.5));
+        assert_eq!(
+            value.get("name").and_then(Value::as_str),
+            Some("a \"quoted\"\n\u{1}name")
+        );
+        assert_eq!(
+            value.get("list"),
+            Some(&Value::Array(vec![Value::Number(1.0), Value::Null]))
+        );
+        assert_eq!(value.to_json(), json);
+    }
+
+    #[test]
+    fn escapes_and_whitespace() {
+        let value = parse(" { \"a\\/b\" : \"\\u00e9\\ud83d\\ude00\" } ").unwrap();
+        assert_eq!(
+            value.get("a/b").and_then(Value::as_str),
+            Some("\u{e9}\u{1f600}")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for input in [
+            "",
+            "{",
+            "{\"a\":}",
+            "[1,]",
+            "[1 2]",
+            "\"unterminated",
+            "tru",
+            "{} {}",
+            "\"\\ud800\"",
+            &"[".repeat(MAX_DEPTH + 1),
+        ] {
+            assert!(parse(input).is_err(), "{input:?}");
+        }
+    }
+}
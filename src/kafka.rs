@@ -0,0 +1,563 @@
+//! Minimal Kafka producer publishing a client's per-interval stats and its
+//! events to a topic, for feeding them into streaming pipelines. It speaks
+//! just enough of the protocol for that: Metadata v1 to find the leader of
+//! the partition messages go to, and Produce v3 with uncompressed v2 record
+//! batches, which brokers from 0.11 on accept. Plaintext only, without TLS
+//! or SASL.
+//!
+//! Messages are JSON objects keyed by the probed host, so all of one
+//! client's go to the same partition in order:
+//!
+//! ```text
+//! {"kind": "stats", "host": HOST, "labels": {KEY: VALUE}, "unix_secs": SECS,
+//!  "stats": STATS}
+//! {"kind": "event", "host": HOST, "labels": {KEY: VALUE}, "unix_secs": SECS,
+//!  "event": EVENT}
+//! ```
+//!
+//! with `STATS` and `EVENT` as serialized by [`Stats::to_json`] and
+//! [`Event::to_json`]. They are queued and published from a thread of
+//! their own; while no broker is reachable the newest [`MAX_QUEUED`] wait.
+
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    client::labels_json,
+    hash::{crc32, crc32c},
+    json, Event, Stats, StopHandle,
+};
+
+/// Messages kept while the broker is unreachable
+pub const MAX_QUEUED: usize = 10_000;
+/// Most bytes of messages sent in one batch, well below the 1 MB brokers
+/// accept by default
+const MAX_BATCH_BYTES: usize = 256 << 10;
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait before the first retry after a failure, doubling with every
+/// further one
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Largest response accepted
+const MAX_RESPONSE: usize = 1 << 20;
+
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+const CLIENT_ID: &str = "loss_lens";
+/// Only the partition leader acknowledges, so a slow follower doesn't hold
+/// up telemetry
+const ACKS_LEADER: i16 = 1;
+const RECORD_BATCH_MAGIC: i8 = 2;
+/// Error code brokers answer with while a topic is being created
+const LEADER_NOT_AVAILABLE: i16 = 5;
+
+/// A message's timestamp in milliseconds since the epoch, and its value
+type Message = (i64, Vec<u8>);
+
+/// Where to publish to, and what to send along.
+#[derive(Clone, Debug)]
+pub struct KafkaConfig {
+    /// Brokers to bootstrap from, `HOST:PORT` each; the first reachable
+    /// one tells where the partition leader is
+    pub brokers: Vec<String>,
+    pub topic: String,
+    /// Host being probed, which messages are keyed by
+    pub host: String,
+    /// See [`ClientConfig::labels`](crate::ClientConfig::labels)
+    pub labels: Vec<(String, String)>,
+}
+
+/// Publishes stats and events to Kafka. Cloning shares the queue and the
+/// thread, so clones can be moved into the stats and event callbacks.
+#[derive(Clone)]
+pub struct KafkaSink {
+    queue: Arc<Mutex<VecDeque<Message>>>,
+    host: String,
+    labels: String,
+    stop: StopHandle,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl KafkaSink {
+    /// Find the partition leader, failing if no broker can tell, and start
+    /// publishing in the background.
+    pub fn start(config: KafkaConfig) -> eyre::Result<Self> {
+        eyre::ensure!(!config.brokers.is_empty(), "no Kafka brokers given");
+        eyre::ensure!(!config.topic.is_empty(), "the Kafka topic must be named");
+        let mut producer = Producer {
+            brokers: config.brokers,
+            topic: config.topic,
+            key: config.host.clone().into_bytes(),
+            leader: None,
+        };
+        producer.connect()?;
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let stop = StopHandle::default();
+        let thread = thread::spawn({
+            let queue = Arc::clone(&queue);
+            let stop = stop.clone();
+            move || producer.run(&queue, &stop)
+        });
+        Ok(Self {
+            queue,
+            host: config.host,
+            labels: labels_json(&config.labels),
+            stop,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        })
+    }
+
+    pub fn stats(&self, stats: &Stats) {
+        self.push("stats", &stats.to_json());
+    }
+
+    pub fn event(&self, event: &Event) {
+        self.push("event", &event.to_json());
+    }
+
+    fn push(&self, kind: &str, json: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let message = json::Object::new()
+            .str("kind", kind)
+            .str("host", &self.host)
+            .raw("labels", &self.labels)
+            .f64("unix_secs", now.as_secs_f64())
+            .raw(kind, json)
+            .finish();
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back((now.as_millis() as i64, message.into_bytes()));
+        while queue.len() > MAX_QUEUED {
+            queue.pop_front();
+        }
+    }
+
+    /// Publish what's still queued, trying once more if need be, and stop.
+    pub fn finish(&self) -> eyre::Result<()> {
+        self.stop.stop();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().unwrap();
+        }
+        let left = self.queue.lock().unwrap().len();
+        eyre::ensure!(left == 0, "{left} messages couldn't be published to Kafka");
+        Ok(())
+    }
+}
+
+/// Publishing side, owned by the background thread.
+struct Producer {
+    brokers: Vec<String>,
+    topic: String,
+    key: Vec<u8>,
+    /// Connection to the leader of the partition, and its index
+    leader: Option<(Connection, i32)>,
+}
+
+impl Producer {
+    /// Publish queued messages until stopped, then once more.
+    fn run(mut self, queue: &Mutex<VecDeque<Message>>, stop: &StopHandle) {
+        let mut failing = false;
+        let mut backoff = FIRST_BACKOFF;
+        let mut retry_at = Instant::now();
+        loop {
+            let stopping = stop.is_stopped();
+            if !stopping {
+                thread::sleep(Duration::from_millis(100));
+            }
+            if stopping || Instant::now() >= retry_at {
+                let batch: Vec<_> = {
+                    let queue = queue.lock().unwrap();
+                    let mut bytes = 0;
+                    // at least one, however large
+                    queue
+                        .iter()
+                        .enumerate()
+                        .take_while(|(i, (_, message))| {
+                            bytes += message.len();
+                            *i == 0 || bytes <= MAX_BATCH_BYTES
+                        })
+                        .map(|(_, message)| message.clone())
+                        .collect()
+                };
+                if !batch.is_empty() {
+                    match self.produce(&batch) {
+                        Ok(()) => {
+                            // only the sender removes from the front, so
+                            // these are still the ones sent unless the
+                            // queue overflowed meanwhile
+                            let mut queue = queue.lock().unwrap();
+                            let sent = queue
+                                .iter()
+                                .zip(&batch)
+                                .take_while(|(queued, sent)| queued == sent)
+                                .count();
+                            queue.drain(..sent);
+                            backoff = FIRST_BACKOFF;
+                            if failing {
+                                failing = false;
+                                crate::log::info!("Publishing to Kafka works again");
+                            }
+                        }
+                        Err(e) => {
+                            self.leader = None;
+                            retry_at = Instant::now() + backoff;
+                            backoff = (backoff * 2).min(MAX_BACKOFF);
+                            // once per outage rather than every retry
+                            if !failing {
+                                failing = true;
+                                crate::log::warn!("Publishing to Kafka failed: {e}");
+                            }
+                        }
+                    }
+                }
+            }
+            if stopping {
+                return;
+            }
+        }
+    }
+
+    fn produce(&mut self, messages: &[Message]) -> eyre::Result<()> {
+        if self.leader.is_none() {
+            self.connect()?;
+        }
+        let (connection, partition) = self.leader.as_mut().unwrap();
+        let batch = record_batch(&self.key, messages);
+        let mut body = Vec::new();
+        put_i16(&mut body, -1); // no transactional id
+        put_i16(&mut body, ACKS_LEADER);
+        put_i32(&mut body, TIMEOUT.as_millis() as i32);
+        put_i32(&mut body, 1);
+        put_string(&mut body, &self.topic);
+        put_i32(&mut body, 1);
+        put_i32(&mut body, *partition);
+        put_i32(&mut body, batch.len() as i32);
+        body.extend_from_slice(&batch);
+        let response = connection.request(API_PRODUCE, 3, &body)?;
+        let mut reader = Reader::new(&response);
+        for _ in 0..reader.i32()? {
+            reader.string()?;
+            for _ in 0..reader.i32()? {
+                let index = reader.i32()?;
+                let error = reader.i16()?;
+                reader.i64()?; // base offset
+                reader.i64()?; // log append time
+                eyre::ensure!(
+                    error == 0,
+                    "partition {index} of {} refused messages with error {error}",
+                    self.topic
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the first reachable broker for the leader of the partition the
+    /// key maps to, and connect to it.
+    fn connect(&mut self) -> eyre::Result<()> {
+        let mut last_error = None;
+        for broker in &self.brokers {
+            match self.find_leader(broker) {
+                Ok((addr, partition)) => {
+                    self.leader = Some((Connection::open(&addr)?, partition));
+                    return Ok(());
+                }
+                Err(e) => {
+                    last_error =
+                        Some(e.wrap_err(format!("asking Kafka broker {broker} for the leader")))
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// Address of the leader of the key's partition, and its index.
+    fn find_leader(&self, broker: &str) -> eyre::Result<(String, i32)> {
+        let mut connection = Connection::open(broker)?;
+        let mut body = Vec::new();
+        put_i32(&mut body, 1);
+        put_string(&mut body, &self.topic);
+        let response = connection.request(API_METADATA, 1, &body)?;
+        let mut reader = Reader::new(&response);
+        let mut brokers = Vec::new();
+        for _ in 0..reader.i32()? {
+            let node_id = reader.i32()?;
+            let host = reader.string()?;
+            let port = reader.i32()?;
+            reader.nullable_string()?; // rack
+            brokers.push((node_id, host, port));
+        }
+        reader.i32()?; // controller id
+        let mut partitions = Vec::new();
+        for _ in 0..reader.i32()? {
+            let error = reader.i16()?;
+            let name = reader.string()?;
+            reader.i8()?; // internal
+            eyre::ensure!(
+                error != LEADER_NOT_AVAILABLE,
+                "topic {name} is still being created"
+            );
+            eyre::ensure!(error == 0, "topic {name} is unavailable with error {error}");
+            for _ in 0..reader.i32()? {
+                reader.i16()?; // error of the partition, shown by its leader
+                let index = reader.i32()?;
+                let leader = reader.i32()?;
+                for _ in 0..2 {
+                    // replicas and in-sync replicas
+                    for _ in 0..reader.i32()? {
+                        reader.i32()?;
+                    }
+                }
+                partitions.push((index, leader));
+            }
+        }
+        eyre::ensure!(
+            !partitions.is_empty(),
+            "topic {} has no partitions",
+            self.topic
+        );
+        partitions.sort_unstable();
+        let (index, leader) = partitions[crc32(&self.key) as usize % partitions.len()];
+        let (_, host, port) = brokers
+            .iter()
+            .find(|(node_id, ..)| *node_id == leader)
+            .ok_or_else(|| eyre::eyre!("partition {index} of {} has no leader", self.topic))?;
+        let addr = if host.contains(':') {
+            format!("[{host}]:{port}")
+        } else {
+            format!("{host}:{port}")
+        };
+        Ok((addr, index))
+    }
+}
+
+/// A connection to one broker, answering requests in order.
+struct Connection {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl Connection {
+    fn open(addr: &str) -> eyre::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| eyre::eyre!("{addr} did not resolve to any address"))?;
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT * 2))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Self {
+            stream,
+            correlation_id: 0,
+        })
+    }
+
+    /// Send a request with header v1 and return the response after its
+    /// header.
+    fn request(&mut self, api_key: i16, version: i16, body: &[u8]) -> eyre::Result<Vec<u8>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut request = Vec::new();
+        put_i32(&mut request, 0); // size, filled in below
+        put_i16(&mut request, api_key);
+        put_i16(&mut request, version);
+        put_i32(&mut request, self.correlation_id);
+        put_string(&mut request, CLIENT_ID);
+        request.extend_from_slice(body);
+        let size = (request.len() - 4) as i32;
+        request[..4].copy_from_slice(&size.to_be_bytes());
+        self.stream.write_all(&request)?;
+
+        let mut size = [0; 4];
+        self.stream.read_exact(&mut size)?;
+        let size = i32::from_be_bytes(size);
+        eyre::ensure!(
+            (4..=MAX_RESPONSE as i32).contains(&size),
+            "response of bad size {size}"
+        );
+        let mut response = vec![0; size as usize];
+        self.stream.read_exact(&mut response)?;
+        let correlation_id = i32::from_be_bytes(response[..4].try_into().unwrap());
+        eyre::ensure!(
+            correlation_id == self.correlation_id,
+            "response to request {correlation_id}, expected {}",
+            self.correlation_id
+        );
+        response.drain(..4);
+        Ok(response)
+    }
+}
+
+/// Uncompressed v2 record batch of `messages`, `(timestamp_ms, value)`
+/// each, all with `key`, at offsets the broker assigns.
+fn record_batch(key: &[u8], messages: &[Message]) -> Vec<u8> {
+    let base_timestamp = messages.first().map_or(0, |(at, _)| *at);
+    let max_timestamp = messages.iter().map(|(at, _)| *at).max().unwrap_or(0);
+    // everything the CRC covers, from the attributes on
+    let mut covered = Vec::new();
+    put_i16(&mut covered, 0); // attributes: no compression, create time
+    put_i32(&mut covered, messages.len() as i32 - 1); // last offset delta
+    put_i64(&mut covered, base_timestamp);
+    put_i64(&mut covered, max_timestamp);
+    put_i64(&mut covered, -1); // no producer id
+    put_i16(&mut covered, -1); // producer epoch
+    put_i32(&mut covered, -1); // base sequence
+    put_i32(&mut covered, messages.len() as i32);
+    for (offset_delta, (at, value)) in messages.iter().enumerate() {
+        let mut record = vec![0]; // attributes
+        put_varint(&mut record, at - base_timestamp);
+        put_varint(&mut record, offset_delta as i64);
+        put_varint(&mut record, key.len() as i64);
+        record.extend_from_slice(key);
+        put_varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value);
+        put_varint(&mut record, 0); // headers
+        put_varint(&mut covered, record.len() as i64);
+        covered.extend_from_slice(&record);
+    }
+    let mut batch = Vec::new();
+    put_i64(&mut batch, 0); // base offset
+                            // length of the partition leader epoch, magic and CRC, then what the CRC
+                            // covers
+    put_i32(&mut batch, (4 + 1 + 4 + covered.len()) as i32);
+    put_i32(&mut batch, -1); // partition leader epoch
+    batch.push(RECORD_BATCH_MAGIC as u8);
+    batch.extend_from_slice(&crc32c(&covered).to_be_bytes());
+    batch.extend_from_slice(&covered);
+    batch
+}
+
+fn put_i16(buf: &mut Vec<u8>, value: i16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i32(buf: &mut Vec<u8>, value: i32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i64(buf: &mut Vec<u8>, value: i64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    put_i16(buf, value.len() as i16);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Zigzag-encoded variable-length integer, as in protobuf.
+fn put_varint(buf: &mut Vec<u8>, value: i64) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag >= 0x80 {
+        buf.push(zigzag as u8 | 0x80);
+        zigzag >>= 7;
+    }
+    buf.push(zigzag as u8);
+}
+
+/// Reads big-endian fields of a response, failing where it's cut short.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> eyre::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| eyre::eyre!("response cut short"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn i8(&mut self) -> eyre::Result<i8> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> eyre::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> eyre::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> eyre::Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn nullable_string(&mut self) -> eyre::Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let bytes = self.take(len as usize)?;
+        Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+    }
+
+    fn string(&mut self) -> eyre::Result<String> {
+        self.nullable_string()?
+            .ok_or_else(|| eyre::eyre!("null where a string was expected"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints() {
+        for (value, encoded) in [
+            (0, &[0x00][..]),
+            (-1, &[0x01]),
+            (1, &[0x02]),
+            (63, &[0x7e]),
+            (-64, &[0x7f]),
+            (64, &[0x80, 0x01]),
+            (300, &[0xd8, 0x04]),
+        ] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            assert_eq!(buf, encoded, "{value}");
+        }
+    }
+
+    #[test]
+    fn record_batch_layout() {
+        let messages = [(1_000, b"{}".to_vec()), (1_250, b"{\"a\":1}".to_vec())];
+        let batch = record_batch(b"host", &messages);
+        let mut reader = Reader::new(&batch);
+        assert_eq!(reader.i64().unwrap(), 0);
+        let length = reader.i32().unwrap();
+        assert_eq!(length as usize, batch.len() - 12);
+        assert_eq!(reader.i32().unwrap(), -1);
+        assert_eq!(reader.i8().unwrap(), RECORD_BATCH_MAGIC);
+        let crc = reader.i32().unwrap() as u32;
+        assert_eq!(crc, crc32c(&batch[21..]));
+        assert_eq!(reader.i16().unwrap(), 0);
+        assert_eq!(reader.i32().unwrap(), 1); // last offset delta
+        assert_eq!(reader.i64().unwrap(), 1_000);
+        assert_eq!(reader.i64().unwrap(), 1_250);
+        reader.take(8 + 2 + 4).unwrap();
+        assert_eq!(reader.i32().unwrap(), 2);
+        // the second record: length, attributes, timestamp delta 250,
+        // offset delta 1, then key and value
+        let second = &batch[reader.pos + 1 + 12..];
+        assert_eq!(second[0], 2 * 18);
+        assert_eq!(&second[1..4], &[0, 0xf4, 0x03]);
+        assert_eq!(second[4], 2);
+        assert_eq!(&second[5..10], b"\x08host");
+        assert_eq!(&second[10..18], b"\x0e{\"a\":1}");
+        assert_eq!(second[18], 0);
+    }
+}
@@ -0,0 +1,304 @@
+//! Loss Lens: measure UDP packet loss in both directions between a probe
+//! client and a reflecting server.
+//!
+//! The client sends sequenced probes at a fixed rate, the server acknowledges
+//! each one together with its own receive counter, and the client compares
+//! what it sent, what the server saw, and what came back.
+
+mod admin;
+pub mod analyze;
+pub mod auth;
+mod batch;
+mod capacity;
+pub mod check;
+pub mod cidr;
+pub mod client;
+pub mod collector;
+pub mod compare;
+#[cfg(unix)]
+pub mod control;
+mod crypto;
+mod ecn;
+pub mod event;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod flent;
+pub mod flood;
+mod gso;
+mod hash;
+#[cfg(target_os = "linux")]
+mod hops;
+mod http;
+pub mod icmp;
+mod json;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod log;
+pub mod mdns;
+pub mod mtu;
+pub mod multicast;
+pub mod nat_timeout;
+pub mod ndjson;
+pub mod noise;
+pub mod periodicity;
+pub mod portmap;
+pub mod protocol;
+mod ratelimit;
+mod recording;
+pub mod rendezvous;
+mod replay;
+pub mod report;
+mod resume;
+pub mod scenario;
+pub mod server;
+pub mod session;
+mod session_log;
+pub mod smokeping;
+mod snapshots;
+pub mod stats;
+pub mod stun;
+#[cfg(unix)]
+pub mod systemd;
+pub mod tcp;
+mod template;
+mod toml;
+pub mod twamp;
+pub mod upload;
+pub mod web;
+mod websocket;
+pub mod zabbix;
+mod zstd;
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use eyre::OptionExt;
+
+pub use auth::Psk;
+pub use cidr::Cidr;
+pub use client::{
+    AdaptiveRate, ClientConfig, ClientControl, ClientHandle, DutyCycle, HopTrace, PacketTrains,
+    PortHopping, ProbeClient, Protocol,
+};
+pub use collector::{Collector, CollectorConfig, Reporter, ReporterConfig};
+pub use compare::Comparison;
+pub use event::Event;
+pub use flood::{FloodConfig, FloodStats, FloodTest};
+#[cfg(feature = "kafka")]
+pub use kafka::{KafkaConfig, KafkaSink};
+pub use mtu::{MtuConfig, MtuStats, MtuTest};
+pub use multicast::{MulticastConfig, MulticastReceiver, MulticastStats};
+pub use nat_timeout::{IdleResult, NatTimeoutConfig, NatTimeoutStats, NatTimeoutTest};
+pub use ndjson::NdjsonWriter;
+pub use periodicity::LossPeriod;
+pub use portmap::PortMapping;
+pub use rendezvous::{Peer, RendezvousConfig, Role};
+pub use server::{
+    AdminConfig, FloodLimits, ProbeServer, ServerConfig, ServerHandle, ServerLimits, ServerSummary,
+};
+pub use session::Session;
+pub use smokeping::{SmokepingConfig, SmokepingSink};
+pub use stats::{
+    EcnStats, Ewma, Histogram, HopStats, Outage, PathStats, RecentLoss, SizeStats, Stats,
+    SymmetricStats,
+};
+pub use stun::{Mapping, NatReport};
+pub use upload::{UploadConfig, Uploader};
+pub use web::Dashboard;
+pub use zabbix::{ZabbixConfig, ZabbixSender};
+
+/// Address family to restrict name resolution to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    pub fn of(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => IpVersion::V4,
+            SocketAddr::V6(_) => IpVersion::V6,
+        }
+    }
+}
+
+impl std::fmt::Display for IpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IpVersion::V4 => "IPv4",
+            IpVersion::V6 => "IPv6",
+        })
+    }
+}
+
+/// First address `host` resolves to, of the given version if any.
+pub(crate) fn resolve(host: &str, version: Option<IpVersion>) -> eyre::Result<SocketAddr> {
+    let mut addrs = host.to_socket_addrs()?;
+    match version {
+        None => addrs
+            .next()
+            .ok_or_eyre("host did not resolve to any address"),
+        Some(version) => addrs
+            .find(|&addr| IpVersion::of(addr) == version)
+            .ok_or_else(|| eyre::eyre!("host did not resolve to any {version} address")),
+    }
+}
+
+/// Local end of the sockets probes are sent from.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LocalBind {
+    pub source_addr: Option<IpAddr>,
+    /// Network interface to send through regardless of the routing table
+    pub interface: Option<String>,
+}
+
+impl LocalBind {
+    /// `version`, or else the source address' version, so the target is
+    /// resolved to an address the socket can reach.
+    pub fn ip_version(&self, version: Option<IpVersion>) -> Option<IpVersion> {
+        version.or(self
+            .source_addr
+            .map(|addr| IpVersion::of(SocketAddr::new(addr, 0))))
+    }
+
+    /// Socket on an ephemeral port for sending to `addr`, of the same family
+    /// so it works without dual-stack support.
+    pub fn socket_for(&self, addr: SocketAddr) -> eyre::Result<UdpSocket> {
+        let source = match (self.source_addr, addr) {
+            (None, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (None, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            (Some(source), _) => {
+                eyre::ensure!(
+                    source.is_ipv4() == addr.is_ipv4(),
+                    "source address {source} can't reach {addr}"
+                );
+                source
+            }
+        };
+        let socket = UdpSocket::bind((source, 0))?;
+        if let Some(interface) = &self.interface {
+            bind_to_device(&socket, interface)
+                .map_err(|e| eyre::eyre!("binding to interface {interface}: {e}"))?;
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr().cast(),
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if rv == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &UdpSocket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "only supported on Linux",
+    ))
+}
+
+/// Cheap handle for asking a running client or server to stop, e.g. from a
+/// signal handler.
+#[derive(Clone, Default)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Minimum sizes; longer packets carry padding, see MAX_PACKET_SIZE
+pub(crate) const CLIENT_TO_SERVER_PACKET_SIZE: usize = 1 + 4 + 4;
+pub(crate) const SERVER_TO_CLIENT_PACKET_SIZE: usize = 1 + 4 + 4;
+/// Largest UDP payload over IPv4. Probes of at least `1 + 4 + 4 + 2` bytes
+/// carry the requested ACK size after the client id, followed by padding.
+pub const MAX_PACKET_SIZE: usize = 65_507;
+pub(crate) const BUF_SIZE: usize = MAX_PACKET_SIZE;
+
+/// Announces the client's probe rate: `[HELLO, packets_per_second, client_id]`,
+/// or in its longer form opens a session, see `session`
+pub(crate) const HELLO_PACKET_CONST: u8 = 1;
+pub(crate) const SEQ_NUM_PACKET_CONST: u8 = 2;
+pub(crate) const ACK_PACKET_CONST: u8 = 3;
+/// ACK for a probe that arrived ECN-capable, with the ECN field it arrived
+/// with appended: `[ACK_ECN, seq, received, ecn]`
+pub(crate) const ACK_ECN_PACKET_CONST: u8 = 8;
+/// TTL-limited probe for tracing hops: `[HOP, round, client_id]`, see `hops`
+pub(crate) const HOP_PACKET_CONST: u8 = 9;
+/// The server's answer to a hop probe that made it all the way, the probe
+/// echoed back with this kind
+pub(crate) const HOP_REPLY_PACKET_CONST: u8 = 10;
+/// Packet the server streams to a multicast group, see `multicast`
+pub(crate) const MULTICAST_PACKET_CONST: u8 = 11;
+/// The server's answer to a session handshake, see `session`
+pub(crate) const WELCOME_PACKET_CONST: u8 = 12;
+/// The server's answer to a packet of a session it doesn't know, e.g. after
+/// a restart: `[UNKNOWN_SESSION, 0, client_id]`, asking for a new handshake
+pub(crate) const UNKNOWN_SESSION_PACKET_CONST: u8 = 13;
+/// The cookie a server requiring them answers handshakes with, see `session`
+pub(crate) const COOKIE_PACKET_CONST: u8 = 14;
+/// Encrypted sessions: the handshake, its answer and packets within them,
+/// see `noise`
+pub(crate) const NOISE_INIT_PACKET_CONST: u8 = 15;
+pub(crate) const NOISE_RESP_PACKET_CONST: u8 = 16;
+pub(crate) const NOISE_DATA_PACKET_CONST: u8 = 17;
+/// Probes the server sends on its own in symmetric sessions, `[SERVER_PROBE,
+/// seq, client_id, sent_micros]`, and the client's answers to them,
+/// `[SERVER_PROBE_ACK, seq, client_id, received]`, see
+/// [`session::FEATURE_SYMMETRIC`]
+pub(crate) const SERVER_PROBE_PACKET_CONST: u8 = 18;
+pub(crate) const SERVER_PROBE_ACK_PACKET_CONST: u8 = 19;
+/// Packet kinds fit in the low bits of the first byte. From protocol
+/// version 2 on its top bits carry the sender's version, which is 0 in
+/// packets from older peers or sent without a session
+pub(crate) const KIND_MASK: u8 = 0x1f;
+
+/// Kind and protocol version of a packet from its first byte.
+pub(crate) fn split_kind(byte: u8) -> (u8, u8) {
+    (byte & KIND_MASK, byte >> 5)
+}
+
+/// First byte of a packet of `kind`, marked with `version` where it's one
+/// that marks packets.
+pub(crate) fn with_version(kind: u8, version: u8) -> u8 {
+    if version >= 2 {
+        kind | version << 5
+    } else {
+        kind
+    }
+}
+
+// 4 and 5 are used by the downstream flood test, see `flood`, 6 and 7 by
+// packet trains, see `capacity`, 20 to 24 by peer-to-peer measurement, see
+// `rendezvous`, and 25 and 26 by NAT timeout tests, see `nat_timeout`
+
+// How long a probe may be late before it counts as lost
+pub(crate) const LATE_WINDOW_SECS: usize = 3;
+// Number of probes sent per second unless configured otherwise
+pub(crate) const DEFAULT_PACKETS_PER_SECOND: u32 = 67;
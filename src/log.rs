@@ -0,0 +1,132 @@
+//! Diagnostics on stderr, apart from the stats on stdout: plain lines for
+//! people, or JSON lines of `{"unix_secs", "level", "message"}` for log
+//! shippers. Messages below the level set with [`init`] are dropped.
+
+use std::{
+    fmt,
+    io::Write,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::json;
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// How much a message matters, most first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// The level `-v` and `-q` given that many times select, from
+    /// [`Level::Info`].
+    pub fn from_verbosity(verbose: u8, quiet: u8) -> Self {
+        match (2 + i16::from(verbose) - i16::from(quiet)).clamp(0, 3) {
+            0 => Self::Error,
+            1 => Self::Warn,
+            2 => Self::Info,
+            _ => Self::Debug,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+/// Log messages of `level` and above, as JSON lines if `json`.
+pub fn init(level: Level, json: bool) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Write a message of `level`; use the macros instead.
+#[doc(hidden)]
+pub fn write(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let unix_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let line = format(
+        level,
+        &args.to_string(),
+        JSON.load(Ordering::Relaxed),
+        unix_secs,
+    );
+    // a whole line at once, so threads' messages don't interleave
+    let _ = std::io::stderr().lock().write_all(line.as_bytes());
+}
+
+fn format(level: Level, message: &str, json: bool, unix_secs: f64) -> String {
+    if json {
+        let object = json::Object::new()
+            .f64("unix_secs", unix_secs)
+            .str("level", level.name())
+            .str("message", message)
+            .finish();
+        return format!("{object}\n");
+    }
+    match level {
+        Level::Error => format!("error: {message}\n"),
+        Level::Warn => format!("warning: {message}\n"),
+        Level::Info | Level::Debug => format!("{message}\n"),
+    }
+}
+
+macro_rules! warn_ {
+    ($($arg:tt)+) => {
+        $crate::log::write($crate::log::Level::Warn, format_args!($($arg)+))
+    };
+}
+// renamed for the `warn` lint attribute it would clash with
+pub(crate) use warn_ as warn;
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log::write($crate::log::Level::Info, format_args!($($arg)+))
+    };
+}
+pub(crate) use info;
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log::write($crate::log::Level::Debug, format_args!($($arg)+))
+    };
+}
+pub(crate) use debug;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_selects_level_and_json_escapes_message() {
+        assert_eq!(Level::from_verbosity(0, 0), Level::Info);
+        assert_eq!(Level::from_verbosity(3, 0), Level::Debug);
+        assert_eq!(Level::from_verbosity(0, 1), Level::Warn);
+        assert_eq!(Level::from_verbosity(1, 5), Level::Error);
+        assert_eq!(
+            format(Level::Warn, "lost \"it\"", true, 1.5),
+            "{\"unix_secs\":1.5,\"level\":\"warn\",\"message\":\"lost \\\"it\\\"\"}\n"
+        );
+        assert_eq!(format(Level::Warn, "lost", false, 1.5), "warning: lost\n");
+    }
+}
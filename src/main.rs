@@ -1,19 +1,79 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    fs::File,
-    io::{ErrorKind, Write},
-    net::{Ipv6Addr, SocketAddr, ToSocketAddrs},
-    process::{Command, Stdio},
-    sync::{
-        atomic::{AtomicBool, AtomicU32, Ordering},
-        Arc,
-    },
+    io::{self, IsTerminal, Write},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
 };
 
 use clap::Parser;
+use loss_lens::{
+    check::{Check, Thresholds},
+    compare,
+    noise::Keypair,
+    protocol::CHECKSUM_PROBE_MIN_SIZE,
+    rendezvous,
+    scenario::Scenario,
+    AdaptiveRate, AdminConfig, ClientConfig, Collector, CollectorConfig, Comparison, Dashboard,
+    DutyCycle, Event, FloodConfig, FloodLimits, FloodTest, HopTrace, IpVersion, MtuConfig, MtuTest,
+    MulticastConfig, MulticastReceiver, NatTimeoutConfig, NatTimeoutTest, NdjsonWriter,
+    PacketTrains, PortHopping, ProbeClient, ProbeServer, Protocol, Psk, RendezvousConfig, Reporter,
+    ReporterConfig, Role, ServerConfig, ServerLimits, SmokepingConfig, SmokepingSink, Stats,
+    StopHandle, UploadConfig, Uploader, ZabbixConfig, ZabbixSender,
+};
+
+// ahead of the modules, which log through them too
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        loss_lens::log::write(loss_lens::log::Level::Warn, format_args!($($arg)+))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        loss_lens::log::write(loss_lens::log::Level::Info, format_args!($($arg)+))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        loss_lens::log::write(loss_lens::log::Level::Debug, format_args!($($arg)+))
+    };
+}
+
+#[cfg(unix)]
+mod daemon;
+#[cfg(windows)]
+mod service;
+#[cfg(unix)]
+mod signals;
+mod tui;
 
 mod args {
+    use std::{
+        net::{IpAddr, SocketAddr},
+        path::PathBuf,
+        time::Duration,
+    };
+
     use clap::{Parser, Subcommand};
+    use loss_lens::{scenario::parse_duration, Cidr};
+
+    // Running in the background from init scripts; a doc comment here would
+    // become the help text of every subcommand flattening it
+    #[derive(clap::Args)]
+    pub struct Daemon {
+        /// Detach from the terminal and keep running in the background
+        #[arg(long)]
+        pub daemon: bool,
+        /// Write the daemon's process id here, removed again on clean shutdown
+        #[arg(long, value_name = "PATH", requires = "daemon")]
+        pub pid_file: Option<PathBuf>,
+        /// Append output to this file instead of discarding it
+        #[arg(long, value_name = "PATH", requires = "daemon")]
+        pub log_file: Option<PathBuf>,
+    }
 
     /// Loss Lens
     #[derive(Parser)]
@@ -21,238 +81,1996 @@ mod args {
     pub struct Args {
         #[command(subcommand)]
         pub command: Commands,
+        /// Log more diagnostics to stderr, down to debug messages
+        #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+        pub verbose: u8,
+        /// Log fewer diagnostics: only warnings, or given twice only errors
+        #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+        pub quiet: u8,
+        /// Log diagnostics as JSON lines, keeping stdout for the stats
+        #[arg(long, global = true)]
+        pub log_json: bool,
+    }
+
+    fn parse_mix_entry(entry: &str) -> Result<(usize, u32), String> {
+        let (size, weight) = entry.split_once(':').unwrap_or((entry, "1"));
+        let size = size
+            .parse()
+            .map_err(|e| format!("bad size {size:?}: {e}"))?;
+        let weight = weight
+            .parse()
+            .map_err(|e| format!("bad weight {weight:?}: {e}"))?;
+        Ok((size, weight))
+    }
+
+    fn parse_label(entry: &str) -> Result<(String, String), String> {
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, got {entry:?}"))?;
+        Ok((key.to_string(), value.to_string()))
     }
 
+    // parsed once, so the size of the client's many flags doesn't matter
+    #[allow(clippy::large_enum_variant)]
     #[derive(Subcommand)]
     pub enum Commands {
+        /// Send a command to a running client's control socket
+        #[cfg(unix)]
+        Ctl {
+            /// One of stats, flush, rotate or stop
+            command: String,
+            /// Control socket path
+            #[arg(long)]
+            socket: Option<PathBuf>,
+        },
         Client {
-            /// Host to connect to
+            /// Host to connect to; given several times, probe each of them
+            /// at once and show them side by side, each recorded to its own
+            /// file
             #[arg(long, default_value = "127.0.0.1:13337")]
+            host: Vec<String>,
+            /// Probe each host listed in this file at once, one per line, as
+            /// if given with --host. Lines starting with # are skipped
+            #[arg(long, value_name = "FILE", conflicts_with_all = ["host", "discover"])]
+            targets: Option<PathBuf>,
+            /// Only connect over IPv4
+            #[arg(short = '4', conflicts_with = "ipv6")]
+            ipv4: bool,
+            /// Only connect over IPv6
+            #[arg(short = '6')]
+            ipv6: bool,
+            /// Send from this local address, e.g. to pick one of several
+            /// uplinks; also picks the IP version
+            #[arg(long, value_name = "IP")]
+            source_addr: Option<IpAddr>,
+            /// Send through this network interface whatever the routing table
+            /// says (Linux only)
+            #[arg(long, value_name = "NAME")]
+            interface: Option<String>,
+            /// Probe through each of these interfaces at once, e.g.
+            /// eth0,wlan0,wwan0, comparing them side by side (Linux only)
+            #[arg(long, value_name = "NAMES", value_delimiter = ',',
+                conflicts_with_all = ["interface", "tui", "web", "scenario", "sweep", "flood", "mtu", "flows", "all_addresses"])]
+            interfaces: Vec<String>,
+            /// Probes sent per second; also sets how long probes may arrive
+            /// late
+            #[arg(long, default_value_t = 67, value_parser = clap::value_parser!(u32).range(1..=10_000))]
+            rate: u32,
+            /// Probe size in bytes (UDP payload); larger probes are padded
+            #[arg(long, value_name = "BYTES", default_value_t = 9, value_parser = clap::value_parser!(u16).range(9..=65_507))]
+            size: u16,
+            /// Have the server pad its ACKs to the probe size too
+            #[arg(long)]
+            pad_acks: bool,
+            /// Cycle probe sizes through SIZE[:WEIGHT] entries instead of a
+            /// fixed size and report loss per size, e.g. 64:7,576:4,1400:1
+            #[arg(long, value_name = "MIX", value_delimiter = ',', value_parser = parse_mix_entry, conflicts_with = "size")]
+            size_mix: Vec<(usize, u32)>,
+            /// Send probes at exponentially distributed intervals with the
+            /// configured mean rate instead of strictly periodically
+            #[arg(long)]
+            poisson: bool,
+            /// Stop after this long, e.g. 90s, 10m or 1h30m
+            #[arg(long, value_parser = parse_duration)]
+            duration: Option<Duration>,
+            /// Stop after sending this many probes
+            #[arg(long, value_name = "N")]
+            count: Option<u32>,
+            /// Also report round-trip loss over the latest probes in each of
+            /// these windows, which unlike the loss since the start still
+            /// moves after hours
+            #[arg(long, value_name = "WINDOWS", value_delimiter = ',', value_parser = parse_duration,
+                default_value = "10s,1m,5m")]
+            loss_windows: Vec<Duration>,
+            /// Half-life of the smoothed loss and round-trip time shown
+            /// alongside the totals, and gone by with --adaptive, so single
+            /// lost probes don't swing them
+            #[arg(long, value_parser = parse_duration, default_value = "5s")]
+            half_life: Duration,
+            /// Print stats this often, also while no ACKs arrive
+            #[arg(long, value_parser = parse_duration, default_value = "1s")]
+            report_interval: Duration,
+            /// Only probe for --active at the start of every period, e.g.
+            /// `--every 15m --active 60s`
+            #[arg(long, value_parser = parse_duration, requires = "active")]
+            every: Option<Duration>,
+            /// How long to probe in each --every period
+            #[arg(long, value_parser = parse_duration, requires = "every")]
+            active: Option<Duration>,
+            /// DiffServ code point to mark probes with, e.g. 46 for EF
+            #[arg(long, value_parser = clap::value_parser!(u8).range(0..64))]
+            dscp: Option<u8>,
+            /// Halve the rate while smoothed round-trip loss stays above
+            /// --adaptive-loss, and ramp back up to --rate once it clears
+            #[arg(long)]
+            adaptive: bool,
+            /// Loss percentage that counts as sustained loss for --adaptive
+            #[arg(
+                long,
+                value_name = "PERCENT",
+                default_value_t = 5.0,
+                requires = "adaptive"
+            )]
+            adaptive_loss: f64,
+            /// Lowest rate --adaptive backs off to
+            #[arg(long, value_name = "N", default_value_t = 1, requires = "adaptive")]
+            min_rate: u32,
+            /// Estimate the upstream bottleneck capacity from the spacing of
+            /// back-to-back packet trains as they reach the server
+            #[arg(long)]
+            capacity: bool,
+            /// How often to send a packet train for --capacity
+            #[arg(long, value_parser = parse_duration, default_value = "10s", requires = "capacity")]
+            train_every: Duration,
+            /// Packets per train for --capacity
+            #[arg(long, value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u8).range(2..), requires = "capacity")]
+            train_length: u8,
+            /// UDP payload size of train packets for --capacity
+            #[arg(long, value_name = "BYTES", default_value_t = 1200, value_parser = clap::value_parser!(u16).range(14..=65507), requires = "capacity")]
+            train_size: u16,
+            /// Send probes ECN-capable and report how many the network marked
+            /// congestion experienced; probes are then at least 10 bytes
+            #[arg(long)]
+            ecn: bool,
+            /// End probes in a CRC-32 the server checks and report how many
+            /// arrived corrupted rather than lost; probes are then at least
+            /// 15 bytes
+            #[arg(long)]
+            checksums: bool,
+            /// Fill probes beyond the header with random bytes, so links
+            /// compressing payloads or middleboxes inspecting them don't
+            /// treat the repetitive probes specially
+            #[arg(long)]
+            random_payload: bool,
+            /// Have the server stream probes of its own back, for servers
+            /// started with --allow-symmetric, and report downstream loss
+            /// and jitter measured on them rather than inferred from ACKs
+            #[arg(long, conflicts_with_all = ["flood", "mtu", "multicast"])]
+            symmetric: bool,
+            /// Rotate through this many source ports, reporting loss and
+            /// round-trip time per port to find a broken ECMP path
+            #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(2..=256))]
+            hop_ports: Option<u16>,
+            /// How long to probe from each port before hopping to the next
+            #[arg(long, value_parser = parse_duration, default_value = "10s", requires = "hop_ports")]
+            hop_every: Duration,
+            /// Also trace the route like mtr and report loss and latency per
+            /// hop (Linux only). Routers that rate-limit ICMP show loss that
+            /// doesn't continue to later hops
+            #[arg(long, conflicts_with_all = ["flood", "flows"])]
+            hops: bool,
+            /// Give up on reaching the server beyond this many hops
+            #[arg(long, value_name = "N", default_value_t = 30, value_parser = clap::value_parser!(u8).range(1..), requires = "hops")]
+            max_hops: u8,
+            /// Traceroute to the server at the start and every minute, and
+            /// store the route in the recording whenever it changed (Linux
+            /// only)
+            #[arg(long)]
+            record_route: bool,
+            /// Before probing, ask this STUN server for the external address
+            /// of the probe socket; given several times, tell from their
+            /// answers how NATs on the way map it. A `server --allow-stun`
+            /// is one too
+            #[arg(long, value_name = "HOST:PORT", conflicts_with_all = ["flood", "mtu", "multicast"])]
+            stun: Vec<String>,
+            /// Ask the server to send packets at this rate for --duration
+            /// (default 10s) and of --size bytes, and measure downstream loss
+            #[arg(long, value_name = "RATE", value_parser = clap::value_parser!(u32).range(1..),
+                conflicts_with_all = ["sweep", "scenario", "tui", "web", "every", "count", "adaptive", "size_mix", "poisson", "pad_acks"])]
+            flood: Option<u32>,
+            /// Find the path MTU by binary search with don't-fragment probes,
+            /// and check whether fragmented packets get through (Linux only)
+            #[arg(long, conflicts_with_all = ["flood", "sweep", "scenario", "tui", "web", "flows"])]
+            mtu: bool,
+            /// Largest UDP payload --mtu tries
+            #[arg(long, value_name = "BYTES", default_value_t = 9000, value_parser = clap::value_parser!(u16).range(11..=65507), requires = "mtu")]
+            mtu_max: u16,
+            /// Stay silent for each of these idle intervals in turn, e.g.
+            /// 10s,30s,60s,120s, and find the one after which a NAT or
+            /// firewall on the way no longer lets the server's answers
+            /// through
+            #[arg(long, value_name = "INTERVALS", value_delimiter = ',', value_parser = parse_duration,
+                conflicts_with_all = ["flood", "mtu", "sweep", "scenario", "tui", "web", "flows"])]
+            nat_timeout: Vec<Duration>,
+            /// Join this multicast group, e.g. 239.1.2.3:13338, and measure
+            /// loss and jitter of what a `server --multicast` streams to it
+            /// instead of probing --host. --source-addr picks the interface
+            /// for IPv4 groups
+            #[arg(long, value_name = "GROUP:PORT",
+                conflicts_with_all = ["flood", "mtu", "sweep", "scenario", "tui", "web", "flows", "all_addresses", "interfaces"])]
+            multicast: Option<SocketAddr>,
+            /// Look for servers advertised on the local network with `server
+            /// --advertise` and pick one instead of giving --host
+            #[arg(long, conflicts_with = "host")]
+            discover: bool,
+            /// Meet another client registering under this name at a `server
+            /// --rendezvous` given as --host, and measure the path between
+            /// the two directly; the first to register reflects, the other
+            /// probes and reports. Where NATs don't let them punch through to
+            /// each other, probes go through the server if it relays. Pick a
+            /// name others can't guess
+            #[arg(long, value_name = "NAME",
+                conflicts_with_all = ["flood", "mtu", "multicast", "discover", "sweep", "scenario", "flows", "all_addresses", "interfaces", "resume", "hop_ports"])]
+            peer: Option<String>,
+            /// Fail over to this host when --host stops answering, and go
+            /// back once it answers again; given several times, try them in
+            /// turn. Probes sent to a silent server count as neither sent nor
+            /// lost
+            #[arg(long, value_name = "HOST",
+                conflicts_with_all = ["flood", "mtu", "nat_timeout", "multicast", "peer", "discover", "every"])]
+            failover: Vec<String>,
+            /// Probe over this many flows at once, each from its own socket
+            /// and so with its own 5-tuple, reporting each flow and the total.
+            /// Per-flow policers and ECMP hashing can hide behind a single flow
+            #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=1024),
+                conflicts_with_all = ["tui", "web", "scenario", "sweep", "flood"])]
+            flows: u32,
+            /// Probe every address the host resolves to at once and compare
+            /// them, e.g. to decide between IPv4 and IPv6
+            #[arg(long, conflicts_with_all = ["tui", "web", "scenario", "sweep", "flood", "mtu", "flows"])]
+            all_addresses: bool,
+            /// Tag results with KEY=VALUE, e.g. site=office; given several
+            /// times, with each. Labels are stored in the recording, shown by
+            /// the web dashboard and sent to --collector, which groups by them
+            #[arg(long, value_name = "KEY=VALUE", value_parser = parse_label)]
+            label: Vec<(String, String)>,
+            /// Look up --host again this often, and whenever the server goes
+            /// silent, following it to a new address, e.g. behind dynamic DNS
+            #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+            reresolve_every: Option<Duration>,
+            /// Keep the session token in this file and resume the session
+            /// when restarted, so the server's counts carry on and the
+            /// recording is appended to instead of starting over
+            #[arg(long, value_name = "FILE",
+                conflicts_with_all = ["flood", "mtu", "multicast", "sweep", "scenario", "flows", "all_addresses", "interfaces"])]
+            resume: Option<PathBuf>,
+            /// Refuse to probe a server speaking another protocol version,
+            /// instead of warning and speaking the older one
+            #[arg(long)]
+            strict_version: bool,
+            /// What answers the probes: a loss_lens server, a TWAMP-Light
+            /// reflector (RFC 5357) such as a router's or `server --twamp`,
+            /// whose test packets are at least 41 bytes, a plain UDP echo
+            /// service (RFC 862), any host answering ICMP echo, for which
+            /// --host needs no port, or any listening TCP port, connected to
+            /// once per probe where UDP is dropped. These only tell
+            /// round-trip loss; ICMP needs root, CAP_NET_RAW or, on Linux,
+            /// net.ipv4.ping_group_range
+            #[arg(long, default_value = "loss-lens", value_name = "loss-lens|twamp|echo|icmp|tcp",
+                conflicts_with_all = ["flood", "mtu", "multicast", "nat_timeout", "peer", "discover"])]
+            protocol: loss_lens::Protocol,
+            /// Authenticate every packet with this pre-shared key, for
+            /// servers started with the same --psk
+            #[arg(long, env = "LOSS_LENS_PSK", hide_env_values = true)]
+            psk: Option<String>,
+            /// Encrypt the session with a Noise handshake for the server
+            /// with this public key, which it prints on start, so
+            /// middleboxes can't recognize or tamper with probes
+            #[arg(long, value_name = "PUBLIC_KEY", conflicts_with_all = ["flood", "mtu", "multicast"])]
+            noise: Option<loss_lens::noise::PublicKey>,
+            /// Step the rate up through these levels, e.g. 50,100,200,400,
+            /// and report the rate at which loss begins
+            #[arg(long, value_name = "RATES", value_delimiter = ',', value_parser = clap::value_parser!(u32).range(1..),
+                conflicts_with_all = ["scenario", "tui", "web", "every", "count", "duration", "adaptive"])]
+            sweep: Vec<u32>,
+            /// How long to probe at each --sweep level
+            #[arg(long, value_parser = parse_duration, default_value = "10s", requires = "sweep")]
+            step: Duration,
+            /// Round-trip loss percentage at which --sweep considers loss to
+            /// have begun
+            #[arg(
+                long,
+                value_name = "PERCENT",
+                default_value_t = 1.0,
+                requires = "sweep"
+            )]
+            sweep_loss: f64,
+            /// Run the phases of a TOML test plan one after another, with
+            /// results per phase; other options are the defaults for phases
+            #[arg(long, value_name = "FILE", conflicts_with_all = ["tui", "web", "every", "count", "duration"])]
+            scenario: Option<PathBuf>,
+            /// Show a live full-screen dashboard instead of periodic stats
+            #[arg(long, conflicts_with = "daemon")]
+            tui: bool,
+            /// Print the periodic stats, events and summary as JSON lines
+            /// instead of text, for log shippers to tail
+            #[arg(long,
+                conflicts_with_all = ["tui", "flood", "mtu", "nat_timeout", "multicast", "sweep", "scenario", "flows", "all_addresses", "interfaces"])]
+            ndjson: bool,
+            /// Serve the live web dashboard, WebSocket stream and REST API on
+            /// this address, e.g. 127.0.0.1:8080
+            #[arg(long)]
+            web: Option<String>,
+            /// Send the latest stats every --report-every to a `loss_lens
+            /// collector` at this URL or HOST:PORT, gathering the results of
+            /// probes at many sites
+            #[arg(long, value_name = "URL",
+                conflicts_with_all = ["flood", "mtu", "nat_timeout", "multicast", "sweep", "scenario", "flows", "all_addresses", "interfaces"])]
+            collector: Option<String>,
+            /// Name to report to --collector under, e.g. the site probed
+            /// from, instead of the host name
+            #[arg(long, value_name = "NAME", requires = "collector")]
+            probe_name: Option<String>,
+            /// How often to report to --collector
+            #[arg(long, value_parser = parse_duration, default_value = "60s", requires = "collector")]
+            report_every: Duration,
+            /// Bearer token the --collector requires
+            #[arg(long, env = "LOSS_LENS_COLLECTOR_TOKEN", hide_env_values = true)]
+            collector_token: Option<String>,
+            /// POST the summary as JSON to this http:// URL at the end of the
+            /// run, retrying with backoff, for backends of one's own
+            #[arg(long, value_name = "URL",
+                conflicts_with_all = ["flood", "mtu", "nat_timeout", "multicast", "sweep", "scenario", "flows", "all_addresses", "interfaces"])]
+            upload_url: Option<String>,
+            /// Also POST a snapshot to --upload-url this often while running
+            #[arg(long, value_parser = parse_duration, requires = "upload_url")]
+            upload_every: Option<Duration>,
+            /// Bearer token the --upload-url requires
+            #[arg(long, env = "LOSS_LENS_UPLOAD_TOKEN", hide_env_values = true)]
+            upload_token: Option<String>,
+            /// Summarize every --smokeping-step for SmokePing, with loss over
+            /// all probes: update this SmokePing RRD with rrdtool, or print
+            /// fping-style lines to stdout instead of the stats with `-`
+            #[arg(long, value_name = "RRD",
+                conflicts_with_all = ["tui", "ndjson", "flood", "mtu", "nat_timeout", "multicast", "sweep", "scenario", "flows", "all_addresses", "interfaces"])]
+            smokeping: Option<PathBuf>,
+            /// Pings per step of the SmokePing target
+            #[arg(long, default_value_t = 20, requires = "smokeping")]
+            smokeping_pings: usize,
+            /// SmokePing's step
+            #[arg(long, value_parser = parse_duration, default_value = "300s", requires = "smokeping")]
+            smokeping_step: Duration,
+            /// Push loss, round-trip times and more every --zabbix-every to
+            /// this Zabbix server or proxy, as trapper items
+            #[arg(long, value_name = "SERVER[:PORT]",
+                conflicts_with_all = ["flood", "mtu", "nat_timeout", "multicast", "sweep", "scenario", "flows", "all_addresses", "interfaces"])]
+            zabbix: Option<String>,
+            /// Zabbix host the items belong to, by default the probed host
+            #[arg(long, value_name = "NAME", requires = "zabbix")]
+            zabbix_host: Option<String>,
+            /// Send a metric as this item key instead of loss_lens.METRIC, or
+            /// not at all with an empty KEY; repeatable
+            #[arg(long, value_name = "METRIC=KEY", value_parser = parse_label, requires = "zabbix")]
+            zabbix_key: Vec<(String, String)>,
+            /// How often to push to Zabbix, with values over that interval
+            #[arg(long, value_parser = parse_duration, default_value = "60s", requires = "zabbix")]
+            zabbix_every: Duration,
+            /// Publish stats every second and events to Kafka, bootstrapping
+            /// from these brokers, e.g. kafka1:9092,kafka2:9092
+            #[cfg(feature = "kafka")]
+            #[arg(long, value_name = "BROKERS", value_delimiter = ',',
+                conflicts_with_all = ["flood", "mtu", "nat_timeout", "multicast", "sweep", "scenario", "flows", "all_addresses", "interfaces"])]
+            kafka: Vec<String>,
+            /// Topic to publish to with --kafka
+            #[cfg(feature = "kafka")]
+            #[arg(long, value_name = "TOPIC", default_value = "loss_lens")]
+            kafka_topic: String,
+            /// Listen for `loss_lens ctl` commands, optionally on a specific
+            /// socket path
+            #[cfg(unix)]
+            #[arg(long, value_name = "PATH")]
+            control: Option<Option<PathBuf>>,
+            #[cfg(unix)]
+            #[command(flatten)]
+            daemon: Daemon,
+        },
+        /// Probe two hosts at once, at the same rate with their probes
+        /// taking turns, and tell whether loss or latency differ beyond
+        /// chance, e.g. whether trouble lies with a particular server or is
+        /// shared
+        Compare {
+            /// The hosts to compare
+            a: String,
+            b: String,
+            /// Only connect over IPv4
+            #[arg(short = '4', conflicts_with = "ipv6")]
+            ipv4: bool,
+            /// Only connect over IPv6
+            #[arg(short = '6')]
+            ipv6: bool,
+            /// Send from this local address
+            #[arg(long, value_name = "IP")]
+            source_addr: Option<IpAddr>,
+            /// Probes sent per second to each host
+            #[arg(long, default_value_t = 67, value_parser = clap::value_parser!(u32).range(1..=10_000))]
+            rate: u32,
+            /// How long to probe; more seconds tell smaller differences
+            #[arg(long, value_parser = parse_duration, default_value = "60s")]
+            duration: Duration,
+            /// Print the result as JSON
+            #[arg(long)]
+            json: bool,
+            /// Authenticate every packet with this pre-shared key, for
+            /// servers started with the same --psk
+            #[arg(long, env = "LOSS_LENS_PSK", hide_env_values = true)]
+            psk: Option<String>,
+        },
+        /// Run a short measurement as a Nagios or Icinga check: print a
+        /// status line with performance data and exit with 0 for OK, 1 for
+        /// WARNING, 2 for CRITICAL or 3 for UNKNOWN
+        Check {
+            /// Host to probe
+            #[arg(long)]
             host: String,
+            /// Only connect over IPv4
+            #[arg(short = '4', conflicts_with = "ipv6")]
+            ipv4: bool,
+            /// Only connect over IPv6
+            #[arg(short = '6')]
+            ipv6: bool,
+            /// Send from this local address
+            #[arg(long, value_name = "IP")]
+            source_addr: Option<IpAddr>,
+            /// Round-trip loss in percent to warn at
+            #[arg(long, default_value_t = 1.0)]
+            warn_loss: f64,
+            /// Round-trip loss in percent to be critical at
+            #[arg(long, default_value_t = 5.0)]
+            crit_loss: f64,
+            /// Probes to send
+            #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u32).range(1..))]
+            samples: u32,
+            /// Probes sent per second
+            #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..=10_000))]
+            rate: u32,
+            /// Authenticate every packet with this pre-shared key, for
+            /// servers started with the same --psk
+            #[arg(long, env = "LOSS_LENS_PSK", hide_env_values = true)]
+            psk: Option<String>,
         },
         Server {
             /// Listen
             #[arg(long, default_value = "127.0.0.1:13337")]
             host: String,
+            /// Serve IPv4 clients too on an IPv6 --host such as [::]:13337,
+            /// even where IPv6 sockets are IPv6-only by default
+            #[arg(long)]
+            dual_stack: bool,
+            /// Serve the admin HTTP API on this address
+            #[arg(long, requires = "admin_token")]
+            admin: Option<String>,
+            /// Bearer token required by the admin API
+            #[arg(long, env = "LOSS_LENS_ADMIN_TOKEN", hide_env_values = true)]
+            admin_token: Option<String>,
+            /// Only serve clients that proved they receive at their source
+            /// address by echoing a cookie, so spoofed packets can't
+            /// reflect traffic anywhere. Needed before exposing a server to
+            /// the internet; clients predating handshakes are ignored
+            #[arg(long)]
+            require_cookie: bool,
+            /// Only answer sources in this CIDR range, e.g. 192.0.2.0/24;
+            /// given several times, in any of them
+            #[arg(long, value_name = "CIDR")]
+            allow: Vec<Cidr>,
+            /// Never answer sources in this CIDR range, even allowed ones;
+            /// given several times, in any of them
+            #[arg(long, value_name = "CIDR")]
+            deny: Vec<Cidr>,
+            /// Only answer clients authenticating their packets with this
+            /// pre-shared key; multicast and mDNS stay unauthenticated
+            #[arg(long, env = "LOSS_LENS_PSK", hide_env_values = true)]
+            psk: Option<String>,
+            /// Offer encrypted sessions with the static key in this file,
+            /// created if missing; clients need the public key it prints
+            #[arg(long, value_name = "FILE")]
+            noise_key: Option<PathBuf>,
+            /// Highest probe rate granted to clients
+            #[arg(long, value_name = "N", default_value_t = 10_000, value_parser = clap::value_parser!(u32).range(1..))]
+            max_rate: u32,
+            /// Handle at most this many packets a second from each source
+            /// address, dropping the rest unanswered
+            #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+            per_ip_rate: Option<u32>,
+            /// Track at most this many clients, forgetting the one heard
+            /// from least recently to make room for a new one
+            #[arg(long, value_name = "N", default_value_t = 10_000, value_parser = clap::value_parser!(u64).range(1..))]
+            max_clients: u64,
+            /// Forget clients silent for this long, e.g. `5m`; they start a
+            /// new session when heard from again
+            #[arg(long, value_name = "DURATION", default_value = "5m", value_parser = parse_duration)]
+            idle_timeout: Duration,
+            /// Serve on this many threads, each with a socket of its own on
+            /// the port, the kernel spreading clients among them. Rate
+            /// limits per source address apply per thread
+            #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..), conflicts_with = "rendezvous")]
+            workers: u16,
+            /// Answer `client --flood` requests. Only enable this where source
+            /// addresses can't be spoofed, as floods go wherever requested
+            #[arg(long)]
+            allow_flood: bool,
+            /// Grant `client --symmetric`, streaming probes back at each such
+            /// client's rate. Like floods they go to whichever address
+            /// handshaked, so combine this with --require-cookie where
+            /// source addresses can be spoofed
+            #[arg(long)]
+            allow_symmetric: bool,
+            /// Introduce `client --peer` clients registering under the same
+            /// name to each other, so they can probe each other directly
+            #[arg(long)]
+            rendezvous: bool,
+            /// Relay packets between `client --peer` clients that couldn't
+            /// punch through to each other
+            #[arg(long, requires = "rendezvous")]
+            relay: bool,
+            /// Answer STUN binding requests, for `client --stun`. Answers are
+            /// a little larger than requests, and unauthenticated even with
+            /// --psk
+            #[arg(long)]
+            allow_stun: bool,
+            /// Highest packet rate granted to a flood
+            #[arg(
+                long,
+                value_name = "N",
+                default_value_t = 1000,
+                requires = "allow_flood"
+            )]
+            max_flood_rate: u32,
+            /// Longest flood granted
+            #[arg(long, value_parser = parse_duration, default_value = "60s", requires = "allow_flood")]
+            max_flood_duration: Duration,
+            /// Largest flood packet payload granted
+            #[arg(
+                long,
+                value_name = "BYTES",
+                default_value_t = 1472,
+                requires = "allow_flood"
+            )]
+            max_flood_size: usize,
+            /// Also stream sequenced packets to this multicast group, e.g.
+            /// 239.1.2.3:13338, for `client --multicast` to measure
+            #[arg(long, value_name = "GROUP:PORT")]
+            multicast: Option<SocketAddr>,
+            /// Packets per second sent to the --multicast group
+            #[arg(long, value_name = "N", default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..=100_000), requires = "multicast")]
+            multicast_rate: u32,
+            /// UDP payload size of --multicast packets
+            #[arg(long, value_name = "BYTES", default_value_t = 17, value_parser = clap::value_parser!(u16).range(17..=65_507), requires = "multicast")]
+            multicast_size: u16,
+            /// Router hops --multicast packets may cross; 1 keeps them on the
+            /// local network
+            #[arg(long, value_name = "N", default_value_t = 1, requires = "multicast")]
+            multicast_ttl: u32,
+            /// Also reflect TWAMP-Light test packets (RFC 5357) on this
+            /// address, 0.0.0.0:862 unless given, for TWAMP senders and
+            /// `client --protocol twamp`. Test packets must be padded to the size of
+            /// their reflection, 41 bytes, so answers are never larger
+            #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "0.0.0.0:862")]
+            twamp: Option<String>,
+            /// Advertise the server on the local network with mDNS for
+            /// `client --discover`, under this name or else the host name
+            #[arg(long, value_name = "NAME")]
+            advertise: Option<Option<String>>,
+            /// Ask the home router to forward the port with NAT-PMP or UPnP,
+            /// and print the address the server is reachable at from the
+            /// internet
+            #[arg(long)]
+            upnp: bool,
+            /// Append when clients start, move, go idle and end their
+            /// sessions, with their counts, to this file as JSON lines
+            #[arg(long, value_name = "PATH")]
+            session_log: Option<PathBuf>,
+            /// Record which probes of each session arrived to a file per
+            /// session in this directory, in the format of the client's
+            /// --recording, for upstream loss without the client's record
+            #[arg(long, value_name = "DIR")]
+            record_dir: Option<PathBuf>,
+            #[cfg(unix)]
+            #[command(flatten)]
+            daemon: Daemon,
+            /// Run under the Windows service manager
+            #[cfg(windows)]
+            #[arg(long)]
+            service: bool,
+        },
+        /// Gather the stats clients at many sites report with `client
+        /// --collector`, and serve a dashboard and API over all of them
+        Collector {
+            /// Listen for reports, and serve the dashboard and API
+            #[arg(long, default_value = "127.0.0.1:13380")]
+            host: String,
+            /// Bearer token clients must report with
+            #[arg(long, env = "LOSS_LENS_COLLECTOR_TOKEN", hide_env_values = true)]
+            token: Option<String>,
+            /// Append reports to this file, and read it back on start
+            #[arg(long, value_name = "FILE")]
+            store: Option<PathBuf>,
+            #[cfg(unix)]
+            #[command(flatten)]
+            daemon: Daemon,
+        },
+        /// Break down loss and outages by hour of day and day of week, from
+        /// the output of `client --ndjson` or a collector's --store
+        Analyze {
+            /// JSON lines files to read, `-` for stdin
+            #[arg(required = true)]
+            files: Vec<PathBuf>,
+            /// Also write a standalone HTML report with charts, the outages
+            /// and the sessions to this file, e.g. for a support ticket
+            #[arg(long, value_name = "PATH")]
+            html: Option<PathBuf>,
+            /// Also export the time series in flent's data format to this
+            /// file, to compare with RRUL and other bufferbloat test archives
+            #[arg(long, value_name = "PATH")]
+            flent: Option<PathBuf>,
+            /// Print a concise Markdown summary instead, for pasting into
+            /// issues and wikis
+            #[arg(long)]
+            markdown: bool,
+            /// Print the report rendered with this Mustache-like template
+            /// instead, fed with the statistics as `analyze --json` prints them
+            #[arg(long, value_name = "FILE", conflicts_with = "markdown")]
+            template: Option<PathBuf>,
+            /// Print the statistics as JSON instead, as templates get them
+            #[arg(long, conflicts_with_all = ["markdown", "template"])]
+            json: bool,
         },
     }
 }
 
-struct ClientSharedState {
-    client_sent: AtomicU32,
-    done: AtomicBool,
-}
-
 fn main() -> eyre::Result<()> {
-    use std::net::UdpSocket;
-    use std::thread;
-    use std::time::{Duration, Instant};
-
-    const CLIENT_TO_SERVER_PACKET_SIZE: usize = 1 + 4 + 4;
-    const SERVER_TO_CLIENT_PACKET_SIZE: usize = 1 + 4 + 4;
-    const BUF_SIZE: usize = 1 + 4 + 4;
-
-    // const HELLO_PACKET_CONST: u8 = 1;
-    const SEQ_NUM_PACKET_CONST: u8 = 2;
-    const ACK_PACKET_CONST: u8 = 3;
-
     let args = args::Args::parse();
-
-    // Number of packets to keep track of
-    const LATE_WINDOW: usize = PACKETS_PER_SECOND * 3;
-    // Number of milliseconds between packets
-    const PACKETS_PER_SECOND: usize = 67;
+    loss_lens::log::init(
+        loss_lens::log::Level::from_verbosity(args.verbose, args.quiet),
+        args.log_json,
+    );
 
     match args.command {
-        args::Commands::Client { host } => {
-            let socket = UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))?;
-            let addr = host.to_socket_addrs()?.next().unwrap();
-            socket.connect(addr)?;
-            let client_id: u32 = rand::random();
-
-            let state = Arc::new(ClientSharedState {
-                client_sent: AtomicU32::new(0),
-                done: AtomicBool::new(false),
-            });
+        #[cfg(unix)]
+        args::Commands::Ctl { command, socket } => {
+            let socket = socket.unwrap_or_else(loss_lens::control::default_path);
+            print!("{}", loss_lens::control::request(&socket, &command)?);
+        }
+        args::Commands::Client {
+            host,
+            targets,
+            ipv4,
+            ipv6,
+            source_addr,
+            interface,
+            interfaces,
+            rate,
+            size,
+            pad_acks,
+            size_mix,
+            poisson,
+            duration,
+            count,
+            loss_windows,
+            half_life,
+            report_interval,
+            every,
+            active,
+            dscp,
+            adaptive,
+            adaptive_loss,
+            min_rate,
+            capacity,
+            train_every,
+            train_length,
+            train_size,
+            ecn,
+            checksums,
+            random_payload,
+            symmetric,
+            hop_ports,
+            hop_every,
+            hops,
+            max_hops,
+            record_route,
+            stun,
+            flood,
+            mtu,
+            mtu_max,
+            nat_timeout,
+            multicast,
+            discover,
+            peer,
+            failover,
+            label,
+            flows,
+            all_addresses,
+            reresolve_every,
+            resume,
+            strict_version,
+            protocol,
+            psk,
+            noise,
+            sweep,
+            step,
+            sweep_loss,
+            scenario,
+            tui,
+            ndjson,
+            web,
+            collector,
+            probe_name,
+            report_every,
+            collector_token,
+            upload_url,
+            upload_every,
+            upload_token,
+            smokeping,
+            smokeping_pings,
+            smokeping_step,
+            zabbix,
+            zabbix_host,
+            zabbix_key,
+            zabbix_every,
+            #[cfg(feature = "kafka")]
+            kafka,
+            #[cfg(feature = "kafka")]
+            kafka_topic,
+            #[cfg(unix)]
+            control,
+            #[cfg(unix)]
+            daemon,
+        } => {
+            #[cfg(unix)]
+            let _pid_file = daemonize(&daemon)?;
 
-            let mut cmd = Command::new("zstd")
-                .arg("-9")
-                .stdin(Stdio::piped())
-                .stdout(File::create("out.zst")?)
-                .spawn()?;
+            let ip_version = match (ipv4, ipv6) {
+                (true, _) => Some(IpVersion::V4),
+                (_, true) => Some(IpVersion::V6),
+                _ => None,
+            };
+            let psk = psk.as_deref().map(Psk::new);
+            let hosts = match targets {
+                Some(path) => read_targets(&path)?,
+                None => host,
+            };
+            eyre::ensure!(
+                hosts.len() == 1
+                    || !(flood.is_some()
+                        || mtu
+                        || !nat_timeout.is_empty()
+                        || multicast.is_some()
+                        || peer.is_some()
+                        || !failover.is_empty()
+                        || flows > 1
+                        || all_addresses
+                        || !interfaces.is_empty()
+                        || resume.is_some()
+                        || !sweep.is_empty()
+                        || scenario.is_some()
+                        || tui
+                        || ndjson
+                        || web.is_some()
+                        || collector.is_some()
+                        || upload_url.is_some()
+                        || smokeping.is_some()
+                        || zabbix.is_some()),
+                "several hosts can only be probed side by side, without modes of their own like --flood or --tui"
+            );
+            #[cfg(feature = "kafka")]
+            eyre::ensure!(
+                hosts.len() == 1 || kafka.is_empty(),
+                "--kafka publishes the results of a single host"
+            );
+            let host = hosts[0].clone();
+            if let Some(rate) = flood {
+                let test = FloodTest::new(FloodConfig {
+                    host,
+                    ip_version,
+                    source_addr,
+                    interface,
+                    packets_per_second: rate,
+                    packet_size: usize::from(size).max(loss_lens::flood::FLOOD_PACKET_MIN_SIZE),
+                    duration: duration.unwrap_or(Duration::from_secs(10)),
+                    psk,
+                })?;
+                ctrlc::set_handler({
+                    let stop = test.stop_handle();
+                    move || stop.stop()
+                })
+                .expect("Error setting Ctrl-C handler");
+                print!("{}", test.run()?);
+                return Ok(());
+            }
+            let host = if discover { pick_reflector()? } else { host };
+            if let Some(group) = multicast {
+                let receiver = MulticastReceiver::new(group, source_addr)?.duration(duration);
+                ctrlc::set_handler({
+                    let stop = receiver.stop_handle();
+                    move || stop.stop()
+                })
+                .expect("Error setting Ctrl-C handler");
+                let stats = receiver.run(|stats| {
+                    println!();
+                    print!("{stats}");
+                })?;
+                println!("\nSummary:");
+                print!("{stats}");
+                return Ok(());
+            }
+            if mtu {
+                let test = MtuTest::new(MtuConfig {
+                    host,
+                    ip_version,
+                    source_addr,
+                    interface,
+                    max_size: mtu_max.into(),
+                    psk,
+                    ..MtuConfig::default()
+                })?;
+                ctrlc::set_handler({
+                    let stop = test.stop_handle();
+                    move || stop.stop()
+                })
+                .expect("Error setting Ctrl-C handler");
+                print!("{}", test.run()?);
+                return Ok(());
+            }
+            if !nat_timeout.is_empty() {
+                let test = NatTimeoutTest::new(NatTimeoutConfig {
+                    host,
+                    ip_version,
+                    source_addr,
+                    interface,
+                    intervals: nat_timeout,
+                    psk,
+                    ..NatTimeoutConfig::default()
+                })?;
+                ctrlc::set_handler({
+                    let stop = test.stop_handle();
+                    move || stop.stop()
+                })
+                .expect("Error setting Ctrl-C handler");
+                print!("{}", test.run()?);
+                return Ok(());
+            }
 
-            ctrlc::set_handler({
-                let state = Arc::clone(&state);
-                move || {
-                    state.done.store(true, Ordering::SeqCst);
+            let config = ClientConfig {
+                host: host.clone(),
+                ip_version,
+                source_addr,
+                interface,
+                recording: Some(PathBuf::from("out.zst")),
+                packets_per_second: rate,
+                // room for the ECN field in ACKs, or the checksum
+                probe_size: if checksums {
+                    usize::from(size).max(CHECKSUM_PROBE_MIN_SIZE)
+                } else if ecn {
+                    size.max(10).into()
+                } else if protocol == Protocol::Twamp {
+                    usize::from(size).max(loss_lens::twamp::REFLECTED_SIZE)
+                } else {
+                    size.into()
+                },
+                pad_acks,
+                size_mix,
+                poisson,
+                duration,
+                count,
+                loss_windows,
+                half_life,
+                report_interval,
+                duty_cycle: every
+                    .zip(active)
+                    .map(|(period, active)| DutyCycle { active, period }),
+                dscp,
+                ecn,
+                checksums,
+                random_payload,
+                symmetric,
+                adaptive: adaptive.then_some(AdaptiveRate {
+                    loss_threshold: adaptive_loss,
+                    min_rate,
+                }),
+                packet_trains: capacity.then_some(PacketTrains {
+                    every: train_every,
+                    length: train_length,
+                    size: train_size.into(),
+                }),
+                port_hopping: hop_ports.map(|ports| PortHopping {
+                    ports: ports.into(),
+                    every: hop_every,
+                }),
+                hops: hops.then_some(HopTrace {
+                    max_hops,
+                    ..HopTrace::default()
+                }),
+                record_route,
+                stun,
+                reresolve_every,
+                resume,
+                strict_version,
+                protocol,
+                psk,
+                noise,
+                failover,
+                labels: label,
+            };
+            if let Some(path) = scenario {
+                run_scenario(&Scenario::load(&path)?, &config)?;
+                return Ok(());
+            }
+            if !sweep.is_empty() {
+                let results = run_scenario(&Scenario::sweep(&sweep, step), &config)?;
+                let onset = results
+                    .iter()
+                    .position(|(_, _, stats)| stats.round_trip_loss() >= sweep_loss);
+                println!();
+                match onset {
+                    Some(i) => {
+                        let (_, config, stats) = &results[i];
+                        println!(
+                            "Loss begins at {} probes/s ({:.2}% round trip)",
+                            config.packets_per_second,
+                            stats.round_trip_loss()
+                        );
+                        if let Some((_, clean, _)) = i.checked_sub(1).map(|i| &results[i]) {
+                            println!("Last clean level: {} probes/s", clean.packets_per_second);
+                        }
+                    }
+                    None => {
+                        if let Some((_, config, _)) = results.last() {
+                            println!(
+                                "No loss above {sweep_loss}% up to {} probes/s",
+                                config.packets_per_second
+                            );
+                        }
+                    }
                 }
+                return Ok(());
+            }
+            if hosts.len() > 1 {
+                return run_targets(&hosts, &config);
+            }
+            if flows > 1 {
+                return run_flows(flows, &config);
+            }
+            if all_addresses {
+                return run_all_addresses(&config);
+            }
+            if !interfaces.is_empty() {
+                return run_interfaces(&interfaces, &config);
+            }
+            let mut client = match peer {
+                Some(name) => {
+                    info!("Waiting for the other client to register as {name:?} at {host}");
+                    let peer = rendezvous::meet(&RendezvousConfig {
+                        host: host.clone(),
+                        name,
+                        ip_version,
+                        source_addr,
+                        interface: config.interface.clone(),
+                        psk: config.psk.clone(),
+                    })?;
+                    let via = if peer.relayed {
+                        warn!("Couldn't punch through to the other client, so packets go through the server's relay");
+                        "through the relay at"
+                    } else {
+                        "at"
+                    };
+                    match peer.role {
+                        Role::Reflect => {
+                            info!(
+                                "Reflecting probes of the other client {via} {}, until interrupted",
+                                peer.addr
+                            );
+                            return reflect(peer.socket, config.psk);
+                        }
+                        Role::Probe => {
+                            info!("Probing the other client {via} {}", peer.addr);
+                            let config = ClientConfig {
+                                host: peer.addr.to_string(),
+                                ..config.clone()
+                            };
+                            ProbeClient::from_socket(peer.socket, config)?
+                        }
+                    }
+                }
+                None => ProbeClient::new(config.clone())?,
+            };
+            if let Some(nat) = client.nat() {
+                info!(
+                    "External address: {}, NAT mapping: {}",
+                    nat.external_addr(),
+                    nat.mapping()
+                );
+            }
+            match client.session() {
+                None => warn!("The server didn't answer the handshake, it's probably older; probing without a session"),
+                Some(session) => {
+                    debug!(
+                        "Session {:08x}: version {}, {} probes/s of up to {} bytes, features {:#x}",
+                        session.token,
+                        session.version,
+                        session.packets_per_second,
+                        session.probe_size,
+                        session.features
+                    );
+                    if session.version != loss_lens::session::PROTOCOL_VERSION {
+                        warn!(
+                            "The server speaks protocol version {}, this client {}; using version {}",
+                            session.version,
+                            loss_lens::session::PROTOCOL_VERSION,
+                            session.negotiated_version()
+                        );
+                    }
+                    if session.packets_per_second != rate {
+                        info!(
+                            "The server limits probing to {} probes/s",
+                            session.packets_per_second
+                        );
+                    }
+                }
+            }
+
+            ctrlc::set_handler({
+                let stop = client.stop_handle();
+                move || stop.stop()
             })
             .expect("Error setting Ctrl-C handler");
 
-            let t = thread::spawn({
-                let state = Arc::clone(&state);
-                let socket = socket.try_clone()?;
-                move || -> eyre::Result<()> {
-                    let done = &state.done;
-                    let client_sent = &state.client_sent;
-                    let start_time = Instant::now();
-                    let mut buf = [0u8; BUF_SIZE];
-                    const SLOT_SIZE: usize = 64;
-                    let mut time_slots = VecDeque::<u64>::new();
-                    let mut seq_offset = 1;
-                    let mut client_received = 0;
-                    let mut server_received = 0;
-                    let mut last_print = 0;
-                    let out = cmd.stdin.as_mut().unwrap();
-
-                    socket.set_read_timeout(Some(Duration::from_millis(50)))?;
-
-                    let rv = (|| {
-                        let mut last_recv: Option<Instant> = None;
-                        let mut lags = [0; 10];
-                        while !done.load(Ordering::SeqCst) {
-                            let (n, _addr) = match socket.recv_from(&mut buf) {
-                                Ok(x) => Ok(x),
-                                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
-                                x => x,
-                            }?;
-                            if let Some(last) = last_recv {
-                                let dur = last.elapsed().as_millis() / 100;
-                                if dur >= 1 {
-                                    dbg!(last.elapsed());
-                                    if (dur as usize) < lags.len() {
-                                        lags[dur as usize] += 1;
-                                    } else {
-                                        lags[lags.len() - 1] += 1;
-                                    }
-                                }
+            #[cfg(unix)]
+            signals::spawn(client.control(), !tui)?;
+
+            #[cfg(unix)]
+            let _control_socket = control
+                .map(|path| {
+                    let path = path.unwrap_or_else(loss_lens::control::default_path);
+                    loss_lens::control::ControlSocket::bind(&path, client.control())
+                })
+                .transpose()?;
+
+            let dashboard = web.map(Dashboard::serve).transpose()?;
+            if let Some(dashboard) = &dashboard {
+                info!("Dashboard at http://{}/", dashboard.local_addr());
+                dashboard.set_config(&config);
+            }
+            let reporter = collector
+                .map(|url| {
+                    Reporter::start(ReporterConfig {
+                        url,
+                        probe: probe_name.unwrap_or_else(loss_lens::mdns::host_name),
+                        target: host.clone(),
+                        labels: config.labels.clone(),
+                        token: collector_token,
+                        every: report_every,
+                    })
+                })
+                .transpose()?;
+            let uploader = upload_url
+                .map(|url| {
+                    Uploader::start(UploadConfig {
+                        url,
+                        host: host.clone(),
+                        labels: config.labels.clone(),
+                        token: upload_token,
+                        every: upload_every,
+                    })
+                })
+                .transpose()?;
+            let zabbix = zabbix
+                .map(|server| {
+                    ZabbixSender::start(ZabbixConfig {
+                        server,
+                        host: zabbix_host.unwrap_or_else(|| host.clone()),
+                        keys: zabbix_key,
+                        every: zabbix_every,
+                    })
+                })
+                .transpose()?;
+            #[cfg(feature = "kafka")]
+            let kafka = (!kafka.is_empty())
+                .then(|| {
+                    loss_lens::KafkaSink::start(loss_lens::KafkaConfig {
+                        brokers: kafka,
+                        topic: kafka_topic,
+                        host: host.clone(),
+                        labels: config.labels.clone(),
+                    })
+                })
+                .transpose()?;
+            let ndjson = ndjson.then(|| NdjsonWriter::new(&host, &config.labels));
+            // fping-style lines take the place of the text stats
+            let fping_lines = smokeping.as_deref() == Some(Path::new("-"));
+            let mut smokeping = smokeping
+                .map(|rrd| {
+                    SmokepingSink::start(SmokepingConfig {
+                        rrd: (rrd != Path::new("-")).then_some(rrd),
+                        host: host.clone(),
+                        pings: smokeping_pings,
+                        step: smokeping_step,
+                    })
+                })
+                .transpose()?;
+            client = client.on_event({
+                let dashboard = dashboard.clone();
+                let ndjson = ndjson.clone();
+                #[cfg(feature = "kafka")]
+                let kafka = kafka.clone();
+                move |event| {
+                    if !tui {
+                        match event {
+                            Event::RateChange { from, to, loss } => {
+                                info!("Rate {from} -> {to} probes/s at {loss:.1}% loss")
+                            }
+                            Event::AddressChange { from, to } => {
+                                info!("Host moved from {from} to {to}")
                             }
-                            last_recv = Some(Instant::now());
-                            if n == SERVER_TO_CLIENT_PACKET_SIZE && buf[0] == ACK_PACKET_CONST {
-                                let received_seq =
-                                    u32::from_be_bytes(buf[1..5].try_into().unwrap());
-                                server_received = u32::from_be_bytes(buf[5..9].try_into().unwrap())
-                                    .max(server_received);
-                                // account for reordering by keeping track of which sequence numbers have not been responded to yet
-                                // remove overly late packets from the datastructure and count them as lost
-                                while time_slots.len() * SLOT_SIZE > LATE_WINDOW {
-                                    if let Some(packets_received) = time_slots.pop_front() {
-                                        let new_rx = packets_received.count_ones();
-                                        // TODO: compression
-                                        // TODO: write timestamps
-                                        out.write_all(&[new_rx as u8])?;
-                                        out.flush()?;
-                                        seq_offset += SLOT_SIZE;
-                                    }
-                                }
-
-                                // packet already counted as lost if it didn't arrive within this window
-                                if received_seq as usize >= seq_offset {
-                                    // make space for new sequence numbers
-                                    while received_seq as usize
-                                        >= time_slots.len() * SLOT_SIZE + seq_offset
-                                    {
-                                        time_slots.push_back(0u64);
-                                    }
-                                    let idx = received_seq as usize - seq_offset;
-                                    if time_slots[idx / SLOT_SIZE] & (1 << (idx % SLOT_SIZE)) == 0 {
-                                        client_received += 1;
-                                    }
-                                    time_slots[idx / SLOT_SIZE] |= 1 << (idx % SLOT_SIZE);
-                                }
-
-                                if server_received as usize - last_print > PACKETS_PER_SECOND
-                                    && server_received > 0
-                                {
-                                    last_print = server_received as usize;
-                                    let elapsed = start_time.elapsed().as_secs_f64();
-                                    let client_sent = client_sent.load(Ordering::SeqCst);
-                                    let upstream_loss = 100.0
-                                        * (1.0 - (server_received as f64 / client_sent as f64));
-                                    let downstream_loss = 100.0
-                                        * (1.0 - (client_received as f64 / server_received as f64));
-
-                                    println!();
-                                    println!(
-                                        "Estimated traffic: {:.02} KiB/s",
-                                        (((client_sent + server_received) * (54)) as f64
-                                            / (1 << 10) as f64)
-                                            / elapsed
-                                    );
-                                    println!("Client sent    : {client_sent}",);
-                                    println!("Server received: {server_received}");
-                                    println!("Client received: {client_received}");
-                                    println!("Client   upstream loss: {upstream_loss:.2}%");
-                                    println!("Client downstream loss: {downstream_loss:.2}%");
-                                    print!("Lags per hour: ");
-                                    let mut lags = lags.clone();
-                                    for i in (0..lags.len() - 1).rev() {
-                                        lags[i] += lags[i + 1];
-                                    }
-                                    for (i, x) in lags[1..].iter().enumerate() {
-                                        print!("{:.02} (>={}ms), ", *x as f64 / elapsed * 3600.0, (i+1)*100);
-                                    }
-                                    println!("");
-                                    println!("Time elapsed: {elapsed:.2} seconds");
-                                }
+                            Event::SessionRestart => {
+                                warn!("The server forgot the session, was it restarted? Opened a new one")
                             }
+                            Event::ServerChange { from, to } => warn!(
+                                "ACKs now come from server epoch {to:08x} instead of {from:08x}, was it restarted or failed over? Counting from scratch"
+                            ),
+                            Event::Failover {
+                                from,
+                                to,
+                                unanswered,
+                            } if *unanswered > 0 => warn!(
+                                "Failed over from {from} to {to}, not counting {unanswered} probes it left unanswered"
+                            ),
+                            Event::Failover { from, to, .. } => {
+                                info!("Switched back from {from} to {to}")
+                            }
+                            Event::SessionResume { first_seq } => {
+                                info!("Resumed the previous session, continuing from probe {first_seq}")
+                            }
+                            Event::ProtocolMismatch { expected, got } => warn!(
+                                "Ignoring packets of protocol version {got}, expected {expected}; was the server replaced?"
+                            ),
+                            _ => {}
                         }
-                        Ok(())
-                    })();
-                    out.flush()?;
-                    dbg!(cmd.wait_with_output())?;
-                    rv
+                    }
+                    if let Some(ndjson) = &ndjson {
+                        ndjson.event(event);
+                    }
+                    if let Some(dashboard) = &dashboard {
+                        dashboard.event(event);
+                    }
+                    #[cfg(feature = "kafka")]
+                    if let Some(kafka) = &kafka {
+                        kafka.event(event);
+                    }
                 }
             });
 
-            for seq in 1u32.. {
-                if state.done.load(Ordering::SeqCst) {
-                    break;
+            let control = client.control();
+            #[cfg(unix)]
+            loss_lens::systemd::notify("READY=1")?;
+            if tui {
+                let mut tui = tui::Tui::new(host)?;
+                let reporter = reporter.clone();
+                let uploader = uploader.clone();
+                let zabbix = zabbix.clone();
+                #[cfg(feature = "kafka")]
+                let kafka = kafka.clone();
+                client.run(move |stats| {
+                    if let Some(dashboard) = &dashboard {
+                        dashboard.update(stats);
+                    }
+                    if let Some(reporter) = &reporter {
+                        reporter.update(stats);
+                    }
+                    if let Some(uploader) = &uploader {
+                        uploader.update(stats);
+                    }
+                    if let Some(zabbix) = &zabbix {
+                        zabbix.update(stats);
+                    }
+                    #[cfg(feature = "kafka")]
+                    if let Some(kafka) = &kafka {
+                        kafka.stats(stats);
+                    }
+                    let _ = tui.update(stats);
+                })?;
+            } else {
+                let reporter = reporter.clone();
+                let uploader = uploader.clone();
+                let zabbix = zabbix.clone();
+                #[cfg(feature = "kafka")]
+                let kafka = kafka.clone();
+                let ndjson = ndjson.clone();
+                let mut trends = (ndjson.is_none() && io::stdout().is_terminal())
+                    .then(|| tui::Trends::new(report_interval));
+                client.run(move |stats| {
+                    if let Some(dashboard) = &dashboard {
+                        dashboard.update(stats);
+                    }
+                    if let Some(reporter) = &reporter {
+                        reporter.update(stats);
+                    }
+                    if let Some(uploader) = &uploader {
+                        uploader.update(stats);
+                    }
+                    if let Some(zabbix) = &zabbix {
+                        zabbix.update(stats);
+                    }
+                    #[cfg(feature = "kafka")]
+                    if let Some(kafka) = &kafka {
+                        kafka.stats(stats);
+                    }
+                    if let Some(smokeping) = &mut smokeping {
+                        smokeping.update(stats);
+                    }
+                    match &ndjson {
+                        Some(ndjson) => ndjson.stats(stats),
+                        None if fping_lines => {}
+                        None => {
+                            println!();
+                            print!("{stats}");
+                            if let Some(trends) = &mut trends {
+                                print!("{}", trends.update(stats));
+                            }
+                        }
+                    }
+                })?;
+            }
+            #[cfg(unix)]
+            loss_lens::systemd::notify("STOPPING=1")?;
+            if let Some(stats) = control.latest_stats() {
+                if let Some(reporter) = &reporter {
+                    if let Err(e) = reporter.finish(&stats) {
+                        warn!("Reporting the summary to the collector failed: {e}");
+                    }
+                }
+                if let Some(uploader) = &uploader {
+                    if let Err(e) = uploader.finish(&stats) {
+                        warn!("Uploading the summary failed: {e:#}");
+                    }
+                }
+                if let Some(zabbix) = &zabbix {
+                    if let Err(e) = zabbix.finish(&stats) {
+                        warn!("Sending the last values to Zabbix failed: {e:#}");
+                    }
+                }
+                #[cfg(feature = "kafka")]
+                if let Some(kafka) = &kafka {
+                    if let Err(e) = kafka.finish() {
+                        warn!("{e}");
+                    }
+                }
+                match &ndjson {
+                    Some(ndjson) => ndjson.summary(&stats),
+                    None if fping_lines => {}
+                    None => print_summary(&stats, config.recording.as_deref()),
+                }
+            }
+        }
+        args::Commands::Analyze {
+            files,
+            html,
+            flent,
+            markdown,
+            template,
+            json,
+        } => {
+            let mut samples = Vec::new();
+            for path in &files {
+                let text = if path == Path::new("-") {
+                    io::read_to_string(io::stdin())?
+                } else {
+                    std::fs::read_to_string(path)
+                        .map_err(|e| eyre::eyre!("reading {}: {e}", path.display()))?
+                };
+                samples.extend(
+                    loss_lens::analyze::read_samples(&text)
+                        .map_err(|e| eyre::eyre!("{}: {e}", path.display()))?,
+                );
+            }
+            eyre::ensure!(!samples.is_empty(), "no stats found in the files");
+            let history = loss_lens::analyze::History::from_samples(&samples);
+            if let Some(path) = template {
+                let template = std::fs::read_to_string(&path)
+                    .map_err(|e| eyre::eyre!("reading {}: {e}", path.display()))?;
+                print!(
+                    "{}",
+                    loss_lens::report::template(&history, &template)
+                        .map_err(|e| eyre::eyre!("{}: {e}", path.display()))?
+                );
+            } else if json {
+                println!("{}", history.to_json());
+            } else if markdown {
+                print!("{}", loss_lens::report::markdown(&history));
+            } else {
+                print!("{}", loss_lens::analyze::TimeOfDay::from_history(&history));
+            }
+            if let Some(path) = html {
+                std::fs::write(&path, loss_lens::report::html(&history))?;
+            }
+            if let Some(path) = flent {
+                std::fs::write(&path, loss_lens::flent::export(&history))?;
+            }
+        }
+        args::Commands::Compare {
+            a,
+            b,
+            ipv4,
+            ipv6,
+            source_addr,
+            rate,
+            duration,
+            json,
+            psk,
+        } => {
+            let base = ClientConfig {
+                ip_version: match (ipv4, ipv6) {
+                    (true, _) => Some(IpVersion::V4),
+                    (_, true) => Some(IpVersion::V6),
+                    _ => None,
+                },
+                source_addr,
+                packets_per_second: rate,
+                duration: Some(duration),
+                psk: psk.as_deref().map(Psk::new),
+                ..ClientConfig::default()
+            };
+            let comparison = run_compare([a, b], &base)?;
+            if json {
+                println!("{}", comparison.to_json());
+            } else {
+                println!();
+                print!("{comparison}");
+            }
+        }
+        args::Commands::Check {
+            host,
+            ipv4,
+            ipv6,
+            source_addr,
+            warn_loss,
+            crit_loss,
+            samples,
+            rate,
+            psk,
+        } => {
+            let config = ClientConfig {
+                host,
+                ip_version: match (ipv4, ipv6) {
+                    (true, _) => Some(IpVersion::V4),
+                    (_, true) => Some(IpVersion::V6),
+                    _ => None,
+                },
+                source_addr,
+                packets_per_second: rate,
+                count: Some(samples),
+                psk: psk.as_deref().map(Psk::new),
+                ..ClientConfig::default()
+            };
+            let thresholds = Thresholds {
+                warn_loss,
+                crit_loss,
+            };
+            // monitoring reads UNKNOWN from the exit code, not an error
+            let status = match run_check(config) {
+                Ok(stats) => {
+                    let check = Check::new(stats, thresholds);
+                    println!("{check}");
+                    check.status
+                }
+                Err(e) => {
+                    println!("{}", Check::failed(&e));
+                    loss_lens::check::Status::Unknown
                 }
-                let mut buf = [0u8; CLIENT_TO_SERVER_PACKET_SIZE];
-                buf[0] = SEQ_NUM_PACKET_CONST;
-                buf[1..5].copy_from_slice(&seq.to_be_bytes());
-                buf[5..9].copy_from_slice(&client_id.to_be_bytes());
+            };
+            std::process::exit(status.exit_code());
+        }
+        args::Commands::Server {
+            host,
+            dual_stack,
+            admin,
+            admin_token,
+            require_cookie,
+            allow,
+            deny,
+            psk,
+            noise_key,
+            max_rate,
+            per_ip_rate,
+            max_clients,
+            idle_timeout,
+            workers,
+            allow_flood,
+            allow_symmetric,
+            rendezvous,
+            relay,
+            allow_stun,
+            max_flood_rate,
+            max_flood_duration,
+            max_flood_size,
+            multicast,
+            multicast_rate,
+            multicast_size,
+            multicast_ttl,
+            twamp,
+            advertise,
+            upnp,
+            session_log,
+            record_dir,
+            #[cfg(unix)]
+            daemon,
+            #[cfg(windows)]
+            service,
+        } => {
+            // before daemonizing, which changes the pid systemd passed it to
+            #[cfg(unix)]
+            let activated = loss_lens::systemd::listen_udp_socket()?;
+            #[cfg(unix)]
+            let _pid_file = daemonize(&daemon)?;
+
+            let noise = noise_key
+                .map(|path| Keypair::load_or_generate(&path))
+                .transpose()?;
+            if let Some(keypair) = &noise {
+                info!("Encrypted sessions with public key {}", keypair.public());
+            }
+            let config = ServerConfig {
+                host,
+                dual_stack,
+                admin: admin
+                    .zip(admin_token)
+                    .map(|(addr, token)| AdminConfig { addr, token }),
+                flood: allow_flood.then_some(FloodLimits {
+                    max_rate: max_flood_rate,
+                    max_duration: max_flood_duration,
+                    max_size: max_flood_size,
+                }),
+                symmetric: allow_symmetric,
+                rendezvous,
+                relay,
+                stun: allow_stun,
+                multicast: multicast.map(|group| MulticastConfig {
+                    group,
+                    packets_per_second: multicast_rate,
+                    packet_size: multicast_size.into(),
+                    ttl: multicast_ttl,
+                }),
+                twamp,
+                limits: ServerLimits {
+                    max_rate,
+                    per_ip_rate,
+                    max_clients: max_clients as usize,
+                    idle_timeout,
+                },
+                advertise: advertise.map(|name| name.unwrap_or_else(loss_lens::mdns::host_name)),
+                upnp,
+                allow,
+                deny,
+                require_cookie,
+                psk: psk.as_deref().map(Psk::new),
+                noise,
+                session_log,
+                record_dir,
+                workers: workers.into(),
+            };
+            #[cfg(unix)]
+            let server = match activated {
+                Some(socket) => ProbeServer::from_socket(socket, config)?,
+                None => ProbeServer::new(config)?,
+            };
+            #[cfg(not(unix))]
+            let server = ProbeServer::new(config)?;
+            if let Some(mapping) = server.port_mapping() {
+                info!(
+                    "Reachable from the internet at {}, forwarded by the gateway via {}",
+                    mapping.external, mapping.method
+                );
+            }
+
+            #[cfg(windows)]
+            if service {
+                return service::run(server);
+            }
 
-                socket.send_to(&buf, addr)?;
+            ctrlc::set_handler({
+                let stop = server.stop_handle();
+                move || stop.stop()
+            })
+            .expect("Error setting Ctrl-C handler");
 
-                state.client_sent.fetch_add(1, Ordering::SeqCst);
+            #[cfg(unix)]
+            signals::spawn_server(server.stop_handle())?;
 
-                thread::sleep(Duration::from_nanos(
-                    1_000_000_000 / PACKETS_PER_SECOND as u64,
-                ));
+            #[cfg(unix)]
+            loss_lens::systemd::notify("READY=1")?;
+            let summary = server.run()?;
+            #[cfg(unix)]
+            loss_lens::systemd::notify("STOPPING=1")?;
+            info!("{summary}");
+        }
+        args::Commands::Collector {
+            host,
+            token,
+            store,
+            #[cfg(unix)]
+            daemon,
+        } => {
+            #[cfg(unix)]
+            let _pid_file = daemonize(&daemon)?;
+
+            let collector = Collector::serve(CollectorConfig {
+                addr: host,
+                token,
+                store,
+            })?;
+            info!(
+                "Collecting at http://{}/, {} probes reported so far",
+                collector.local_addr(),
+                collector.probes()
+            );
+            let stop = StopHandle::default();
+            ctrlc::set_handler({
+                let stop = stop.clone();
+                move || stop.stop()
+            })
+            .expect("Error setting Ctrl-C handler");
+            #[cfg(unix)]
+            signals::spawn_server(stop.clone())?;
+            #[cfg(unix)]
+            loss_lens::systemd::notify("READY=1")?;
+            while !stop.is_stopped() {
+                thread::sleep(Duration::from_millis(100));
             }
-            t.join().unwrap()?;
         }
-        args::Commands::Server { host } => {
-            let socket = UdpSocket::bind(host)?;
+    }
 
-            let mut rx_map = HashMap::new();
-            let mut buf = [0u8; BUF_SIZE];
+    Ok(())
+}
 
-            let mut last_check = Instant::now();
+/// Run each phase of `scenario` as its own measurement, with its own
+/// recording next to the configured one, then print a table of the results
+/// and return them.
+fn run_scenario(
+    scenario: &Scenario,
+    base: &ClientConfig,
+) -> eyre::Result<Vec<(String, ClientConfig, Stats)>> {
+    let aborted = StopHandle::default();
+    let current = Arc::new(Mutex::new(None::<StopHandle>));
+    ctrlc::set_handler({
+        let aborted = aborted.clone();
+        let current = Arc::clone(&current);
+        move || {
+            aborted.stop();
+            if let Some(stop) = &*current.lock().unwrap() {
+                stop.stop();
+            }
+        }
+    })
+    .expect("Error setting Ctrl-C handler");
 
-            loop {
-                match socket.recv_from(&mut buf) {
-                    Ok((n, addr)) if n == CLIENT_TO_SERVER_PACKET_SIZE => {
-                        let now = Instant::now();
-                        if rx_map.len() > 1000 && last_check.elapsed().as_secs() > 1 {
-                            last_check = now;
-                            rx_map.retain(|_, x: &mut (u32, Instant)| x.1.elapsed().as_secs() < 10)
-                        }
-                        let client_id = u32::from_be_bytes(buf[5..9].try_into().unwrap());
-                        let e = rx_map.entry(client_id).or_insert_with(|| (0, now));
-                        e.0 += 1;
-                        e.1 = now;
-                        buf[0] = ACK_PACKET_CONST;
-                        buf[5..9].copy_from_slice(u32::to_be_bytes(e.0).as_slice());
-                        socket.send_to(&buf, addr)?;
-                    }
-                    _ => {}
+    let mut results = Vec::new();
+    for (i, phase) in scenario.phases.iter().enumerate() {
+        if aborted.is_stopped() {
+            break;
+        }
+        let tag: String = phase
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
                 }
+            })
+            .collect();
+        let config = with_recording_suffix(&phase.config(base), &format!("{}-{tag}", i + 1));
+        println!(
+            "Phase {}/{}: {} ({:?})",
+            i + 1,
+            scenario.phases.len(),
+            phase.name,
+            phase.duration
+        );
+        let client = ProbeClient::new(config.clone())?;
+        *current.lock().unwrap() = Some(client.stop_handle());
+        let control = client.control();
+        client.run(|stats| {
+            println!();
+            print!("{stats}");
+        })?;
+        if let Some(stats) = control.latest_stats() {
+            println!("\nPhase {} summary:", phase.name);
+            print!("{stats}");
+            results.push((phase.name.clone(), config, stats));
+        }
+        println!();
+    }
+
+    println!(
+        "{:<16} {:>6} {:>6} {:>5} {:>8} {:>10} {:>10} {:>9}",
+        "Phase", "Rate", "Size", "DSCP", "Sent", "Upstream", "Downstream", "Max gap"
+    );
+    for (name, config, stats) in &results {
+        println!(
+            "{:<16} {:>6} {:>6} {:>5} {:>8} {:>9.2}% {:>9.2}% {:>7}ms",
+            name,
+            config.packets_per_second,
+            config.probe_size,
+            config
+                .dscp
+                .map_or_else(|| "-".to_string(), |d| d.to_string()),
+            stats.client_sent,
+            stats.upstream_loss(),
+            stats.downstream_loss(),
+            stats.max_gap.as_millis()
+        );
+    }
+    Ok(results)
+}
+
+/// Run `flows` clients side by side, printing a table of each flow and the
+/// total.
+fn run_flows(flows: u32, base: &ClientConfig) -> eyre::Result<()> {
+    let runs = (1..=flows)
+        .map(|i| {
+            (
+                i.to_string(),
+                with_recording_suffix(base, &format!("flow{i}")),
+            )
+        })
+        .collect();
+    let latest = run_side_by_side("Flow", runs)?;
+    print!("{}", Stats::combine(&latest));
+    Ok(())
+}
+
+/// Probe every address the host resolves to at once, printing a table per
+/// address and comparing IPv6 with IPv4.
+fn run_all_addresses(base: &ClientConfig) -> eyre::Result<()> {
+    // a source address only reaches its own family
+    let version = base.ip_version.or(base
+        .source_addr
+        .map(|ip| IpVersion::of(SocketAddr::new(ip, 0))));
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in base.host.to_socket_addrs()? {
+        if !addrs.contains(&addr) && version.is_none_or(|v| IpVersion::of(addr) == v) {
+            addrs.push(addr);
+        }
+    }
+    eyre::ensure!(!addrs.is_empty(), "host did not resolve to any address");
+    let runs = addrs
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| {
+            let mut config = with_recording_suffix(base, &format!("addr{}", i + 1));
+            config.host = addr.to_string();
+            (addr.ip().to_string(), config)
+        })
+        .collect();
+    let latest = run_side_by_side("Address", runs)?;
+
+    let family = |version| {
+        let stats: Vec<Stats> = addrs
+            .iter()
+            .zip(&latest)
+            .filter(|(&addr, _)| IpVersion::of(addr) == version)
+            .map(|(_, stats)| stats.clone())
+            .collect();
+        (!stats.is_empty()).then(|| Stats::combine(&stats))
+    };
+    if let (Some(v4), Some(v6)) = (family(IpVersion::V4), family(IpVersion::V6)) {
+        let loss = (v6.round_trip_loss(), v4.round_trip_loss());
+        let rtt = (
+            v6.mean_rtt.as_secs_f64() * 1000.0,
+            v4.mean_rtt.as_secs_f64() * 1000.0,
+        );
+        // a point of loss, or a fifth and 5ms more latency
+        if loss.0 > loss.1 + 1.0 || rtt.0 > rtt.1 * 1.2 + 5.0 {
+            println!(
+                "IPv6 is materially worse than IPv4: {:.2}% vs {:.2}% loss, {:.1}ms vs {:.1}ms round trip",
+                loss.0, loss.1, rtt.0, rtt.1
+            );
+        } else {
+            println!("IPv6 is on par with IPv4");
+        }
+    }
+    Ok(())
+}
+
+/// Probe through each interface at once, printing a table per interface.
+fn run_interfaces(interfaces: &[String], base: &ClientConfig) -> eyre::Result<()> {
+    let runs = interfaces
+        .iter()
+        .map(|interface| {
+            let mut config = with_recording_suffix(base, interface);
+            config.interface = Some(interface.clone());
+            (interface.clone(), config)
+        })
+        .collect();
+    run_side_by_side("Interface", runs)?;
+    Ok(())
+}
+
+/// Probe each host at once, printing a table per host.
+fn run_targets(hosts: &[String], base: &ClientConfig) -> eyre::Result<()> {
+    let runs = hosts
+        .iter()
+        .map(|host| {
+            // host names and ports, but nothing to trip up file names
+            let suffix: String = host
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            let mut config = with_recording_suffix(base, &suffix);
+            config.host = host.clone();
+            (host.clone(), config)
+        })
+        .collect();
+    run_side_by_side("Host", runs)?;
+    Ok(())
+}
+
+/// The hosts listed in a --targets file.
+fn read_targets(path: &Path) -> eyre::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)?;
+    let hosts: Vec<String> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    eyre::ensure!(!hosts.is_empty(), "no hosts in {}", path.display());
+    Ok(hosts)
+}
+
+/// The final stats of a `check`, which ends after its probes.
+fn run_check(config: ClientConfig) -> eyre::Result<Stats> {
+    let client = ProbeClient::new(config)?;
+    let control = client.control();
+    client.run(|_| {})?;
+    control
+        .latest_stats()
+        .ok_or_else(|| eyre::eyre!("no stats"))
+}
+
+/// Probe both hosts at once, the second's probes half an interval after the
+/// first's, sampling the round-trip time of each every second.
+fn run_compare(hosts: [String; 2], base: &ClientConfig) -> eyre::Result<Comparison> {
+    let mut clients = Vec::new();
+    for host in &hosts {
+        let config = ClientConfig {
+            host: host.clone(),
+            ..base.clone()
+        };
+        clients.push(ProbeClient::new(config)?);
+    }
+    let interval = Duration::from_secs(1) / base.packets_per_second;
+    let mut handles = Vec::new();
+    for client in clients {
+        if !handles.is_empty() {
+            thread::sleep(interval / 2);
+        }
+        let control = client.control();
+        handles.push((client.spawn(), control));
+    }
+    ctrlc::set_handler({
+        let stops: Vec<_> = handles
+            .iter()
+            .map(|(handle, _)| handle.stop_handle())
+            .collect();
+        move || stops.iter().for_each(StopHandle::stop)
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    info!(
+        "Comparing {} and {} for {}s",
+        hosts[0],
+        hosts[1],
+        base.duration.unwrap_or_default().as_secs()
+    );
+    let mut latest = [Stats::default(), Stats::default()];
+    let mut rtt_diffs_ms = Vec::new();
+    while !handles.iter().all(|(handle, _)| handle.is_finished()) {
+        thread::sleep(Duration::from_secs(1));
+        let mut rtts = [None, None];
+        for (((handle, _), latest), rtt) in handles.iter().zip(&mut latest).zip(&mut rtts) {
+            handle.events().try_iter().for_each(drop);
+            if let Some(stats) = handle.stats().try_iter().last() {
+                *rtt = compare::interval_rtt_ms(latest, &stats);
+                *latest = stats;
             }
         }
+        if let [Some(a), Some(b)] = rtts {
+            rtt_diffs_ms.push(a - b);
+        }
+    }
+    for (i, (handle, control)) in handles.into_iter().enumerate() {
+        handle.join()?;
+        if let Some(stats) = control.latest_stats() {
+            latest[i] = stats;
+        }
+    }
+    Ok(Comparison {
+        names: hosts,
+        stats: latest,
+        rtt_diffs_ms,
+    })
+}
+
+/// List the servers advertised on the local network and have the user pick
+/// one, returning its address as --host.
+fn pick_reflector() -> eyre::Result<String> {
+    info!("Looking for servers on the local network...");
+    let found = loss_lens::mdns::discover(Duration::from_secs(2))?;
+    eyre::ensure!(
+        !found.is_empty(),
+        "no servers found; are they running with --advertise?"
+    );
+    for (i, reflector) in found.iter().enumerate() {
+        eprintln!(
+            "{:>3}) {:<24} {:<24} {:.1} ms",
+            i + 1,
+            reflector.name,
+            reflector.addr,
+            reflector.rtt.as_secs_f64() * 1000.0
+        );
+    }
+    if let [reflector] = found.as_slice() {
+        info!("Probing {}", reflector.name);
+        return Ok(reflector.addr.to_string());
+    }
+    loop {
+        eprint!("Server [1-{}]: ", found.len());
+        io::stderr().flush()?;
+        let mut line = String::new();
+        eyre::ensure!(io::stdin().read_line(&mut line)? > 0, "no server picked");
+        match line.trim().parse::<usize>() {
+            Ok(i) if (1..=found.len()).contains(&i) => return Ok(found[i - 1].addr.to_string()),
+            _ => eprintln!("Enter a number between 1 and {}", found.len()),
+        }
     }
+}
 
+/// Serve the other client met at a rendezvous until interrupted, granting
+/// it symmetric mode as the measurement is between the two anyway.
+fn reflect(socket: UdpSocket, psk: Option<Psk>) -> eyre::Result<()> {
+    let server = ProbeServer::from_socket(
+        socket,
+        ServerConfig {
+            symmetric: true,
+            psk,
+            ..ServerConfig::default()
+        },
+    )?;
+    ctrlc::set_handler({
+        let stop = server.stop_handle();
+        move || stop.stop()
+    })
+    .expect("Error setting Ctrl-C handler");
+    server.run()?;
     Ok(())
 }
+
+/// `base` recording to a file named after the original plus `suffix`.
+fn with_recording_suffix(base: &ClientConfig, suffix: &str) -> ClientConfig {
+    let mut config = base.clone();
+    config.recording = base.recording.as_deref().map(|recording| {
+        let mut name = recording.file_stem().unwrap_or_default().to_owned();
+        name.push(format!("-{suffix}.zst"));
+        recording.with_file_name(name)
+    });
+    config
+}
+
+/// Run named clients side by side, printing a table of them every second
+/// and once more at the end. Returns the final stats of each.
+fn run_side_by_side(label: &str, runs: Vec<(String, ClientConfig)>) -> eyre::Result<Vec<Stats>> {
+    let mut clients = Vec::new();
+    let mut names = Vec::new();
+    for (name, config) in runs {
+        let client = ProbeClient::new(config)?;
+        let control = client.control();
+        clients.push((client.spawn(), control));
+        names.push(name);
+    }
+    ctrlc::set_handler({
+        let stops: Vec<_> = clients
+            .iter()
+            .map(|(handle, _)| handle.stop_handle())
+            .collect();
+        move || stops.iter().for_each(StopHandle::stop)
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let mut latest = vec![Stats::default(); clients.len()];
+    while !clients.iter().all(|(handle, _)| handle.is_finished()) {
+        thread::sleep(Duration::from_secs(1));
+        for ((handle, _), latest) in clients.iter().zip(&mut latest) {
+            // events aren't reported per client, but mustn't pile up
+            handle.events().try_iter().for_each(drop);
+            if let Some(stats) = handle.stats().try_iter().last() {
+                *latest = stats;
+            }
+        }
+        println!();
+        print_side_by_side(label, &names, &latest);
+    }
+    for (i, (handle, control)) in clients.into_iter().enumerate() {
+        handle.join()?;
+        if let Some(stats) = control.latest_stats() {
+            latest[i] = stats;
+        }
+    }
+    println!("\nSummary:");
+    print_side_by_side(label, &names, &latest);
+    Ok(latest)
+}
+
+/// The end-of-run summary: the final stats, every outage and where the
+/// recording went, set apart from the periodic blocks before it.
+fn print_summary(stats: &Stats, recording: Option<&Path>) {
+    println!("\n===== Summary =====");
+    print!("{stats}");
+    if !stats.outages.is_empty() {
+        println!("Outages ({}):", stats.outages.len());
+        for outage in &stats.outages {
+            println!(
+                "  at {:.1}s for {:.1}s",
+                outage.start.as_secs_f64(),
+                outage.duration.as_secs_f64()
+            );
+        }
+    }
+    if let Some(recording) = recording {
+        let recording = std::fs::canonicalize(recording).unwrap_or_else(|_| recording.into());
+        println!("Recording: {}", recording.display());
+    }
+}
+
+fn print_side_by_side(label: &str, names: &[String], runs: &[Stats]) {
+    let width = names
+        .iter()
+        .map(String::len)
+        .chain([label.len(), 3])
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{:<width$} {:>8} {:>10} {:>10} {:>9} {:>9} {:>8}",
+        label, "Sent", "Upstream", "Downstream", "RTT", "Max gap", "Outages"
+    );
+    let total = Stats::combine(runs);
+    let rows = names
+        .iter()
+        .map(String::as_str)
+        .zip(runs)
+        .chain([("all", &total)]);
+    for (name, stats) in rows {
+        println!(
+            "{:<width$} {:>8} {:>9.2}% {:>9.2}% {:>7.1}ms {:>7}ms {:>8}",
+            name,
+            stats.client_sent,
+            stats.upstream_loss(),
+            stats.downstream_loss(),
+            stats.mean_rtt.as_secs_f64() * 1000.0,
+            stats.max_gap.as_millis(),
+            stats.outages.len()
+        );
+    }
+}
+
+#[cfg(unix)]
+fn daemonize(args: &args::Daemon) -> eyre::Result<Option<daemon::PidFile>> {
+    args.daemon
+        .then(|| daemon::daemonize(args.pid_file.as_deref(), args.log_file.as_deref()))
+        .transpose()
+}
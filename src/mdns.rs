@@ -0,0 +1,440 @@
+//! Finding servers on the local network with mDNS/DNS-SD (RFC 6762 and
+//! 6763), so test machines don't need each other's addresses typed in.
+//!
+//! Only what's needed is implemented: a server answers queries for
+//! [`SERVICE`] with PTR, SRV, TXT and address records, and [`discover`] sends
+//! one such query and collects the answers. IPv4 only.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::StopHandle;
+
+/// DNS-SD service type servers are advertised as
+pub const SERVICE: &str = "_loss-lens._udp.local";
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// in questions a request for a unicast answer, in answers a claim to be the
+// only owner of the name
+const CLASS_TOP_BIT: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const TTL: u32 = 120;
+// RFC 6762 section 6.7: answers to one-shot queries from other ports
+const ONE_SHOT_TTL: u32 = 10;
+
+/// A server that answered [`discover`].
+#[derive(Clone, Debug)]
+pub struct Reflector {
+    /// Instance name it's advertised under, by default its host name
+    pub name: String,
+    pub addr: SocketAddr,
+    /// How long its answer took
+    pub rtt: Duration,
+}
+
+/// Ask the local network for servers, collecting answers for `wait`.
+pub fn discover(wait: Duration) -> eyre::Result<Vec<Reflector>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let mut query = Message::new(0, 0, 1, 0, 0);
+    query.name(SERVICE);
+    query.u16(TYPE_PTR);
+    query.u16(CLASS_IN);
+    let sent = Instant::now();
+    socket.send_to(&query.0, (MDNS_GROUP, MDNS_PORT))?;
+
+    let mut found: Vec<Reflector> = Vec::new();
+    let mut buf = [0u8; 9000];
+    while let Some(remaining) = wait.checked_sub(sent.elapsed()).filter(|d| !d.is_zero()) {
+        socket.set_read_timeout(Some(remaining))?;
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let rtt = sent.elapsed();
+        let msg = &buf[..n];
+        let Some(records) = parse(msg, true) else {
+            continue;
+        };
+        for ptr in records
+            .iter()
+            .filter(|r| r.rtype == TYPE_PTR && r.name.eq_ignore_ascii_case(SERVICE))
+        {
+            let Some((instance, _)) = read_name(msg, ptr.data) else {
+                continue;
+            };
+            let Some((target, port)) = records
+                .iter()
+                .find(|r| r.rtype == TYPE_SRV && r.name.eq_ignore_ascii_case(&instance))
+                .and_then(|srv| {
+                    let data = msg.get(srv.data..srv.data + 6)?;
+                    let port = u16::from_be_bytes([data[4], data[5]]);
+                    Some((read_name(msg, srv.data + 6)?.0, port))
+                })
+            else {
+                continue;
+            };
+            // the address records are optional, the sender is the server
+            let ip = records
+                .iter()
+                .filter(|r| r.name.eq_ignore_ascii_case(&target))
+                .find_map(|r| match (r.rtype, msg.get(r.data..r.data + r.len)?) {
+                    (TYPE_A, data) if data.len() == 4 => {
+                        Some(IpAddr::from(<[u8; 4]>::try_from(data).unwrap()))
+                    }
+                    (TYPE_AAAA, data) if data.len() == 16 => {
+                        Some(IpAddr::from(<[u8; 16]>::try_from(data).unwrap()))
+                    }
+                    _ => None,
+                })
+                .unwrap_or(from.ip());
+            let addr = SocketAddr::new(ip, port);
+            if found.iter().all(|r| r.addr != addr) {
+                let name = instance
+                    .strip_suffix(SERVICE)
+                    .and_then(|name| name.strip_suffix('.'))
+                    .unwrap_or(&instance)
+                    .to_string();
+                found.push(Reflector { name, addr, rtt });
+            }
+        }
+    }
+    found.sort_by(|a, b| a.name.cmp(&b.name).then(a.addr.cmp(&b.addr)));
+    Ok(found)
+}
+
+/// Answer queries for [`SERVICE`] on a background thread until `done`,
+/// advertising `instance` at `server`. An unspecified `server` address is
+/// advertised as whichever local address faces the one asking.
+pub(crate) fn spawn_responder(
+    instance: &str,
+    server: SocketAddr,
+    done: StopHandle,
+) -> eyre::Result<JoinHandle<eyre::Result<()>>> {
+    let socket = bind_shared(MDNS_PORT)?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let label = label(instance);
+    let instance = format!("{label}.{SERVICE}");
+    // not the machine's own host name, which other responders may own
+    let target = format!("{label}-loss-lens.local");
+
+    Ok(thread::spawn(move || {
+        let mut buf = [0u8; 9000];
+        while !done.is_stopped() {
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let msg = &buf[..n];
+            if msg.len() < 12 || u16::from_be_bytes([msg[2], msg[3]]) & FLAG_RESPONSE != 0 {
+                continue;
+            }
+            let Some(questions) = parse(msg, false) else {
+                continue;
+            };
+            let Some(question) = questions.iter().find(|q| {
+                matches!(q.rtype, TYPE_PTR | TYPE_ANY) && q.name.eq_ignore_ascii_case(SERVICE)
+            }) else {
+                continue;
+            };
+            let ip = if server.ip().is_unspecified() {
+                match local_ip_towards(from) {
+                    Ok(ip) => ip,
+                    Err(_) => continue,
+                }
+            } else {
+                server.ip()
+            };
+            // queries from other ports than 5353 come from simple resolvers
+            // that expect a plain unicast DNS answer
+            let one_shot = from.port() != MDNS_PORT;
+            let id = if one_shot {
+                u16::from_be_bytes([msg[0], msg[1]])
+            } else {
+                0
+            };
+            let answer = answer(id, one_shot, &instance, &target, server.port(), ip);
+            let to = if one_shot || question.class & CLASS_TOP_BIT != 0 {
+                from
+            } else {
+                SocketAddr::from((MDNS_GROUP, MDNS_PORT))
+            };
+            // a network that drops mDNS shouldn't take the server down
+            let _ = socket.send_to(&answer, to);
+        }
+        Ok(())
+    }))
+}
+
+fn answer(id: u16, one_shot: bool, instance: &str, target: &str, port: u16, ip: IpAddr) -> Vec<u8> {
+    let (ttl, unique) = if one_shot {
+        (ONE_SHOT_TTL, CLASS_IN)
+    } else {
+        (TTL, CLASS_IN | CLASS_TOP_BIT)
+    };
+    let mut msg = Message::new(
+        id,
+        FLAG_RESPONSE | FLAG_AUTHORITATIVE,
+        one_shot.into(),
+        1,
+        3,
+    );
+    if one_shot {
+        msg.name(SERVICE);
+        msg.u16(TYPE_PTR);
+        msg.u16(CLASS_IN);
+    }
+    let mut data = Vec::new();
+    encode_name(instance, &mut data);
+    msg.record(SERVICE, TYPE_PTR, CLASS_IN, ttl, &data);
+
+    let mut data = vec![0, 0, 0, 0];
+    data.extend_from_slice(&port.to_be_bytes());
+    encode_name(target, &mut data);
+    msg.record(instance, TYPE_SRV, unique, ttl, &data);
+    // no key/value pairs
+    msg.record(instance, TYPE_TXT, unique, ttl, &[0]);
+    match ip {
+        IpAddr::V4(ip) => msg.record(target, TYPE_A, unique, ttl, &ip.octets()),
+        IpAddr::V6(ip) => msg.record(target, TYPE_AAAA, unique, ttl, &ip.octets()),
+    }
+    msg.0
+}
+
+/// A DNS message being written.
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(id: u16, flags: u16, questions: u16, answers: u16, additional: u16) -> Self {
+        let mut msg = Self(Vec::with_capacity(512));
+        for field in [id, flags, questions, answers, 0, additional] {
+            msg.u16(field);
+        }
+        msg
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn name(&mut self, name: &str) {
+        encode_name(name, &mut self.0);
+    }
+
+    fn record(&mut self, name: &str, rtype: u16, class: u16, ttl: u32, data: &[u8]) {
+        self.name(name);
+        self.u16(rtype);
+        self.u16(class);
+        self.0.extend_from_slice(&ttl.to_be_bytes());
+        self.u16(data.len() as u16);
+        self.0.extend_from_slice(data);
+    }
+}
+
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// A question, or a resource record whose data starts at `data`.
+struct Entry {
+    name: String,
+    rtype: u16,
+    class: u16,
+    data: usize,
+    len: usize,
+}
+
+/// The questions of a message, or with `records` its answer, authority and
+/// additional records; `None` if it's malformed.
+fn parse(msg: &[u8], records: bool) -> Option<Vec<Entry>> {
+    let count = |i: usize| {
+        Some(usize::from(u16::from_be_bytes([
+            *msg.get(i)?,
+            *msg.get(i + 1)?,
+        ])))
+    };
+    let questions = count(4)?;
+    let total = count(6)? + count(8)? + count(10)?;
+    let mut pos = 12;
+    let mut entries = Vec::new();
+    for _ in 0..questions {
+        let (name, end) = read_name(msg, pos)?;
+        let fixed = msg.get(end..end + 4)?;
+        entries.push(Entry {
+            name,
+            rtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            class: u16::from_be_bytes([fixed[2], fixed[3]]),
+            data: end + 4,
+            len: 0,
+        });
+        pos = end + 4;
+    }
+    if !records {
+        return Some(entries);
+    }
+    entries.clear();
+    for _ in 0..total {
+        let (name, end) = read_name(msg, pos)?;
+        let fixed = msg.get(end..end + 10)?;
+        let len = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+        let data = end + 10;
+        msg.get(data..data + len)?;
+        entries.push(Entry {
+            name,
+            rtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            class: u16::from_be_bytes([fixed[2], fixed[3]]),
+            data,
+            len,
+        });
+        pos = data + len;
+    }
+    Some(entries)
+}
+
+/// The possibly compressed name at `pos`, and where it ends.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // bounds pointer loops
+    for _ in 0..128 {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => return Some((name, end.unwrap_or(pos + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                let target = usize::from(u16::from_be_bytes([len, *msg.get(pos + 1)?]) & 0x3fff);
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            len => {
+                let label = msg.get(pos + 1..pos + 1 + usize::from(len))?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                pos += 1 + usize::from(len);
+            }
+        }
+    }
+    None
+}
+
+/// `name` as a single DNS label.
+fn label(name: &str) -> String {
+    let mut label = name.replace('.', "-");
+    while label.len() > 63 {
+        label.pop();
+    }
+    if label.is_empty() {
+        label.push_str("loss-lens");
+    }
+    label
+}
+
+/// Local address packets to `addr` are sent from.
+fn local_ip_towards(addr: SocketAddr) -> io::Result<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// This machine's host name, the default instance name to advertise.
+pub fn host_name() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            // just the first label of a fully qualified name
+            if let Some(name) = String::from_utf8_lossy(&buf[..len]).split('.').next() {
+                if !name.is_empty() {
+                    return name.to_string();
+                }
+            }
+        }
+    }
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "loss-lens".to_string())
+}
+
+/// Bind the mDNS port alongside other responders on this machine.
+#[cfg(unix)]
+fn bind_shared(port: u16) -> eyre::Result<UdpSocket> {
+    use std::{
+        mem,
+        os::fd::{AsRawFd, FromRawFd},
+    };
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // owned right away so it's closed on errors
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let on: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let rv = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                option,
+                (&on as *const libc::c_int).cast(),
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        if rv == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+    sockaddr.sin_port = port.to_be();
+    let rv = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            (&sockaddr as *const libc::sockaddr_in).cast(),
+            mem::size_of_val(&sockaddr) as libc::socklen_t,
+        )
+    };
+    if rv == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn bind_shared(port: u16) -> eyre::Result<UdpSocket> {
+    Ok(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?)
+}
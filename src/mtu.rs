@@ -0,0 +1,330 @@
+//! Path MTU discovery: binary search for the largest probe that arrives with
+//! the don't-fragment bit set, then check whether a larger one that has to be
+//! fragmented still gets through. Paths that black-hole either show up as
+//! "random" loss of large packets otherwise.
+//!
+//! Plain probes are used, asking for minimal ACKs so only the upstream
+//! direction is tested. Sizes include the tag of a pre-shared key.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::{self, Psk},
+    json,
+    protocol::{self, Reply},
+    resolve, IpVersion, LocalBind, StopHandle, CLIENT_TO_SERVER_PACKET_SIZE, MAX_PACKET_SIZE,
+    SERVER_TO_CLIENT_PACKET_SIZE,
+};
+
+/// Smallest probe carrying the requested ACK size
+const MIN_SIZE: usize = CLIENT_TO_SERVER_PACKET_SIZE + 2;
+
+/// Settings for an [`MtuTest`].
+#[derive(Clone, Debug)]
+pub struct MtuConfig {
+    /// Server to probe
+    pub host: String,
+    /// Only connect over this IP version
+    pub ip_version: Option<IpVersion>,
+    /// Local address to probe from
+    pub source_addr: Option<IpAddr>,
+    /// Network interface to probe through, Linux only
+    pub interface: Option<String>,
+    /// Largest UDP payload to try
+    pub max_size: usize,
+    /// Probes per size before it counts as not deliverable
+    pub attempts: u32,
+    /// How long to wait for each probe's ACK
+    pub timeout: Duration,
+    /// Key for servers that require one, see [`crate::auth`]
+    pub psk: Option<Psk>,
+}
+
+impl Default for MtuConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1:13337".to_string(),
+            ip_version: None,
+            source_addr: None,
+            interface: None,
+            max_size: 9000,
+            attempts: 3,
+            timeout: Duration::from_secs(1),
+            psk: None,
+        }
+    }
+}
+
+/// Result of an [`MtuTest`].
+#[derive(Clone, Debug)]
+pub struct MtuStats {
+    /// Largest UDP payload that arrived unfragmented
+    pub largest_payload: u32,
+    /// IP and UDP headers on top of the payload
+    pub header_size: u32,
+    /// Whether `largest_payload` is below the configured maximum, i.e. a limit
+    /// was found
+    pub limited: bool,
+    /// Payload of the probe sent fragmented, if a limit was found
+    pub fragmented_size: Option<u32>,
+    /// Whether the fragmented probe arrived
+    pub fragments_delivered: Option<bool>,
+    pub probes_sent: u32,
+}
+
+impl MtuStats {
+    /// Largest IP packet that made it unfragmented.
+    pub fn path_mtu(&self) -> u32 {
+        self.largest_payload + self.header_size
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("largest_payload", self.largest_payload.into())
+            .u64("path_mtu", self.path_mtu().into())
+            .raw("limited", if self.limited { "true" } else { "false" })
+            .raw(
+                "fragmented_size",
+                &self
+                    .fragmented_size
+                    .map_or_else(|| "null".to_string(), |size| size.to_string()),
+            )
+            .raw(
+                "fragments_delivered",
+                self.fragments_delivered
+                    .map_or("null", |delivered| if delivered { "true" } else { "false" }),
+            )
+            .u64("probes_sent", self.probes_sent.into())
+            .finish()
+    }
+}
+
+impl fmt::Display for MtuStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.limited {
+            writeln!(
+                f,
+                "Path MTU       : {} ({}B payload)",
+                self.path_mtu(),
+                self.largest_payload
+            )?;
+        } else {
+            writeln!(
+                f,
+                "Path MTU       : at least {} ({}B payload, the largest tried)",
+                self.path_mtu(),
+                self.largest_payload
+            )?;
+        }
+        if let (Some(size), Some(delivered)) = (self.fragmented_size, self.fragments_delivered) {
+            writeln!(
+                f,
+                "Fragmented     : {} ({size}B payload)",
+                if delivered { "delivered" } else { "dropped" }
+            )?;
+            if !delivered {
+                writeln!(
+                    f,
+                    "Packets above the path MTU are lost, not just fragmented"
+                )?;
+            }
+        }
+        writeln!(f, "Probes sent    : {}", self.probes_sent)
+    }
+}
+
+/// Client side of a path MTU test.
+pub struct MtuTest {
+    config: MtuConfig,
+    socket: UdpSocket,
+    header_size: u32,
+    client_id: u32,
+    seq: u32,
+    probes_sent: u32,
+    done: StopHandle,
+}
+
+impl MtuTest {
+    pub fn new(config: MtuConfig) -> eyre::Result<Self> {
+        let min_size = MIN_SIZE + auth::overhead(config.psk.as_ref());
+        eyre::ensure!(
+            (min_size..=MAX_PACKET_SIZE).contains(&config.max_size),
+            "maximum size must be between {min_size} and {MAX_PACKET_SIZE}"
+        );
+        eyre::ensure!(config.attempts > 0, "attempts must be positive");
+        let local = LocalBind {
+            source_addr: config.source_addr,
+            interface: config.interface.clone(),
+        };
+        let addr = resolve(&config.host, local.ip_version(config.ip_version))?;
+        let socket = local.socket_for(addr)?;
+        let header_size = match addr {
+            SocketAddr::V4(_) => 20 + 8,
+            SocketAddr::V6(_) => 40 + 8,
+        };
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        Ok(Self {
+            config,
+            socket,
+            header_size,
+            client_id: rand::random(),
+            seq: 0,
+            probes_sent: 0,
+            done: StopHandle::default(),
+        })
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.done.clone()
+    }
+
+    /// Whether a probe of `size` got through within the configured attempts.
+    fn delivered(&mut self, size: usize) -> eyre::Result<bool> {
+        let psk = self.config.psk.as_ref();
+        let mut probe = vec![0u8; size - auth::overhead(psk)];
+        let mut buf = [0u8; 64];
+        for _ in 0..self.config.attempts {
+            eyre::ensure!(!self.done.is_stopped(), "stopped");
+            self.seq += 1;
+            let ack_size = Some(SERVER_TO_CLIENT_PACKET_SIZE as u16);
+            protocol::write_probe(&mut probe, 0, self.seq, self.client_id, ack_size);
+            self.probes_sent += 1;
+            match self.socket.send(&auth::seal(psk, &probe)) {
+                Ok(_) => {}
+                // larger than the local interface or a known path MTU
+                Err(e) if too_big(&e) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            let deadline = Instant::now() + self.config.timeout;
+            while Instant::now() < deadline && !self.done.is_stopped() {
+                let n = match self.socket.recv(&mut buf) {
+                    Ok(n) => n,
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                        ) =>
+                    {
+                        continue
+                    }
+                    // ICMP fragmentation needed from a router on the way
+                    Err(e) if too_big(&e) => return Ok(false),
+                    Err(e) => return Err(e.into()),
+                };
+                let Some(n) = auth::open(psk, &buf[..n]) else {
+                    continue;
+                };
+                if let Ok((_, Reply::Ack { seq, .. })) = protocol::reply(&buf[..n]) {
+                    if seq == self.seq {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Search for the path MTU, then send one fragmented probe above it.
+    pub fn run(mut self) -> eyre::Result<MtuStats> {
+        set_dont_fragment(&self.socket, true)?;
+        let min_size = MIN_SIZE + auth::overhead(self.config.psk.as_ref());
+        eyre::ensure!(
+            self.delivered(min_size)?,
+            "no answer from the server even to small probes"
+        );
+        // `low` is known to get through, anything above `high` is not
+        let mut low = min_size;
+        let mut high = self.config.max_size;
+        while low < high {
+            let mid = (low + high).div_ceil(2);
+            if self.delivered(mid)? {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        let limited = low < self.config.max_size;
+
+        let mut fragmented_size = None;
+        let mut fragments_delivered = None;
+        if limited {
+            set_dont_fragment(&self.socket, false)?;
+            // large enough to need a second fragment whatever the headers
+            let size = (low + 512).min(MAX_PACKET_SIZE);
+            fragmented_size = Some(size as u32);
+            fragments_delivered = Some(self.delivered(size)?);
+        }
+        Ok(MtuStats {
+            largest_payload: low as u32,
+            header_size: self.header_size,
+            limited,
+            fragmented_size,
+            fragments_delivered,
+            probes_sent: self.probes_sent,
+        })
+    }
+}
+
+/// Set or clear DF, ignoring the kernel's cached path MTU so larger probes
+/// are still sent and can be seen to fail.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, on: bool) -> eyre::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = match (socket.local_addr()?, on) {
+        (SocketAddr::V4(_), true) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        ),
+        (SocketAddr::V4(_), false) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DONT,
+        ),
+        (SocketAddr::V6(_), true) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        ),
+        (SocketAddr::V6(_), false) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DONT,
+        ),
+    };
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if rv == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn too_big(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn too_big(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &UdpSocket, _on: bool) -> eyre::Result<()> {
+    eyre::bail!("path MTU discovery is only supported on Linux")
+}
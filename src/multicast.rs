@@ -0,0 +1,310 @@
+//! Multicast loss: the server streams sequenced packets to a group and every
+//! client that joins it measures loss and jitter on its own, as multicast
+//! replication problems often affect only some receivers.
+//!
+//! Packets are `[MULTICAST, seq, session, tx_micros]` followed by padding,
+//! where `session` changes when the sender restarts and `tx_micros` is the
+//! sender's clock, only used for differences.
+
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{json, StopHandle, BUF_SIZE, MULTICAST_PACKET_CONST};
+
+/// Smallest multicast packet; larger ones are padded
+pub const MULTICAST_PACKET_MIN_SIZE: usize = 1 + 4 + 4 + 8;
+
+/// Settings for streaming to a multicast group.
+#[derive(Clone, Debug)]
+pub struct MulticastConfig {
+    /// Group and port to send to, e.g. `239.1.2.3:13338`
+    pub group: SocketAddr,
+    pub packets_per_second: u32,
+    /// UDP payload size of each packet
+    pub packet_size: usize,
+    /// Router hops the packets may cross; 1 keeps them on the local network
+    pub ttl: u32,
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        Self {
+            group: SocketAddr::from((Ipv4Addr::new(239, 1, 2, 3), 13338)),
+            packets_per_second: 100,
+            packet_size: MULTICAST_PACKET_MIN_SIZE,
+            ttl: 1,
+        }
+    }
+}
+
+impl MulticastConfig {
+    pub(crate) fn validate(&self) -> eyre::Result<()> {
+        eyre::ensure!(
+            self.group.ip().is_multicast(),
+            "{} is not a multicast address",
+            self.group.ip()
+        );
+        eyre::ensure!(
+            self.packets_per_second > 0,
+            "multicast rate must be positive"
+        );
+        eyre::ensure!(
+            (MULTICAST_PACKET_MIN_SIZE..=BUF_SIZE).contains(&self.packet_size),
+            "multicast packet size must be between {MULTICAST_PACKET_MIN_SIZE} and {BUF_SIZE}"
+        );
+        Ok(())
+    }
+}
+
+/// Send to the group on a background thread until `done`.
+pub(crate) fn spawn_sender(
+    config: MulticastConfig,
+    done: StopHandle,
+) -> eyre::Result<JoinHandle<eyre::Result<()>>> {
+    config.validate()?;
+    let socket = match config.group.ip() {
+        IpAddr::V4(_) => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+            socket.set_multicast_ttl_v4(config.ttl)?;
+            socket
+        }
+        IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+    };
+    Ok(thread::spawn(move || {
+        let mut packet = vec![0u8; config.packet_size];
+        packet[0] = MULTICAST_PACKET_CONST;
+        packet[5..9].copy_from_slice(&rand::random::<u32>().to_be_bytes());
+        let interval = Duration::from_secs(1) / config.packets_per_second;
+        let start = Instant::now();
+        let mut seq = 0u32;
+        while !done.is_stopped() {
+            packet[1..5].copy_from_slice(&seq.to_be_bytes());
+            let tx_micros = start.elapsed().as_micros() as u64;
+            packet[9..17].copy_from_slice(&tx_micros.to_be_bytes());
+            socket.send_to(&packet, config.group)?;
+            seq = seq.wrapping_add(1);
+            if let Some(wait) = (start + interval * seq).checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+        Ok(())
+    }))
+}
+
+/// What one receiver saw of a multicast stream.
+#[derive(Clone, Debug, Default)]
+pub struct MulticastStats {
+    /// Distinct packets received
+    pub received: u32,
+    /// Packets sent from the first to the highest one received
+    pub expected: u32,
+    pub duplicates: u32,
+    /// Packets that arrived after one with a higher sequence number
+    pub reordered: u32,
+    /// Interarrival jitter as in RFC 3550
+    pub jitter: Duration,
+    /// Largest gap between packets
+    pub max_gap: Duration,
+    /// Times the sender restarted, which resets the counters
+    pub sender_restarts: u32,
+    /// Time since the first packet
+    pub elapsed: Duration,
+}
+
+impl MulticastStats {
+    /// Percentage of packets that never arrived.
+    pub fn loss(&self) -> f64 {
+        100.0 * (1.0 - (self.received as f64 / self.expected as f64))
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("received", self.received.into())
+            .u64("expected", self.expected.into())
+            .f64("loss", self.loss())
+            .u64("duplicates", self.duplicates.into())
+            .u64("reordered", self.reordered.into())
+            .f64("jitter_ms", self.jitter.as_secs_f64() * 1000.0)
+            .f64("max_gap_ms", self.max_gap.as_secs_f64() * 1000.0)
+            .u64("sender_restarts", self.sender_restarts.into())
+            .f64("elapsed_secs", self.elapsed.as_secs_f64())
+            .finish()
+    }
+}
+
+impl fmt::Display for MulticastStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Expected       : {}", self.expected)?;
+        writeln!(f, "Received       : {}", self.received)?;
+        writeln!(f, "Multicast loss : {:.2}%", self.loss())?;
+        writeln!(
+            f,
+            "Reordered      : {}, duplicated: {}",
+            self.reordered, self.duplicates
+        )?;
+        writeln!(
+            f,
+            "Jitter         : {:.2}ms",
+            self.jitter.as_secs_f64() * 1000.0
+        )?;
+        writeln!(
+            f,
+            "Max gap        : {:.1}ms",
+            self.max_gap.as_secs_f64() * 1000.0
+        )?;
+        if self.sender_restarts > 0 {
+            writeln!(f, "Sender restarts: {}", self.sender_restarts)?;
+        }
+        writeln!(f, "Time elapsed: {:.2} seconds", self.elapsed.as_secs_f64())
+    }
+}
+
+/// Client side: joins a group and measures what arrives.
+pub struct MulticastReceiver {
+    socket: UdpSocket,
+    duration: Option<Duration>,
+    done: StopHandle,
+}
+
+impl MulticastReceiver {
+    /// Join `group`, on the interface with address `interface` for IPv4 or
+    /// index `interface` for IPv6, or the default one.
+    pub fn new(group: SocketAddr, interface: Option<IpAddr>) -> eyre::Result<Self> {
+        eyre::ensure!(
+            group.ip().is_multicast(),
+            "{} is not a multicast address",
+            group.ip()
+        );
+        let socket = match (group.ip(), interface) {
+            (IpAddr::V4(ip), interface) => {
+                let interface = match interface {
+                    Some(IpAddr::V4(interface)) => interface,
+                    Some(IpAddr::V6(_)) => eyre::bail!("IPv4 groups need an IPv4 interface"),
+                    None => Ipv4Addr::UNSPECIFIED,
+                };
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+                socket.join_multicast_v4(&ip, &interface)?;
+                socket
+            }
+            (IpAddr::V6(ip), interface) => {
+                eyre::ensure!(
+                    interface.is_none(),
+                    "picking the interface for IPv6 groups isn't supported"
+                );
+                let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, group.port()))?;
+                socket.join_multicast_v6(&ip, 0)?;
+                socket
+            }
+        };
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        Ok(Self {
+            socket,
+            duration: None,
+            done: StopHandle::default(),
+        })
+    }
+
+    /// Stop after receiving for this long.
+    pub fn duration(mut self, duration: Option<Duration>) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.done.clone()
+    }
+
+    /// Receive until stopped, calling `on_stats` about once a second, and
+    /// return the final stats.
+    pub fn run(self, mut on_stats: impl FnMut(&MulticastStats)) -> eyre::Result<MulticastStats> {
+        let mut buf = vec![0u8; BUF_SIZE];
+        let mut stats = MulticastStats::default();
+        let mut session = None;
+        let mut first: Option<(u32, Instant)> = None;
+        let mut highest = 0u32;
+        // bitmap of received sequence numbers from the first one on
+        let mut seen: Vec<u64> = Vec::new();
+        // last arrival, and its transit time in microseconds
+        let mut last: Option<(Instant, i64)> = None;
+        let mut jitter_micros = 0.0;
+        let start = Instant::now();
+        let mut last_report = Instant::now();
+
+        while !self.done.is_stopped() && self.duration.is_none_or(|d| start.elapsed() < d) {
+            if last_report.elapsed() >= Duration::from_secs(1) && first.is_some() {
+                last_report = Instant::now();
+                on_stats(&stats);
+            }
+            let n = match self.socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if n < MULTICAST_PACKET_MIN_SIZE || buf[0] != MULTICAST_PACKET_CONST {
+                continue;
+            }
+            let now = Instant::now();
+            let seq = u32::from_be_bytes(buf[1..5].try_into().unwrap());
+            let packet_session = u32::from_be_bytes(buf[5..9].try_into().unwrap());
+            let tx_micros = u64::from_be_bytes(buf[9..17].try_into().unwrap());
+            if session != Some(packet_session) {
+                if session.is_some() {
+                    let restarts = stats.sender_restarts + 1;
+                    stats = MulticastStats {
+                        sender_restarts: restarts,
+                        ..MulticastStats::default()
+                    };
+                    last = None;
+                    jitter_micros = 0.0;
+                }
+                session = Some(packet_session);
+                first = Some((seq, now));
+                highest = seq;
+                seen.clear();
+            }
+            let (first_seq, first_at) = first.unwrap();
+            // packets from before the first one received are ignored
+            let Some(offset) = seq.checked_sub(first_seq) else {
+                continue;
+            };
+            let offset = offset as usize;
+            if offset / 64 >= seen.len() {
+                seen.resize(offset / 64 + 1, 0);
+            }
+            if seen[offset / 64] & (1 << (offset % 64)) != 0 {
+                stats.duplicates += 1;
+                continue;
+            }
+            seen[offset / 64] |= 1 << (offset % 64);
+            stats.received += 1;
+            if seq < highest {
+                stats.reordered += 1;
+            }
+            highest = highest.max(seq);
+            stats.expected = highest - first_seq + 1;
+            stats.elapsed = now - first_at;
+
+            let transit = now.duration_since(start).as_micros() as i64 - tx_micros as i64;
+            if let Some((last_at, last_transit)) = last {
+                stats.max_gap = stats.max_gap.max(now - last_at);
+                let d = (transit - last_transit).abs() as f64;
+                jitter_micros += (d - jitter_micros) / 16.0;
+                stats.jitter = Duration::from_micros(jitter_micros as u64);
+            }
+            last = Some((now, transit));
+        }
+        Ok(stats)
+    }
+}
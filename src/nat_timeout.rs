@@ -0,0 +1,400 @@
+//! NAT mapping timeout characterization: stay silent for longer and longer
+//! idle intervals and see whether the server can still reach the client
+//! after each. NATs and stateful firewalls forget idle UDP mappings, often
+//! within tens of seconds, and drop what the server sends until the client
+//! sends again, so low-rate measurements see loss nothing on the path
+//! causes.
+//!
+//! The client asks `[NAT_TIMEOUT_REQUEST, nonce, client_id, delay_secs]`,
+//! padded to the size of both answers so there's nothing to amplify. The
+//! server answers right away and once more `delay_secs` later with
+//! `[NAT_TIMEOUT_REPLY, nonce, delayed, port]`, `port` being the one it saw
+//! the request come from. The client sends nothing in between, so the late
+//! answer only arrives through a mapping that survived, and another port in
+//! the answer to the next request tells the NAT made a new one.
+
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::{self, Psk},
+    json,
+    protocol::{self, Reply},
+    resolve,
+    session::{self, Session, PROTOCOL_VERSION},
+    with_version, IpVersion, LocalBind, StopHandle, BUF_SIZE,
+};
+
+pub(crate) const NAT_TIMEOUT_REQUEST_PACKET_CONST: u8 = 25;
+pub(crate) const NAT_TIMEOUT_REPLY_PACKET_CONST: u8 = 26;
+pub(crate) const NAT_TIMEOUT_REPLY_SIZE: usize = 1 + 4 + 1 + 2;
+/// Room for both answers, of which only the first 11 bytes are read
+pub(crate) const NAT_TIMEOUT_REQUEST_SIZE: usize = 2 * NAT_TIMEOUT_REPLY_SIZE;
+/// Longest delay the server agrees to answer after
+pub(crate) const MAX_DELAY_SECS: u16 = 600;
+
+/// Settings for a [`NatTimeoutTest`].
+#[derive(Clone, Debug)]
+pub struct NatTimeoutConfig {
+    /// Server to probe
+    pub host: String,
+    /// Only connect over this IP version
+    pub ip_version: Option<IpVersion>,
+    /// Local address to probe from
+    pub source_addr: Option<IpAddr>,
+    /// Network interface to probe through, Linux only
+    pub interface: Option<String>,
+    /// How long to stay silent, in whole seconds, one after the other
+    pub intervals: Vec<Duration>,
+    /// How long to wait for answers, beyond the delay for late ones
+    pub timeout: Duration,
+    /// Requests sent before giving up on the server
+    pub attempts: u32,
+    /// Key for servers that require one, see [`crate::auth`]
+    pub psk: Option<Psk>,
+}
+
+impl Default for NatTimeoutConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1:13337".to_string(),
+            ip_version: None,
+            source_addr: None,
+            interface: None,
+            intervals: [10, 30, 60, 120].map(Duration::from_secs).to_vec(),
+            timeout: Duration::from_secs(2),
+            attempts: 3,
+            psk: None,
+        }
+    }
+}
+
+/// What happened during one idle interval.
+#[derive(Clone, Copy, Debug)]
+pub struct IdleResult {
+    pub idle: Duration,
+    /// Whether the server's answer at the end of it arrived, i.e. the
+    /// mapping was still there
+    pub kept: bool,
+    /// Port the server saw the client at before and after
+    pub port_before: u16,
+    pub port_after: u16,
+}
+
+impl IdleResult {
+    /// Whether the client came out with another mapping than it went in
+    /// with.
+    pub fn remapped(&self) -> bool {
+        self.port_before != self.port_after
+    }
+}
+
+/// Result of a [`NatTimeoutTest`].
+#[derive(Clone, Debug)]
+pub struct NatTimeoutStats {
+    /// One for each interval tried, in order
+    pub results: Vec<IdleResult>,
+    pub requests_sent: u32,
+}
+
+impl NatTimeoutStats {
+    /// The first idle interval the mapping didn't survive, if any.
+    pub fn expired_after(&self) -> Option<Duration> {
+        self.results.iter().find(|r| !r.kept).map(|r| r.idle)
+    }
+
+    /// The longest idle interval below [`Self::expired_after`] the mapping
+    /// survived.
+    pub fn kept_for(&self) -> Option<Duration> {
+        let expired = self.expired_after().unwrap_or(Duration::MAX);
+        self.results
+            .iter()
+            .filter(|r| r.kept && r.idle < expired)
+            .map(|r| r.idle)
+            .max()
+    }
+
+    pub fn to_json(&self) -> String {
+        let secs = |idle: Option<Duration>| {
+            idle.map_or_else(|| "null".to_string(), |idle| idle.as_secs().to_string())
+        };
+        json::Object::new()
+            .raw(
+                "intervals",
+                &json::array(self.results.iter().map(|r| {
+                    json::Object::new()
+                        .u64("idle_secs", r.idle.as_secs())
+                        .raw("kept", if r.kept { "true" } else { "false" })
+                        .u64("port_before", r.port_before.into())
+                        .u64("port_after", r.port_after.into())
+                        .finish()
+                })),
+            )
+            .raw("kept_secs", &secs(self.kept_for()))
+            .raw("expired_secs", &secs(self.expired_after()))
+            .u64("requests_sent", self.requests_sent.into())
+            .finish()
+    }
+}
+
+impl fmt::Display for NatTimeoutStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for r in &self.results {
+            let label = format!("Idle {}s", r.idle.as_secs());
+            write!(
+                f,
+                "{label:<15}: {}",
+                if r.kept {
+                    "mapping kept"
+                } else {
+                    "mapping expired"
+                }
+            )?;
+            if r.remapped() {
+                write!(f, ", port {} now {}", r.port_before, r.port_after)?;
+            }
+            writeln!(f)?;
+        }
+        match (self.kept_for(), self.expired_after()) {
+            (Some(kept), Some(expired)) => writeln!(
+                f,
+                "NAT timeout    : between {}s and {}s",
+                kept.as_secs(),
+                expired.as_secs()
+            )?,
+            (None, Some(expired)) => writeln!(
+                f,
+                "NAT timeout    : under {}s, the shortest tried",
+                expired.as_secs()
+            )?,
+            (_, None) => {
+                if let Some(longest) = self.results.iter().map(|r| r.idle).max() {
+                    writeln!(
+                        f,
+                        "NAT timeout    : over {}s, the longest tried",
+                        longest.as_secs()
+                    )?;
+                }
+            }
+        }
+        if self.expired_after().is_some() {
+            writeln!(
+                f,
+                "Sessions idle for longer lose what the server sends until the client sends again"
+            )?;
+        }
+        writeln!(f, "Requests sent  : {}", self.requests_sent)
+    }
+}
+
+/// `[NAT_TIMEOUT_REQUEST, nonce, client_id, delay_secs]` and padding.
+fn request(
+    version: u8,
+    nonce: u32,
+    client_id: u32,
+    delay_secs: u16,
+) -> [u8; NAT_TIMEOUT_REQUEST_SIZE] {
+    let mut packet = [0u8; NAT_TIMEOUT_REQUEST_SIZE];
+    packet[0] = with_version(NAT_TIMEOUT_REQUEST_PACKET_CONST, version);
+    packet[1..5].copy_from_slice(&nonce.to_be_bytes());
+    packet[5..9].copy_from_slice(&client_id.to_be_bytes());
+    packet[9..11].copy_from_slice(&delay_secs.to_be_bytes());
+    packet
+}
+
+/// `[NAT_TIMEOUT_REPLY, nonce, delayed, port]`
+pub(crate) fn reply(
+    version: u8,
+    nonce: u32,
+    delayed: bool,
+    port: u16,
+) -> [u8; NAT_TIMEOUT_REPLY_SIZE] {
+    let mut packet = [0u8; NAT_TIMEOUT_REPLY_SIZE];
+    packet[0] = with_version(NAT_TIMEOUT_REPLY_PACKET_CONST, version);
+    packet[1..5].copy_from_slice(&nonce.to_be_bytes());
+    packet[5] = delayed.into();
+    packet[6..8].copy_from_slice(&port.to_be_bytes());
+    packet
+}
+
+/// Client side of a NAT timeout test.
+pub struct NatTimeoutTest {
+    config: NatTimeoutConfig,
+    socket: UdpSocket,
+    client_id: u32,
+    version: u8,
+    requests_sent: u32,
+    done: StopHandle,
+}
+
+impl NatTimeoutTest {
+    pub fn new(config: NatTimeoutConfig) -> eyre::Result<Self> {
+        eyre::ensure!(!config.intervals.is_empty(), "no idle intervals to try");
+        for idle in &config.intervals {
+            eyre::ensure!(
+                idle.subsec_nanos() == 0 && (1..=MAX_DELAY_SECS.into()).contains(&idle.as_secs()),
+                "idle intervals must be whole seconds from 1s to {MAX_DELAY_SECS}s"
+            );
+        }
+        eyre::ensure!(config.attempts > 0, "attempts must be positive");
+        let local = LocalBind {
+            source_addr: config.source_addr,
+            interface: config.interface.clone(),
+        };
+        let addr = resolve(&config.host, local.ip_version(config.ip_version))?;
+        let socket = local.socket_for(addr)?;
+        socket.connect(addr)?;
+        let (client_id, version) = open_session(&socket, config.psk.as_ref())?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        Ok(Self {
+            config,
+            socket,
+            client_id,
+            version,
+            requests_sent: 0,
+            done: StopHandle::default(),
+        })
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.done.clone()
+    }
+
+    /// The next answer from the server, waiting until `deadline`.
+    fn recv(&self, buf: &mut [u8], deadline: Instant) -> eyre::Result<Option<Reply>> {
+        let psk = self.config.psk.as_ref();
+        while Instant::now() < deadline && !self.done.is_stopped() {
+            let n = match self.socket.recv(buf) {
+                Ok(n) => n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock
+                            | ErrorKind::TimedOut
+                            | ErrorKind::Interrupted
+                            | ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let Some(n) = auth::open(psk, &buf[..n]) else {
+                continue;
+            };
+            if let Ok((_, reply)) = protocol::reply(&buf[..n]) {
+                return Ok(Some(reply));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Ask for a late answer after `delay`, retrying until the immediate
+    /// one arrives. Returns the request's nonce, the port the server saw
+    /// and when its answer came, or `None` if stopped.
+    fn request(&mut self, delay: Duration) -> eyre::Result<Option<(u32, u16, Instant)>> {
+        let nonce = rand::random();
+        let mut buf = vec![0u8; BUF_SIZE];
+        for _ in 0..self.config.attempts {
+            let packet = request(self.version, nonce, self.client_id, delay.as_secs() as u16);
+            self.requests_sent += 1;
+            self.socket
+                .send(&auth::seal(self.config.psk.as_ref(), &packet))?;
+            let deadline = Instant::now() + self.config.timeout;
+            while let Some(reply) = self.recv(&mut buf, deadline)? {
+                match reply {
+                    Reply::NatTimeout {
+                        nonce: answered,
+                        delayed: false,
+                        port,
+                    } if answered == nonce => return Ok(Some((nonce, port, Instant::now()))),
+                    // forgotten, or with cookies seen from a new port
+                    Reply::UnknownSession { client_id } if client_id == self.client_id => {
+                        (self.client_id, self.version) =
+                            open_session(&self.socket, self.config.psk.as_ref())?;
+                        self.socket
+                            .set_read_timeout(Some(Duration::from_millis(100)))?;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            if self.done.is_stopped() {
+                return Ok(None);
+            }
+        }
+        eyre::bail!("no answer from the server to NAT timeout requests; it may be too old")
+    }
+
+    /// Whether the late answer to `nonce` arrives by `deadline`, `None` if
+    /// stopped before.
+    fn late_answer(&self, nonce: u32, deadline: Instant) -> eyre::Result<Option<bool>> {
+        let mut buf = vec![0u8; BUF_SIZE];
+        while let Some(reply) = self.recv(&mut buf, deadline)? {
+            if let Reply::NatTimeout {
+                nonce: answered,
+                delayed: true,
+                ..
+            } = reply
+            {
+                if answered == nonce {
+                    return Ok(Some(true));
+                }
+            }
+        }
+        Ok((!self.done.is_stopped()).then_some(false))
+    }
+
+    /// Go through the idle intervals, each ending in the request for the
+    /// next, and a last one asking for no late answer. Stopping early
+    /// returns the intervals done.
+    pub fn run(mut self) -> eyre::Result<NatTimeoutStats> {
+        let mut results = Vec::new();
+        let intervals = self.config.intervals.clone();
+        let Some(mut pending) = self.request(intervals[0])? else {
+            eyre::bail!("stopped");
+        };
+        for (i, &idle) in intervals.iter().enumerate() {
+            let (nonce, port_before, answered) = pending;
+            let Some(kept) = self.late_answer(nonce, answered + idle + self.config.timeout)? else {
+                break;
+            };
+            let next = intervals.get(i + 1).copied().unwrap_or(Duration::ZERO);
+            let Some(next) = self.request(next)? else {
+                break;
+            };
+            results.push(IdleResult {
+                idle,
+                kept,
+                port_before,
+                port_after: next.1,
+            });
+            pending = next;
+        }
+        Ok(NatTimeoutStats {
+            results,
+            requests_sent: self.requests_sent,
+        })
+    }
+}
+
+/// Handshake for a session to send requests in, as the server only answers
+/// them in known ones. Returns its token and protocol version.
+fn open_session(socket: &UdpSocket, psk: Option<&Psk>) -> eyre::Result<(u32, u8)> {
+    let proposal = Session {
+        version: PROTOCOL_VERSION,
+        packets_per_second: 1,
+        probe_size: NAT_TIMEOUT_REQUEST_SIZE as u16,
+        features: 0,
+        token: rand::random(),
+    };
+    let session = session::handshake(socket, proposal, None, psk)?.map(|welcome| welcome.session);
+    Ok((
+        session.map_or(proposal.token, |session| session.token),
+        session.map_or(0, |session| session.negotiated_version()),
+    ))
+}
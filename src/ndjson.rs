@@ -0,0 +1,68 @@
+//! A client's per-interval stats and its events as JSON lines on stdout, for
+//! log shippers like Vector or fluent-bit to tail the process directly.
+//! Lines are shaped like the Kafka messages:
+//!
+//! ```text
+//! {"kind": "stats", "host": HOST, "labels": {KEY: VALUE}, "unix_secs": SECS,
+//!  "stats": STATS}
+//! {"kind": "event", "host": HOST, "labels": {KEY: VALUE}, "unix_secs": SECS,
+//!  "event": EVENT}
+//! ```
+//!
+//! with `STATS` and `EVENT` as serialized by [`Stats::to_json`] and
+//! [`Event::to_json`], and a last line of kind `summary` with the final
+//! stats in `summary`. Each line is flushed right away.
+
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{client::labels_json, json, Event, Stats};
+
+/// Writes JSON lines to stdout. Cloning is cheap, so clones can be moved
+/// into the stats and event callbacks.
+#[derive(Clone, Debug)]
+pub struct NdjsonWriter {
+    host: String,
+    labels: String,
+}
+
+impl NdjsonWriter {
+    /// Lines about `host`, carrying `labels` as in
+    /// [`ClientConfig::labels`](crate::ClientConfig::labels).
+    pub fn new(host: &str, labels: &[(String, String)]) -> Self {
+        Self {
+            host: host.to_string(),
+            labels: labels_json(labels),
+        }
+    }
+
+    pub fn stats(&self, stats: &Stats) {
+        self.write("stats", &stats.to_json());
+    }
+
+    pub fn event(&self, event: &Event) {
+        self.write("event", &event.to_json());
+    }
+
+    pub fn summary(&self, stats: &Stats) {
+        self.write("summary", &stats.to_json());
+    }
+
+    fn write(&self, kind: &str, json: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = json::Object::new()
+            .str("kind", kind)
+            .str("host", &self.host)
+            .raw("labels", &self.labels)
+            .f64("unix_secs", now.as_secs_f64())
+            .raw(kind, json)
+            .finish();
+        let mut stdout = io::stdout().lock();
+        // a reader going away mustn't end the measurement
+        let _ = writeln!(stdout, "{line}").and_then(|()| stdout.flush());
+    }
+}
@@ -0,0 +1,461 @@
+//! Encrypted sessions: instead of the plain handshake the client can run a
+//! `Noise_IK_25519_ChaChaPoly_SHA256` handshake (see noiseprotocol.org)
+//! carrying it, knowing the server's static key in advance. Every packet
+//! after that is encrypted and authenticated with keys of that session, so
+//! middleboxes see neither packet kinds nor sequence numbers and can't single
+//! out probes to deprioritize them, or tamper with them unnoticed.
+//!
+//! The handshake is `[NOISE_INIT, client_index, e, s, payload, cookie]`
+//! carrying the padded session handshake, with the cookie if the server asked
+//! for one in plaintext after it, so the server checks it before spending any
+//! key exchange on spoofed sources. It's answered by `[NOISE_RESP, client_index, e,
+//! payload]` carrying the welcome. Then packets are sent as `[NOISE_DATA,
+//! receiver_index, nonce, ciphertext]`, wrapping what would be sent in
+//! plaintext; the server's index is the session token. Nonces are explicit as
+//! UDP loses and reorders packets, and replays are dropped.
+//!
+//! Clients aren't authenticated, a fresh static key is used for every
+//! handshake; see [`crate::auth`] for keeping strangers out. Cookies and
+//! unknown session answers stay in plaintext, as do hop tracing, MTU tests
+//! and floods.
+
+use std::{
+    borrow::Cow,
+    fmt, fs,
+    io::{ErrorKind, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    crypto::{self, x25519, x25519_base, AEAD_TAG_SIZE},
+    hash::{hmac_sha256, sha256},
+    replay::ReplayWindow,
+    NOISE_DATA_PACKET_CONST, NOISE_INIT_PACKET_CONST, NOISE_RESP_PACKET_CONST,
+};
+
+/// Exactly `HASHLEN` long, so it's the initial hash as is
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_IK_25519_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"loss_lens";
+const KEY_SIZE: usize = 32;
+const DATA_HEADER_SIZE: usize = 1 + 4 + 8;
+/// Bytes encryption adds to every packet
+pub const OVERHEAD: usize = DATA_HEADER_SIZE + AEAD_TAG_SIZE;
+
+/// Public X25519 key, written as 64 hex digits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(pub [u8; KEY_SIZE]);
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({self})")
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        Ok(Self(parse_hex_key(s)?))
+    }
+}
+
+fn parse_hex_key(s: &str) -> eyre::Result<[u8; KEY_SIZE]> {
+    let s = s.trim();
+    eyre::ensure!(
+        s.len() == 2 * KEY_SIZE && s.is_ascii(),
+        "keys are {} hex digits",
+        2 * KEY_SIZE
+    );
+    let mut key = [0u8; KEY_SIZE];
+    for (byte, digits) in key.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)?;
+    }
+    Ok(key)
+}
+
+/// Static key pair of a server, identifying it to clients.
+#[derive(Clone)]
+pub struct Keypair {
+    secret: [u8; KEY_SIZE],
+    public: PublicKey,
+}
+
+impl Keypair {
+    pub fn generate() -> Self {
+        Self::from_secret(rand::random())
+    }
+
+    pub fn from_secret(secret: [u8; KEY_SIZE]) -> Self {
+        Self {
+            secret,
+            public: PublicKey(x25519_base(&secret)),
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+
+    /// Load the secret key, as hex, from `path`, or generate one and store it
+    /// there if the file doesn't exist yet.
+    pub fn load_or_generate(path: &Path) -> eyre::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(hex) => Ok(Self::from_secret(parse_hex_key(&hex).map_err(|e| {
+                eyre::eyre!("reading the key in {}: {e}", path.display())
+            })?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let keypair = Self::generate();
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                let mut file = options.open(path)?;
+                for byte in keypair.secret {
+                    write!(file, "{byte:02x}")?;
+                }
+                writeln!(file)?;
+                Ok(keypair)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// never print the secret
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Keypair({})", self.public)
+    }
+}
+
+fn dh(secret: &[u8; KEY_SIZE], public: &[u8; KEY_SIZE]) -> Option<[u8; KEY_SIZE]> {
+    let shared = x25519(secret, public);
+    // low-order points, which would make the key predictable
+    (shared != [0; KEY_SIZE]).then_some(shared)
+}
+
+fn hkdf(chaining_key: &[u8; 32], input: &[u8]) -> [[u8; 32]; 2] {
+    let temp = hmac_sha256(chaining_key, input);
+    let first = hmac_sha256(&temp, &[1]);
+    let mut data = [0u8; 33];
+    data[..32].copy_from_slice(&first);
+    data[32] = 2;
+    [first, hmac_sha256(&temp, &data)]
+}
+
+fn nonce(n: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&n.to_le_bytes());
+    nonce
+}
+
+/// Noise `SymmetricState`
+#[derive(Clone)]
+struct Symmetric {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+    key: Option<[u8; 32]>,
+    n: u64,
+}
+
+impl Symmetric {
+    fn new(responder: &PublicKey) -> Self {
+        let mut state = Self {
+            chaining_key: *PROTOCOL_NAME,
+            hash: *PROTOCOL_NAME,
+            key: None,
+            n: 0,
+        };
+        state.mix_hash(PROLOGUE);
+        // IK: the initiator knows the responder's static key
+        state.mix_hash(&responder.0);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut input = Vec::with_capacity(32 + data.len());
+        input.extend_from_slice(&self.hash);
+        input.extend_from_slice(data);
+        self.hash = sha256(&input);
+    }
+
+    fn mix_key(&mut self, input: &[u8; 32]) {
+        let [chaining_key, key] = hkdf(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        self.key = Some(key);
+        self.n = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match self.key {
+            Some(key) => {
+                let ciphertext = crypto::seal(&key, &nonce(self.n), &self.hash, plaintext);
+                self.n += 1;
+                ciphertext
+            }
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let key = self.key?;
+        let mut plaintext = ciphertext.to_vec();
+        let len = crypto::open(&key, &nonce(self.n), &self.hash, &mut plaintext)?;
+        plaintext.truncate(len);
+        self.n += 1;
+        self.mix_hash(ciphertext);
+        Some(plaintext)
+    }
+
+    /// Keys for the initiator's and the responder's packets.
+    fn split(&self) -> [[u8; 32]; 2] {
+        hkdf(&self.chaining_key, &[])
+    }
+}
+
+/// Client side of a handshake waiting for the server's answer.
+pub(crate) struct Initiator {
+    state: Symmetric,
+    ephemeral: [u8; KEY_SIZE],
+    static_secret: [u8; KEY_SIZE],
+    index: u32,
+}
+
+impl Initiator {
+    /// Start a handshake with the server owning `server`, carrying `payload`.
+    /// Returns the first message to send, `None` for keys that can't be
+    /// used.
+    pub fn new(server: &PublicKey, payload: &[u8]) -> Option<(Self, Vec<u8>)> {
+        let mut state = Symmetric::new(server);
+        let ephemeral = Keypair::generate();
+        let static_key = Keypair::generate();
+        let index = rand::random::<u32>();
+        let mut message = vec![NOISE_INIT_PACKET_CONST];
+        message.extend_from_slice(&index.to_be_bytes());
+        // -> e, es, s, ss
+        message.extend_from_slice(&ephemeral.public.0);
+        state.mix_hash(&ephemeral.public.0);
+        state.mix_key(&dh(&ephemeral.secret, &server.0)?);
+        message.extend_from_slice(&state.encrypt_and_hash(&static_key.public.0));
+        state.mix_key(&dh(&static_key.secret, &server.0)?);
+        message.extend_from_slice(&state.encrypt_and_hash(payload));
+        Some((
+            Self {
+                state,
+                ephemeral: ephemeral.secret,
+                static_secret: static_key.secret,
+                index,
+            },
+            message,
+        ))
+    }
+
+    /// The payload of the server's answer and the session, `None` if
+    /// `packet` isn't a valid answer to this handshake.
+    pub fn finish(&self, packet: &[u8]) -> Option<(Vec<u8>, Transport)> {
+        if packet.len() < 1 + 4 + KEY_SIZE + AEAD_TAG_SIZE
+            || packet[0] != NOISE_RESP_PACKET_CONST
+            || packet[1..5] != self.index.to_be_bytes()
+        {
+            return None;
+        }
+        let mut state = self.state.clone();
+        // <- e, ee, se
+        let remote_ephemeral: [u8; KEY_SIZE] = packet[5..37].try_into().unwrap();
+        state.mix_hash(&remote_ephemeral);
+        state.mix_key(&dh(&self.ephemeral, &remote_ephemeral)?);
+        state.mix_key(&dh(&self.static_secret, &remote_ephemeral)?);
+        let payload = state.decrypt_and_hash(&packet[37..])?;
+        let [send, receive] = state.split();
+        Some((payload, Transport::new(send, receive)))
+    }
+}
+
+/// A handshake the server received, to be answered with
+/// [`Incoming::respond`].
+pub(crate) struct Incoming {
+    state: Symmetric,
+    remote_ephemeral: [u8; KEY_SIZE],
+    remote_static: [u8; KEY_SIZE],
+    /// The client's index, for packets to it
+    pub index: u32,
+    /// What the client sent along, the session handshake
+    pub payload: Vec<u8>,
+}
+
+impl Incoming {
+    /// Read the first message of a handshake with `keypair`, `None` if it's
+    /// not a valid one.
+    pub fn read(keypair: &Keypair, packet: &[u8]) -> Option<Self> {
+        let encrypted_static = 1 + 4 + KEY_SIZE..1 + 4 + 2 * KEY_SIZE + AEAD_TAG_SIZE;
+        if packet.len() < encrypted_static.end + AEAD_TAG_SIZE
+            || packet[0] != NOISE_INIT_PACKET_CONST
+        {
+            return None;
+        }
+        let mut state = Symmetric::new(&keypair.public);
+        let remote_ephemeral: [u8; KEY_SIZE] = packet[5..37].try_into().unwrap();
+        state.mix_hash(&remote_ephemeral);
+        state.mix_key(&dh(&keypair.secret, &remote_ephemeral)?);
+        let remote_static: [u8; KEY_SIZE] = state
+            .decrypt_and_hash(&packet[encrypted_static.clone()])?
+            .try_into()
+            .ok()?;
+        state.mix_key(&dh(&keypair.secret, &remote_static)?);
+        let payload = state.decrypt_and_hash(&packet[encrypted_static.end..])?;
+        Some(Self {
+            state,
+            remote_ephemeral,
+            remote_static,
+            index: u32::from_be_bytes(packet[1..5].try_into().unwrap()),
+            payload,
+        })
+    }
+
+    /// The answer carrying `payload`, and the session.
+    pub fn respond(mut self, payload: &[u8]) -> Option<(Vec<u8>, Transport)> {
+        let ephemeral = Keypair::generate();
+        let mut message = vec![NOISE_RESP_PACKET_CONST];
+        message.extend_from_slice(&self.index.to_be_bytes());
+        message.extend_from_slice(&ephemeral.public.0);
+        self.state.mix_hash(&ephemeral.public.0);
+        self.state
+            .mix_key(&dh(&ephemeral.secret, &self.remote_ephemeral)?);
+        self.state
+            .mix_key(&dh(&ephemeral.secret, &self.remote_static)?);
+        message.extend_from_slice(&self.state.encrypt_and_hash(payload));
+        let [receive, send] = self.state.split();
+        Some((message, Transport::new(send, receive)))
+    }
+}
+
+/// Keys of an established session in both directions.
+pub(crate) struct Transport {
+    send_key: [u8; 32],
+    receive_key: [u8; 32],
+    send_nonce: AtomicU64,
+    /// Nonces up to 960 behind the highest one are still accepted once
+    replays: Mutex<ReplayWindow<16>>,
+}
+
+impl Transport {
+    fn new(send_key: [u8; 32], receive_key: [u8; 32]) -> Self {
+        Self {
+            send_key,
+            receive_key,
+            send_nonce: AtomicU64::new(0),
+            replays: Mutex::new(ReplayWindow::default()),
+        }
+    }
+
+    /// `packet` encrypted for the peer, which knows the session as `index`.
+    pub fn seal(&self, index: u32, packet: &[u8]) -> Vec<u8> {
+        let n = self.send_nonce.fetch_add(1, Ordering::Relaxed);
+        let mut header = [0u8; DATA_HEADER_SIZE];
+        header[0] = NOISE_DATA_PACKET_CONST;
+        header[1..5].copy_from_slice(&index.to_be_bytes());
+        header[5..].copy_from_slice(&n.to_be_bytes());
+        let mut sealed = header.to_vec();
+        sealed.extend_from_slice(&crypto::seal(&self.send_key, &nonce(n), &header, packet));
+        sealed
+    }
+
+    /// Decrypt a `NOISE_DATA` packet to the start of `packet`, returning its
+    /// length. `None` for forged, corrupted and replayed packets.
+    pub fn open(&self, packet: &mut [u8]) -> Option<usize> {
+        if packet.len() < OVERHEAD || packet[0] != NOISE_DATA_PACKET_CONST {
+            return None;
+        }
+        let n = u64::from_be_bytes(packet[5..DATA_HEADER_SIZE].try_into().unwrap());
+        if !self.replays.lock().unwrap().is_fresh(n) {
+            return None;
+        }
+        let (header, data) = packet.split_at_mut(DATA_HEADER_SIZE);
+        let len = crypto::open(&self.receive_key, &nonce(n), header, data)?;
+        // only counted as seen once it's known to be genuine
+        if !self.replays.lock().unwrap().insert(n) {
+            return None;
+        }
+        packet.copy_within(DATA_HEADER_SIZE..DATA_HEADER_SIZE + len, 0);
+        Some(len)
+    }
+}
+
+/// Receiver index of a `NOISE_DATA` packet.
+pub(crate) fn data_index(packet: &[u8]) -> Option<u32> {
+    (packet.len() >= OVERHEAD && packet[0] == NOISE_DATA_PACKET_CONST)
+        .then(|| u32::from_be_bytes(packet[1..5].try_into().unwrap()))
+}
+
+/// `packet` as sent, encrypted if there's a session.
+pub(crate) fn seal<'a>(session: Option<(&Transport, u32)>, packet: &'a [u8]) -> Cow<'a, [u8]> {
+    match session {
+        Some((transport, index)) => Cow::Owned(transport.seal(index, packet)),
+        None => Cow::Borrowed(packet),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_round_trip() {
+        let server = Keypair::generate();
+        let (initiator, message) = Initiator::new(&server.public(), b"hello").unwrap();
+        let incoming = Incoming::read(&server, &message).unwrap();
+        assert_eq!(incoming.payload, b"hello");
+        let client_index = incoming.index;
+        let (answer, server_transport) = incoming.respond(b"welcome").unwrap();
+        let (payload, client_transport) = initiator.finish(&answer).unwrap();
+        assert_eq!(payload, b"welcome");
+
+        let packet = client_transport.seal(7, b"probe");
+        assert_eq!(data_index(&packet), Some(7));
+        let len = server_transport.open(&mut packet.clone()).unwrap();
+        assert_eq!(len, 5);
+        // replayed
+        assert_eq!(server_transport.open(&mut packet.clone()), None);
+        let mut packet = server_transport.seal(client_index, b"ack");
+        let len = client_transport.open(&mut packet).unwrap();
+        assert_eq!(&packet[..len], b"ack");
+    }
+
+    #[test]
+    fn tampering_is_rejected() {
+        let server = Keypair::generate();
+        let (initiator, message) = Initiator::new(&server.public(), b"hello").unwrap();
+        let mut tampered = message.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Incoming::read(&server, &tampered).is_none());
+        assert!(Incoming::read(&Keypair::generate(), &message).is_none());
+
+        let incoming = Incoming::read(&server, &message).unwrap();
+        let (answer, server_transport) = incoming.respond(b"welcome").unwrap();
+        let mut tampered = answer.clone();
+        tampered[40] ^= 1;
+        assert!(initiator.finish(&tampered).is_none());
+        let (_, client_transport) = initiator.finish(&answer).unwrap();
+
+        let mut packet = server_transport.seal(1, b"ack");
+        packet[DATA_HEADER_SIZE] ^= 1;
+        assert_eq!(client_transport.open(&mut packet), None);
+        packet[DATA_HEADER_SIZE] ^= 1;
+        packet[5] ^= 1;
+        // the header is authenticated too
+        assert_eq!(client_transport.open(&mut packet), None);
+    }
+}
@@ -0,0 +1,172 @@
+//! Periodic loss, e.g. a Wi-Fi access point scanning channels on a timer:
+//! the lost probes are counted per 100ms of probing and the autocorrelation
+//! of those counts is searched for peaks. Time is reckoned from sequence
+//! numbers at the configured rate, so pauses of a duty cycle don't count
+//! and periods are only approximate under adaptive rate control.
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::json;
+
+const BIN: Duration = Duration::from_millis(100);
+/// Shorter periods are hard to tell from bursts of loss
+const MIN_PERIOD: Duration = Duration::from_secs(1);
+const MAX_PERIOD: Duration = Duration::from_secs(300);
+/// Only the latest bins are searched, bounding the work to tens of
+/// milliseconds
+const MAX_BINS: usize = 36_000;
+/// Weaker autocorrelation isn't reported as a period
+const MIN_CONFIDENCE: f64 = 0.2;
+/// Fewer losses can't show a pattern
+const MIN_LOST: u64 = 8;
+/// Periods reported at most
+const MAX_PERIODS: usize = 3;
+
+/// A period loss recurs with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LossPeriod {
+    pub period: Duration,
+    /// Autocorrelation of the loss at that lag, from 0 to 1 for loss
+    /// recurring like clockwork
+    pub confidence: f64,
+}
+
+impl LossPeriod {
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .f64("period_secs", self.period.as_secs_f64())
+            .f64("confidence", self.confidence)
+            .finish()
+    }
+}
+
+/// Probes lost per 100ms.
+pub(crate) struct LossSeries {
+    probes_per_bin: f64,
+    /// Bin of `bins[0]`
+    first_bin: u64,
+    bins: VecDeque<u32>,
+    lost: u64,
+}
+
+impl LossSeries {
+    pub(crate) fn new(packets_per_second: u32) -> Self {
+        Self {
+            probes_per_bin: f64::from(packets_per_second) * BIN.as_secs_f64(),
+            first_bin: 0,
+            bins: VecDeque::new(),
+            lost: 0,
+        }
+    }
+
+    /// Count probe `seq` as arrived or lost.
+    pub(crate) fn record(&mut self, seq: u64, lost: bool) {
+        let bin = (seq as f64 / self.probes_per_bin) as u64;
+        if self.bins.is_empty() {
+            self.first_bin = bin;
+        }
+        let Some(i) = bin.checked_sub(self.first_bin) else {
+            return;
+        };
+        while self.bins.len() as u64 <= i {
+            self.bins.push_back(0);
+        }
+        while self.bins.len() > MAX_BINS {
+            self.bins.pop_front();
+            self.first_bin += 1;
+        }
+        if lost {
+            if let Some(count) = self.bins.get_mut((bin - self.first_bin) as usize) {
+                *count += 1;
+            }
+            self.lost += 1;
+        }
+    }
+
+    /// Periods of the loss so far, most pronounced first, leaving out
+    /// multiples of those reported.
+    pub(crate) fn periods(&self) -> Vec<LossPeriod> {
+        let min_lag = (MIN_PERIOD.as_secs_f64() / BIN.as_secs_f64()) as usize;
+        let max_lag = ((MAX_PERIOD.as_secs_f64() / BIN.as_secs_f64()) as usize)
+            // at least a few repetitions
+            .min(self.bins.len() / 3);
+        if self.lost < MIN_LOST || max_lag <= min_lag + 1 {
+            return Vec::new();
+        }
+        let n = self.bins.len() as f64;
+        let mean = self.bins.iter().map(|&count| f64::from(count)).sum::<f64>() / n;
+        let x: Vec<f64> = self.bins.iter().map(|&c| f64::from(c) - mean).collect();
+        let variance = x.iter().map(|x| x * x).sum::<f64>();
+        if variance == 0.0 {
+            return Vec::new();
+        }
+        let r: Vec<f64> = (0..=max_lag)
+            .map(|lag| {
+                if lag < min_lag - 1 {
+                    return 0.0;
+                }
+                x.iter().zip(&x[lag..]).map(|(a, b)| a * b).sum::<f64>() / variance
+            })
+            .collect();
+        // beyond what chance would correlate at the 0.1% level
+        let threshold = MIN_CONFIDENCE.max(3.3 / n.sqrt());
+        let mut peaks: Vec<(f64, f64)> = (min_lag..max_lag)
+            .filter(|&lag| r[lag] >= threshold && r[lag] >= r[lag - 1] && r[lag] > r[lag + 1])
+            .map(|lag| {
+                // vertex of the parabola through the peak and its neighbours
+                let (left, mid, right) = (r[lag - 1], r[lag], r[lag + 1]);
+                let curvature = left - 2.0 * mid + right;
+                let offset = if curvature < 0.0 {
+                    0.5 * (left - right) / curvature
+                } else {
+                    0.0
+                };
+                (lag as f64 + offset, mid)
+            })
+            .collect();
+        peaks.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut periods: Vec<f64> = Vec::new();
+        for &(lag, _) in &peaks {
+            let harmonic = periods.iter().any(|&period| {
+                let multiple = (lag / period).round();
+                multiple >= 1.0 && (lag - multiple * period).abs() <= 1.0 + 0.02 * lag
+            });
+            if !harmonic {
+                periods.push(lag);
+            }
+        }
+        periods
+            .into_iter()
+            .take(MAX_PERIODS)
+            .map(|lag| LossPeriod {
+                period: BIN.mul_f64(lag),
+                confidence: r[lag.round() as usize].min(1.0),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_loss_every_ten_seconds() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut series = LossSeries::new(50);
+        for seq in 0..50 * 600 {
+            // half a second of loss every 10s, and scattered random loss
+            let scan = seq % 500 < 25;
+            series.record(seq, scan || rng.random_bool(0.02));
+        }
+        let periods = series.periods();
+        let period = periods[0].period.as_secs_f64();
+        assert!((9.9..=10.1).contains(&period), "{periods:?}");
+        assert!(periods[0].confidence > 0.5, "{periods:?}");
+        assert!(periods[1..]
+            .iter()
+            .all(|p| p.confidence < periods[0].confidence));
+    }
+}
@@ -0,0 +1,440 @@
+//! Asking the local gateway to forward the server's port, so a reflector
+//! hosted at home is reachable without configuring the router. NAT-PMP (RFC
+//! 6886) is tried first, as it's a single UDP exchange, then UPnP IGD: an
+//! SSDP search for the gateway, its description over HTTP, and SOAP calls to
+//! its WAN connection service.
+//!
+//! Mappings are leased for an hour, renewed halfway through while the
+//! server runs and removed when it stops. IPv4 only, as IPv6 needs no NAT.
+
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    http::{self, Url},
+    StopHandle,
+};
+
+/// How long mappings are asked for
+const LEASE: Duration = Duration::from_secs(3600);
+
+const NAT_PMP_PORT: u16 = 5351;
+const NAT_PMP_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_MAP_UDP: u8 = 1;
+/// Answers have the request's opcode plus this
+const NAT_PMP_ANSWER: u8 = 128;
+/// Wait for an answer this long at first, doubling with every retry, see
+/// RFC 6886 section 3.1
+const NAT_PMP_TIMEOUT: Duration = Duration::from_millis(250);
+const NAT_PMP_ATTEMPTS: u32 = 4;
+
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+/// How long gateways get to answer the search
+const SSDP_WAIT: Duration = Duration::from_secs(3);
+const GATEWAY_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services able to map ports, by preference
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+/// UPnP error for gateways that only map ports for good
+const ONLY_PERMANENT_LEASES: &str = "725";
+const CONFLICTING_MAPPING: &str = "718";
+
+/// Protocol a mapping was made with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    NatPmp,
+    Upnp,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Method::NatPmp => "NAT-PMP",
+            Method::Upnp => "UPnP",
+        })
+    }
+}
+
+/// A port the gateway forwards to the server.
+#[derive(Clone, Debug)]
+pub struct PortMapping {
+    /// Where clients on the internet reach the server
+    pub external: SocketAddr,
+    /// The server's address the gateway forwards to
+    pub internal: SocketAddr,
+    pub method: Method,
+    gateway: Gateway,
+}
+
+#[derive(Clone, Debug)]
+enum Gateway {
+    NatPmp(SocketAddr),
+    Upnp {
+        control: Url,
+        service: &'static str,
+        /// 0 where the gateway only maps ports for good
+        lease_secs: u64,
+    },
+}
+
+/// Map the port of the server at `local`, with NAT-PMP or else UPnP.
+pub(crate) fn map(local: SocketAddr) -> eyre::Result<PortMapping> {
+    let local_ip = match local.ip() {
+        IpAddr::V4(ip) => Some(ip).filter(|ip| !ip.is_unspecified()),
+        IpAddr::V6(ip) if ip.is_unspecified() => None,
+        IpAddr::V6(_) => eyre::bail!("port mapping needs a server listening on IPv4"),
+    };
+    let nat_pmp = match default_gateway() {
+        Some(gateway) => match map_nat_pmp(gateway, local_ip, local.port()) {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => e.to_string(),
+        },
+        None => "no default gateway found".to_string(),
+    };
+    map_upnp(local_ip, local.port())
+        .map_err(|e| eyre::eyre!("couldn't map the port: NAT-PMP: {nat_pmp}; UPnP: {e}"))
+}
+
+/// Renew `mapping` on a background thread until `done`, then remove it.
+pub(crate) fn spawn_renewal(mapping: PortMapping, done: StopHandle) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let lease_secs = match mapping.gateway {
+            Gateway::NatPmp(_) => LEASE.as_secs(),
+            Gateway::Upnp { lease_secs, .. } => lease_secs,
+        };
+        let mut renew_at = Instant::now() + Duration::from_secs(lease_secs / 2);
+        while !done.is_stopped() {
+            thread::sleep(Duration::from_millis(100));
+            if lease_secs > 0 && Instant::now() >= renew_at {
+                // a minute's room to try again before the lease runs out
+                let retry = if renew(&mapping, LEASE).is_ok() {
+                    lease_secs / 2
+                } else {
+                    60
+                };
+                renew_at = Instant::now() + Duration::from_secs(retry);
+            }
+        }
+        // leases run out anyway if this doesn't get through
+        let _ = unmap(&mapping);
+    })
+}
+
+fn renew(mapping: &PortMapping, lease: Duration) -> eyre::Result<()> {
+    match &mapping.gateway {
+        Gateway::NatPmp(gateway) => {
+            nat_pmp_map(*gateway, mapping.internal, mapping.external.port(), lease)?;
+        }
+        Gateway::Upnp {
+            control, service, ..
+        } => add_port_mapping(control, service, mapping, lease.as_secs())?,
+    }
+    Ok(())
+}
+
+fn unmap(mapping: &PortMapping) -> eyre::Result<()> {
+    match &mapping.gateway {
+        // a lifetime of 0 removes the mapping
+        Gateway::NatPmp(gateway) => {
+            nat_pmp_map(*gateway, mapping.internal, 0, Duration::ZERO)?;
+        }
+        Gateway::Upnp {
+            control, service, ..
+        } => {
+            let args = format!(
+                "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>UDP</NewProtocol>",
+                mapping.external.port()
+            );
+            soap(control, service, "DeletePortMapping", &args)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    // columns are Iface, Destination, Gateway, ..., addresses in hex in
+    // host byte order
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// The address of this host facing `remote`, where the server listens on
+/// any.
+fn internal_ip(local_ip: Option<Ipv4Addr>, remote: SocketAddr) -> eyre::Result<Ipv4Addr> {
+    if let Some(ip) = local_ip {
+        return Ok(ip);
+    }
+    // connecting picks the source address the route takes
+    let route = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    route.connect(remote)?;
+    match route.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => eyre::bail!("no IPv4 address facing the gateway"),
+    }
+}
+
+fn map_nat_pmp(
+    gateway: Ipv4Addr,
+    local_ip: Option<Ipv4Addr>,
+    port: u16,
+) -> eyre::Result<PortMapping> {
+    let gateway = SocketAddr::from((gateway, NAT_PMP_PORT));
+    let internal = SocketAddr::from((internal_ip(local_ip, gateway)?, port));
+    let answer = nat_pmp_request(gateway, internal, &[0, NAT_PMP_EXTERNAL_ADDRESS], 12)?;
+    let external_ip = Ipv4Addr::new(answer[8], answer[9], answer[10], answer[11]);
+    let external_port = nat_pmp_map(gateway, internal, port, LEASE)?;
+    Ok(PortMapping {
+        external: SocketAddr::from((external_ip, external_port)),
+        internal,
+        method: Method::NatPmp,
+        gateway: Gateway::NatPmp(gateway),
+    })
+}
+
+/// Map UDP port `internal`, preferably to `external_port`, returning the
+/// external port the gateway picked.
+fn nat_pmp_map(
+    gateway: SocketAddr,
+    internal: SocketAddr,
+    external_port: u16,
+    lease: Duration,
+) -> eyre::Result<u16> {
+    let mut request = [0u8; 12];
+    request[1] = NAT_PMP_MAP_UDP;
+    request[4..6].copy_from_slice(&internal.port().to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&(lease.as_secs() as u32).to_be_bytes());
+    let answer = nat_pmp_request(gateway, internal, &request, 16)?;
+    Ok(u16::from_be_bytes([answer[10], answer[11]]))
+}
+
+/// The gateway's successful answer to `request`, of at least `size` bytes.
+/// Sent from the `internal` address, as mappings are for the one asking.
+fn nat_pmp_request(
+    gateway: SocketAddr,
+    internal: SocketAddr,
+    request: &[u8],
+    size: usize,
+) -> eyre::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((internal.ip(), 0))?;
+    socket.connect(gateway)?;
+    let mut buf = [0u8; 64];
+    let mut timeout = NAT_PMP_TIMEOUT;
+    for _ in 0..NAT_PMP_ATTEMPTS {
+        socket.send(request)?;
+        let deadline = Instant::now() + timeout;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                // nothing listening on the gateway
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                    eyre::bail!("the gateway {} doesn't speak it", gateway.ip())
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if n < size || buf[0] != 0 || buf[1] != request[1] + NAT_PMP_ANSWER {
+                continue;
+            }
+            return match u16::from_be_bytes([buf[2], buf[3]]) {
+                0 => Ok(buf[..n].to_vec()),
+                2 => Err(eyre::eyre!("the gateway refused")),
+                3 => Err(eyre::eyre!("the gateway isn't connected")),
+                4 => Err(eyre::eyre!("the gateway is out of ports")),
+                code => Err(eyre::eyre!("the gateway failed with result code {code}")),
+            };
+        }
+        timeout *= 2;
+    }
+    eyre::bail!("no answer from the gateway {}", gateway.ip())
+}
+
+fn map_upnp(local_ip: Option<Ipv4Addr>, port: u16) -> eyre::Result<PortMapping> {
+    let location = discover_gateway()?;
+    let (status, description) = http::request(&location, "GET", &[], "")?;
+    eyre::ensure!(
+        status == 200,
+        "the gateway's description at {location} answered with status {status}"
+    );
+    let (service, control) = wan_service(&description)
+        .ok_or_else(|| eyre::eyre!("the gateway at {location} can't map ports"))?;
+    let control = location.join(control);
+    let gateway = control.addr()?;
+    let mut mapping = PortMapping {
+        external: SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        internal: SocketAddr::from((internal_ip(local_ip, gateway)?, port)),
+        method: Method::Upnp,
+        gateway: Gateway::Upnp {
+            control: control.clone(),
+            service,
+            lease_secs: LEASE.as_secs(),
+        },
+    };
+    let lease_secs = match add_port_mapping(&control, service, &mapping, LEASE.as_secs()) {
+        Err(e) if e.to_string().contains(ONLY_PERMANENT_LEASES) => {
+            add_port_mapping(&control, service, &mapping, 0)?;
+            0
+        }
+        Err(e) if e.to_string().contains(CONFLICTING_MAPPING) => {
+            eyre::bail!("the gateway already forwards port {port} elsewhere")
+        }
+        result => result.map(|()| LEASE.as_secs())?,
+    };
+    let answer = soap(&control, service, "GetExternalIPAddress", "")?;
+    let external = tag(&answer, "NewExternalIPAddress")
+        .and_then(|ip| ip.trim().parse::<Ipv4Addr>().ok())
+        .ok_or_else(|| eyre::eyre!("the gateway didn't tell its external address"))?;
+    mapping.external = SocketAddr::from((external, port));
+    mapping.gateway = Gateway::Upnp {
+        control,
+        service,
+        lease_secs,
+    };
+    Ok(mapping)
+}
+
+/// Location of the first gateway answering an SSDP search.
+fn discover_gateway() -> eyre::Result<Url> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_GROUP}:{SSDP_PORT}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {GATEWAY_DEVICE}\r\n\r\n"
+    );
+    let sent = Instant::now();
+    // repeated, as it's multicast and may get lost
+    for _ in 0..2 {
+        socket.send_to(search.as_bytes(), (SSDP_GROUP, SSDP_PORT))?;
+    }
+    let mut buf = [0u8; 2048];
+    while let Some(left) = SSDP_WAIT
+        .checked_sub(sent.elapsed())
+        .filter(|d| !d.is_zero())
+    {
+        socket.set_read_timeout(Some(left))?;
+        let n = match socket.recv(&mut buf) {
+            Ok(n) => n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let answer = String::from_utf8_lossy(&buf[..n]);
+        let location = answer.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim())
+        });
+        if let Some(url) = location.and_then(Url::parse) {
+            return Ok(url);
+        }
+    }
+    eyre::bail!("no gateway answered the search")
+}
+
+/// The preferred service in the gateway's `description` able to map ports,
+/// with its control URL.
+fn wan_service(description: &str) -> Option<(&'static str, &str)> {
+    let services: Vec<_> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| Some((tag(service, "serviceType")?, tag(service, "controlURL")?)))
+        .collect();
+    WAN_SERVICES.into_iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(service, _)| service.trim() == wanted)
+            .map(|&(_, control)| (wanted, control.trim()))
+    })
+}
+
+fn add_port_mapping(
+    control: &Url,
+    service: &str,
+    mapping: &PortMapping,
+    lease_secs: u64,
+) -> eyre::Result<()> {
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{}</NewExternalPort>\
+         <NewProtocol>UDP</NewProtocol>\
+         <NewInternalPort>{}</NewInternalPort>\
+         <NewInternalClient>{}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>loss_lens</NewPortMappingDescription>\
+         <NewLeaseDuration>{lease_secs}</NewLeaseDuration>",
+        mapping.external.port(),
+        mapping.internal.port(),
+        mapping.internal.ip(),
+    );
+    soap(control, service, "AddPortMapping", &args)?;
+    Ok(())
+}
+
+/// Call `action` of `service`, returning the answer's body.
+fn soap(control: &Url, service: &str, action: &str, args: &str) -> eyre::Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body></s:Envelope>"
+    );
+    let soap_action = format!("\"{service}#{action}\"");
+    let (status, answer) = http::request(
+        control,
+        "POST",
+        &[
+            ("Content-Type", "text/xml; charset=\"utf-8\""),
+            ("SOAPAction", &soap_action),
+        ],
+        &body,
+    )?;
+    if status != 200 {
+        let code = tag(&answer, "errorCode").unwrap_or("none");
+        let description = tag(&answer, "errorDescription").unwrap_or("");
+        eyre::bail!("{action} failed with status {status}, UPnP error {code} {description}");
+    }
+    Ok(answer)
+}
+
+/// Text of the first `<name>` element in `xml`, namespaced or not.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = xml
+        .find(&format!("<{name}>"))
+        .map(|at| at + name.len() + 2)?;
+    let close = xml[open..].find(&format!("</{name}>"))?;
+    Some(&xml[open..open + close])
+}
@@ -0,0 +1,675 @@
+//! Parsing and encoding of the packets exchanged by client and server.
+//!
+//! Servers exposed to the internet get all sorts of packets from scanners,
+//! so every packet is checked here once, for a known kind, a version this
+//! build speaks and the length its kind needs, before anything reads its
+//! fields. Callers then match on what it is instead of indexing bytes.
+//!
+//! Encrypted packets are unwrapped before they get here, see
+//! [`crate::noise`], as are PSK tags, see [`crate::auth`]. Floods,
+//! multicast streams and hop replies are parsed by their own modules.
+//!
+//! Public so the fuzz targets in `fuzz/` can reach it.
+
+use std::fmt;
+
+use crate::{
+    capacity::{TRAIN_ACK_PACKET_CONST, TRAIN_ACK_SIZE, TRAIN_PACKET_CONST},
+    flood::{FLOOD_PACKET_CONST, FLOOD_REQUEST_PACKET_CONST, FLOOD_REQUEST_SIZE},
+    hash::crc32,
+    nat_timeout::{
+        NAT_TIMEOUT_REPLY_PACKET_CONST, NAT_TIMEOUT_REPLY_SIZE, NAT_TIMEOUT_REQUEST_PACKET_CONST,
+        NAT_TIMEOUT_REQUEST_SIZE,
+    },
+    rendezvous::{NAME_SIZE, REGISTER_PACKET_CONST, REGISTER_RELAY, REGISTER_SIZE},
+    session::{self, Session, COOKIE_ANSWER_SIZE, COOKIE_SIZE, HANDSHAKE_SIZE, PROTOCOL_VERSION},
+    split_kind, with_version, ACK_ECN_PACKET_CONST, ACK_PACKET_CONST, CLIENT_TO_SERVER_PACKET_SIZE,
+    COOKIE_PACKET_CONST, HELLO_PACKET_CONST, HOP_PACKET_CONST, HOP_REPLY_PACKET_CONST,
+    MULTICAST_PACKET_CONST, NOISE_DATA_PACKET_CONST, NOISE_INIT_PACKET_CONST,
+    NOISE_RESP_PACKET_CONST, SEQ_NUM_PACKET_CONST, SERVER_PROBE_ACK_PACKET_CONST,
+    SERVER_PROBE_PACKET_CONST, SERVER_TO_CLIENT_PACKET_SIZE, UNKNOWN_SESSION_PACKET_CONST,
+    WELCOME_PACKET_CONST,
+};
+
+/// Probes at least this long carry the requested ACK size
+pub const PADDED_PROBE_MIN_SIZE: usize = CLIENT_TO_SERVER_PACKET_SIZE + 2;
+/// Probes with a checksum end in it, after the requested ACK size and the
+/// padding, see [`write_checksum`]
+pub const CHECKSUM_PROBE_MIN_SIZE: usize = PADDED_PROBE_MIN_SIZE + 4;
+/// ACKs in sessions with checksums carry the server's count of corrupted
+/// probes: `[ACK, seq, received, ecn, corrupted]`, the ECN field only
+/// meaningful in `ACK_ECN`
+pub const CHECKSUM_ACK_SIZE: usize = SERVER_TO_CLIENT_PACKET_SIZE + 1 + 4;
+/// ACKs in sessions with epochs end in the server's, where the probe has
+/// room for it: `[ACK, seq, received, ecn, corrupted, epoch]`, see
+/// [`session::FEATURE_EPOCH`]
+pub const EPOCH_ACK_SIZE: usize = CHECKSUM_ACK_SIZE + 4;
+/// The server's own probes in symmetric sessions carry their send time
+pub const SERVER_PROBE_SIZE: usize = 1 + 4 + 4 + 8;
+/// The client's answers to them carry its count of them
+pub const SERVER_PROBE_ACK_SIZE: usize = 1 + 4 + 4 + 4;
+/// Train packets carry their index and the train's length after the header
+const TRAIN_PACKET_MIN_SIZE: usize = CLIENT_TO_SERVER_PACKET_SIZE + 2;
+
+/// What a packet is, from the low bits of its first byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    Hello = HELLO_PACKET_CONST,
+    Probe = SEQ_NUM_PACKET_CONST,
+    Ack = ACK_PACKET_CONST,
+    FloodRequest = FLOOD_REQUEST_PACKET_CONST,
+    Flood = FLOOD_PACKET_CONST,
+    Train = TRAIN_PACKET_CONST,
+    TrainAck = TRAIN_ACK_PACKET_CONST,
+    AckEcn = ACK_ECN_PACKET_CONST,
+    Hop = HOP_PACKET_CONST,
+    HopReply = HOP_REPLY_PACKET_CONST,
+    Multicast = MULTICAST_PACKET_CONST,
+    Welcome = WELCOME_PACKET_CONST,
+    UnknownSession = UNKNOWN_SESSION_PACKET_CONST,
+    Cookie = COOKIE_PACKET_CONST,
+    NoiseInit = NOISE_INIT_PACKET_CONST,
+    NoiseResp = NOISE_RESP_PACKET_CONST,
+    NoiseData = NOISE_DATA_PACKET_CONST,
+    ServerProbe = SERVER_PROBE_PACKET_CONST,
+    ServerProbeAck = SERVER_PROBE_ACK_PACKET_CONST,
+    Register = REGISTER_PACKET_CONST,
+    NatTimeoutRequest = NAT_TIMEOUT_REQUEST_PACKET_CONST,
+    NatTimeoutReply = NAT_TIMEOUT_REPLY_PACKET_CONST,
+}
+
+impl Kind {
+    const ALL: [Kind; 22] = [
+        Kind::Hello,
+        Kind::Probe,
+        Kind::Ack,
+        Kind::FloodRequest,
+        Kind::Flood,
+        Kind::Train,
+        Kind::TrainAck,
+        Kind::AckEcn,
+        Kind::Hop,
+        Kind::HopReply,
+        Kind::Multicast,
+        Kind::Welcome,
+        Kind::UnknownSession,
+        Kind::Cookie,
+        Kind::NoiseInit,
+        Kind::NoiseResp,
+        Kind::NoiseData,
+        Kind::ServerProbe,
+        Kind::ServerProbeAck,
+        Kind::Register,
+        Kind::NatTimeoutRequest,
+        Kind::NatTimeoutReply,
+    ];
+
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|&k| k as u8 == kind)
+    }
+
+    /// Shortest packet of this kind
+    fn min_size(self) -> usize {
+        match self {
+            Kind::Hello | Kind::Probe | Kind::Ack | Kind::Hop | Kind::UnknownSession => {
+                CLIENT_TO_SERVER_PACKET_SIZE
+            }
+            Kind::AckEcn => SERVER_TO_CLIENT_PACKET_SIZE + 1,
+            Kind::Train => TRAIN_PACKET_MIN_SIZE,
+            Kind::TrainAck => TRAIN_ACK_SIZE,
+            Kind::FloodRequest => FLOOD_REQUEST_SIZE,
+            Kind::Welcome => HANDSHAKE_SIZE,
+            Kind::Cookie => COOKIE_ANSWER_SIZE,
+            Kind::ServerProbe => SERVER_PROBE_SIZE,
+            Kind::ServerProbeAck => SERVER_PROBE_ACK_SIZE,
+            Kind::Register => REGISTER_SIZE,
+            Kind::NatTimeoutRequest => NAT_TIMEOUT_REQUEST_SIZE,
+            Kind::NatTimeoutReply => NAT_TIMEOUT_REPLY_SIZE,
+            // checked where they're parsed
+            Kind::Flood
+            | Kind::HopReply
+            | Kind::Multicast
+            | Kind::NoiseInit
+            | Kind::NoiseResp
+            | Kind::NoiseData => 1,
+        }
+    }
+}
+
+/// Why a packet was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    Empty,
+    UnknownKind(u8),
+    /// Marked with a version newer than this build's
+    Version(u8),
+    /// Shorter than its kind needs
+    Truncated {
+        kind: Kind,
+        len: usize,
+    },
+    /// A valid packet, but not one the receiving side expects
+    Unexpected(Kind),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Empty => f.write_str("empty packet"),
+            Error::UnknownKind(kind) => write!(f, "unknown packet kind {kind}"),
+            Error::Version(version) => write!(f, "unsupported protocol version {version}"),
+            Error::Truncated { kind, len } => write!(f, "{kind:?} packet of only {len} bytes"),
+            Error::Unexpected(kind) => write!(f, "unexpected {kind:?} packet"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// First byte of a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub kind: Kind,
+    /// Protocol version the packet is marked with, 0 if unmarked
+    pub version: u8,
+}
+
+/// The header of `packet`, if it's of a known kind and version and long
+/// enough for that kind. Marking starts with version 2, see `with_version`,
+/// so packets marked 1 are rejected too, as nothing sends them.
+pub fn header(packet: &[u8]) -> Result<Header, Error> {
+    let &first = packet.first().ok_or(Error::Empty)?;
+    let (kind, version) = split_kind(first);
+    let kind = Kind::from_u8(kind).ok_or(Error::UnknownKind(kind))?;
+    if version > PROTOCOL_VERSION || version == 1 {
+        return Err(Error::Version(version));
+    }
+    if packet.len() < kind.min_size() {
+        return Err(Error::Truncated {
+            kind,
+            len: packet.len(),
+        });
+    }
+    Ok(Header { kind, version })
+}
+
+/// A packet the server answers, as sent by a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// The periodic rate announcement
+    Hello {
+        packets_per_second: u32,
+        client_id: u32,
+    },
+    /// Opens a session, see [`crate::session`]; a cookie echo follows it in
+    /// the padding
+    Handshake(Session),
+    Probe {
+        seq: u32,
+        client_id: u32,
+        /// Requested size of the ACK, in padded probes
+        ack_size: Option<u16>,
+    },
+    /// TTL-limited probe, echoed as it is to the session it names
+    Hop { client_id: u32 },
+    Train {
+        train_id: u32,
+        client_id: u32,
+        index: u8,
+    },
+    FloodRequest {
+        packets_per_second: u32,
+        client_id: u32,
+        duration_millis: u32,
+        packet_size: u16,
+    },
+    /// Answer to a probe of the server's own in a symmetric session
+    ServerProbeAck {
+        seq: u32,
+        client_id: u32,
+        /// Server probes the client received in this session so far
+        received: u32,
+    },
+    /// A client looking for another to probe directly, see
+    /// [`crate::rendezvous`]
+    Register {
+        nonce: u32,
+        name: [u8; NAME_SIZE],
+        /// Whether punching through failed, so the client asks to be relayed
+        relay: bool,
+        /// Punching attempts the client made before
+        attempt: u8,
+    },
+    /// Asks for an answer now and another after `delay_secs`, see
+    /// [`crate::nat_timeout`]
+    NatTimeout {
+        nonce: u32,
+        client_id: u32,
+        delay_secs: u16,
+    },
+}
+
+impl Request {
+    /// Client id or session token the packet was sent under.
+    pub fn client_id(&self) -> Option<u32> {
+        match *self {
+            Request::Hello { client_id, .. }
+            | Request::Probe { client_id, .. }
+            | Request::Train { client_id, .. }
+            | Request::FloodRequest { client_id, .. }
+            | Request::ServerProbeAck { client_id, .. }
+            | Request::Hop { client_id }
+            | Request::NatTimeout { client_id, .. } => Some(client_id),
+            Request::Handshake(_) | Request::Register { .. } => None,
+        }
+    }
+}
+
+/// Parse a packet received by the server.
+pub fn request(packet: &[u8]) -> Result<(Header, Request), Error> {
+    let header = header(packet)?;
+    let request = match header.kind {
+        Kind::Hello if packet.len() >= HANDSHAKE_SIZE => {
+            Request::Handshake(Session::decode(packet).unwrap())
+        }
+        Kind::Hello => Request::Hello {
+            packets_per_second: u32_at(packet, 1),
+            client_id: u32_at(packet, 5),
+        },
+        Kind::Probe => Request::Probe {
+            seq: u32_at(packet, 1),
+            client_id: u32_at(packet, 5),
+            ack_size: (packet.len() >= PADDED_PROBE_MIN_SIZE).then(|| u16_at(packet, 9)),
+        },
+        Kind::Hop => Request::Hop {
+            client_id: u32_at(packet, 5),
+        },
+        Kind::Train => Request::Train {
+            train_id: u32_at(packet, 1),
+            client_id: u32_at(packet, 5),
+            index: packet[9],
+        },
+        Kind::FloodRequest => Request::FloodRequest {
+            packets_per_second: u32_at(packet, 1),
+            client_id: u32_at(packet, 5),
+            duration_millis: u32_at(packet, 9),
+            packet_size: u16_at(packet, 13),
+        },
+        Kind::ServerProbeAck => Request::ServerProbeAck {
+            seq: u32_at(packet, 1),
+            client_id: u32_at(packet, 5),
+            received: u32_at(packet, 9),
+        },
+        Kind::Register => Request::Register {
+            nonce: u32_at(packet, 1),
+            name: packet[5..5 + NAME_SIZE].try_into().unwrap(),
+            relay: packet[5 + NAME_SIZE] & REGISTER_RELAY != 0,
+            attempt: packet[6 + NAME_SIZE],
+        },
+        Kind::NatTimeoutRequest => Request::NatTimeout {
+            nonce: u32_at(packet, 1),
+            client_id: u32_at(packet, 5),
+            delay_secs: u16_at(packet, 9),
+        },
+        kind => return Err(Error::Unexpected(kind)),
+    };
+    Ok((header, request))
+}
+
+/// A packet the client receives from the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reply {
+    Ack {
+        seq: u32,
+        /// Probes the server received in this session so far
+        received: u32,
+        /// ECN field the probe arrived with, if it was sent ECN-capable
+        ecn: Option<u8>,
+    },
+    TrainAck {
+        train_id: u32,
+        index: u8,
+        /// Server time the train packet arrived at
+        rx_micros: u64,
+    },
+    Welcome(Session),
+    UnknownSession {
+        client_id: u32,
+    },
+    Cookie([u8; COOKIE_SIZE]),
+    /// Probe of the server's own in a symmetric session
+    ServerProbe {
+        seq: u32,
+        client_id: u32,
+        /// Server time it was sent at
+        sent_micros: u64,
+    },
+    /// Answer to a NAT timeout request, right away or `delayed`
+    NatTimeout {
+        nonce: u32,
+        delayed: bool,
+        /// Source port the server saw the request come from
+        port: u16,
+    },
+}
+
+/// Parse a packet received by the client.
+pub fn reply(packet: &[u8]) -> Result<(Header, Reply), Error> {
+    let header = header(packet)?;
+    let reply = match header.kind {
+        Kind::Ack | Kind::AckEcn => Reply::Ack {
+            seq: u32_at(packet, 1),
+            received: u32_at(packet, 5),
+            ecn: (header.kind == Kind::AckEcn).then(|| packet[SERVER_TO_CLIENT_PACKET_SIZE]),
+        },
+        Kind::TrainAck => Reply::TrainAck {
+            train_id: u32_at(packet, 1),
+            index: packet[5],
+            rx_micros: u64::from_be_bytes(packet[6..14].try_into().unwrap()),
+        },
+        Kind::Welcome => Reply::Welcome(Session::decode(packet).unwrap()),
+        Kind::UnknownSession => Reply::UnknownSession {
+            client_id: u32_at(packet, 5),
+        },
+        Kind::Cookie => Reply::Cookie(session::decode_cookie(packet).unwrap()),
+        Kind::ServerProbe => Reply::ServerProbe {
+            seq: u32_at(packet, 1),
+            client_id: u32_at(packet, 5),
+            sent_micros: u64::from_be_bytes(packet[9..17].try_into().unwrap()),
+        },
+        Kind::NatTimeoutReply => Reply::NatTimeout {
+            nonce: u32_at(packet, 1),
+            delayed: packet[5] != 0,
+            port: u16_at(packet, 6),
+        },
+        kind => return Err(Error::Unexpected(kind)),
+    };
+    Ok((header, reply))
+}
+
+/// `[HELLO, packets_per_second, client_id]`
+pub fn hello(
+    version: u8,
+    packets_per_second: u32,
+    client_id: u32,
+) -> [u8; CLIENT_TO_SERVER_PACKET_SIZE] {
+    let mut packet = [0u8; CLIENT_TO_SERVER_PACKET_SIZE];
+    packet[0] = with_version(HELLO_PACKET_CONST, version);
+    packet[1..5].copy_from_slice(&packets_per_second.to_be_bytes());
+    packet[5..9].copy_from_slice(&client_id.to_be_bytes());
+    packet
+}
+
+/// Write the header of probe `seq` into `buf`, which is followed by
+/// padding; the ACK size only fits in padded probes.
+pub fn write_probe(buf: &mut [u8], version: u8, seq: u32, client_id: u32, ack_size: Option<u16>) {
+    buf[0] = with_version(SEQ_NUM_PACKET_CONST, version);
+    buf[1..5].copy_from_slice(&seq.to_be_bytes());
+    buf[5..9].copy_from_slice(&client_id.to_be_bytes());
+    if let Some(ack_size) = ack_size.filter(|_| buf.len() >= PADDED_PROBE_MIN_SIZE) {
+        buf[9..11].copy_from_slice(&ack_size.to_be_bytes());
+    }
+}
+
+/// The padding of `probe` beyond its header, free to fill with anything.
+pub fn payload(probe: &mut [u8]) -> &mut [u8] {
+    let start = PADDED_PROBE_MIN_SIZE.min(probe.len());
+    &mut probe[start..]
+}
+
+/// Fill the padding of `probe` with a pattern seeded by `seq`, so a
+/// checksum covers more than zeros.
+pub fn write_pattern(probe: &mut [u8], seq: u32) {
+    // xorshift32, whose state must not be 0
+    let mut state = (seq ^ 0x9e37_79b9).max(1);
+    for chunk in payload(probe).chunks_mut(4) {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        chunk.copy_from_slice(&state.to_be_bytes()[..chunk.len()]);
+    }
+}
+
+/// End `probe`, whose header and padding are already written, in a CRC-32
+/// of the rest, so probes corrupted on the way are told apart from lost
+/// ones. Links mangling payloads, or checksum offload bugs hiding it from
+/// UDP, then show.
+pub fn write_checksum(probe: &mut [u8]) {
+    let end = probe.len() - 4;
+    let crc = crc32(&probe[..end]);
+    probe[end..].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// Whether `probe` ends in the CRC-32 of the rest of it.
+pub fn checksum_valid(probe: &[u8]) -> bool {
+    if probe.len() < CHECKSUM_PROBE_MIN_SIZE {
+        return false;
+    }
+    let (data, crc) = probe.split_at(probe.len() - 4);
+    crc32(data).to_be_bytes() == crc
+}
+
+/// The server's count of corrupted probes in an ACK of a session with
+/// checksums.
+pub fn corrupted(ack: &[u8]) -> Option<u32> {
+    (ack.len() >= CHECKSUM_ACK_SIZE).then(|| u32_at(ack, SERVER_TO_CLIENT_PACKET_SIZE + 1))
+}
+
+/// The server's epoch in an ACK of a session with epochs.
+pub fn epoch(ack: &[u8]) -> Option<u32> {
+    (ack.len() >= EPOCH_ACK_SIZE).then(|| u32_at(ack, CHECKSUM_ACK_SIZE))
+}
+
+/// Turn `probe` into its ACK in place and return the ACK's length: padded
+/// as requested, but never larger than the probe itself so the server can't
+/// be used for amplification. Only probes that arrived ECN-capable, with a
+/// nonzero `ecn`, get the longer ACK, which older clients wouldn't
+/// understand, only sessions with checksums get the count of `corrupted`
+/// probes, and only sessions with epochs the `epoch`.
+pub fn write_ack(
+    probe: &mut [u8],
+    version: u8,
+    ack_size: Option<u16>,
+    received: u32,
+    ecn: u8,
+    corrupted: Option<u32>,
+    epoch: Option<u32>,
+) -> usize {
+    let mut len = ack_size.map_or(SERVER_TO_CLIENT_PACKET_SIZE, |requested| {
+        usize::from(requested).clamp(SERVER_TO_CLIENT_PACKET_SIZE, probe.len())
+    });
+    probe[0] = with_version(ACK_PACKET_CONST, version);
+    probe[5..9].copy_from_slice(&received.to_be_bytes());
+    if ecn != 0 && probe.len() > SERVER_TO_CLIENT_PACKET_SIZE {
+        probe[0] = with_version(ACK_ECN_PACKET_CONST, version);
+        probe[SERVER_TO_CLIENT_PACKET_SIZE] = ecn;
+        len = len.max(SERVER_TO_CLIENT_PACKET_SIZE + 1);
+    }
+    if let Some(corrupted) = corrupted.filter(|_| probe.len() >= CHECKSUM_ACK_SIZE) {
+        probe[SERVER_TO_CLIENT_PACKET_SIZE + 1..CHECKSUM_ACK_SIZE]
+            .copy_from_slice(&corrupted.to_be_bytes());
+        len = len.max(CHECKSUM_ACK_SIZE);
+    }
+    if let Some(epoch) = epoch.filter(|_| probe.len() >= EPOCH_ACK_SIZE) {
+        // zero unless counted, as the probe's padding is still there
+        if corrupted.is_none() {
+            probe[SERVER_TO_CLIENT_PACKET_SIZE + 1..CHECKSUM_ACK_SIZE].fill(0);
+        }
+        if ecn == 0 {
+            probe[SERVER_TO_CLIENT_PACKET_SIZE] = 0;
+        }
+        probe[CHECKSUM_ACK_SIZE..EPOCH_ACK_SIZE].copy_from_slice(&epoch.to_be_bytes());
+        len = len.max(EPOCH_ACK_SIZE);
+    }
+    len
+}
+
+/// `[UNKNOWN_SESSION, 0, client_id]`
+pub fn unknown_session(version: u8, client_id: u32) -> [u8; SERVER_TO_CLIENT_PACKET_SIZE] {
+    let mut packet = [0u8; SERVER_TO_CLIENT_PACKET_SIZE];
+    packet[0] = with_version(UNKNOWN_SESSION_PACKET_CONST, version);
+    packet[5..9].copy_from_slice(&client_id.to_be_bytes());
+    packet
+}
+
+/// `[SERVER_PROBE, seq, client_id, sent_micros]`
+pub fn server_probe(
+    version: u8,
+    seq: u32,
+    client_id: u32,
+    sent_micros: u64,
+) -> [u8; SERVER_PROBE_SIZE] {
+    let mut packet = [0u8; SERVER_PROBE_SIZE];
+    packet[0] = with_version(SERVER_PROBE_PACKET_CONST, version);
+    packet[1..5].copy_from_slice(&seq.to_be_bytes());
+    packet[5..9].copy_from_slice(&client_id.to_be_bytes());
+    packet[9..17].copy_from_slice(&sent_micros.to_be_bytes());
+    packet
+}
+
+/// `[SERVER_PROBE_ACK, seq, client_id, received]`
+pub fn server_probe_ack(
+    version: u8,
+    seq: u32,
+    client_id: u32,
+    received: u32,
+) -> [u8; SERVER_PROBE_ACK_SIZE] {
+    let mut packet = [0u8; SERVER_PROBE_ACK_SIZE];
+    packet[0] = with_version(SERVER_PROBE_ACK_PACKET_CONST, version);
+    packet[1..5].copy_from_slice(&seq.to_be_bytes());
+    packet[5..9].copy_from_slice(&client_id.to_be_bytes());
+    packet[9..13].copy_from_slice(&received.to_be_bytes());
+    packet
+}
+
+/// The 64-bit counter whose low 32 bits are `wire` closest to `near`.
+///
+/// Sequence numbers and receive counters only carry 32 bits, which wrap
+/// after days at high rates, so both sides count in 64 bits and widen what
+/// they receive relative to the latest value.
+pub fn widen(wire: u32, near: u64) -> u64 {
+    let delta = wire.wrapping_sub(near as u32) as i32;
+    near.checked_add_signed(delta.into()).unwrap_or(wire.into())
+}
+
+fn u32_at(packet: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(packet[at..at + 4].try_into().unwrap())
+}
+
+fn u16_at(packet: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes(packet[at..at + 2].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(version: u8, len: usize) -> Vec<u8> {
+        let mut probe = vec![0; len];
+        write_probe(&mut probe, version, 7, 42, Some(64));
+        probe
+    }
+
+    #[test]
+    fn packets_are_checked_before_being_read() {
+        assert_eq!(header(&[]), Err(Error::Empty));
+        for kind in [0, 21, 27, 31] {
+            assert_eq!(header(&[kind; 32]), Err(Error::UnknownKind(kind)));
+        }
+        let truncated = Error::Truncated {
+            kind: Kind::Probe,
+            len: CLIENT_TO_SERVER_PACKET_SIZE - 1,
+        };
+        assert_eq!(
+            request(&probe(2, CLIENT_TO_SERVER_PACKET_SIZE)[..CLIENT_TO_SERVER_PACKET_SIZE - 1]),
+            Err(truncated)
+        );
+        // 1 marks nothing, see `with_version`
+        for version in [1, PROTOCOL_VERSION + 1, 7] {
+            let mut packet = probe(0, CLIENT_TO_SERVER_PACKET_SIZE);
+            packet[0] |= version << 5;
+            assert_eq!(request(&packet), Err(Error::Version(version)));
+        }
+        // valid, but sent the other way
+        assert_eq!(reply(&probe(2, 64)), Err(Error::Unexpected(Kind::Probe)));
+        let answer = unknown_session(2, 42);
+        assert_eq!(
+            request(&answer),
+            Err(Error::Unexpected(Kind::UnknownSession))
+        );
+    }
+
+    #[test]
+    fn packets_parse_back() {
+        let (header, parsed) = request(&probe(2, CLIENT_TO_SERVER_PACKET_SIZE)).unwrap();
+        assert_eq!(header.version, 2);
+        let unpadded = Request::Probe {
+            seq: 7,
+            client_id: 42,
+            ack_size: None,
+        };
+        assert_eq!(parsed, unpadded);
+        // unmarked, and as long as UDP allows
+        let (header, parsed) = request(&probe(0, crate::MAX_PACKET_SIZE)).unwrap();
+        assert_eq!(header.version, 0);
+        let padded = Request::Probe {
+            seq: 7,
+            client_id: 42,
+            ack_size: Some(64),
+        };
+        assert_eq!(parsed, padded);
+        let hello = request(&hello(2, 100, 42)).unwrap().1;
+        let expected = Request::Hello {
+            packets_per_second: 100,
+            client_id: 42,
+        };
+        assert_eq!(hello, expected);
+
+        let mut ack = probe(2, 64);
+        let len = write_ack(&mut ack, 2, None, 5, 2, None, None);
+        let expected = Reply::Ack {
+            seq: 7,
+            received: 5,
+            ecn: Some(2),
+        };
+        assert_eq!(reply(&ack[..len]).unwrap().1, expected);
+        assert_eq!(
+            reply(&unknown_session(2, 42)).unwrap().1,
+            Reply::UnknownSession { client_id: 42 }
+        );
+        let expected = Reply::ServerProbe {
+            seq: 3,
+            client_id: 42,
+            sent_micros: 1 << 40,
+        };
+        assert_eq!(reply(&server_probe(2, 3, 42, 1 << 40)).unwrap().1, expected);
+    }
+
+    #[test]
+    fn acks_never_outgrow_their_probe() {
+        for len in [CLIENT_TO_SERVER_PACKET_SIZE, 10, 11, 15, 18, 22, 64, 1400] {
+            for ack_size in [None, Some(0), Some(20), Some(u16::MAX)] {
+                for (ecn, corrupted, epoch) in
+                    [(0, None, None), (2, Some(1), Some(9)), (0, None, Some(9))]
+                {
+                    let mut packet = probe(2, len);
+                    let ack_len = write_ack(&mut packet, 2, ack_size, 5, ecn, corrupted, epoch);
+                    assert!(ack_len <= len, "{ack_len} > {len}");
+                    assert!(reply(&packet[..ack_len]).is_ok());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn widening_crosses_the_wrap_both_ways() {
+        let wrap = 1u64 << 32;
+        assert_eq!(widen(5, 3), 5);
+        assert_eq!(widen(1, wrap - 2), wrap + 1);
+        // a late one from before the wrap
+        assert_eq!(widen(u32::MAX - 4, wrap + 3), wrap - 5);
+        // nothing below zero
+        assert_eq!(widen(u32::MAX, 0), u64::from(u32::MAX));
+    }
+}
@@ -0,0 +1,76 @@
+//! Token buckets per source address, capping the packets the server handles
+//! from each, so one abusive or misconfigured client can't take the
+//! reflector's capacity, e.g. by probing far above the rate it was granted.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// How often buckets are swept; a bucket refills within a second, so ones
+/// untouched that long are full and can go without changing anything
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub(crate) struct RateLimiter {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            last_sweep: now,
+        }
+    }
+
+    /// Whether a packet from `ip` arriving at `now` may be handled, at
+    /// `rate` a second with bursts of up to a second's worth, counting it.
+    pub fn allow(&mut self, ip: IpAddr, rate: u32, now: Instant) -> bool {
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.last_sweep = now;
+            self.buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated) < SWEEP_INTERVAL);
+        }
+        let capacity = f64::from(rate);
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * capacity;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_refills_per_address() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(start);
+        let a = IpAddr::from([192, 0, 2, 1]);
+        let b = IpAddr::from([192, 0, 2, 2]);
+        assert_eq!((0..15).filter(|_| limiter.allow(a, 10, start)).count(), 10);
+        assert!(limiter.allow(b, 10, start));
+        assert!(!limiter.allow(a, 10, start + Duration::from_millis(50)));
+        assert!(limiter.allow(a, 10, start + Duration::from_millis(150)));
+        // swept once full again, and still allowed a whole burst
+        let later = start + Duration::from_secs(5);
+        assert_eq!((0..15).filter(|_| limiter.allow(b, 10, later)).count(), 10);
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{json, zstd};
+
+pub(crate) enum Message {
+    /// Number of acknowledged probes in the next slot
+    Slot(u8),
+    /// End the current zstd frame so everything so far is on disk; later
+    /// slots go into a new frame appended to the same file.
+    Flush(Sender<eyre::Result<()>>),
+    /// Move the current file aside and continue in a fresh one, replying
+    /// with the path the old data was moved to.
+    Rotate(Sender<eyre::Result<PathBuf>>),
+    /// Session metadata, stored in a skippable frame between the slots
+    /// before and after it, with the number of slots so far added as
+    /// `slot`. The latest metadata is repeated at the start of rotated files.
+    /// In a record continued by [`Recorder::resume`] `slot` counts from the
+    /// first sequence number, so slots missed while the client was down show
+    /// up as a jump in it.
+    Metadata(json::Object),
+    /// Finalize the record; sent by [`Recorder::finish`] since control
+    /// handles may keep senders alive indefinitely.
+    Close,
+}
+
+/// Receive record, zstd compressed by an external `zstd` process if one is
+/// installed and in-process otherwise. Each byte is the number of probes that
+/// were acknowledged within one 64-probe slot. Metadata such as the route
+/// taken sits in skippable frames, which decompressing ignores.
+///
+/// Writing happens on a dedicated thread so a slow disk never stalls the
+/// receive loop.
+pub(crate) struct Recorder {
+    tx: Sender<Message>,
+    thread: JoinHandle<eyre::Result<()>>,
+}
+
+/// Where slots are compressed: an external `zstd` process, or in-process
+/// when the tool isn't installed.
+enum Sink {
+    Process(Child),
+    InProcess(zstd::FrameWriter<File>),
+}
+
+impl Sink {
+    /// Open `path` for writing slots, after writing `metadata` as a
+    /// skippable frame if given.
+    fn open(path: &Path, append: bool, metadata: Option<&str>) -> eyre::Result<Self> {
+        let mut out = if append {
+            OpenOptions::new().append(true).create(true).open(path)?
+        } else {
+            File::create(path)?
+        };
+        if let Some(metadata) = metadata {
+            zstd::write_skippable_frame(&mut out, metadata.as_bytes())?;
+        }
+        let spawned = Command::new("zstd")
+            .arg("-9")
+            .stdin(Stdio::piped())
+            .stdout(out.try_clone()?)
+            .spawn();
+        match spawned {
+            Ok(cmd) => Ok(Sink::Process(cmd)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(Sink::InProcess(zstd::FrameWriter::new(out)))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> eyre::Result<()> {
+        match self {
+            Sink::Process(cmd) => {
+                let out = cmd.stdin.as_mut().unwrap();
+                out.write_all(data)?;
+                out.flush()?;
+            }
+            Sink::InProcess(writer) => writer.write(data)?,
+        }
+        Ok(())
+    }
+
+    /// End the zstd frame and release the file, which Windows requires before
+    /// it can be renamed.
+    fn close(self) -> eyre::Result<()> {
+        match self {
+            Sink::Process(mut cmd) => {
+                drop(cmd.stdin.take());
+                let status = cmd.wait()?;
+                eyre::ensure!(status.success(), "zstd exited with {status}");
+            }
+            Sink::InProcess(writer) => writer.finish()?.sync_all()?,
+        }
+        Ok(())
+    }
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> eyre::Result<Self> {
+        let sink = Sink::open(path, false, None)?;
+        Ok(Self::spawn(path, sink, 0))
+    }
+
+    /// Continue the record in `path` after a restart, with slots from `slot`
+    /// on. A frame the previous run left unfinished, e.g. by crashing, is
+    /// dropped, as nothing after it would decode.
+    pub fn resume(path: &Path, slot: u64) -> eyre::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => {
+                let complete = zstd::complete_len(&data);
+                if complete < data.len() {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(path)?
+                        .set_len(complete as u64)?;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let sink = Sink::open(path, true, None)?;
+        Ok(Self::spawn(path, sink, slot))
+    }
+
+    fn spawn(path: &Path, mut sink: Sink, mut slots: u64) -> Self {
+        let path = path.to_path_buf();
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || -> eyre::Result<()> {
+            let mut metadata = None::<String>;
+            while let Ok(message) = rx.recv() {
+                match message {
+                    Message::Slot(received) => {
+                        // TODO: write timestamps
+                        sink.write(&[received])?;
+                        slots += 1;
+                    }
+                    Message::Flush(reply) => {
+                        let rv = sink.close();
+                        sink = Sink::open(&path, true, None)?;
+                        let _ = reply.send(rv);
+                    }
+                    Message::Rotate(reply) => {
+                        let rv = sink.close();
+                        let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                        let mut rotated = path.clone().into_os_string();
+                        rotated.push(format!(".{secs}"));
+                        let rotated = PathBuf::from(rotated);
+                        let rv = rv.and_then(|()| Ok(fs::rename(&path, &rotated)?));
+                        // a fresh file needs the metadata again to stand alone
+                        let repeat = metadata.as_deref().filter(|_| rv.is_ok());
+                        sink = Sink::open(&path, rv.is_err(), repeat)?;
+                        let _ = reply.send(rv.map(|()| rotated));
+                    }
+                    Message::Metadata(object) => {
+                        sink.close()?;
+                        let latest = object.u64("slot", slots).finish();
+                        sink = Sink::open(&path, true, Some(&latest))?;
+                        metadata = Some(latest);
+                    }
+                    Message::Close => break,
+                }
+            }
+            sink.close()
+        });
+        Self { tx, thread }
+    }
+
+    /// Channel for completed slots and control messages.
+    pub fn sender(&self) -> Sender<Message> {
+        self.tx.clone()
+    }
+
+    pub fn finish(self) -> eyre::Result<()> {
+        // fails only if the thread already ended with an error
+        let _ = self.tx.send(Message::Close);
+        self.thread.join().unwrap()
+    }
+}
+
+/// Probes per slot
+const SLOT_SIZE: u64 = 64;
+/// Slots kept open for reordered probes on the server, as far back as its
+/// replay window accepts them
+const SERVER_OPEN_SLOTS: usize = 63;
+/// Sequence numbers jumping further ahead than this many slots start the
+/// record over at the new one rather than filling the gap, so a client
+/// can't have the server write gigabytes of empty slots
+const MAX_SLOT_GAP: u64 = 1 << 20;
+
+/// Counts of probes received per slot of sequence numbers, decided once
+/// probes of a slot can no longer arrive in time.
+#[derive(Debug, Default)]
+struct SlotWindow {
+    /// Number of the first open slot; slot `k` holds probes `64k + 1` to
+    /// `64k + 64`, as on the client
+    first: u64,
+    open: VecDeque<u64>,
+}
+
+impl SlotWindow {
+    /// Count probe `seq`, pushing the slots it decides to `decided`;
+    /// returns whether the record starts over at a later slot.
+    fn insert(&mut self, seq: u64, decided: &mut Vec<u8>) -> bool {
+        let Some(index) = seq.checked_sub(1) else {
+            return false;
+        };
+        let slot = index / SLOT_SIZE;
+        if slot < self.first {
+            return false;
+        }
+        let mut jumped = false;
+        if slot - self.first >= self.open.len() as u64 + MAX_SLOT_GAP {
+            decided.extend(self.drain());
+            self.first = slot;
+            jumped = true;
+        }
+        while slot >= self.first + self.open.len() as u64 {
+            self.open.push_back(0);
+        }
+        self.open[(slot - self.first) as usize] |= 1 << (index % SLOT_SIZE);
+        while self.open.len() > SERVER_OPEN_SLOTS {
+            let bits = self.open.pop_front().unwrap();
+            decided.push(bits.count_ones() as u8);
+            self.first += 1;
+        }
+        jumped
+    }
+
+    /// The open slots' counts, closing them.
+    fn drain(&mut self) -> impl Iterator<Item = u8> + '_ {
+        self.first += self.open.len() as u64;
+        self.open.drain(..).map(|bits| bits.count_ones() as u8)
+    }
+}
+
+/// A receive record of one client's probes kept by the server, in the
+/// format of the client's, for upstream loss to be analyzed on the server's
+/// side too. It starts with a metadata frame, and one with `restart_slot`
+/// wherever sequence numbers jumped far ahead.
+pub(crate) struct SessionRecording {
+    recorder: Recorder,
+    slots: SlotWindow,
+    decided: Vec<u8>,
+}
+
+impl SessionRecording {
+    pub fn create(path: &Path, metadata: json::Object) -> eyre::Result<Self> {
+        let recorder = Recorder::create(path)?;
+        let _ = recorder.tx.send(Message::Metadata(metadata));
+        Ok(Self {
+            recorder,
+            slots: SlotWindow::default(),
+            decided: Vec::new(),
+        })
+    }
+
+    pub fn record(&mut self, seq: u64) {
+        let restarted = self.slots.insert(seq, &mut self.decided);
+        // a failed recorder reports its error on finishing
+        for slot in self.decided.drain(..) {
+            let _ = self.recorder.tx.send(Message::Slot(slot));
+        }
+        if restarted {
+            let restart = json::Object::new().u64("restart_slot", self.slots.first);
+            let _ = self.recorder.tx.send(Message::Metadata(restart));
+        }
+    }
+
+    /// Write the slots still open, then finish the record.
+    pub fn finish(mut self) -> eyre::Result<()> {
+        for slot in self.slots.drain() {
+            let _ = self.recorder.tx.send(Message::Slot(slot));
+        }
+        self.recorder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_decided_once_out_of_the_window() {
+        let mut window = SlotWindow::default();
+        let mut decided = Vec::new();
+        for seq in [1, 3, 64, 65, 64 * 70] {
+            assert!(!window.insert(seq, &mut decided));
+        }
+        // slots 0 to 6 are out of the 63 kept open behind slot 69
+        assert_eq!(decided, [3, 1, 0, 0, 0, 0, 0]);
+        // too late now
+        window.insert(2, &mut decided);
+        assert_eq!(decided.len(), 7);
+        assert!(window.insert(64 * (70 + MAX_SLOT_GAP + 63) + 1, &mut decided));
+        assert_eq!(decided.len(), 70);
+        assert_eq!(window.drain().collect::<Vec<_>>(), [1]);
+    }
+}
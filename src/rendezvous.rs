@@ -0,0 +1,599 @@
+//! Peer-to-peer measurement: two clients meet at a server and then probe
+//! each other directly, e.g. to measure the path between two homes rather
+//! than each home to a datacenter. The server only brokers addresses.
+//!
+//! Both register under a shared name with `[REGISTER, nonce, name_hash]`,
+//! padded to the size of the answer, repeated until the other one has
+//! registered too. The server then tells each the other's address as it saw
+//! it, `[PEER, nonce, role, ip, port]` with IPv4 addresses mapped to IPv6,
+//! and which role to take: the client that registered first reflects, the
+//! other probes it. Both then send `[PUNCH, heard]` to each other from the
+//! socket they registered with at the same time, so NATs on either side let
+//! the other's packets in as they take them for answers, until each has
+//! heard the other.
+//!
+//! Punching that fails is retried a few times. Registrations carry the
+//! attempt, and the server only answers once both clients registered for
+//! the same one, so they start over together. NATs that pick a new port for
+//! every destination can't be punched through at all, so after the last
+//! attempt both register asking to be relayed. A server that relays answers
+//! with `[RELAY, nonce, role, port]`, the port of a socket forwarding
+//! packets between the two, which each binds its address to with
+//! `[RELAY_BIND, nonce]` while punching through it like through the other's
+//! NAT; a port of 0 means the server doesn't relay.
+//!
+//! Anyone who knows the name can take the other's place, so pick one that
+//! can't be guessed, or use a pre-shared key.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::{self, Psk},
+    hash::sha256,
+    resolve, IpVersion, LocalBind, StopHandle, BUF_SIZE,
+};
+
+pub(crate) const REGISTER_PACKET_CONST: u8 = 20;
+const PEER_PACKET_CONST: u8 = 21;
+const PUNCH_PACKET_CONST: u8 = 22;
+const RELAY_PACKET_CONST: u8 = 23;
+const RELAY_BIND_PACKET_CONST: u8 = 24;
+pub(crate) const NAME_SIZE: usize = 16;
+const PEER_SIZE: usize = 1 + 4 + 1 + 16 + 2;
+/// No larger than what answers it, so nothing to amplify. The name is
+/// followed by flags and the attempt
+pub(crate) const REGISTER_SIZE: usize = PEER_SIZE;
+/// Registration flag asking to be relayed
+pub(crate) const REGISTER_RELAY: u8 = 1;
+const RELAY_SIZE: usize = 1 + 4 + 1 + 2;
+const RELAY_BIND_SIZE: usize = 1 + 4;
+
+/// How often registrations are repeated while waiting for the other client
+const REGISTER_EVERY: Duration = Duration::from_millis(500);
+/// Registrations not repeated for this long are dropped
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Names the server tracks at once; registrations under further ones are
+/// ignored
+const MAX_NAMES: usize = 10_000;
+const PUNCH_EVERY: Duration = Duration::from_millis(100);
+/// Punching for this long without hearing the other client gives up
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Punching attempts before asking to be relayed
+const PUNCH_ATTEMPTS: u8 = 3;
+/// Relays the server runs at once; further pairs aren't relayed
+const MAX_RELAYS: usize = 16;
+/// Relays stop once nothing was forwarded for this long
+const RELAY_IDLE: Duration = Duration::from_secs(30);
+
+/// Settings for [`meet`].
+#[derive(Clone, Debug)]
+pub struct RendezvousConfig {
+    /// Server brokering the addresses
+    pub host: String,
+    /// Name both clients register under
+    pub name: String,
+    /// Only connect over this IP version
+    pub ip_version: Option<IpVersion>,
+    /// Local address to register and probe from
+    pub source_addr: Option<IpAddr>,
+    /// Network interface to use, Linux only
+    pub interface: Option<String>,
+    /// Key for servers that require one, see [`crate::auth`]; the clients
+    /// then authenticate their packets to each other with it as well
+    pub psk: Option<Psk>,
+}
+
+impl Default for RendezvousConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1:13337".to_string(),
+            name: String::new(),
+            ip_version: None,
+            source_addr: None,
+            interface: None,
+            psk: None,
+        }
+    }
+}
+
+/// What each client does once they've met.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Run a reflector on [`Peer::socket`], see
+    /// [`ProbeServer::from_socket`](crate::ProbeServer::from_socket)
+    Reflect,
+    /// Probe the other client from [`Peer::socket`], see
+    /// [`ProbeClient::from_socket`](crate::ProbeClient::from_socket)
+    Probe,
+}
+
+/// The other client, reachable from `socket`.
+#[derive(Debug)]
+pub struct Peer {
+    /// Where the other client's packets come from: its own address, or the
+    /// server's relay between the two
+    pub addr: SocketAddr,
+    pub role: Role,
+    /// Whether punching through failed so packets go through the server,
+    /// and the results include the detour
+    pub relayed: bool,
+    /// The socket registered with, which NATs on the way now let the other
+    /// client's packets through to
+    pub socket: UdpSocket,
+}
+
+/// Register under `config.name` until another client does too, then open
+/// the way to it, directly or else through a relay on the server. Blocks
+/// until then, however long the other one takes to
+/// show up.
+pub fn meet(config: &RendezvousConfig) -> eyre::Result<Peer> {
+    let local = LocalBind {
+        source_addr: config.source_addr,
+        interface: config.interface.clone(),
+    };
+    let server = resolve(&config.host, local.ip_version(config.ip_version))?;
+    let socket = local.socket_for(server)?;
+    let psk = config.psk.as_ref();
+    let nonce = rand::random();
+    let name = sha256(config.name.as_bytes())[..NAME_SIZE]
+        .try_into()
+        .unwrap();
+    for attempt in 0..PUNCH_ATTEMPTS {
+        let registration = Registering {
+            nonce,
+            name,
+            relay: false,
+            attempt,
+        };
+        let (addr, role) = register(&socket, server, registration, psk)?;
+        eyre::ensure!(
+            addr.is_ipv4() == server.is_ipv4(),
+            "the other client is at {addr}, which the socket to the server can't reach"
+        );
+        if punch(&socket, addr, None, psk)? {
+            return Ok(Peer {
+                addr,
+                role,
+                relayed: false,
+                socket,
+            });
+        }
+    }
+    let registration = Registering {
+        nonce,
+        name,
+        relay: true,
+        attempt: PUNCH_ATTEMPTS,
+    };
+    let (addr, role) = register(&socket, server, registration, psk)?;
+    eyre::ensure!(
+        addr.port() != 0,
+        "couldn't punch through to the other client, and the server doesn't relay"
+    );
+    let mut bind = [0u8; RELAY_BIND_SIZE];
+    bind[0] = RELAY_BIND_PACKET_CONST;
+    bind[1..5].copy_from_slice(&nonce.to_be_bytes());
+    eyre::ensure!(
+        punch(&socket, addr, Some(&bind), psk)?,
+        "couldn't punch through to the other client, nor reach it through the server's relay at {addr}"
+    );
+    Ok(Peer {
+        addr,
+        role,
+        relayed: true,
+        socket,
+    })
+}
+
+#[derive(Clone, Copy)]
+struct Registering {
+    nonce: u32,
+    name: [u8; NAME_SIZE],
+    relay: bool,
+    attempt: u8,
+}
+
+/// Register until the server answers with the other client's address, or
+/// when asking to be relayed, its relay's.
+fn register(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    registration: Registering,
+    psk: Option<&Psk>,
+) -> eyre::Result<(SocketAddr, Role)> {
+    let Registering {
+        nonce,
+        name,
+        relay,
+        attempt,
+    } = registration;
+    let mut packet = [0u8; REGISTER_SIZE];
+    packet[0] = REGISTER_PACKET_CONST;
+    packet[1..5].copy_from_slice(&nonce.to_be_bytes());
+    packet[5..5 + NAME_SIZE].copy_from_slice(&name);
+    if relay {
+        packet[5 + NAME_SIZE] = REGISTER_RELAY;
+    }
+    packet[6 + NAME_SIZE] = attempt;
+    let packet = auth::seal(psk, &packet);
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        socket.send_to(&packet, server)?;
+        let deadline = Instant::now() + REGISTER_EVERY;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            let Some((n, from)) = recv_from(socket, &mut buf, left)? else {
+                break;
+            };
+            if from != server {
+                continue;
+            }
+            let Some(n) = auth::open(psk, &buf[..n]) else {
+                continue;
+            };
+            let answer = if relay {
+                decode_relay(&buf[..n], nonce, server)
+            } else {
+                decode_peer(&buf[..n], nonce)
+            };
+            if let Some(answer) = answer {
+                return Ok(answer);
+            }
+        }
+    }
+}
+
+/// Send punches to `peer`, each after `bind` if given, until both have
+/// heard each other or anything else arrives from it, meaning it's done
+/// punching already. Returns whether that happened in time.
+fn punch(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    bind: Option<&[u8]>,
+    psk: Option<&Psk>,
+) -> eyre::Result<bool> {
+    let deadline = Instant::now() + PUNCH_TIMEOUT;
+    let mut heard = false;
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut next_punch = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        if now >= next_punch {
+            if let Some(bind) = bind {
+                socket.send_to(&auth::seal(psk, bind), peer)?;
+            }
+            socket.send_to(&auth::seal(psk, &[PUNCH_PACKET_CONST, heard.into()]), peer)?;
+            next_punch = now + PUNCH_EVERY;
+        }
+        let Some((n, from)) = recv_from(socket, &mut buf, next_punch - now)? else {
+            continue;
+        };
+        if from != peer {
+            continue;
+        }
+        let Some(n) = auth::open(psk, &buf[..n]) else {
+            continue;
+        };
+        match buf[..n] {
+            [PUNCH_PACKET_CONST, 0] => heard = true,
+            [PUNCH_PACKET_CONST, _] => {
+                // repeated in case some get lost, as the other client stops
+                // once one arrives
+                for _ in 0..3 {
+                    socket.send_to(&auth::seal(psk, &[PUNCH_PACKET_CONST, 1]), peer)?;
+                }
+                return Ok(true);
+            }
+            _ => return Ok(true),
+        }
+    }
+}
+
+/// A datagram within `timeout`, `None` if none arrived.
+fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> eyre::Result<Option<(usize, SocketAddr)>> {
+    socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+    match socket.recv_from(buf) {
+        Ok(received) => Ok(Some(received)),
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+            ) =>
+        {
+            Ok(None)
+        }
+        // the server's or the other client's port closed, e.g. an ICMP
+        // error for an earlier packet; the next may still get through
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn decode_peer(packet: &[u8], nonce: u32) -> Option<(SocketAddr, Role)> {
+    if packet.len() < PEER_SIZE
+        || packet[0] != PEER_PACKET_CONST
+        || packet[1..5] != nonce.to_be_bytes()
+    {
+        return None;
+    }
+    let role = decode_role(packet[5]);
+    let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[6..22]).unwrap());
+    let ip = ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4);
+    let port = u16::from_be_bytes([packet[22], packet[23]]);
+    Some((SocketAddr::new(ip, port), role))
+}
+
+/// The relay's address on the `server`, with a port of 0 if it has none.
+fn decode_relay(packet: &[u8], nonce: u32, server: SocketAddr) -> Option<(SocketAddr, Role)> {
+    if packet.len() < RELAY_SIZE
+        || packet[0] != RELAY_PACKET_CONST
+        || packet[1..5] != nonce.to_be_bytes()
+    {
+        return None;
+    }
+    let port = u16::from_be_bytes([packet[6], packet[7]]);
+    Some((SocketAddr::new(server.ip(), port), decode_role(packet[5])))
+}
+
+fn decode_role(byte: u8) -> Role {
+    match byte {
+        0 => Role::Reflect,
+        _ => Role::Probe,
+    }
+}
+
+fn encode_role(role: Role) -> u8 {
+    match role {
+        Role::Reflect => 0,
+        Role::Probe => 1,
+    }
+}
+
+fn encode_peer(nonce: u32, role: Role, addr: SocketAddr) -> [u8; PEER_SIZE] {
+    let mut packet = [0u8; PEER_SIZE];
+    packet[0] = PEER_PACKET_CONST;
+    packet[1..5].copy_from_slice(&nonce.to_be_bytes());
+    packet[5] = encode_role(role);
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    packet[6..22].copy_from_slice(&ip.octets());
+    packet[22..24].copy_from_slice(&addr.port().to_be_bytes());
+    packet
+}
+
+fn encode_relay(nonce: u32, role: Role, port: u16) -> [u8; RELAY_SIZE] {
+    let mut packet = [0u8; RELAY_SIZE];
+    packet[0] = RELAY_PACKET_CONST;
+    packet[1..5].copy_from_slice(&nonce.to_be_bytes());
+    packet[5] = encode_role(role);
+    packet[6..8].copy_from_slice(&port.to_be_bytes());
+    packet
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Registration {
+    nonce: u32,
+    addr: SocketAddr,
+    relay: bool,
+    attempt: u8,
+    last_seen: Instant,
+}
+
+impl Registration {
+    fn is_fresh(&self, now: Instant) -> bool {
+        now.duration_since(self.last_seen) < REGISTRATION_TIMEOUT
+    }
+}
+
+/// What the server's relays need to know of it.
+pub(crate) struct Relaying {
+    /// Address the relays bind to, the one clients reach the server at
+    pub ip: IpAddr,
+    pub psk: Option<Psk>,
+    /// Stops the relays along with the server
+    pub done: StopHandle,
+}
+
+struct Relay {
+    nonces: [u32; 2],
+    port: u16,
+    stop: StopHandle,
+    thread: JoinHandle<()>,
+}
+
+/// The server's side: who registered under which name, at most two each,
+/// and the relays between pairs that couldn't punch through.
+pub(crate) struct Registry {
+    names: HashMap<[u8; NAME_SIZE], [Option<Registration>; 2]>,
+    relaying: Option<Relaying>,
+    relays: HashMap<[u8; NAME_SIZE], Relay>,
+}
+
+impl Registry {
+    /// Without `relaying`, pairs that couldn't punch through are told the
+    /// server doesn't relay.
+    pub fn new(relaying: Option<Relaying>) -> Self {
+        Self {
+            names: HashMap::new(),
+            relaying,
+            relays: HashMap::new(),
+        }
+    }
+
+    /// Record a registration from `addr`, returning the answers to send
+    /// once both clients of the name have registered for the same attempt:
+    /// each gets the other's address, or if they asked to be relayed, the
+    /// relay's port.
+    pub fn register(
+        &mut self,
+        name: [u8; NAME_SIZE],
+        nonce: u32,
+        relay: bool,
+        attempt: u8,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> Vec<(SocketAddr, Vec<u8>)> {
+        if self.names.len() >= MAX_NAMES && !self.names.contains_key(&name) {
+            self.names.retain(|_, registrations| {
+                registrations
+                    .iter()
+                    .flatten()
+                    .any(|registration| registration.is_fresh(now))
+            });
+            if self.names.len() >= MAX_NAMES {
+                return Vec::new();
+            }
+        }
+        let registrations = self.names.entry(name).or_default();
+        for slot in registrations.iter_mut() {
+            if slot.is_some_and(|registration| !registration.is_fresh(now)) {
+                *slot = None;
+            }
+        }
+        let slot = registrations
+            .iter()
+            .position(|slot| slot.is_some_and(|registration| registration.nonce == nonce))
+            .or_else(|| registrations.iter().position(Option::is_none));
+        // a third client under the name
+        let Some(slot) = slot else {
+            return Vec::new();
+        };
+        registrations[slot] = Some(Registration {
+            nonce,
+            addr,
+            relay,
+            attempt,
+            last_seen: now,
+        });
+        let [Some(first), Some(second)] = *registrations else {
+            return Vec::new();
+        };
+        // the other is still punching, or hasn't noticed it failed yet
+        if first.attempt != second.attempt || first.relay != second.relay {
+            return Vec::new();
+        }
+        if !relay {
+            return vec![
+                (
+                    first.addr,
+                    encode_peer(first.nonce, Role::Reflect, second.addr).to_vec(),
+                ),
+                (
+                    second.addr,
+                    encode_peer(second.nonce, Role::Probe, first.addr).to_vec(),
+                ),
+            ];
+        }
+        let port = self.relay(name, [first.nonce, second.nonce]);
+        vec![
+            (
+                first.addr,
+                encode_relay(first.nonce, Role::Reflect, port).to_vec(),
+            ),
+            (
+                second.addr,
+                encode_relay(second.nonce, Role::Probe, port).to_vec(),
+            ),
+        ]
+    }
+
+    /// Port of the relay between the clients with `nonces`, started if it
+    /// isn't running yet; 0 if there's none for them.
+    fn relay(&mut self, name: [u8; NAME_SIZE], nonces: [u32; 2]) -> u16 {
+        let Some(relaying) = &self.relaying else {
+            return 0;
+        };
+        self.relays.retain(|_, relay| !relay.thread.is_finished());
+        if let Some(relay) = self.relays.get(&name) {
+            if relay.nonces == nonces {
+                return relay.port;
+            }
+            // clients registering anew under the name
+            relay.stop.stop();
+            self.relays.remove(&name);
+        }
+        if self.relays.len() >= MAX_RELAYS {
+            return 0;
+        }
+        let Ok(socket) = UdpSocket::bind((relaying.ip, 0)) else {
+            return 0;
+        };
+        let Ok(port) = socket.local_addr().map(|addr| addr.port()) else {
+            return 0;
+        };
+        let stop = StopHandle::default();
+        let thread = {
+            let psk = relaying.psk.clone();
+            let stop = stop.clone();
+            let done = relaying.done.clone();
+            thread::spawn(move || forward(socket, nonces, psk, stop, done))
+        };
+        self.relays.insert(
+            name,
+            Relay {
+                nonces,
+                port,
+                stop,
+                thread,
+            },
+        );
+        port
+    }
+}
+
+/// Forward packets between the two clients binding to `socket` with their
+/// `nonces`, verbatim so they're authenticated end to end, until stopped or
+/// idle.
+fn forward(
+    socket: UdpSocket,
+    nonces: [u32; 2],
+    psk: Option<Psk>,
+    stop: StopHandle,
+    done: StopHandle,
+) {
+    let mut ends: [Option<SocketAddr>; 2] = [None; 2];
+    let mut last_forwarded = Instant::now();
+    let mut buf = vec![0u8; BUF_SIZE];
+    while !stop.is_stopped() && !done.is_stopped() && last_forwarded.elapsed() < RELAY_IDLE {
+        let (n, from) = match recv_from(&socket, &mut buf, Duration::from_millis(100)) {
+            Ok(Some(received)) => received,
+            Ok(None) => continue,
+            Err(_) => return,
+        };
+        // binds aren't forwarded, or the other end would take one for the
+        // end of punching
+        if let Some(m) = auth::open(psk.as_ref(), &buf[..n]) {
+            if m == RELAY_BIND_SIZE && buf[0] == RELAY_BIND_PACKET_CONST {
+                if let Some(end) = nonces
+                    .iter()
+                    .position(|nonce| buf[1..5] == nonce.to_be_bytes())
+                {
+                    ends[end] = Some(from);
+                }
+                continue;
+            }
+        }
+        let Some(end) = ends.iter().position(|&end| end == Some(from)) else {
+            continue;
+        };
+        if let Some(other) = ends[1 - end] {
+            if socket.send_to(&buf[..n], other).is_ok() {
+                last_forwarded = Instant::now();
+            }
+        }
+    }
+}
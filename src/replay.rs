@@ -0,0 +1,98 @@
+//! Sliding anti-replay window like IPsec's (RFC 4303), for sequence numbers
+//! and nonces that each may only be accepted once.
+
+/// Numbers seen recently, in blocks of 64 by number / 64 modulo `BLOCKS`.
+/// Numbers more than `(BLOCKS - 1) * 64` behind the highest one are too old
+/// to tell and rejected.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ReplayWindow<const BLOCKS: usize> {
+    highest: u64,
+    blocks: [(u64, u64); BLOCKS],
+}
+
+impl<const BLOCKS: usize> Default for ReplayWindow<BLOCKS> {
+    fn default() -> Self {
+        Self {
+            highest: 0,
+            blocks: [(0, 0); BLOCKS],
+        }
+    }
+}
+
+impl<const BLOCKS: usize> ReplayWindow<BLOCKS> {
+    const SPAN: u64 = (BLOCKS as u64 - 1) * 64;
+
+    /// Highest number recorded so far.
+    pub fn highest(&self) -> u64 {
+        self.highest
+    }
+
+    /// Whether `n` may be new, i.e. is neither seen nor too old.
+    pub fn is_fresh(&self, n: u64) -> bool {
+        if n.saturating_add(Self::SPAN) < self.highest {
+            return false;
+        }
+        let (block, bits) = self.blocks[(n / 64) as usize % BLOCKS];
+        block < n / 64 || (block == n / 64 && bits & 1 << (n % 64) == 0)
+    }
+
+    /// Record `n`, returning whether it was new.
+    pub fn insert(&mut self, n: u64) -> bool {
+        if !self.is_fresh(n) {
+            return false;
+        }
+        let slot = &mut self.blocks[(n / 64) as usize % BLOCKS];
+        if slot.0 != n / 64 {
+            *slot = (n / 64, 0);
+        }
+        slot.1 |= 1 << (n % 64);
+        self.highest = self.highest.max(n);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spans 3 * 64 numbers
+    type Window = ReplayWindow<4>;
+
+    #[test]
+    fn numbers_are_accepted_once_in_any_order() {
+        let mut window = Window::default();
+        for n in [10, 12, 11, 150, 100, 0] {
+            assert!(window.insert(n), "{n}");
+        }
+        for n in [10, 12, 11, 150, 100, 0] {
+            assert!(!window.insert(n), "{n} again");
+        }
+        assert_eq!(window.highest(), 150);
+        assert!(window.insert(149));
+    }
+
+    #[test]
+    fn numbers_behind_the_window_are_rejected() {
+        let mut window = Window::default();
+        assert!(window.insert(1000));
+        assert!(!window.is_fresh(1000 - Window::SPAN - 1));
+        assert!(window.insert(1000 - Window::SPAN));
+    }
+
+    #[test]
+    fn far_jumps_and_reused_blocks_forget_old_bits() {
+        let mut window = Window::default();
+        assert!(window.insert(5));
+        // the same block slot as 5, a whole window later
+        assert!(window.insert(5 + 4 * 64));
+        assert!(!window.insert(5));
+        assert!(window.insert(6 + 4 * 64));
+        // far beyond the window, every slot holds an old block
+        let far = 5 + 100 * 64;
+        assert!(window.insert(far));
+        for n in far - Window::SPAN..far {
+            assert!(window.is_fresh(n), "{n}");
+        }
+        assert!(!window.is_fresh(5 + 4 * 64));
+    }
+}
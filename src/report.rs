@@ -0,0 +1,370 @@
+//! Reports of an analyzed [`History`] to hand on: a standalone HTML page,
+//! e.g. to attach to a support ticket, with summary tables, the charts drawn
+//! as inline SVG, the outages and the sessions measured, viewable without
+//! network access; a concise Markdown summary to paste into issues and
+//! wikis; and reports in any format from a user's template, see
+//! [`template`].
+
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    analyze::{History, LocalTime, Percentiles, TimeOfDay, DAYS, TIME_ZONE},
+    json,
+    template::Template,
+};
+
+/// Columns of the loss chart
+const LOSS_BUCKETS: usize = 300;
+const CHART_WIDTH: f64 = 900.0;
+const CHART_HEIGHT: f64 = 160.0;
+/// Height of a source's row in the outage timeline
+const TIMELINE_ROW: f64 = 22.0;
+/// Outages listed, the longest ones
+const MAX_OUTAGES_LISTED: usize = 100;
+/// Outages listed in the Markdown summary, which is meant to be short
+const MAX_OUTAGES_MARKDOWN: usize = 20;
+
+const STYLE: &str = "body{font-family:sans-serif;max-width:960px;margin:1em auto;color:#222}\
+table{border-collapse:collapse;margin:.5em 0}td,th{padding:3px 8px;border:1px solid #ddd;font-size:13px}\
+th{background:#f4f4f4}.heat td{text-align:center;font-size:11px;padding:3px 4px}\
+svg{background:#fafafa;border:1px solid #ddd}svg text{font-size:11px;fill:#555}";
+
+/// The standalone HTML report of `history`.
+pub fn html(history: &History) -> String {
+    let total = history.total();
+    let (first, last) = history.span().unwrap_or_default();
+    let longest = history
+        .outages
+        .iter()
+        .map(|outage| outage.duration)
+        .max()
+        .unwrap_or_default();
+    let down: Duration = history.outages.iter().map(|outage| outage.duration).sum();
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Loss Lens report</title>\n\
+         <style>{STYLE}</style></head><body>\n<h1>Loss Lens report</h1>\n\
+         <p>From {} to {} ({TIME_ZONE}), generated {}</p>\n",
+        LocalTime::at(first),
+        LocalTime::at(last),
+        LocalTime::at(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+        )
+    );
+
+    html.push_str("<h2>Summary</h2>\n<table>\n");
+    for (name, value) in [
+        ("Probes sent", total.sent.to_string()),
+        ("Probes answered", total.received.to_string()),
+        ("Round-trip loss", format!("{:.3}%", total.loss())),
+        ("Outages", total.outages.to_string()),
+        ("Longest outage", format!("{:.1} s", longest.as_secs_f64())),
+        ("Time in outages", format!("{:.1} s", down.as_secs_f64())),
+    ] {
+        let _ = writeln!(html, "<tr><th>{name}</th><td>{value}</td></tr>");
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(
+        "<h2>Sessions</h2>\n<table>\n<tr><th>Host</th><th>Labels</th><th>Runs</th><th>From</th>\
+         <th>To</th><th>Probes</th><th>Loss</th><th>Outages</th><th>RTT p50 / p99</th></tr>\n",
+    );
+    for source in &history.sources {
+        let labels = source
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", escape(key), escape(value)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{labels}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.3}%</td><td>{}</td><td>{}</td></tr>",
+            escape(&source.name),
+            source.runs,
+            LocalTime::at(source.first_unix),
+            LocalTime::at(source.last_unix),
+            source.total.sent,
+            source.total.loss(),
+            source.total.outages,
+            source.rtt.map_or_else(
+                || "-".to_string(),
+                |rtt| format!("{} / {}", ms(rtt.p50), ms(rtt.p99))
+            )
+        );
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Loss over time</h2>\n");
+    loss_chart(&mut html, history, first, last);
+    html.push_str("<h2>Outages</h2>\n");
+    if history.outages.is_empty() {
+        html.push_str("<p>None.</p>\n");
+    } else {
+        outage_timeline(&mut html, history, first, last);
+        outage_table(&mut html, history);
+    }
+    html.push_str("<h2>Loss by time of day</h2>\n");
+    heatmap(&mut html, &TimeOfDay::from_history(history));
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// The Markdown summary of `history`: totals and percentiles per host, loss
+/// by hour of day and the longest outages.
+pub fn markdown(history: &History) -> String {
+    let total = history.total();
+    let (first, last) = history.span().unwrap_or_default();
+    let mut md = String::new();
+    let _ = writeln!(md, "## Loss Lens report\n");
+    let _ = writeln!(
+        md,
+        "From {} to {} ({TIME_ZONE}): {} probes, {:.3}% round-trip loss, {} outages.\n",
+        LocalTime::at(first),
+        LocalTime::at(last),
+        total.sent,
+        total.loss(),
+        total.outages
+    );
+
+    md.push_str(
+        "| Host | Runs | Probes | Loss | Outages | RTT p50 | RTT p95 | RTT p99 | RTT max |\n\
+         |---|--:|--:|--:|--:|--:|--:|--:|--:|\n",
+    );
+    for source in &history.sources {
+        let rtt = |pick: fn(&Percentiles) -> Duration| {
+            source
+                .rtt
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |rtt| ms(pick(rtt)))
+        };
+        let _ = writeln!(
+            md,
+            "| {} | {} | {} | {:.3}% | {} | {} | {} | {} | {} |",
+            source.name.replace('|', "\\|"),
+            source.runs,
+            source.total.sent,
+            source.total.loss(),
+            source.total.outages,
+            rtt(|rtt| rtt.p50),
+            rtt(|rtt| rtt.p95),
+            rtt(|rtt| rtt.p99),
+            rtt(|rtt| rtt.max)
+        );
+    }
+
+    let _ = writeln!(md, "\n### Loss by hour of day ({TIME_ZONE})\n");
+    md.push_str("| Hour | Probes | Loss | Outages |\n|--:|--:|--:|--:|\n");
+    for (hour, cell) in TimeOfDay::from_history(history)
+        .by_hour()
+        .iter()
+        .enumerate()
+    {
+        if cell.sent > 0 || cell.outages > 0 {
+            let _ = writeln!(
+                md,
+                "| {hour:02}:00 | {} | {:.3}% | {} |",
+                cell.sent,
+                cell.loss(),
+                cell.outages
+            );
+        }
+    }
+
+    md.push_str("\n### Outages\n\n");
+    if history.outages.is_empty() {
+        md.push_str("None.\n");
+        return md;
+    }
+    let outages = history.longest_outages(MAX_OUTAGES_MARKDOWN);
+    if outages.len() < history.outages.len() {
+        let _ = writeln!(
+            md,
+            "The {} longest of {}:\n",
+            outages.len(),
+            history.outages.len()
+        );
+    }
+    md.push_str("| Start | Duration | Host |\n|---|--:|---|\n");
+    for outage in outages {
+        let _ = writeln!(
+            md,
+            "| {} | {:.1} s | {} |",
+            LocalTime::at(outage.start_unix),
+            outage.duration.as_secs_f64(),
+            history.sources[outage.source].name.replace('|', "\\|")
+        );
+    }
+    md
+}
+
+/// `history` rendered with a Mustache-like `template`, fed with what
+/// [`History::to_json`] serializes, e.g. `{{total.loss:.2}}` or
+/// `{{#outages}}{{start}} {{duration_secs:.1}}{{/outages}}`. See the
+/// `template` module for the syntax.
+pub fn template(history: &History, template: &str) -> eyre::Result<String> {
+    let template = Template::parse(template)?;
+    Ok(template.render(&json::parse(&history.to_json())?))
+}
+
+/// Round-trip loss in [`LOSS_BUCKETS`] columns over the whole history, as
+/// bars scaled to the worst, but at least 1%.
+fn loss_chart(html: &mut String, history: &History, first: f64, last: f64) {
+    let span = (last - first).max(1.0);
+    let mut buckets = vec![(0u64, 0u64); LOSS_BUCKETS];
+    for interval in &history.intervals {
+        let i = ((interval.end_unix - first) / span * LOSS_BUCKETS as f64) as usize;
+        let bucket = &mut buckets[i.min(LOSS_BUCKETS - 1)];
+        bucket.0 += interval.sent;
+        bucket.1 += interval.received;
+    }
+    let losses: Vec<Option<f64>> = buckets
+        .iter()
+        .map(|&(sent, received)| {
+            (sent > 0).then(|| 100.0 * (1.0 - received as f64 / sent as f64).max(0.0))
+        })
+        .collect();
+    let scale = losses.iter().flatten().copied().fold(1.0, f64::max);
+    let _ = writeln!(
+        html,
+        "<svg width=\"{CHART_WIDTH}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">",
+        CHART_HEIGHT + 20.0
+    );
+    let width = CHART_WIDTH / LOSS_BUCKETS as f64;
+    for (i, loss) in losses.iter().enumerate() {
+        let Some(loss) = loss else { continue };
+        let height = loss / scale * CHART_HEIGHT;
+        let _ = writeln!(
+            html,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{height:.1}\" fill=\"#d33\"><title>{loss:.2}%</title></rect>",
+            i as f64 * width,
+            CHART_HEIGHT - height,
+            width.max(1.0)
+        );
+    }
+    axis(html, first, last, CHART_HEIGHT);
+    let _ = writeln!(html, "<text x=\"4\" y=\"12\">{scale:.1}%</text>\n</svg>");
+}
+
+/// A row per source with its outages where they happened.
+fn outage_timeline(html: &mut String, history: &History, first: f64, last: f64) {
+    let span = (last - first).max(1.0);
+    let height = TIMELINE_ROW * history.sources.len() as f64;
+    let _ = writeln!(
+        html,
+        "<svg width=\"{CHART_WIDTH}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">",
+        height + 20.0
+    );
+    for (i, source) in history.sources.iter().enumerate() {
+        let _ = writeln!(
+            html,
+            "<text x=\"4\" y=\"{:.1}\">{}</text>",
+            i as f64 * TIMELINE_ROW + 15.0,
+            escape(&source.name)
+        );
+    }
+    for outage in &history.outages {
+        let _ = writeln!(
+            html,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#d33\"><title>{} for {:.1} s</title></rect>",
+            (outage.start_unix - first) / span * CHART_WIDTH,
+            outage.source as f64 * TIMELINE_ROW + 2.0,
+            (outage.duration.as_secs_f64() / span * CHART_WIDTH).max(2.0),
+            TIMELINE_ROW - 4.0,
+            LocalTime::at(outage.start_unix),
+            outage.duration.as_secs_f64()
+        );
+    }
+    axis(html, first, last, height);
+    html.push_str("</svg>\n");
+}
+
+/// The longest outages, in the order they happened.
+fn outage_table(html: &mut String, history: &History) {
+    let outages = history.longest_outages(MAX_OUTAGES_LISTED);
+    if outages.len() < history.outages.len() {
+        let _ = writeln!(
+            html,
+            "<p>The {} longest of {}:</p>",
+            outages.len(),
+            history.outages.len()
+        );
+    }
+    html.push_str("<table>\n<tr><th>Start</th><th>Duration</th><th>Host</th></tr>\n");
+    for outage in outages {
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{:.1} s</td><td>{}</td></tr>",
+            LocalTime::at(outage.start_unix),
+            outage.duration.as_secs_f64(),
+            escape(&history.sources[outage.source].name)
+        );
+    }
+    html.push_str("</table>\n");
+}
+
+/// Loss per day and hour, green to red saturating at 5%.
+fn heatmap(html: &mut String, report: &TimeOfDay) {
+    html.push_str("<table class=\"heat\">\n<tr><th></th>");
+    for hour in 0..24 {
+        let _ = write!(html, "<th>{hour:02}</th>");
+    }
+    html.push_str("</tr>\n");
+    for (day, hours) in DAYS.iter().zip(&report.cells) {
+        let _ = write!(html, "<tr><th>{day}</th>");
+        for cell in hours {
+            if cell.sent == 0 {
+                html.push_str("<td></td>");
+                continue;
+            }
+            let hue = 120.0 * (1.0 - (cell.loss() / 5.0).min(1.0));
+            let _ = write!(
+                html,
+                "<td style=\"background:hsl({hue:.0},70%,60%)\" title=\"{} probes, {} outages\">{:.2}</td>",
+                cell.sent,
+                cell.outages,
+                cell.loss()
+            );
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+}
+
+/// Start and end time under a chart `height` high.
+fn axis(html: &mut String, first: f64, last: f64, height: f64) {
+    let _ = writeln!(
+        html,
+        "<text x=\"4\" y=\"{:.1}\">{}</text><text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>",
+        height + 15.0,
+        LocalTime::at(first),
+        CHART_WIDTH - 4.0,
+        height + 15.0,
+        LocalTime::at(last)
+    );
+}
+
+fn ms(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,94 @@
+//! State file of a client resuming its session after a restart, see
+//! [`ClientConfig::resume`](crate::ClientConfig::resume): the host, the
+//! session token and the secret resuming it takes, in the TOML subset of
+//! scenario files. The secret is as good as the session, so the file is
+//! only readable by its owner where permissions allow.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    json,
+    session::{ResumeSecret, RESUME_SECRET_SIZE},
+    toml::{self, Value},
+};
+
+/// Token and resume secret of the session last opened with `host`, if
+/// `path` holds one. Anything unreadable just means starting over, as the
+/// file is rewritten with the new session anyway.
+pub(crate) fn load(path: &Path, host: &str) -> eyre::Result<Option<(u32, ResumeSecret)>> {
+    let input = match fs::read_to_string(path) {
+        Ok(input) => input,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(eyre::eyre!("reading {}: {e}", path.display())),
+    };
+    let Ok(tables) = toml::parse(&input) else {
+        return Ok(None);
+    };
+    let entries = &tables[0].entries;
+    let get = |key: &str| {
+        entries
+            .iter()
+            .find(|(k, _, _)| k == key)
+            .map(|(_, value, _)| value)
+    };
+    // a session with another server is of no use
+    if get("host") != Some(&Value::String(host.to_string())) {
+        return Ok(None);
+    }
+    let token = match get("token") {
+        Some(&Value::Integer(token)) => u32::try_from(token).ok(),
+        _ => None,
+    };
+    // files of versions predating secrets, and sessions with servers that
+    // hand none out, can't be resumed
+    let secret = match get("secret") {
+        Some(Value::String(secret)) => decode_hex(secret),
+        _ => None,
+    };
+    Ok(token.zip(secret))
+}
+
+/// Store the session `token` opened with `host` and the secret resuming it
+/// takes, replacing the file whole so a crash midway leaves the previous
+/// one.
+pub(crate) fn save(
+    path: &Path,
+    host: &str,
+    token: u32,
+    secret: Option<&ResumeSecret>,
+) -> eyre::Result<()> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    let mut contents = format!(
+        "# session of a loss_lens client, resumed when it restarts\nhost = {}\ntoken = {token}\n",
+        json::string(host)
+    );
+    if let Some(secret) = secret {
+        let secret: String = secret.iter().map(|byte| format!("{byte:02x}")).collect();
+        contents.push_str(&format!("secret = \"{secret}\"\n"));
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&temporary)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .and_then(|()| fs::rename(&temporary, path))
+        .map_err(|e| eyre::eyre!("writing {}: {e}", path.display()))
+}
+
+fn decode_hex(hex: &str) -> Option<ResumeSecret> {
+    if hex.len() != 2 * RESUME_SECRET_SIZE || !hex.is_ascii() {
+        return None;
+    }
+    let mut secret = [0u8; RESUME_SECRET_SIZE];
+    for (byte, digits) in secret.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(secret)
+}
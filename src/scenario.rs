@@ -0,0 +1,231 @@
+//! Scripted test plans: a TOML file of phases that the client runs one after
+//! another, each with its own settings and results.
+//!
+//! ```toml
+//! [[phase]]
+//! name = "baseline"
+//! duration = "30s"
+//!
+//! [[phase]]
+//! name = "large-ef"
+//! duration = "1m"
+//! rate = 100
+//! size = 1400
+//! dscp = 46
+//! ```
+//!
+//! Settings a phase leaves out keep the client's configuration.
+//!
+//! A bandwidth sweep is the same thing generated from a list of rates, see
+//! [`Scenario::sweep`].
+
+use std::{path::Path, time::Duration};
+
+use crate::{
+    toml::{self, Value},
+    ClientConfig,
+};
+
+#[derive(Clone, Debug)]
+pub struct Phase {
+    pub name: String,
+    pub duration: Duration,
+    pub packets_per_second: Option<u32>,
+    pub probe_size: Option<usize>,
+    pub dscp: Option<u8>,
+    pub pad_acks: Option<bool>,
+    pub poisson: Option<bool>,
+}
+
+impl Phase {
+    /// `base` with this phase's settings applied.
+    pub fn config(&self, base: &ClientConfig) -> ClientConfig {
+        let mut config = base.clone();
+        config.duration = Some(self.duration);
+        if let Some(rate) = self.packets_per_second {
+            config.packets_per_second = rate;
+        }
+        if let Some(size) = self.probe_size {
+            config.probe_size = size;
+            config.size_mix.clear();
+        }
+        if self.dscp.is_some() {
+            config.dscp = self.dscp;
+        }
+        if let Some(pad_acks) = self.pad_acks {
+            config.pad_acks = pad_acks;
+        }
+        if let Some(poisson) = self.poisson {
+            config.poisson = poisson;
+        }
+        config
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    pub phases: Vec<Phase>,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(input: &str) -> eyre::Result<Self> {
+        let mut phases = Vec::new();
+        for table in toml::parse(input)? {
+            if table.name.is_empty() {
+                if let Some((key, _, line)) = table.entries.first() {
+                    eyre::bail!("line {line}: unexpected top-level key {key}");
+                }
+                continue;
+            }
+            eyre::ensure!(
+                table.name == "phase" && table.array,
+                "line {}: unknown table {}, expected [[phase]]",
+                table.line,
+                table.name
+            );
+            let mut phase = Phase {
+                name: format!("phase{}", phases.len() + 1),
+                duration: Duration::ZERO,
+                packets_per_second: None,
+                probe_size: None,
+                dscp: None,
+                pad_acks: None,
+                poisson: None,
+            };
+            let mut has_duration = false;
+            for (key, value, line) in table.entries {
+                let invalid = || eyre::eyre!("line {line}: invalid value for {key}");
+                match (key.as_str(), value) {
+                    ("name", Value::String(name)) => phase.name = name,
+                    ("duration", Value::String(s)) => {
+                        phase.duration =
+                            parse_duration(&s).map_err(|e| eyre::eyre!("line {line}: {e}"))?;
+                        has_duration = true;
+                    }
+                    ("duration", Value::Integer(secs)) => {
+                        phase.duration =
+                            Duration::from_secs(secs.try_into().map_err(|_| invalid())?);
+                        has_duration = true;
+                    }
+                    ("rate", Value::Integer(rate)) => {
+                        phase.packets_per_second = Some(rate.try_into().map_err(|_| invalid())?)
+                    }
+                    ("size", Value::Integer(size)) => {
+                        phase.probe_size = Some(size.try_into().map_err(|_| invalid())?)
+                    }
+                    ("dscp", Value::Integer(dscp)) => {
+                        phase.dscp = Some(
+                            u8::try_from(dscp)
+                                .ok()
+                                .filter(|&d| d < 64)
+                                .ok_or_else(invalid)?,
+                        )
+                    }
+                    ("pad_acks", Value::Boolean(pad)) => phase.pad_acks = Some(pad),
+                    ("poisson", Value::Boolean(poisson)) => phase.poisson = Some(poisson),
+                    (
+                        "name" | "duration" | "rate" | "size" | "dscp" | "pad_acks" | "poisson",
+                        _,
+                    ) => return Err(invalid()),
+                    _ => eyre::bail!("line {line}: unknown key {key}"),
+                }
+            }
+            eyre::ensure!(
+                has_duration,
+                "line {}: phase {} needs a duration",
+                table.line,
+                phase.name
+            );
+            phases.push(phase);
+        }
+        eyre::ensure!(!phases.is_empty(), "scenario has no phases");
+        Ok(Self { phases })
+    }
+
+    /// One phase of `step` per rate, in increasing order, to find the rate
+    /// at which loss begins.
+    pub fn sweep(rates: &[u32], step: Duration) -> Self {
+        let mut rates = rates.to_vec();
+        rates.sort_unstable();
+        rates.dedup();
+        let phases = rates
+            .into_iter()
+            .map(|rate| Phase {
+                name: format!("{rate}pps"),
+                duration: step,
+                packets_per_second: Some(rate),
+                probe_size: None,
+                dscp: None,
+                pad_acks: None,
+                poisson: None,
+            })
+            .collect();
+        Self { phases }
+    }
+}
+
+/// Whole numbers with units `ms`, `s`, `m`, `h` or `d`, optionally combined
+/// like `1h30m`. A bare number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    if let Ok(secs) = s.parse() {
+        return Ok(Duration::from_secs(secs));
+    }
+    if s.is_empty() {
+        return Err("empty duration".into());
+    }
+    let too_long = || format!("duration {s:?} is too long");
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit_len = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len() - digits);
+        let (value, unit) = (&rest[..digits], &rest[digits..digits + unit_len]);
+        let value: u64 = value.parse().map_err(|_| format!("bad duration {s:?}"))?;
+        let secs = |per: u64| value.checked_mul(per).map(Duration::from_secs);
+        let part = match unit {
+            "ms" => Some(Duration::from_millis(value)),
+            "s" => Some(Duration::from_secs(value)),
+            "m" => secs(60),
+            "h" => secs(3600),
+            "d" => secs(86400),
+            _ => return Err(format!("bad duration unit {unit:?} in {s:?}")),
+        };
+        total = part
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(too_long)?;
+        rest = &rest[digits + unit_len..];
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_add_up_or_are_rejected() {
+        let secs = Duration::from_secs;
+        assert_eq!(parse_duration("90"), Ok(secs(90)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1h30m"), Ok(secs(5400)));
+        assert_eq!(parse_duration("1d2h3m4s"), Ok(secs(93784)));
+        for bad in ["", "s", "10 s", "1x", "1h-1m", "1.5s"] {
+            assert!(parse_duration(bad).is_err(), "{bad:?}");
+        }
+        for long in [
+            "1s18446744073709551615s",
+            "213503982334602d",
+            "18446744073709551615m",
+        ] {
+            assert!(parse_duration(long).is_err(), "{long:?}");
+        }
+    }
+}
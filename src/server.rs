@@ -0,0 +1,1623 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    admin,
+    auth::{self, Psk},
+    batch::{BatchReceiver, BatchSender},
+    capacity::{self, TRAIN_ACK_SIZE},
+    cidr::Cidr,
+    ecn::{self, ECN_MASK},
+    flood::{FLOOD_PACKET_CONST, FLOOD_PACKET_MIN_SIZE},
+    gso::SegmentSender,
+    json, mdns,
+    multicast::{self, MulticastConfig},
+    nat_timeout::{self, MAX_DELAY_SECS, NAT_TIMEOUT_REPLY_SIZE},
+    noise::{self, Incoming, Keypair, Transport},
+    portmap::{self, PortMapping},
+    protocol::{self, Header, Request},
+    ratelimit::RateLimiter,
+    recording::SessionRecording,
+    rendezvous::{Registry, Relaying},
+    replay::ReplayWindow,
+    resolve,
+    session::{
+        self, CookieJar, ResumeSecret, Resumption, Session, COOKIE_SIZE, FEATURE_CHECKSUMS,
+        FEATURE_ECN, FEATURE_EPOCH, FEATURE_FLOOD, FEATURE_HOPS, FEATURE_PAD_ACKS, FEATURE_RESUME,
+        FEATURE_SYMMETRIC, FEATURE_TRAINS, PADDED_HANDSHAKE_SIZE, PROTOCOL_VERSION,
+        RESUME_HANDSHAKE_SIZE,
+    },
+    session_log::SessionLog,
+    stun, twamp, with_version, IpVersion, StopHandle, BUF_SIZE, CLIENT_TO_SERVER_PACKET_SIZE,
+    DEFAULT_PACKETS_PER_SECOND, HOP_REPLY_PACKET_CONST, MAX_PACKET_SIZE, NOISE_INIT_PACKET_CONST,
+};
+
+/// Settings for a [`ProbeServer`].
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Address to listen on
+    pub host: String,
+    /// Accept IPv4 as well on an IPv6 `host` such as `[::]:13337`, even
+    /// where sockets are IPv6-only by default
+    pub dual_stack: bool,
+    pub limits: ServerLimits,
+    /// Serve the admin HTTP API
+    pub admin: Option<AdminConfig>,
+    /// Answer downstream flood requests within these limits; they are
+    /// ignored when unset, as anyone who can spoof a source address could
+    /// aim a flood at it
+    pub flood: Option<FloodLimits>,
+    /// Grant symmetric sessions, streaming probes of the server's own to
+    /// the client, see [`FEATURE_SYMMETRIC`]. Like floods they go to
+    /// whichever address handshaked, so this is best combined with
+    /// `require_cookie` where sources can be spoofed
+    pub symmetric: bool,
+    /// Introduce clients registering under the same name to each other, for
+    /// them to probe each other directly, see [`crate::rendezvous`]
+    pub rendezvous: bool,
+    /// Relay packets between clients that met at the rendezvous but
+    /// couldn't punch through to each other, on a port of their own each
+    pub relay: bool,
+    /// Answer STUN binding requests with the address they came from, so
+    /// clients can ask the server how NATs map them, see [`crate::stun`]
+    pub stun: bool,
+    /// Also stream sequenced packets to a multicast group for clients
+    /// joining it to measure
+    pub multicast: Option<MulticastConfig>,
+    /// Also reflect TWAMP-Light test packets on this address, see
+    /// [`crate::twamp`]
+    pub twamp: Option<String>,
+    /// Advertise the server on the local network with mDNS under this
+    /// instance name, see [`crate::mdns`]
+    pub advertise: Option<String>,
+    /// Ask the local gateway to forward the server's port, with NAT-PMP or
+    /// else UPnP, so it's reachable from the internet when hosted behind a
+    /// home router, see [`crate::portmap`]
+    pub upnp: bool,
+    /// Only answer sources in these ranges, if any are given
+    pub allow: Vec<Cidr>,
+    /// Never answer sources in these ranges, even allowed ones
+    pub deny: Vec<Cidr>,
+    /// Only count clients that completed a handshake echoing an
+    /// address-bound cookie, so spoofed sources can't create state or have
+    /// ACKs sent to them. Clients predating handshakes are ignored
+    pub require_cookie: bool,
+    /// Only answer packets authenticated with this key, and authenticate
+    /// every answer, see [`crate::auth`]
+    pub psk: Option<Psk>,
+    /// Static key for encrypted sessions, see [`crate::noise`]; plaintext
+    /// clients are still served
+    pub noise: Option<Keypair>,
+    /// Append the lifecycle of client sessions to this file as JSON lines,
+    /// see [`crate::session_log`]
+    pub session_log: Option<PathBuf>,
+    /// Record which probes of each session arrived to a file per session
+    /// in this directory, in the format of the client's recording, named
+    /// `CLIENT_ID-UNIX_SECS.zst`. A session's record ends once it's
+    /// forgotten, and one coming back gets a new one
+    pub record_dir: Option<PathBuf>,
+    /// Serve on this many threads, each with a socket of its own bound to
+    /// the port with `SO_REUSEPORT`, the kernel sharding clients among them
+    /// by address and port. Unix only, and not with `rendezvous`, whose
+    /// clients must meet on one socket. `per_ip_rate` applies per thread
+    pub workers: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1:13337".to_string(),
+            dual_stack: false,
+            limits: ServerLimits::default(),
+            admin: None,
+            flood: None,
+            symmetric: false,
+            rendezvous: false,
+            relay: false,
+            stun: false,
+            multicast: None,
+            twamp: None,
+            advertise: None,
+            upnp: false,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            require_cookie: false,
+            psk: None,
+            noise: None,
+            session_log: None,
+            record_dir: None,
+            workers: 1,
+        }
+    }
+}
+
+/// Caps applied to every flood request, see [`crate::flood`].
+#[derive(Clone, Copy, Debug)]
+pub struct FloodLimits {
+    pub max_rate: u32,
+    pub max_duration: Duration,
+    /// Largest flood packet payload
+    pub max_size: usize,
+}
+
+impl Default for FloodLimits {
+    fn default() -> Self {
+        Self {
+            max_rate: 1000,
+            max_duration: Duration::from_secs(60),
+            max_size: 1472,
+        }
+    }
+}
+
+/// Floods running at once; further requests are ignored
+const MAX_FLOODS: usize = 8;
+/// Symmetric sessions streaming at once; further handshakes asking for one
+/// are refused it
+const MAX_STREAMS: usize = 64;
+/// Streams pause while their client has been silent this long, so ones
+/// aimed at a spoofed source or a client gone away don't last until the
+/// session expires
+const STREAM_SILENCE: Duration = Duration::from_secs(2);
+/// Failed answers are logged at most this often
+const SEND_ERROR_INTERVAL: Duration = Duration::from_secs(1);
+/// NAT timeout tests waiting for their late answer at most, one per client
+const MAX_LATE_ANSWERS: usize = 1024;
+/// How often clients are checked for having gone idle, and recordings for
+/// having ended
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Blocking waits are cut this short to notice stop requests
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Sessions recorded at once with a `record_dir`; later ones aren't, as
+/// each takes a thread and possibly a zstd process
+const MAX_RECORDINGS: usize = 256;
+
+/// The answer a NAT timeout test waits for at the end of its idle time,
+/// see [`crate::nat_timeout`].
+struct LateAnswer {
+    due: Instant,
+    client_id: u32,
+    addr: SocketAddr,
+    packet: [u8; NAT_TIMEOUT_REPLY_SIZE],
+    /// Keys and the client's index, if the request came encrypted
+    session: Option<(Arc<Transport>, u32)>,
+}
+
+/// Answers that couldn't be sent, e.g. for a full send buffer or an ICMP
+/// error an earlier one caused, which only that client misses out on.
+#[derive(Default)]
+struct SendErrors {
+    last_logged: Cell<Option<Instant>>,
+    suppressed: Cell<u32>,
+}
+
+impl SendErrors {
+    fn log(&self, addr: SocketAddr, e: &io::Error) {
+        if self
+            .last_logged
+            .get()
+            .is_some_and(|logged| logged.elapsed() < SEND_ERROR_INTERVAL)
+        {
+            self.suppressed.set(self.suppressed.get() + 1);
+            return;
+        }
+        match self.suppressed.replace(0) {
+            0 => crate::log::warn!("Answering {addr} failed: {e}"),
+            n => crate::log::warn!("Answering {addr} failed: {e}, and {n} more answers before"),
+        }
+        self.last_logged.set(Some(Instant::now()));
+    }
+}
+
+/// Client table housekeeping, adjustable at runtime through the admin API.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerLimits {
+    /// Clients silent for this long are forgotten
+    pub idle_timeout: Duration,
+    /// Clients tracked at most, split evenly between workers; a new one
+    /// beyond takes the place of the one heard from least recently
+    pub max_clients: usize,
+    /// Highest probe rate granted in session handshakes
+    pub max_rate: u32,
+    /// Packets handled a second from each source address, with bursts of
+    /// up to a second's worth; further ones are dropped unanswered. Mind
+    /// that capacity tests send trains in bursts
+    pub per_ip_rate: Option<u32>,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            // outlasting outages clients want to measure across
+            idle_timeout: Duration::from_secs(300),
+            max_clients: 10_000,
+            max_rate: 10_000,
+            per_ip_rate: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AdminConfig {
+    /// Address for the admin HTTP API
+    pub addr: String,
+    /// Bearer token every request must present
+    pub token: String,
+}
+
+/// Per-client bookkeeping.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientEntry {
+    pub received: u64,
+    /// Probes received again, e.g. from a duplicating middlebox or replayed,
+    /// which don't count as received
+    pub duplicates: u32,
+    /// Sequence numbers received; up to 4032 behind the highest one, so
+    /// probes reordered further than that are dropped too
+    pub replays: ReplayWindow<64>,
+    /// Whether the session's probes end in a checksum
+    pub checksums: bool,
+    /// Whether the session's ACKs carry the server's epoch
+    pub epochs: bool,
+    /// Probes received with a wrong checksum, which don't count as received
+    pub corrupted: u32,
+    /// Probe rate announced by the client, if any
+    pub packets_per_second: Option<u32>,
+    /// The server's own probes in symmetric sessions
+    pub stream: Option<Stream>,
+    /// What resuming the session takes, see [`FEATURE_RESUME`]
+    pub resume_secret: ResumeSecret,
+    /// Whether the session was opened by an encrypted handshake, so it's
+    /// never resumed in plaintext
+    pub encrypted: bool,
+    /// Whether the session was opened by a handshake, rather than by a
+    /// client predating them, whose id anyone can make up
+    pub handshaked: bool,
+    /// Start of the second hop probes are counted in, and how many were
+    /// answered in it
+    pub hop_window: Instant,
+    pub hop_replies: u32,
+    pub addr: SocketAddr,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+}
+
+impl ClientEntry {
+    /// Whether another hop probe may be answered, counting it: up to
+    /// `max_rate` a second, the highest probe rate a session is granted.
+    fn hop_allowed(&mut self, now: Instant, max_rate: u32) -> bool {
+        if now.duration_since(self.hop_window) >= Duration::from_secs(1) {
+            self.hop_window = now;
+            self.hop_replies = 0;
+        }
+        self.hop_replies = self.hop_replies.saturating_add(1);
+        self.hop_replies <= max_rate
+    }
+}
+
+/// Counters of the probes the server streams to a client, see
+/// [`FEATURE_SYMMETRIC`].
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Stream {
+    pub sent: u64,
+    /// Received by the client, as of its latest answer
+    pub acked: u64,
+}
+
+/// Clients and the keys of their encrypted sessions, by token. A token is
+/// in the shard of its remainder by the number of workers, and each worker
+/// hands out tokens of its own shard, which the kernel keeps sending the
+/// client's packets to by address and port.
+#[derive(Default)]
+pub(crate) struct Shard {
+    /// Changed through [`Shard::insert`], [`Shard::touch`] and
+    /// [`Shard::remove`], which keep the indexes below in step
+    pub clients: HashMap<u32, ClientEntry>,
+    /// Keys of encrypted sessions, with the client's index for them;
+    /// forgotten with the client
+    pub sessions: HashMap<u32, (Arc<Transport>, u32)>,
+    /// Handshaked clients by when they were last heard from, so the least
+    /// recent is found without a scan
+    by_last_seen: BTreeSet<(Instant, u32)>,
+    /// Likewise clients predating handshakes
+    legacy_by_last_seen: BTreeSet<(Instant, u32)>,
+}
+
+impl Shard {
+    fn index(&mut self, handshaked: bool) -> &mut BTreeSet<(Instant, u32)> {
+        if handshaked {
+            &mut self.by_last_seen
+        } else {
+            &mut self.legacy_by_last_seen
+        }
+    }
+
+    fn insert(&mut self, token: u32, e: ClientEntry) -> &mut ClientEntry {
+        self.index(e.handshaked).insert((e.last_seen, token));
+        self.clients.entry(token).insert_entry(e).into_mut()
+    }
+
+    /// Mark the client of `token` heard from `now`.
+    fn touch(&mut self, token: u32, now: Instant) -> Option<&mut ClientEntry> {
+        let (last_seen, handshaked) = self
+            .clients
+            .get(&token)
+            .map(|e| (e.last_seen, e.handshaked))?;
+        let index = self.index(handshaked);
+        index.remove(&(last_seen, token));
+        index.insert((now, token));
+        let e = self.clients.get_mut(&token)?;
+        e.last_seen = now;
+        Some(e)
+    }
+
+    /// Forget the client of `token`, with the keys of an encrypted session,
+    /// which would otherwise still decrypt its packets.
+    pub fn remove(&mut self, token: u32) -> Option<ClientEntry> {
+        let e = self.clients.remove(&token)?;
+        self.index(e.handshaked).remove(&(e.last_seen, token));
+        self.sessions.remove(&token);
+        Some(e)
+    }
+
+    /// The client heard from least recently, only among those predating
+    /// handshakes if `legacy`, and of those first otherwise.
+    fn least_recent(&self, legacy: bool) -> Option<u32> {
+        let oldest = |index: &BTreeSet<(Instant, u32)>| index.first().map(|&(_, token)| token);
+        oldest(&self.legacy_by_last_seen).or_else(|| {
+            if legacy {
+                None
+            } else {
+                oldest(&self.by_last_seen)
+            }
+        })
+    }
+
+    /// Forget a client last heard from at `cutoff` or before.
+    fn pop_expired(&mut self, cutoff: Instant) -> Option<(u32, ClientEntry)> {
+        let &(_, token) = [&self.by_last_seen, &self.legacy_by_last_seen]
+            .into_iter()
+            .filter_map(BTreeSet::first)
+            .filter(|&&(last_seen, _)| last_seen <= cutoff)
+            .min()?;
+        Some((token, self.remove(token)?))
+    }
+}
+
+/// State shared between the reflector loops and the admin API.
+pub(crate) struct ServerState {
+    /// A shard per worker, see [`Shard`]
+    pub shards: Vec<Mutex<Shard>>,
+    pub limits: Mutex<ServerLimits>,
+    pub started: Instant,
+    /// Picked at random on start and sent in ACKs, see [`FEATURE_EPOCH`]
+    pub epoch: u32,
+    pub reflected: AtomicU64,
+    /// Packets dropped for [`ServerLimits::per_ip_rate`]
+    pub rate_limited: AtomicU64,
+    /// Sessions started, new and resumed ones alike
+    pub served: AtomicU64,
+    /// Clients forgotten for [`ServerLimits::idle_timeout`]
+    pub expired: AtomicU64,
+    /// Clients forgotten for [`ServerLimits::max_clients`]
+    pub evicted: AtomicU64,
+    /// Running floods by client id
+    pub floods: Mutex<HashMap<u32, StopHandle>>,
+    /// Running streams of symmetric sessions by token
+    pub streams: Mutex<HashMap<u32, StopHandle>>,
+}
+
+impl ServerState {
+    /// The shard `token` is kept in.
+    pub fn shard(&self, token: u32) -> &Mutex<Shard> {
+        &self.shards[token as usize % self.shards.len()]
+    }
+}
+
+/// Reflector that acknowledges probes with a per-client receive counter.
+pub struct ProbeServer {
+    socket: UdpSocket,
+    /// Further sockets on the same port, see [`ServerConfig::workers`]
+    shards: Vec<UdpSocket>,
+    done: StopHandle,
+    state: Arc<ServerState>,
+    flood: Option<FloodLimits>,
+    symmetric: bool,
+    rendezvous: bool,
+    relay: bool,
+    stun: bool,
+    multicast: Option<MulticastConfig>,
+    twamp: Option<UdpSocket>,
+    advertise: Option<String>,
+    port_mapping: Option<PortMapping>,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    require_cookie: bool,
+    psk: Option<Psk>,
+    noise: Option<Keypair>,
+    session_log: Option<SessionLog>,
+    record_dir: Option<PathBuf>,
+}
+
+impl ProbeServer {
+    pub fn new(config: ServerConfig) -> eyre::Result<Self> {
+        eyre::ensure!(config.workers > 0, "a server needs at least one worker");
+        eyre::ensure!(
+            config.workers == 1 || !config.rendezvous,
+            "the rendezvous needs a single worker"
+        );
+        let reuse_port = config.workers > 1;
+        let socket = if config.dual_stack || reuse_port {
+            let version = config.dual_stack.then_some(IpVersion::V6);
+            bind(
+                resolve(&config.host, version)?,
+                config.dual_stack,
+                reuse_port,
+            )?
+        } else {
+            UdpSocket::bind(&config.host)?
+        };
+        // the same port even if the first picked one
+        let shards = (1..config.workers)
+            .map(|_| bind(socket.local_addr()?, config.dual_stack, true))
+            .collect::<eyre::Result<_>>()?;
+        Self::with_sockets(socket, shards, config)
+    }
+
+    /// Serve on an already bound socket, e.g. one passed in by systemd socket
+    /// activation. `config.host` and `config.workers` are ignored.
+    pub fn from_socket(socket: UdpSocket, config: ServerConfig) -> eyre::Result<Self> {
+        Self::with_sockets(socket, Vec::new(), config)
+    }
+
+    fn with_sockets(
+        socket: UdpSocket,
+        shards: Vec<UdpSocket>,
+        config: ServerConfig,
+    ) -> eyre::Result<Self> {
+        let state = Arc::new(ServerState {
+            shards: (0..=shards.len()).map(|_| Mutex::default()).collect(),
+            limits: Mutex::new(config.limits),
+            started: Instant::now(),
+            epoch: rand::random(),
+            reflected: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            served: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            floods: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+        });
+        if let Some(multicast) = &config.multicast {
+            multicast.validate()?;
+        }
+        let twamp = config
+            .twamp
+            .as_deref()
+            .map(|addr| {
+                UdpSocket::bind(addr)
+                    .map_err(|e| eyre::eyre!("binding the TWAMP-Light reflector to {addr}: {e}"))
+            })
+            .transpose()?;
+        if let Some(admin) = &config.admin {
+            admin::serve(admin, Arc::clone(&state))?;
+        }
+        let port_mapping = config
+            .upnp
+            .then(|| portmap::map(socket.local_addr()?))
+            .transpose()?;
+        let session_log = config
+            .session_log
+            .as_deref()
+            .map(SessionLog::open)
+            .transpose()?;
+        if let Some(dir) = &config.record_dir {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| eyre::eyre!("creating {}: {e}", dir.display()))?;
+        }
+        Ok(Self {
+            socket,
+            shards,
+            done: StopHandle::default(),
+            state,
+            flood: config.flood,
+            symmetric: config.symmetric,
+            rendezvous: config.rendezvous,
+            relay: config.relay,
+            stun: config.stun,
+            multicast: config.multicast,
+            twamp,
+            advertise: config.advertise,
+            port_mapping,
+            allow: config.allow,
+            deny: config.deny,
+            require_cookie: config.require_cookie,
+            psk: config.psk,
+            noise: config.noise,
+            session_log,
+            record_dir: config.record_dir,
+        })
+    }
+
+    pub fn local_addr(&self) -> eyre::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Where the TWAMP-Light reflector listens, if configured.
+    pub fn twamp_addr(&self) -> eyre::Result<Option<SocketAddr>> {
+        Ok(self.twamp.as_ref().map(UdpSocket::local_addr).transpose()?)
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.done.clone()
+    }
+
+    /// The port the gateway forwards to the server if configured to ask
+    /// for one, kept until the server stops.
+    pub fn port_mapping(&self) -> Option<&PortMapping> {
+        self.port_mapping.as_ref()
+    }
+
+    /// Run the server on a background thread.
+    pub fn spawn(self) -> ServerHandle {
+        let stop = self.stop_handle();
+        let thread = thread::spawn(move || self.run());
+        ServerHandle { stop, thread }
+    }
+
+    /// Serve probes until stopped, finishing the session log and
+    /// recordings before returning.
+    pub fn run(self) -> eyre::Result<ServerSummary> {
+        let multicast = self
+            .multicast
+            .clone()
+            .map(|config| multicast::spawn_sender(config, self.done.clone()))
+            .transpose()?;
+        let reflector = self
+            .twamp
+            .as_ref()
+            .map(|socket| twamp::spawn_reflector(socket.try_clone()?, self.done.clone()))
+            .transpose()?;
+        let responder = self
+            .advertise
+            .as_deref()
+            .map(|instance| {
+                mdns::spawn_responder(instance, self.socket.local_addr()?, self.done.clone())
+            })
+            .transpose()?;
+        let renewal = self
+            .port_mapping
+            .clone()
+            .map(|mapping| portmap::spawn_renewal(mapping, self.done.clone()));
+
+        thread::scope(|scope| {
+            scope.spawn(|| self.keep_house());
+            let shards: Vec<_> = self
+                .shards
+                .iter()
+                .enumerate()
+                .map(|(i, socket)| {
+                    let server = &self;
+                    scope.spawn(move || server.serve(i + 1, socket))
+                })
+                .collect();
+            let served = self.serve(0, &self.socket);
+            shards
+                .into_iter()
+                .map(|shard| shard.join().unwrap())
+                .fold(served, Result::and)
+        })?;
+        if let Some(log) = &self.session_log {
+            for shard in &self.state.shards {
+                for (&client_id, e) in &shard.lock().unwrap().clients {
+                    log.end(client_id, e, "shutdown");
+                }
+            }
+        }
+        if let Some(sender) = multicast {
+            sender.join().unwrap()?;
+        }
+        if let Some(reflector) = reflector {
+            reflector.join().unwrap()?;
+        }
+        if let Some(renewal) = renewal {
+            renewal.join().unwrap();
+        }
+        if let Some(responder) = responder {
+            responder.join().unwrap()?;
+        }
+        Ok(ServerSummary {
+            clients_served: self.state.served.load(Ordering::Relaxed),
+            packets_reflected: self.state.reflected.load(Ordering::Relaxed),
+            uptime: self.state.started.elapsed(),
+        })
+    }
+
+    /// Answer what arrives on `socket` as worker `worker` until stopped; one
+    /// failing stops the others too.
+    fn serve(&self, worker: usize, socket: &UdpSocket) -> eyre::Result<()> {
+        let served = self.serve_until_stopped(worker, socket);
+        if served.is_err() {
+            self.done.stop();
+        }
+        served
+    }
+
+    /// Forget clients silent for the idle timeout, on a timer of its own
+    /// so it doesn't wait for packets to arrive.
+    fn keep_house(&self) {
+        let mut last_check = Instant::now();
+        while !self.done.is_stopped() {
+            thread::sleep(STOP_POLL_INTERVAL);
+            if last_check.elapsed() < IDLE_CHECK_INTERVAL {
+                continue;
+            }
+            last_check = Instant::now();
+            let idle_timeout = self.state.limits.lock().unwrap().idle_timeout;
+            // not up long enough for anyone to be idle that long
+            let Some(cutoff) = last_check.checked_sub(idle_timeout) else {
+                continue;
+            };
+            // a shard at a time, so workers wait for one at most
+            for shard in &self.state.shards {
+                let mut shard = shard.lock().unwrap();
+                while let Some((client_id, e)) = shard.pop_expired(cutoff) {
+                    self.state.expired.fetch_add(1, Ordering::Relaxed);
+                    if let Some(log) = &self.session_log {
+                        log.end(client_id, &e, "expired");
+                    }
+                }
+            }
+        }
+    }
+
+    fn serve_until_stopped(&self, worker: usize, socket: &UdpSocket) -> eyre::Result<()> {
+        // woken for the next late answer, or else to notice stop requests
+        let mut timeout = STOP_POLL_INTERVAL;
+        socket.set_read_timeout(Some(timeout))?;
+        ecn::enable_receive(socket);
+
+        let mut cookies = self.require_cookie.then(CookieJar::new);
+        let psk = self.psk.as_ref();
+        let send_errors = SendErrors::default();
+        let mut receiver = BatchReceiver::new();
+        // answers go out together once what arrived together is handled
+        let outbox = RefCell::new(BatchSender::default());
+        let send_to = |packet: &[u8], addr: SocketAddr| outbox.borrow_mut().push(packet, addr);
+        let flush = || {
+            outbox
+                .borrow_mut()
+                .flush(socket, |addr, e| send_errors.log(addr, e));
+        };
+        let send_plain = |packet: &[u8], addr: SocketAddr| send_to(&auth::seal(psk, packet), addr);
+        let relaying = if self.relay {
+            Some(Relaying {
+                ip: socket.local_addr()?.ip(),
+                psk: self.psk.clone(),
+                done: self.done.clone(),
+            })
+        } else {
+            None
+        };
+        let mut registry = self.rendezvous.then(|| Registry::new(relaying));
+        let mut late_answers: Vec<LateAnswer> = Vec::new();
+        let mut next_due = None::<Instant>;
+
+        let mut buf = vec![0u8; BUF_SIZE];
+
+        let mut limits = *self.state.limits.lock().unwrap();
+        let mut last_recording_check = Instant::now();
+        let mut rate_limiter = RateLimiter::new(Instant::now());
+        let mut recordings: HashMap<u32, SessionRecording> = HashMap::new();
+        // recordings of ended sessions being finished, waited for on stop
+        let mut finishing: Vec<JoinHandle<()>> = Vec::new();
+        // sessions whose recording failed or didn't fit, until they end
+        let mut unrecorded: HashSet<u32> = HashSet::new();
+
+        #[cfg(unix)]
+        let mut watchdog = crate::systemd::Watchdog::from_env();
+
+        while !self.done.is_stopped() {
+            #[cfg(unix)]
+            watchdog.ping();
+            if self.record_dir.is_some() && last_recording_check.elapsed() >= IDLE_CHECK_INTERVAL {
+                last_recording_check = Instant::now();
+                // expired or evicted
+                let active = |client_id: &u32| {
+                    let shard = self.state.shard(*client_id).lock().unwrap();
+                    shard.clients.contains_key(client_id)
+                };
+                let ended: Vec<u32> = recordings
+                    .keys()
+                    .copied()
+                    .filter(|id| !active(id))
+                    .collect();
+                for client_id in ended {
+                    let recording = recordings.remove(&client_id).unwrap();
+                    // off the reflector loop, as that waits for zstd
+                    finishing.push(thread::spawn(move || {
+                        if let Err(e) = recording.finish() {
+                            crate::log::warn!("Recording session {client_id:08x} failed: {e:#}");
+                        }
+                    }));
+                }
+                finishing.retain(|thread| !thread.is_finished());
+                unrecorded.retain(active);
+            }
+            if next_due.is_some_and(|due| due <= Instant::now()) {
+                let now = Instant::now();
+                late_answers.retain(|late| {
+                    if late.due > now {
+                        return true;
+                    }
+                    let session = late
+                        .session
+                        .as_ref()
+                        .map(|(transport, client_index)| (&**transport, *client_index));
+                    send_plain(&noise::seal(session, &late.packet), late.addr);
+                    false
+                });
+                next_due = late_answers.iter().map(|late| late.due).min();
+            }
+            let wait = next_due.map_or(STOP_POLL_INTERVAL, |due| {
+                due.saturating_duration_since(Instant::now())
+                    .clamp(Duration::from_millis(1), STOP_POLL_INTERVAL)
+            });
+            if wait != timeout {
+                timeout = wait;
+                socket.set_read_timeout(Some(timeout))?;
+            }
+            if receiver.is_empty() {
+                flush();
+                // once a batch rather than for every packet
+                limits = *self.state.limits.lock().unwrap();
+            }
+            match receiver.recv_from(socket, &mut buf) {
+                // before any state is kept for the source, even a bucket
+                Ok((_, addr, _)) if !self.admitted(addr.ip()) => {}
+                // before anything else, which is what it spares
+                Ok((_, addr, _))
+                    if limits.per_ip_rate.is_some_and(|rate| {
+                        !rate_limiter.allow(addr.ip(), rate, Instant::now())
+                    }) =>
+                {
+                    self.state.rate_limited.fetch_add(1, Ordering::Relaxed);
+                }
+                // unauthenticated, like any STUN server's
+                Ok((n, addr, _)) if self.stun && stun::is_request(&buf[..n]) => {
+                    send_to(&stun::answer(&buf[..n], addr), addr);
+                }
+                // longer probes are padded, see MAX_PACKET_SIZE
+                Ok((n, addr, tos)) if n >= CLIENT_TO_SERVER_PACKET_SIZE + auth::overhead(psk) => {
+                    // forged packets get no answer at all
+                    let Some(mut n) = auth::open(psk, &buf[..n]) else {
+                        continue;
+                    };
+                    let mut encrypted = None;
+                    if let Some(keypair) = &self.noise {
+                        if buf[0] == NOISE_INIT_PACKET_CONST {
+                            let Some(len) = n.checked_sub(COOKIE_SIZE) else {
+                                continue;
+                            };
+                            let (message, echoed) = buf[..n].split_at(len);
+                            if let Some(cookies) = &mut cookies {
+                                // before any key exchange, which spoofed
+                                // sources would otherwise cost
+                                if !cookies.check_echo(addr, echoed) {
+                                    send_plain(&cookies.answer(addr), addr);
+                                    continue;
+                                }
+                            }
+                            if let Some(incoming) = Incoming::read(keypair, message) {
+                                self.accept_encrypted(incoming, worker, &limits, addr, send_plain)?;
+                            }
+                            continue;
+                        }
+                        if let Some(index) = noise::data_index(&buf[..n]) {
+                            let shard = self.state.shard(index).lock().unwrap();
+                            let session = shard.sessions.get(&index).cloned();
+                            drop(shard);
+                            let Some((transport, client_index)) = session else {
+                                // the keys are lost with the session, so
+                                // this has to be in plaintext
+                                let reject = protocol::unknown_session(PROTOCOL_VERSION, index);
+                                send_plain(&reject, addr);
+                                continue;
+                            };
+                            let Some(len) = transport.open(&mut buf[..n]) else {
+                                continue;
+                            };
+                            n = len;
+                            encrypted = Some((transport, client_index, index));
+                        }
+                    }
+                    // anything malformed, unknown or marked by a client newer
+                    // than this server without agreeing on a version first
+                    let Ok((Header { version, .. }, request)) = protocol::request(&buf[..n]) else {
+                        continue;
+                    };
+                    // answers go back the way the packet came
+                    let send = |packet: &[u8], addr: SocketAddr| {
+                        let session = encrypted
+                            .as_ref()
+                            .map(|(transport, client_index, _)| (&**transport, *client_index));
+                        send_plain(&noise::seal(session, packet), addr)
+                    };
+                    if let Request::Register {
+                        nonce,
+                        name,
+                        relay,
+                        attempt,
+                    } = request
+                    {
+                        if let Some(registry) = &mut registry {
+                            let now = Instant::now();
+                            for (to, answer) in
+                                registry.register(name, nonce, relay, attempt, addr, now)
+                            {
+                                send_plain(&answer, to);
+                            }
+                        }
+                        continue;
+                    }
+                    if let Some(cookies) = &mut cookies {
+                        if matches!(request, Request::Handshake(_))
+                            && !cookies.check(addr, &buf[..n])
+                        {
+                            // unpadded handshakes would make this an amplifier
+                            if n >= PADDED_HANDSHAKE_SIZE {
+                                send(&cookies.answer(addr), addr);
+                            }
+                            continue;
+                        }
+                    }
+                    let now = Instant::now();
+                    if let Request::Handshake(hello) = request {
+                        let (welcome, answer) = self.accept(
+                            hello,
+                            &buf[..n],
+                            encrypted.is_some(),
+                            &limits,
+                            worker,
+                            addr,
+                            now,
+                        );
+                        send(&answer, addr);
+                        let session = encrypted.as_ref().map(|(transport, client_index, _)| {
+                            (Arc::clone(transport), *client_index)
+                        });
+                        self.start_stream(&welcome, hello.negotiated_version(), session)?;
+                        continue;
+                    }
+                    let Some(client_id) = request.client_id() else {
+                        continue;
+                    };
+                    let mut shard = self.state.shard(client_id).lock().unwrap();
+                    if let Request::Hop { .. } = request {
+                        // only for known sessions, as for probes, but from
+                        // any port of their address as every TTL has its own
+                        // socket; in plaintext even for encrypted sessions,
+                        // see `noise`
+                        let answer = shard.clients.get_mut(&client_id).is_some_and(|e| {
+                            (cookies.is_none() || e.addr.ip() == addr.ip())
+                                && e.hop_allowed(now, limits.max_rate)
+                        });
+                        drop(shard);
+                        if answer {
+                            // same size as the probe, so nothing to amplify
+                            buf[0] = with_version(HOP_REPLY_PACKET_CONST, version);
+                            send(&buf[..n], addr);
+                        }
+                        continue;
+                    }
+                    // sessions only speak for themselves, and encrypted ones
+                    // only encrypted, so their tokens read off the wire are
+                    // of no use
+                    match &encrypted {
+                        Some((_, _, index)) if *index != client_id => continue,
+                        None if shard.sessions.contains_key(&client_id) => continue,
+                        _ => {}
+                    }
+                    // sessions are only opened by handshakes, so an unknown
+                    // one was forgotten, e.g. in a restart, and has to
+                    // handshake again. With cookies a session is only valid
+                    // from the address that echoed one
+                    let known = shard
+                        .clients
+                        .get(&client_id)
+                        .is_some_and(|e| cookies.is_none() || e.addr == addr);
+                    if !known && cookies.is_some() && version < 2 {
+                        continue;
+                    }
+                    if !known && version >= 2 {
+                        drop(shard);
+                        send(&protocol::unknown_session(version, client_id), addr);
+                        continue;
+                    }
+                    let new = !shard.clients.contains_key(&client_id);
+                    if new && !self.make_room(&mut shard, limits.max_clients, true) {
+                        continue;
+                    }
+                    let e = if new {
+                        self.state.served.fetch_add(1, Ordering::Relaxed);
+                        shard.insert(
+                            client_id,
+                            ClientEntry {
+                                received: 0,
+                                duplicates: 0,
+                                replays: ReplayWindow::default(),
+                                checksums: false,
+                                epochs: false,
+                                corrupted: 0,
+                                packets_per_second: None,
+                                stream: None,
+                                // never handed out, so never resumed
+                                resume_secret: rand::random(),
+                                encrypted: false,
+                                handshaked: false,
+                                hop_window: now,
+                                hop_replies: 0,
+                                addr,
+                                first_seen: now,
+                                last_seen: now,
+                            },
+                        )
+                    } else {
+                        shard.touch(client_id, now).unwrap()
+                    };
+                    if let Some(log) = &self.session_log {
+                        if new {
+                            log.start(client_id, e);
+                        }
+                        if e.addr != addr {
+                            log.address_change(client_id, e.addr, addr);
+                        }
+                    }
+                    e.addr = addr;
+                    let (seq, ack_size) = match request {
+                        Request::Probe { seq, ack_size, .. } => (seq, ack_size),
+                        Request::Hello {
+                            packets_per_second, ..
+                        } => {
+                            e.packets_per_second = Some(packets_per_second.min(limits.max_rate));
+                            continue;
+                        }
+                        Request::Train {
+                            train_id, index, ..
+                        } => {
+                            drop(shard);
+                            // smaller train packets would make this an amplifier
+                            if n >= TRAIN_ACK_SIZE {
+                                let rx_micros = now.duration_since(self.state.started).as_micros();
+                                let mut ack =
+                                    capacity::train_ack(train_id, index, rx_micros as u64);
+                                ack[0] = with_version(ack[0], version);
+                                send(&ack, addr);
+                            }
+                            continue;
+                        }
+                        Request::FloodRequest { .. } => {
+                            drop(shard);
+                            self.start_flood(socket, request, addr, version)?;
+                            continue;
+                        }
+                        Request::ServerProbeAck { received, .. } => {
+                            if let Some(stream) = &mut e.stream {
+                                let received = protocol::widen(received, stream.acked);
+                                stream.acked = stream.acked.max(received);
+                            }
+                            continue;
+                        }
+                        Request::NatTimeout {
+                            nonce, delay_secs, ..
+                        } => {
+                            drop(shard);
+                            send(
+                                &nat_timeout::reply(version, nonce, false, addr.port()),
+                                addr,
+                            );
+                            late_answers.retain(|late| late.client_id != client_id);
+                            if (1..=MAX_DELAY_SECS).contains(&delay_secs)
+                                && late_answers.len() < MAX_LATE_ANSWERS
+                            {
+                                let due = now + Duration::from_secs(delay_secs.into());
+                                late_answers.push(LateAnswer {
+                                    due,
+                                    client_id,
+                                    addr,
+                                    packet: nat_timeout::reply(version, nonce, true, addr.port()),
+                                    session: encrypted.as_ref().map(
+                                        |(transport, client_index, _)| {
+                                            (Arc::clone(transport), *client_index)
+                                        },
+                                    ),
+                                });
+                                next_due = Some(next_due.map_or(due, |next| next.min(due)));
+                            }
+                            continue;
+                        }
+                        Request::Handshake(_) | Request::Hop { .. } | Request::Register { .. } => {
+                            continue
+                        }
+                    };
+                    // not answered, as any of it may be wrong; the count
+                    // goes out with the next ACK
+                    if e.checksums && !protocol::checksum_valid(&buf[..n]) {
+                        e.corrupted += 1;
+                        continue;
+                    }
+                    let seq = protocol::widen(seq, e.replays.highest());
+                    // neither counted nor answered a second time
+                    if !e.replays.insert(seq) {
+                        e.duplicates += 1;
+                        continue;
+                    }
+                    e.received += 1;
+                    if let Some(dir) = &self.record_dir {
+                        if !recordings.contains_key(&client_id) && !unrecorded.contains(&client_id)
+                        {
+                            match self.start_recording(dir, client_id, e, recordings.len()) {
+                                Ok(recording) => {
+                                    recordings.insert(client_id, recording);
+                                }
+                                Err(error) => {
+                                    crate::log::warn!(
+                                        "Not recording session {client_id:08x}: {error:#}"
+                                    );
+                                    unrecorded.insert(client_id);
+                                }
+                            }
+                        }
+                        if let Some(recording) = recordings.get_mut(&client_id) {
+                            recording.record(seq);
+                        }
+                    }
+                    // wraps on the wire, clients widen it again
+                    let received = e.received as u32;
+                    let corrupted = e.checksums.then_some(e.corrupted);
+                    let epoch = e.epochs.then_some(self.state.epoch);
+                    drop(shard);
+                    let ecn = tos.map_or(0, |tos| tos & ECN_MASK);
+                    let len = protocol::write_ack(
+                        &mut buf[..n],
+                        version,
+                        ack_size,
+                        received,
+                        ecn,
+                        corrupted,
+                        epoch,
+                    );
+                    send(&buf[..len], addr);
+                    self.state.reflected.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        }
+        flush();
+        for (client_id, recording) in recordings {
+            if let Err(e) = recording.finish() {
+                crate::log::warn!("Recording session {client_id:08x} failed: {e:#}");
+            }
+        }
+        for thread in finishing {
+            thread.join().unwrap();
+        }
+        Ok(())
+    }
+
+    /// Forget the clients of `shard` heard from least recently until there's
+    /// room for one more within its share of `max_clients`, those predating
+    /// handshakes first. Room for another of those, whose ids anyone can
+    /// make up, is only made among them. Returns whether there's room.
+    fn make_room(&self, shard: &mut Shard, max_clients: usize, legacy: bool) -> bool {
+        let max_clients = max_clients.div_ceil(self.state.shards.len()).max(1);
+        while shard.clients.len() >= max_clients {
+            let Some(client_id) = shard.least_recent(legacy) else {
+                return false;
+            };
+            let e = shard.remove(client_id).unwrap();
+            self.state.evicted.fetch_add(1, Ordering::Relaxed);
+            if let Some(log) = &self.session_log {
+                log.end(client_id, &e, "evicted");
+            }
+        }
+        true
+    }
+
+    /// Whether `ip` is allowed and not denied.
+    fn admitted(&self, ip: IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+            && !self.deny.iter().any(|net| net.contains(ip))
+    }
+
+    /// Grant what the server supports of the handshake `packet`, which came
+    /// `encrypted` or not, and register the session under a fresh token of
+    /// `worker`'s shard, or resume the one it asks for. Returns what was
+    /// granted and the welcome to answer with.
+    #[allow(clippy::too_many_arguments)]
+    fn accept(
+        &self,
+        hello: Session,
+        packet: &[u8],
+        encrypted: bool,
+        limits: &ServerLimits,
+        worker: usize,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> (Session, Vec<u8>) {
+        let mut supported = FEATURE_ECN
+            | FEATURE_PAD_ACKS
+            | FEATURE_TRAINS
+            | FEATURE_HOPS
+            | FEATURE_CHECKSUMS
+            | FEATURE_EPOCH;
+        if self.flood.is_some() {
+            supported |= FEATURE_FLOOD;
+        }
+        if self.symmetric && self.state.streams.lock().unwrap().len() < MAX_STREAMS {
+            supported |= FEATURE_SYMMETRIC;
+        }
+        let mut welcome = Session {
+            version: PROTOCOL_VERSION,
+            packets_per_second: hello.packets_per_second.clamp(1, limits.max_rate.max(1)),
+            probe_size: hello.probe_size.min(MAX_PACKET_SIZE as u16),
+            features: hello.features & supported,
+            token: hello.token,
+        };
+        // shorter handshakes would make the longer welcome an amplifier
+        let resume = hello.features & FEATURE_RESUME != 0 && packet.len() >= RESUME_HANDSHAKE_SIZE;
+        let echoed = session::echoed_resume_secret(packet);
+        // a fresh one with every welcome, so one read off the wire, or
+        // echoed in a resuming handshake, is soon of no use
+        let resume_secret: ResumeSecret = rand::random();
+        let mut shard = self.state.shard(hello.token).lock().unwrap();
+        let resumed = shard.clients.get(&hello.token).is_some_and(|e| {
+            resume
+                && (encrypted || !e.encrypted)
+                && echoed.is_some_and(|echoed| session::secrets_equal(&echoed, &e.resume_secret))
+        });
+        if resumed {
+            let e = shard.touch(hello.token, now).unwrap();
+            e.checksums = welcome.features & FEATURE_CHECKSUMS != 0;
+            e.epochs = welcome.features & FEATURE_EPOCH != 0;
+            e.packets_per_second = Some(welcome.packets_per_second);
+            // the stream starts over
+            e.stream = (welcome.features & FEATURE_SYMMETRIC != 0).then(Stream::default);
+            e.resume_secret = resume_secret;
+            e.encrypted = encrypted;
+            e.addr = addr;
+            if let Some(log) = &self.session_log {
+                log.resume(hello.token, e);
+            }
+            self.state.served.fetch_add(1, Ordering::Relaxed);
+            welcome.features |= FEATURE_RESUME;
+            let resumption = Resumption {
+                highest_seq: e.replays.highest(),
+                received: e.received,
+                corrupted: e.corrupted,
+            };
+            let answer = welcome.welcome(Some(&resume_secret), Some(&resumption));
+            return (welcome, answer);
+        }
+        drop(shard);
+        let mut shard = self.state.shards[worker].lock().unwrap();
+        let shards = self.state.shards.len();
+        welcome.token = loop {
+            let token: u32 = rand::random();
+            if token as usize % shards == worker && !shard.clients.contains_key(&token) {
+                break token;
+            }
+        };
+        let token = welcome.token;
+        self.make_room(&mut shard, limits.max_clients, false);
+        let e = shard.insert(
+            token,
+            ClientEntry {
+                received: 0,
+                duplicates: 0,
+                replays: ReplayWindow::default(),
+                checksums: welcome.features & FEATURE_CHECKSUMS != 0,
+                epochs: welcome.features & FEATURE_EPOCH != 0,
+                corrupted: 0,
+                packets_per_second: Some(welcome.packets_per_second),
+                stream: (welcome.features & FEATURE_SYMMETRIC != 0).then(Stream::default),
+                resume_secret,
+                encrypted,
+                handshaked: true,
+                hop_window: now,
+                hop_replies: 0,
+                addr,
+                first_seen: now,
+                last_seen: now,
+            },
+        );
+        self.state.served.fetch_add(1, Ordering::Relaxed);
+        if let Some(log) = &self.session_log {
+            log.start(token, e);
+        }
+        // unpadded handshakes would make the longer welcome an amplifier
+        let carried = (packet.len() >= PADDED_HANDSHAKE_SIZE).then_some(&resume_secret);
+        (welcome, welcome.welcome(carried, None))
+    }
+
+    /// Start recording the session of `client_id` in `dir`, unless
+    /// `recording` sessions are already.
+    fn start_recording(
+        &self,
+        dir: &Path,
+        client_id: u32,
+        e: &ClientEntry,
+        recording: usize,
+    ) -> eyre::Result<SessionRecording> {
+        eyre::ensure!(
+            recording < MAX_RECORDINGS,
+            "{MAX_RECORDINGS} sessions are being recorded already"
+        );
+        let unix_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut metadata = json::Object::new()
+            .u64("client_id", client_id.into())
+            .str("addr", &e.addr.to_string())
+            .u64("start_unix", unix_secs)
+            .raw("encrypted", &e.encrypted.to_string());
+        if let Some(rate) = e.packets_per_second {
+            metadata = metadata.u64("packets_per_second", rate.into());
+        }
+        SessionRecording::create(
+            &dir.join(format!("{client_id:08x}-{unix_secs}.zst")),
+            metadata,
+        )
+    }
+
+    /// Answer a Noise handshake carrying a session handshake, whose cookie
+    /// was checked already if required.
+    fn accept_encrypted(
+        &self,
+        incoming: Incoming,
+        worker: usize,
+        limits: &ServerLimits,
+        addr: SocketAddr,
+        send: impl Fn(&[u8], SocketAddr),
+    ) -> eyre::Result<()> {
+        let Some(hello) = Session::decode(&incoming.payload) else {
+            return Ok(());
+        };
+        let client_index = incoming.index;
+        let now = Instant::now();
+        let payload = &incoming.payload;
+        let (welcome, answer) = self.accept(hello, payload, true, limits, worker, addr, now);
+        // smaller than the handshake, so nothing to amplify
+        let Some((answer, transport)) = incoming.respond(&answer) else {
+            return Ok(());
+        };
+        let transport = Arc::new(transport);
+        self.state
+            .shard(welcome.token)
+            .lock()
+            .unwrap()
+            .sessions
+            .insert(welcome.token, (Arc::clone(&transport), client_index));
+        send(&answer, addr);
+        self.start_stream(
+            &welcome,
+            hello.negotiated_version(),
+            Some((transport, client_index)),
+        )
+    }
+
+    /// Stream probes of the server's own to the client of a symmetric
+    /// session at its probe rate, marked with `version` and within the
+    /// encrypted session if any, replacing any stream it had. Runs until
+    /// the session expires, pausing while the client is silent.
+    fn start_stream(
+        &self,
+        welcome: &Session,
+        version: u8,
+        encrypted: Option<(Arc<Transport>, u32)>,
+    ) -> eyre::Result<()> {
+        if welcome.features & FEATURE_SYMMETRIC == 0 {
+            return Ok(());
+        }
+        let token = welcome.token;
+        let mut streams = self.state.streams.lock().unwrap();
+        if let Some(previous) = streams.remove(&token) {
+            previous.stop();
+        }
+        let stop = StopHandle::default();
+        streams.insert(token, stop.clone());
+        drop(streams);
+
+        let socket = self.socket.try_clone()?;
+        let server_done = self.done.clone();
+        let state = Arc::clone(&self.state);
+        let psk = self.psk.clone();
+        thread::spawn(move || {
+            let mut next_send = Instant::now();
+            let mut seq = 0u64;
+            while !stop.is_stopped() && !server_done.is_stopped() {
+                let (target, rate) = {
+                    let mut shard = state.shard(token).lock().unwrap();
+                    let idle_timeout = state.limits.lock().unwrap().idle_timeout;
+                    let Some(e) = shard
+                        .clients
+                        .get_mut(&token)
+                        .filter(|e| e.last_seen.elapsed() < idle_timeout)
+                    else {
+                        break;
+                    };
+                    let rate = e
+                        .packets_per_second
+                        .unwrap_or(DEFAULT_PACKETS_PER_SECOND)
+                        .max(1);
+                    let Some(stream) = &mut e.stream else {
+                        break;
+                    };
+                    // sequence numbers only count what was sent
+                    let silent = e.last_seen.elapsed() >= STREAM_SILENCE;
+                    if !silent {
+                        seq += 1;
+                        stream.sent = seq;
+                    }
+                    ((!silent).then_some(e.addr), rate)
+                };
+                if let Some(addr) = target {
+                    let micros = state.started.elapsed().as_micros() as u64;
+                    // wraps on the wire, clients widen it again
+                    let probe = protocol::server_probe(version, seq as u32, token, micros);
+                    let session = encrypted
+                        .as_ref()
+                        .map(|(transport, client_index)| (&**transport, *client_index));
+                    let packet = noise::seal(session, &probe);
+                    if socket
+                        .send_to(&auth::seal(psk.as_ref(), &packet), addr)
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                let interval = Duration::from_secs(1) / rate;
+                next_send += interval;
+                let now = Instant::now();
+                match next_send.checked_duration_since(now) {
+                    Some(wait) => thread::sleep(wait),
+                    // fell behind, e.g. after a rate change: skip ahead
+                    // instead of bursting to catch up
+                    None if now - next_send > interval => next_send = now,
+                    None => {}
+                }
+            }
+            let mut streams = state.streams.lock().unwrap();
+            if streams
+                .get(&token)
+                .is_some_and(|current| Arc::ptr_eq(&current.0, &stop.0))
+            {
+                streams.remove(&token);
+            }
+        });
+        Ok(())
+    }
+
+    /// Replace the client's running flood, if any, with the one `request`
+    /// asks for, sending packets marked with `version`.
+    fn start_flood(
+        &self,
+        socket: &UdpSocket,
+        request: Request,
+        addr: SocketAddr,
+        version: u8,
+    ) -> eyre::Result<()> {
+        let Some(limits) = self.flood else {
+            return Ok(());
+        };
+        let Request::FloodRequest {
+            packets_per_second,
+            client_id,
+            duration_millis,
+            packet_size,
+        } = request
+        else {
+            return Ok(());
+        };
+        let rate = packets_per_second.min(limits.max_rate);
+        let duration = Duration::from_millis(duration_millis.into()).min(limits.max_duration);
+        let size = usize::from(packet_size).clamp(
+            FLOOD_PACKET_MIN_SIZE,
+            limits.max_size.max(FLOOD_PACKET_MIN_SIZE),
+        );
+
+        let mut floods = self.state.floods.lock().unwrap();
+        if let Some(previous) = floods.remove(&client_id) {
+            previous.stop();
+        }
+        if rate == 0 || duration.is_zero() || floods.len() >= MAX_FLOODS {
+            return Ok(());
+        }
+        let stop = StopHandle::default();
+        floods.insert(client_id, stop.clone());
+        drop(floods);
+
+        let total = ((f64::from(rate) * duration.as_secs_f64()).round() as u32).max(1);
+        let socket = socket.try_clone()?;
+        let server_done = self.done.clone();
+        let state = Arc::clone(&self.state);
+        let psk = self.psk.clone();
+        thread::spawn(move || {
+            let mut packet = vec![0u8; size];
+            packet[0] = with_version(FLOOD_PACKET_CONST, version);
+            packet[5..9].copy_from_slice(&total.to_be_bytes());
+            packet[9..13].copy_from_slice(&rate.to_be_bytes());
+            let sealed_size = size + auth::overhead(psk.as_ref());
+            let mut sender = SegmentSender::new();
+            let mut batch = Vec::new();
+            let interval = Duration::from_secs(1) / rate;
+            let start = Instant::now();
+            let mut seq = 0;
+            while seq < total {
+                if stop.is_stopped() || server_done.is_stopped() {
+                    break;
+                }
+                // all that's due, in as few sends as offload allows
+                let due = (start.elapsed().as_secs_f64() * f64::from(rate)) as u32 + 1;
+                let capacity = sender.capacity(sealed_size) as u32;
+                let end = due.clamp(seq + 1, total).min(seq.saturating_add(capacity));
+                batch.clear();
+                for seq in seq..end {
+                    packet[1..5].copy_from_slice(&seq.to_be_bytes());
+                    batch.extend_from_slice(&auth::seal(psk.as_ref(), &packet));
+                }
+                if sender.send(&socket, &batch, sealed_size, addr).is_err() {
+                    break;
+                }
+                seq = end;
+                if let Some(wait) = (start + interval * seq).checked_duration_since(Instant::now())
+                {
+                    thread::sleep(wait);
+                }
+            }
+            let mut floods = state.floods.lock().unwrap();
+            if floods
+                .get(&client_id)
+                .is_some_and(|current| Arc::ptr_eq(&current.0, &stop.0))
+            {
+                floods.remove(&client_id);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Bind a socket to `addr` with options std doesn't offer: if
+/// `dual_stack`, an IPv6 one with `IPV6_V6ONLY` cleared, so IPv4 clients
+/// are served too with IPv4-mapped addresses; if `reuse_port`, with
+/// `SO_REUSEPORT` so further sockets can share the port.
+#[cfg(unix)]
+fn bind(addr: SocketAddr, dual_stack: bool, reuse_port: bool) -> eyre::Result<UdpSocket> {
+    use std::{
+        mem,
+        os::fd::{AsRawFd, FromRawFd},
+    };
+
+    let set = |socket: &UdpSocket, level, option, value: libc::c_int| {
+        let rv = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                option,
+                (&value as *const libc::c_int).cast(),
+                mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+        if rv == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    let family = match addr {
+        SocketAddr::V4(_) if dual_stack => eyre::bail!("dual-stack sockets need an IPv6 address"),
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // owned right away so it's closed on errors
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    if dual_stack {
+        set(&socket, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 0)?;
+    }
+    if reuse_port {
+        set(&socket, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+    }
+    let rv = match addr {
+        SocketAddr::V4(addr) => {
+            let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = addr.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            unsafe {
+                libc::bind(
+                    socket.as_raw_fd(),
+                    (&sockaddr as *const libc::sockaddr_in).cast(),
+                    mem::size_of_val(&sockaddr) as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = addr.port().to_be();
+            sockaddr.sin6_flowinfo = addr.flowinfo();
+            sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+            sockaddr.sin6_scope_id = addr.scope_id();
+            unsafe {
+                libc::bind(
+                    socket.as_raw_fd(),
+                    (&sockaddr as *const libc::sockaddr_in6).cast(),
+                    mem::size_of_val(&sockaddr) as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if rv == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn bind(addr: SocketAddr, dual_stack: bool, reuse_port: bool) -> eyre::Result<UdpSocket> {
+    eyre::ensure!(!dual_stack, "dual-stack sockets are only supported on Unix");
+    eyre::ensure!(!reuse_port, "several workers are only supported on Unix");
+    Ok(UdpSocket::bind(addr)?)
+}
+
+/// What a server did until it was stopped.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerSummary {
+    pub clients_served: u64,
+    pub packets_reflected: u64,
+    pub uptime: Duration,
+}
+
+impl std::fmt::Display for ServerSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Up {:.1} s, {} sessions served, {} packets reflected",
+            self.uptime.as_secs_f64(),
+            self.clients_served,
+            self.packets_reflected
+        )
+    }
+}
+
+/// A server running on a background thread, see [`ProbeServer::spawn`].
+pub struct ServerHandle {
+    stop: StopHandle,
+    thread: JoinHandle<eyre::Result<ServerSummary>>,
+}
+
+impl ServerHandle {
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    pub fn stop(self) -> eyre::Result<ServerSummary> {
+        self.stop.stop();
+        self.thread.join().unwrap()
+    }
+}
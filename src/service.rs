@@ -0,0 +1,213 @@
+//! Running the server as a Windows service, with start/stop control from the
+//! service manager and status messages in the Application event log.
+//!
+//! Register it with e.g.
+//! `sc.exe create loss_lens binPath= "C:\path\loss_lens.exe server --service --host 0.0.0.0:13337"`.
+//!
+//! The handful of Win32 calls needed are declared here directly.
+
+use std::{
+    ffi::{c_void, OsStr},
+    iter,
+    os::windows::ffi::OsStrExt,
+    ptr,
+    sync::{Mutex, OnceLock},
+};
+
+use loss_lens::{ProbeServer, StopHandle};
+
+const SERVICE_NAME: &str = "loss_lens";
+
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+const SERVICE_ACCEPT_STOP: u32 = 0x1;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 0x4;
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+const ERROR_SERVICE_SPECIFIC_ERROR: u32 = 1066;
+const EVENTLOG_ERROR_TYPE: u16 = 0x1;
+const EVENTLOG_INFORMATION_TYPE: u16 = 0x4;
+
+type Handle = *mut c_void;
+
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+#[repr(C)]
+struct ServiceTableEntryW {
+    service_name: *mut u16,
+    service_proc: Option<unsafe extern "system" fn(u32, *mut *mut u16)>,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntryW) -> i32;
+    fn RegisterServiceCtrlHandlerExW(
+        name: *const u16,
+        handler: unsafe extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32,
+        context: *mut c_void,
+    ) -> Handle;
+    fn SetServiceStatus(handle: Handle, status: *const ServiceStatus) -> i32;
+    fn RegisterEventSourceW(server: *const u16, source: *const u16) -> Handle;
+    fn ReportEventW(
+        log: Handle,
+        kind: u16,
+        category: u16,
+        event_id: u32,
+        user_sid: *mut c_void,
+        num_strings: u16,
+        data_size: u32,
+        strings: *const *const u16,
+        data: *const c_void,
+    ) -> i32;
+    fn DeregisterEventSource(log: Handle) -> i32;
+}
+
+/// Handed from [`run`] to the service main, which the dispatcher calls on a
+/// thread of its own.
+static SERVER: Mutex<Option<ProbeServer>> = Mutex::new(None);
+static STOP: OnceLock<StopHandle> = OnceLock::new();
+/// `SERVICE_STATUS_HANDLE`, stored as an integer to be shareable
+static STATUS_HANDLE: OnceLock<usize> = OnceLock::new();
+
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(iter::once(0)).collect()
+}
+
+/// Serve as a service until the service manager stops it. Fails when not
+/// started by the service manager.
+pub fn run(server: ProbeServer) -> eyre::Result<()> {
+    let _ = STOP.set(server.stop_handle());
+    *SERVER.lock().unwrap() = Some(server);
+    let mut name = wide(SERVICE_NAME);
+    let table = [
+        ServiceTableEntryW {
+            service_name: name.as_mut_ptr(),
+            service_proc: Some(service_main),
+        },
+        ServiceTableEntryW {
+            service_name: ptr::null_mut(),
+            service_proc: None,
+        },
+    ];
+    // blocks until the service has stopped
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let name = wide(SERVICE_NAME);
+    let handle = RegisterServiceCtrlHandlerExW(name.as_ptr(), control_handler, ptr::null_mut());
+    if handle.is_null() {
+        log(
+            EVENTLOG_ERROR_TYPE,
+            "Registering the service control handler failed",
+        );
+        return;
+    }
+    let _ = STATUS_HANDLE.set(handle as usize);
+
+    let Some(server) = SERVER.lock().unwrap().take() else {
+        return;
+    };
+    match server.local_addr() {
+        Ok(addr) => log(EVENTLOG_INFORMATION_TYPE, &format!("Listening on {addr}")),
+        Err(_) => log(EVENTLOG_INFORMATION_TYPE, "Started"),
+    }
+    set_status(SERVICE_RUNNING, NO_ERROR);
+    let exit_code = match server.run() {
+        Ok(summary) => {
+            log(EVENTLOG_INFORMATION_TYPE, &format!("Stopped. {summary}"));
+            NO_ERROR
+        }
+        Err(e) => {
+            log(EVENTLOG_ERROR_TYPE, &format!("Server failed: {e}"));
+            ERROR_SERVICE_SPECIFIC_ERROR
+        }
+    };
+    set_status(SERVICE_STOPPED, exit_code);
+}
+
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, NO_ERROR);
+            if let Some(stop) = STOP.get() {
+                stop.stop();
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+fn set_status(state: u32, exit_code: u32) {
+    let Some(&handle) = STATUS_HANDLE.get() else {
+        return;
+    };
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        win32_exit_code: exit_code,
+        service_specific_exit_code: u32::from(exit_code == ERROR_SERVICE_SPECIFIC_ERROR),
+        check_point: 0,
+        // the reflect loop notices stop requests within its 100ms timeout
+        wait_hint: if state == SERVICE_STOP_PENDING {
+            1000
+        } else {
+            0
+        },
+    };
+    unsafe { SetServiceStatus(handle as Handle, &status) };
+}
+
+/// Write a message to the Application event log.
+fn log(kind: u16, message: &str) {
+    let source = wide(SERVICE_NAME);
+    let message = wide(message);
+    unsafe {
+        let log = RegisterEventSourceW(ptr::null(), source.as_ptr());
+        if log.is_null() {
+            return;
+        }
+        let strings = [message.as_ptr()];
+        ReportEventW(
+            log,
+            kind,
+            0,
+            0,
+            ptr::null_mut(),
+            1,
+            0,
+            strings.as_ptr(),
+            ptr::null(),
+        );
+        DeregisterEventSource(log);
+    }
+}
@@ -0,0 +1,535 @@
+//! Session handshake: before probing, the client proposes its parameters and
+//! the features it wants, and the server answers with what it accepted and a
+//! session token that then stands in for the client id in every packet.
+//!
+//! The handshake is `[HELLO, packets_per_second, client_id, version,
+//! probe_size, features]`, a longer form of the periodic rate announcement,
+//! and the answer `[WELCOME, packets_per_second, token, version, probe_size,
+//! features]`. Servers that predate it ignore the extra bytes and don't
+//! answer, so clients fall back to probing without a session.
+//!
+//! Servers exposed to the internet can require a cookie first: they answer a
+//! handshake with `[COOKIE, cookie]`, a MAC of the client's address under a
+//! secret rotated every minute, and only accept handshakes echoing it in
+//! their padding. Spoofed sources never see the cookie, so they can't open
+//! sessions or have ACKs sent anywhere, and the server keeps no state until
+//! the echo arrives.
+//!
+//! A client restarted with its previous token can ask to resume that
+//! session instead, see [`FEATURE_RESUME`]: the server then keeps counting
+//! where it left off and appends where it stands to the welcome, `[...,
+//! highest_seq, received, corrupted]`, for the client to continue from.
+//! Handshakes asking for it are padded to come out at least as long. As the
+//! token is in every packet, resuming also takes the secret the welcome
+//! carried after the features, echoed after the cookie; it changes with every
+//! welcome, and those of encrypted sessions are only ever sent encrypted, nor
+//! are they resumed in plaintext.
+//!
+//! Each side sends its own protocol version and both then speak the lower
+//! one. From version 2 on every other packet is marked with it, see
+//! `with_version`, so packets from a peer speaking another version are told
+//! apart instead of miscounted.
+
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::{self, Psk},
+    hash::hmac_sha256,
+    noise::{Initiator, PublicKey, Transport},
+    protocol::{self, Reply},
+    COOKIE_PACKET_CONST, HELLO_PACKET_CONST, WELCOME_PACKET_CONST,
+};
+
+/// Version of the packet formats spoken by this build
+pub const PROTOCOL_VERSION: u8 = 2;
+pub(crate) const HANDSHAKE_SIZE: usize = 1 + 4 + 4 + 1 + 2 + 4;
+pub(crate) const COOKIE_SIZE: usize = 16;
+/// Handshakes carry room for the cookie, so the cookie answer is smaller
+/// than what it answers
+pub(crate) const PADDED_HANDSHAKE_SIZE: usize = HANDSHAKE_SIZE + COOKIE_SIZE;
+pub(crate) const COOKIE_ANSWER_SIZE: usize = 1 + COOKIE_SIZE;
+pub(crate) const RESUMPTION_SIZE: usize = 8 + 8 + 4;
+pub(crate) const RESUME_SECRET_SIZE: usize = 16;
+/// Welcomes carrying a resume secret, no larger than padded handshakes
+pub(crate) const WELCOME_SIZE: usize = HANDSHAKE_SIZE + RESUME_SECRET_SIZE;
+/// Welcomes of resumed sessions, and what handshakes asking for one are
+/// padded to so the answer is no larger
+pub(crate) const RESUME_HANDSHAKE_SIZE: usize = WELCOME_SIZE + RESUMPTION_SIZE;
+/// How often the cookie secret changes; cookies of the previous secret are
+/// still accepted
+const COOKIE_ROTATION: Duration = Duration::from_secs(60);
+
+/// ECN-capable probes answered with the ECN field they arrived with
+pub const FEATURE_ECN: u32 = 1 << 0;
+/// ACKs padded to a requested size
+pub const FEATURE_PAD_ACKS: u32 = 1 << 1;
+/// Packet trains for capacity estimates
+pub const FEATURE_TRAINS: u32 = 1 << 2;
+/// Answers to TTL-limited hop probes
+pub const FEATURE_HOPS: u32 = 1 << 3;
+/// Downstream floods
+pub const FEATURE_FLOOD: u32 = 1 << 4;
+/// Probes ending in a CRC-32 the server checks, with ACKs carrying the
+/// count of corrupted ones, see [`crate::protocol::write_checksum`]
+pub const FEATURE_CHECKSUMS: u32 = 1 << 5;
+/// Resuming the session whose token the handshake carries, e.g. after the
+/// client restarted; granted only if the server still knows it, see
+/// [`Resumption`]
+pub const FEATURE_RESUME: u32 = 1 << 6;
+/// ACKs ending in an epoch the server picks at random when it starts, so
+/// clients notice when their session's counts come from another instance,
+/// e.g. after an anycast failover, see [`crate::protocol::EPOCH_ACK_SIZE`]
+pub const FEATURE_EPOCH: u32 = 1 << 7;
+/// The server also streams probes of its own to the client at the session's
+/// rate, which the client answers, so each direction's loss and jitter are
+/// measured on their own instead of downstream loss being inferred from
+/// ACKs, see [`crate::ClientConfig::symmetric`]
+pub const FEATURE_SYMMETRIC: u32 = 1 << 8;
+
+/// Handshakes sent before concluding the server predates them
+const ATTEMPTS: u32 = 3;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Parameters of a handshake, as proposed by the client or accepted by the
+/// server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Session {
+    /// Protocol version of the side that sent it
+    pub version: u8,
+    pub packets_per_second: u32,
+    /// Largest probe, UDP payload
+    pub probe_size: u16,
+    /// `FEATURE_*` bits
+    pub features: u32,
+    /// Client id to use in every packet, chosen by the server
+    pub token: u32,
+}
+
+impl Session {
+    pub(crate) fn encode(&self, kind: u8) -> [u8; HANDSHAKE_SIZE] {
+        let mut packet = [0u8; HANDSHAKE_SIZE];
+        packet[0] = kind;
+        packet[1..5].copy_from_slice(&self.packets_per_second.to_be_bytes());
+        packet[5..9].copy_from_slice(&self.token.to_be_bytes());
+        packet[9] = self.version;
+        packet[10..12].copy_from_slice(&self.probe_size.to_be_bytes());
+        packet[12..16].copy_from_slice(&self.features.to_be_bytes());
+        packet
+    }
+
+    /// The handshake, echoing `cookie` if the server sent one.
+    pub(crate) fn hello(&self, cookie: Option<[u8; COOKIE_SIZE]>) -> [u8; PADDED_HANDSHAKE_SIZE] {
+        let mut packet = [0u8; PADDED_HANDSHAKE_SIZE];
+        packet[..HANDSHAKE_SIZE].copy_from_slice(&self.encode(HELLO_PACKET_CONST));
+        if let Some(cookie) = cookie {
+            packet[HANDSHAKE_SIZE..].copy_from_slice(&cookie);
+        }
+        packet
+    }
+
+    /// The server's answer accepting a handshake, followed by the secret
+    /// resuming the session takes and where the session stood if it was
+    /// resumed.
+    pub(crate) fn welcome(
+        &self,
+        resume_secret: Option<&ResumeSecret>,
+        resumption: Option<&Resumption>,
+    ) -> Vec<u8> {
+        let mut packet = self.encode(WELCOME_PACKET_CONST).to_vec();
+        if let Some(resume_secret) = resume_secret {
+            packet.extend_from_slice(resume_secret);
+        }
+        if let Some(resumption) = resumption {
+            packet.extend_from_slice(&resumption.encode());
+        }
+        packet
+    }
+
+    pub(crate) fn decode(packet: &[u8]) -> Option<Self> {
+        let packet = packet.get(..HANDSHAKE_SIZE)?;
+        Some(Self {
+            packets_per_second: u32::from_be_bytes(packet[1..5].try_into().unwrap()),
+            token: u32::from_be_bytes(packet[5..9].try_into().unwrap()),
+            version: packet[9],
+            probe_size: u16::from_be_bytes(packet[10..12].try_into().unwrap()),
+            features: u32::from_be_bytes(packet[12..16].try_into().unwrap()),
+        })
+    }
+
+    /// Version both sides speak, for a server's answer.
+    pub fn negotiated_version(&self) -> u8 {
+        self.version.min(PROTOCOL_VERSION)
+    }
+
+    /// Requested features the server turned down, as names.
+    pub fn refused(&self, requested: u32) -> Vec<&'static str> {
+        [
+            (FEATURE_ECN, "ECN"),
+            (FEATURE_PAD_ACKS, "padded ACKs"),
+            (FEATURE_TRAINS, "packet trains"),
+            (FEATURE_HOPS, "hop tracing"),
+            (FEATURE_FLOOD, "floods"),
+            (FEATURE_CHECKSUMS, "checksums"),
+            (FEATURE_SYMMETRIC, "symmetric mode"),
+        ]
+        .into_iter()
+        .filter(|&(feature, _)| requested & feature != 0 && self.features & feature == 0)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "protocol {}, {} probes/s, up to {} bytes",
+            self.version, self.packets_per_second, self.probe_size
+        )
+    }
+}
+
+/// Where a resumed session stood on the server when the client came back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resumption {
+    /// Highest sequence number received, for the client to continue after
+    pub highest_seq: u64,
+    /// Probes received, which ACKs keep counting from
+    pub received: u64,
+    /// Probes received with a wrong checksum, likewise
+    pub corrupted: u32,
+}
+
+impl Resumption {
+    fn encode(&self) -> [u8; RESUMPTION_SIZE] {
+        let mut packet = [0u8; RESUMPTION_SIZE];
+        packet[..8].copy_from_slice(&self.highest_seq.to_be_bytes());
+        packet[8..16].copy_from_slice(&self.received.to_be_bytes());
+        packet[16..].copy_from_slice(&self.corrupted.to_be_bytes());
+        packet
+    }
+
+    /// From a welcome granting [`FEATURE_RESUME`], `None` for any other.
+    pub(crate) fn decode(welcome: &[u8]) -> Option<Self> {
+        let session = Session::decode(welcome)?;
+        if session.features & FEATURE_RESUME == 0 {
+            return None;
+        }
+        let packet = welcome.get(WELCOME_SIZE..RESUME_HANDSHAKE_SIZE)?;
+        Some(Self {
+            highest_seq: u64::from_be_bytes(packet[..8].try_into().unwrap()),
+            received: u64::from_be_bytes(packet[8..16].try_into().unwrap()),
+            corrupted: u32::from_be_bytes(packet[16..].try_into().unwrap()),
+        })
+    }
+}
+
+/// What resuming a session takes besides its token, handed out with every
+/// welcome.
+pub(crate) type ResumeSecret = [u8; RESUME_SECRET_SIZE];
+
+/// The resume secret of a welcome, `None` from servers predating them.
+pub(crate) fn decode_resume_secret(welcome: &[u8]) -> Option<ResumeSecret> {
+    welcome
+        .get(HANDSHAKE_SIZE..WELCOME_SIZE)
+        .map(|secret| secret.try_into().unwrap())
+}
+
+/// The resume secret a handshake echoes, if it's long enough to carry one.
+pub(crate) fn echoed_resume_secret(handshake: &[u8]) -> Option<ResumeSecret> {
+    handshake
+        .get(PADDED_HANDSHAKE_SIZE..PADDED_HANDSHAKE_SIZE + RESUME_SECRET_SIZE)
+        .map(|secret| secret.try_into().unwrap())
+}
+
+/// Whether two secrets are equal, in constant time so they can't be
+/// guessed byte by byte.
+pub(crate) fn secrets_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Cookie from a server's `[COOKIE, cookie]` answer.
+pub(crate) fn decode_cookie(packet: &[u8]) -> Option<[u8; COOKIE_SIZE]> {
+    packet
+        .get(1..COOKIE_ANSWER_SIZE)
+        .map(|cookie| cookie.try_into().unwrap())
+}
+
+/// The server's secrets for issuing and checking cookies.
+pub(crate) struct CookieJar {
+    /// Current and previous secret
+    secrets: [[u8; 32]; 2],
+    rotated: Instant,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self {
+            secrets: [rand::random(), rand::random()],
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= COOKIE_ROTATION {
+            self.secrets = [rand::random(), self.secrets[0]];
+            self.rotated = Instant::now();
+        }
+    }
+
+    /// `[COOKIE, cookie]` for a client at `addr`.
+    pub fn answer(&mut self, addr: SocketAddr) -> [u8; COOKIE_ANSWER_SIZE] {
+        self.rotate();
+        let mut answer = [0u8; COOKIE_ANSWER_SIZE];
+        answer[0] = COOKIE_PACKET_CONST;
+        answer[1..].copy_from_slice(&cookie(&self.secrets[0], addr));
+        answer
+    }
+
+    /// Whether a padded handshake from `addr` echoes a cookie issued to it.
+    pub fn check(&mut self, addr: SocketAddr, handshake: &[u8]) -> bool {
+        handshake
+            .get(HANDSHAKE_SIZE..PADDED_HANDSHAKE_SIZE)
+            .is_some_and(|echoed| self.check_echo(addr, echoed))
+    }
+
+    /// Whether `echoed` is a cookie issued to `addr`.
+    pub fn check_echo(&mut self, addr: SocketAddr, echoed: &[u8]) -> bool {
+        self.rotate();
+        self.secrets
+            .iter()
+            .any(|secret| secrets_equal(&cookie(secret, addr), echoed))
+    }
+}
+
+fn cookie(secret: &[u8; 32], addr: SocketAddr) -> [u8; COOKIE_SIZE] {
+    // IPv4-mapped and plain IPv4 addresses alike
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    let mut data = [0u8; 18];
+    data[..16].copy_from_slice(&ip.octets());
+    data[16..].copy_from_slice(&addr.port().to_be_bytes());
+    hmac_sha256(secret, &data)[..COOKIE_SIZE]
+        .try_into()
+        .unwrap()
+}
+
+/// What the server accepted of a handshake.
+pub(crate) struct Welcome {
+    pub session: Session,
+    /// Where the session stood if it was resumed
+    pub resumption: Option<Resumption>,
+    /// What resuming the session later takes, from servers handing one out
+    pub resume_secret: Option<ResumeSecret>,
+    /// Keys of an encrypted session
+    pub transport: Option<Transport>,
+}
+
+/// Propose `hello` on a connected `socket` and wait for the server's answer,
+/// echoing a cookie if it asks for one, and `resume_secret` for resumed
+/// sessions. `None` if it never answers, i.e. predates the handshake. Other
+/// packets arriving meanwhile are dropped, as are answers not authenticated
+/// with `psk`.
+pub(crate) fn handshake(
+    socket: &UdpSocket,
+    hello: Session,
+    resume_secret: Option<&ResumeSecret>,
+    psk: Option<&Psk>,
+) -> eyre::Result<Option<Welcome>> {
+    negotiate(socket, hello, resume_secret, psk, None)
+}
+
+/// [`handshake`] within a Noise handshake with the server owning `server`,
+/// see [`crate::noise`], also returning the session's keys. `None` if the
+/// server never answers, e.g. for lack of the key.
+pub(crate) fn encrypted_handshake(
+    socket: &UdpSocket,
+    hello: Session,
+    resume_secret: Option<&ResumeSecret>,
+    psk: Option<&Psk>,
+    server: &PublicKey,
+) -> eyre::Result<Option<Welcome>> {
+    negotiate(socket, hello, resume_secret, psk, Some(server))
+}
+
+/// The Noise handshake with the server owning `server` carrying the padded
+/// `hello`, with the cookie following in plaintext instead of in the
+/// padding, so servers check it before any key exchange. `None` for keys
+/// that can't be used.
+pub(crate) fn encrypted_hello(
+    server: &PublicKey,
+    hello: &[u8],
+    cookie: Option<[u8; COOKIE_SIZE]>,
+) -> Option<(Initiator, Vec<u8>)> {
+    let mut payload = hello.to_vec();
+    payload[HANDSHAKE_SIZE..PADDED_HANDSHAKE_SIZE].fill(0);
+    let (initiator, mut message) = Initiator::new(server, &payload)?;
+    message.extend_from_slice(&cookie.unwrap_or_default());
+    Some((initiator, message))
+}
+
+fn negotiate(
+    socket: &UdpSocket,
+    hello: Session,
+    resume_secret: Option<&ResumeSecret>,
+    psk: Option<&Psk>,
+    server: Option<&PublicKey>,
+) -> eyre::Result<Option<Welcome>> {
+    let previous_timeout = socket.read_timeout()?;
+    socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+    let welcome = exchange(socket, hello, resume_secret, psk, server);
+    // whatever happened, as the socket may be probed through next
+    socket.set_read_timeout(previous_timeout)?;
+    welcome
+}
+
+/// The handshakes of [`negotiate`], on a socket with a short read timeout.
+fn exchange(
+    socket: &UdpSocket,
+    hello: Session,
+    resume_secret: Option<&ResumeSecret>,
+    psk: Option<&Psk>,
+    server: Option<&PublicKey>,
+) -> eyre::Result<Option<Welcome>> {
+    let mut buf = [0u8; 128];
+    let mut welcome = None;
+    let mut cookie = None;
+    // a new one for every attempt, as answers to earlier ones are useless
+    let mut initiator;
+    let send = |cookie| -> eyre::Result<Option<Initiator>> {
+        let mut packet = hello.hello(cookie).to_vec();
+        if hello.features & FEATURE_RESUME != 0 {
+            packet.resize(RESUME_HANDSHAKE_SIZE, 0);
+            if let Some(resume_secret) = resume_secret {
+                packet[PADDED_HANDSHAKE_SIZE..][..RESUME_SECRET_SIZE]
+                    .copy_from_slice(resume_secret);
+            }
+        }
+        let (initiator, packet) = match server {
+            Some(server) => {
+                let (initiator, message) = encrypted_hello(server, &packet, cookie)
+                    .ok_or_else(|| eyre::eyre!("unusable server key"))?;
+                (Some(initiator), message)
+            }
+            None => (None, packet),
+        };
+        socket.send(&auth::seal(psk, &packet))?;
+        Ok(initiator)
+    };
+    'attempts: for attempt in 0..ATTEMPTS {
+        if attempt > 0 {
+            crate::log::debug!("No answer to handshake attempt {attempt}, trying again");
+        }
+        initiator = send(cookie)?;
+        let deadline = Instant::now() + ATTEMPT_TIMEOUT;
+        while Instant::now() < deadline {
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                // nothing listening yet, like a server that doesn't answer
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let Some(n) = auth::open(psk, &buf[..n]) else {
+                continue;
+            };
+            if let Some(initiator) = &initiator {
+                let answer = initiator.finish(&buf[..n]);
+                if let Some(session) = answer.and_then(|(payload, transport)| {
+                    Some(Welcome {
+                        session: Session::decode(&payload)?,
+                        resumption: Resumption::decode(&payload),
+                        resume_secret: decode_resume_secret(&payload),
+                        transport: Some(transport),
+                    })
+                }) {
+                    welcome = Some(session);
+                    break 'attempts;
+                }
+            }
+            match protocol::reply(&buf[..n]) {
+                Ok((_, Reply::Welcome(session))) if initiator.is_none() => {
+                    welcome = Some(Welcome {
+                        session,
+                        resumption: Resumption::decode(&buf[..n]),
+                        resume_secret: decode_resume_secret(&buf[..n]),
+                        transport: None,
+                    });
+                    break 'attempts;
+                }
+                // taken once, so a server that keeps rejecting it has the
+                // attempts run out
+                Ok((_, Reply::Cookie(answer))) if cookie.is_none() => {
+                    crate::log::debug!("The server asked for a cookie; handshaking again with it");
+                    cookie = Some(answer);
+                    initiator = send(cookie)?;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(welcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(cookie: [u8; COOKIE_SIZE]) -> [u8; PADDED_HANDSHAKE_SIZE] {
+        let hello = Session {
+            version: PROTOCOL_VERSION,
+            packets_per_second: 100,
+            probe_size: 9,
+            features: 0,
+            token: 1,
+        };
+        hello.hello(Some(cookie))
+    }
+
+    /// As if the secret was last rotated `ago`.
+    fn age(jar: &mut CookieJar, ago: Duration) {
+        jar.rotated = Instant::now().checked_sub(ago).unwrap();
+    }
+
+    #[test]
+    fn cookie_is_accepted_until_it_expires() {
+        let addr = "192.0.2.1:4000".parse().unwrap();
+        let mut jar = CookieJar::new();
+        let cookie = decode_cookie(&jar.answer(addr)).unwrap();
+        assert!(jar.check(addr, &handshake(cookie)));
+        // one rotation later it's of the previous secret, still accepted
+        age(&mut jar, COOKIE_ROTATION);
+        assert!(jar.check(addr, &handshake(cookie)));
+        age(&mut jar, COOKIE_ROTATION);
+        assert!(!jar.check(addr, &handshake(cookie)));
+    }
+
+    #[test]
+    fn cookie_is_bound_to_the_address() {
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let mut jar = CookieJar::new();
+        let cookie = decode_cookie(&jar.answer(addr)).unwrap();
+        for other in ["192.0.2.2:4000", "192.0.2.1:4001", "[2001:db8::1]:4000"] {
+            assert!(!jar.check(other.parse().unwrap(), &handshake(cookie)));
+        }
+        // the same address, whether IPv4-mapped or not
+        let mapped = "[::ffff:192.0.2.1]:4000".parse().unwrap();
+        assert!(jar.check(mapped, &handshake(cookie)));
+        assert!(!jar.check(addr, &handshake([0; COOKIE_SIZE])));
+        assert!(!jar.check(addr, &handshake(cookie)[..HANDSHAKE_SIZE]));
+    }
+}
@@ -0,0 +1,111 @@
+//! The server's log of client sessions, for an operator to see who used
+//! the reflector and to correlate with what clients report: JSON lines
+//! appended to a file, e.g.
+//!
+//! ```text
+//! {"unix_secs": SECS, "event": "start", "client_id": ID, "addr": ADDR, "encrypted": false}
+//! {"unix_secs": SECS, "event": "address_change", "client_id": ID, "from": ADDR, "to": ADDR}
+//! {"unix_secs": SECS, "event": "end", "client_id": ID, "addr": ADDR, "reason": "shutdown",
+//!  "first_seen_unix": SECS, "last_seen_unix": SECS, "received": N, "duplicates": N, "corrupted": N}
+//! ```
+//!
+//! Events are `start` for a new session, `resume` for one resumed by a
+//! handshake, `address_change` when a session's packets come from another
+//! address, e.g. after a NAT rebinding, and `end` with the session's counts
+//! when it's forgotten: with `reason` `expired` once silent for the idle
+//! timeout, `evicted` to make room for a new one past the client limit, or
+//! `shutdown` when the server stops.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{json, server::ClientEntry};
+
+pub(crate) struct SessionLog {
+    file: File,
+    /// When the log was opened, to tell Unix times of `Instant`s
+    opened: Instant,
+    opened_unix: f64,
+}
+
+impl SessionLog {
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| eyre::eyre!("opening the session log {}: {e}", path.display()))?;
+        Ok(Self {
+            file,
+            opened: Instant::now(),
+            opened_unix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        })
+    }
+
+    pub fn start(&self, client_id: u32, e: &ClientEntry) {
+        self.write(
+            self.event("start", client_id)
+                .str("addr", &e.addr.to_string())
+                .raw("encrypted", &e.encrypted.to_string()),
+        );
+    }
+
+    pub fn resume(&self, client_id: u32, e: &ClientEntry) {
+        self.write(
+            self.event("resume", client_id)
+                .str("addr", &e.addr.to_string())
+                .raw("encrypted", &e.encrypted.to_string()),
+        );
+    }
+
+    pub fn address_change(&self, client_id: u32, from: SocketAddr, to: SocketAddr) {
+        self.write(
+            self.event("address_change", client_id)
+                .str("from", &from.to_string())
+                .str("to", &to.to_string()),
+        );
+    }
+
+    pub fn end(&self, client_id: u32, e: &ClientEntry, reason: &str) {
+        self.write(self.counts(self.event("end", client_id).str("reason", reason), e));
+    }
+
+    fn event(&self, event: &str, client_id: u32) -> json::Object {
+        json::Object::new()
+            .f64("unix_secs", self.unix_secs(Instant::now()))
+            .str("event", event)
+            .u64("client_id", client_id.into())
+    }
+
+    fn counts(&self, object: json::Object, e: &ClientEntry) -> json::Object {
+        object
+            .str("addr", &e.addr.to_string())
+            .f64("first_seen_unix", self.unix_secs(e.first_seen))
+            .f64("last_seen_unix", self.unix_secs(e.last_seen))
+            .u64("received", e.received)
+            .u64("duplicates", e.duplicates.into())
+            .u64("corrupted", e.corrupted.into())
+    }
+
+    fn unix_secs(&self, at: Instant) -> f64 {
+        match at.checked_duration_since(self.opened) {
+            Some(since) => self.opened_unix + since.as_secs_f64(),
+            None => self.opened_unix - self.opened.duration_since(at).as_secs_f64(),
+        }
+    }
+
+    fn write(&self, object: json::Object) {
+        // a whole line at once, appended
+        if let Err(e) = (&self.file).write_all(format!("{}\n", object.finish()).as_bytes()) {
+            crate::log::warn!("Writing the session log failed: {e}");
+        }
+    }
+}
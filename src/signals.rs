@@ -0,0 +1,84 @@
+//! Runtime actions on signals, in addition to Ctrl-C shutdown:
+//!
+//! - SIGUSR1 prints the latest stats snapshot and flushes the recording
+//! - SIGHUP rotates the recording and re-resolves the target host
+//! - SIGTERM shuts down cleanly like Ctrl-C, finalizing the recording
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use loss_lens::{ClientControl, StopHandle};
+
+static USR1: AtomicBool = AtomicBool::new(false);
+static HUP: AtomicBool = AtomicBool::new(false);
+static TERM: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // only async-signal-safe work here; the watcher thread does the rest
+    match signal {
+        libc::SIGUSR1 => USR1.store(true, Ordering::SeqCst),
+        libc::SIGHUP => HUP.store(true, Ordering::SeqCst),
+        libc::SIGTERM => TERM.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+}
+
+/// Install the handlers and act on them from a background thread. Stats are
+/// printed only if `print_stats`, since they would garble the TUI.
+pub fn spawn(control: ClientControl, print_stats: bool) -> eyre::Result<()> {
+    install(&[libc::SIGUSR1, libc::SIGHUP, libc::SIGTERM])?;
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(100));
+        if TERM.swap(false, Ordering::SeqCst) {
+            control.stop();
+        }
+        if USR1.swap(false, Ordering::SeqCst) {
+            if print_stats {
+                match control.latest_stats() {
+                    Some(stats) => print!("\n{stats}"),
+                    None => println!("\nNo stats yet"),
+                }
+            }
+            if let Err(e) = control.flush() {
+                warn!("Flushing recording failed: {e}");
+            }
+        }
+        if HUP.swap(false, Ordering::SeqCst) {
+            match control.rotate() {
+                Ok(rotated) => info!("Rotated recording to {}", rotated.display()),
+                Err(e) => warn!("Rotating recording failed: {e}"),
+            }
+            match control.reresolve() {
+                Ok(Some(addr)) => info!("Target now resolves to {addr}"),
+                Ok(None) => {}
+                Err(e) => warn!("Re-resolving target failed: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Stop the server on SIGTERM.
+pub fn spawn_server(stop: StopHandle) -> eyre::Result<()> {
+    install(&[libc::SIGTERM])?;
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(100));
+        if TERM.swap(false, Ordering::SeqCst) {
+            stop.stop();
+        }
+    });
+    Ok(())
+}
+
+fn install(signals: &[libc::c_int]) -> eyre::Result<()> {
+    for &signal in signals {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
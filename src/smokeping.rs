@@ -0,0 +1,246 @@
+//! Output for SmokePing, summarizing every step of probes the way its probes
+//! report a cycle of pings: the loss, the median round-trip time and the
+//! sorted round-trip times of a fixed number of pings. Loss is measured
+//! over all probes of the step, thousands rather than SmokePing's usual 20
+//! pings, and stored unrounded, so an RRD shows e.g. 0.3 of 20 pings lost.
+//!
+//! Either a SmokePing RRD, with data sources `uptime`, `loss`, `median` and
+//! `ping1` to `pingN`, is updated with `rrdtool`, which has to be installed,
+//! or `fping -C N`-style lines are printed to stdout:
+//!
+//! ```text
+//! HOST : 12.31 12.40 - 12.52 ...
+//! ```
+//!
+//! with round-trip times in milliseconds and a `-` per ping lost. The round-
+//! trip times stand for the distribution of those of all probes: they are
+//! its quantiles.
+
+use std::{
+    path::PathBuf,
+    process::Command,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Histogram, Stats};
+
+/// Where to send a client's stats, and how to summarize them.
+#[derive(Clone, Debug)]
+pub struct SmokepingConfig {
+    /// RRD to update, or `None` for fping-style lines on stdout
+    pub rrd: Option<PathBuf>,
+    /// Host being probed, for the fping-style lines
+    pub host: String,
+    /// Pings per step, as set for the target in SmokePing
+    pub pings: usize,
+    /// SmokePing's `step`
+    pub step: Duration,
+}
+
+/// A step's probes, counted from the stats at its start.
+struct StepStart {
+    elapsed: Duration,
+    sent: u64,
+    received: u64,
+    rtts: Histogram,
+}
+
+/// Summarizes a client's stats every [`SmokepingConfig::step`].
+pub struct SmokepingSink {
+    config: SmokepingConfig,
+    start: Option<StepStart>,
+}
+
+/// A step as SmokePing stores it.
+#[derive(Debug, PartialEq)]
+struct Cycle {
+    /// In pings, unrounded
+    loss: f64,
+    median: Option<Duration>,
+    /// As many as there are pings, with lost ones as `None` split between
+    /// both ends like SmokePing does, so the median stays in the middle
+    pings: Vec<Option<Duration>>,
+}
+
+impl Cycle {
+    fn new(sent: u64, received: u64, rtts: &Histogram, pings: usize) -> Self {
+        let loss = match sent {
+            0 => 0.0,
+            _ => (1.0 - received as f64 / sent as f64).clamp(0.0, 1.0) * pings as f64,
+        };
+        let answered = match rtts.count() {
+            0 => 0,
+            _ => pings - (loss.round() as usize).min(pings),
+        };
+        let lost = pings - answered;
+        let mut values = vec![None; lost / 2];
+        values.extend(
+            (0..answered)
+                .map(|i| Some(rtts.percentile(100.0 * (i as f64 + 0.5) / answered as f64))),
+        );
+        values.resize(pings, None);
+        Self {
+            loss,
+            median: (answered > 0).then(|| rtts.percentile(50.0)),
+            pings: values,
+        }
+    }
+
+    /// Arguments to `rrdtool update`, at `unix_secs`.
+    fn rrd_update(&self, unix_secs: u64) -> Vec<String> {
+        let secs = |value: Option<Duration>| {
+            value.map_or_else(
+                || "U".to_string(),
+                |value| format!("{:e}", value.as_secs_f64()),
+            )
+        };
+        let template: Vec<String> = ["uptime", "loss", "median"]
+            .into_iter()
+            .map(String::from)
+            .chain((1..=self.pings.len()).map(|i| format!("ping{i}")))
+            .collect();
+        let values: Vec<String> = [unix_secs.to_string(), "U".to_string()]
+            .into_iter()
+            .chain([self.loss.to_string(), secs(self.median)])
+            .chain(self.pings.iter().map(|&ping| secs(ping)))
+            .collect();
+        vec![
+            "--template".to_string(),
+            template.join(":"),
+            values.join(":"),
+        ]
+    }
+
+    fn fping_line(&self, host: &str) -> String {
+        let pings: Vec<String> = self
+            .pings
+            .iter()
+            .map(|ping| {
+                ping.map_or_else(
+                    || "-".to_string(),
+                    |ping| format!("{:.2}", ping.as_secs_f64() * 1000.0),
+                )
+            })
+            .collect();
+        format!("{host} : {}", pings.join(" "))
+    }
+}
+
+impl SmokepingSink {
+    /// Checks that `rrdtool` can read the RRD and that it has a data source
+    /// for each ping.
+    pub fn start(config: SmokepingConfig) -> eyre::Result<Self> {
+        eyre::ensure!(config.pings > 0, "SmokePing needs at least one ping");
+        if let Some(rrd) = &config.rrd {
+            let output = Command::new("rrdtool")
+                .arg("info")
+                .arg(rrd)
+                .output()
+                .map_err(|e| eyre::eyre!("running rrdtool: {e}"))?;
+            let info = String::from_utf8_lossy(&output.stdout);
+            eyre::ensure!(
+                output.status.success(),
+                "rrdtool info {}: {}",
+                rrd.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            let has = |ds: &str| info.contains(&format!("ds[{ds}].index"));
+            eyre::ensure!(
+                has("loss") && has("median") && has(&format!("ping{}", config.pings)),
+                "{} isn't a SmokePing RRD for {} pings",
+                rrd.display(),
+                config.pings
+            );
+        }
+        Ok(Self {
+            config,
+            start: None,
+        })
+    }
+
+    /// Summarize the step once `stats` are a step past its start.
+    pub fn update(&mut self, stats: &Stats) {
+        let Some(start) = &self.start else {
+            self.start = Some(StepStart::of(stats));
+            return;
+        };
+        if stats.elapsed < start.elapsed + self.config.step {
+            return;
+        }
+        let cycle = Cycle::new(
+            stats.client_sent.saturating_sub(start.sent),
+            stats.client_received.saturating_sub(start.received),
+            &stats.rtts.since(&start.rtts),
+            self.config.pings,
+        );
+        self.start = Some(StepStart::of(stats));
+        match &self.config.rrd {
+            Some(rrd) => {
+                let unix_secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let rrd = rrd.clone();
+                // off the stats callback, which holds up probing
+                thread::spawn(move || {
+                    let output = Command::new("rrdtool")
+                        .arg("update")
+                        .arg(&rrd)
+                        .args(cycle.rrd_update(unix_secs))
+                        .output();
+                    match output {
+                        Ok(output) if output.status.success() => {}
+                        Ok(output) => crate::log::warn!(
+                            "Updating {} failed: {}",
+                            rrd.display(),
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        ),
+                        Err(e) => crate::log::warn!("Running rrdtool failed: {e}"),
+                    }
+                });
+            }
+            None => println!("{}", cycle.fping_line(&self.config.host)),
+        }
+    }
+}
+
+impl StepStart {
+    fn of(stats: &Stats) -> Self {
+        Self {
+            elapsed: stats.elapsed,
+            sent: stats.client_sent,
+            received: stats.client_received,
+            rtts: stats.rtts.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn losses_pad_both_ends_and_stay_fractional() {
+        let mut rtts = Histogram::default();
+        for ms in 10..=19 {
+            rtts.record(Duration::from_millis(ms));
+        }
+        let cycle = Cycle::new(1000, 985, &rtts, 4);
+        assert!((cycle.loss - 0.06).abs() < 1e-9);
+        assert_eq!(cycle.pings.iter().flatten().count(), 4);
+        let cycle = Cycle::new(100, 60, &rtts, 5);
+        assert_eq!(
+            cycle.pings.iter().map(Option::is_some).collect::<Vec<_>>(),
+            [false, true, true, true, false]
+        );
+        assert!(cycle.fping_line("h").starts_with("h : - 11."));
+        let update = cycle.rrd_update(7);
+        assert_eq!(
+            update[1],
+            "uptime:loss:median:ping1:ping2:ping3:ping4:ping5"
+        );
+        assert!(update[2].starts_with("7:U:2:"));
+        assert!(update[2].ends_with(":U"));
+    }
+}
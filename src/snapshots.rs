@@ -0,0 +1,59 @@
+//! A client's latest stats, handed on every so often from a thread of their
+//! own so a slow collector or endpoint doesn't hold up probing.
+
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{Stats, StopHandle};
+
+/// Keeps the latest [`Stats`] for a thread passing them on every so often,
+/// until stopped. Cloning shares the thread.
+#[derive(Clone)]
+pub(crate) struct Snapshots {
+    latest: Arc<Mutex<Option<Stats>>>,
+    stop: StopHandle,
+}
+
+impl Snapshots {
+    /// Pass the latest stats, if new, to `send` every `every`, along with a
+    /// check of whether the next ones are due or stopping, for giving up on
+    /// retries.
+    pub fn start(
+        every: Duration,
+        mut send: impl FnMut(&Stats, &dyn Fn() -> bool) + Send + 'static,
+    ) -> Self {
+        let latest = Arc::new(Mutex::new(None::<Stats>));
+        let stop = StopHandle::default();
+        thread::spawn({
+            let latest = Arc::clone(&latest);
+            let stop = stop.clone();
+            move || {
+                let mut last = Instant::now();
+                while !stop.is_stopped() {
+                    thread::sleep(Duration::from_millis(100));
+                    if last.elapsed() < every {
+                        continue;
+                    }
+                    last = Instant::now();
+                    let Some(stats) = latest.lock().unwrap().take() else {
+                        continue;
+                    };
+                    send(&stats, &|| stop.is_stopped() || last.elapsed() >= every);
+                }
+            }
+        });
+        Self { latest, stop }
+    }
+
+    /// Keep `stats` to pass on when next due.
+    pub fn update(&self, stats: &Stats) {
+        *self.latest.lock().unwrap() = Some(stats.clone());
+    }
+
+    pub fn stop(&self) {
+        self.stop.stop();
+    }
+}
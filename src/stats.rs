@@ -0,0 +1,872 @@
+use std::{fmt, net::IpAddr, time::Duration};
+
+use crate::{json, periodicity::LossPeriod};
+
+/// Lag thresholds rates are reported for are every 100ms below this many
+/// times 100ms.
+pub const LAG_BUCKETS: usize = 10;
+
+/// Bits of the value kept per histogram bucket, so values are recorded to
+/// within 1/64 of them
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKET_HALF: usize = 1 << (SUB_BUCKET_BITS - 1);
+/// Percentiles reported of histograms, and their names in JSON
+const PERCENTILES: [(f64, &str); 4] = [
+    (50.0, "p50_ms"),
+    (95.0, "p95_ms"),
+    (99.0, "p99_ms"),
+    (99.9, "p99_9_ms"),
+];
+
+/// Gap between ACKs from which on the link is considered down.
+pub const OUTAGE_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Ethernet, IP and UDP headers on top of each packet's payload
+pub(crate) const HEADER_OVERHEAD: u64 = 45;
+
+/// E-model rating (ITU-T G.107) of a G.711 call with packet loss
+/// concealment over a path with `loss` percent random loss, round-trip time
+/// `rtt` and jitter `jitter`: 93.2 at best, below 50 unusable. The one-way
+/// delay is taken as half the round trip plus a jitter buffer of twice the
+/// jitter.
+pub fn r_factor(loss: f64, rtt: Duration, jitter: Duration) -> f64 {
+    let delay_ms = (rtt / 2 + jitter * 2).as_secs_f64() * 1000.0;
+    let delay_impairment = 0.024 * delay_ms + 0.11 * (delay_ms - 177.3).max(0.0);
+    // G.113's packet loss robustness of G.711 with concealment
+    let loss_impairment = 95.0 * loss / (loss + 25.1);
+    (93.2 - delay_impairment - loss_impairment).max(0.0)
+}
+
+/// Mean opinion score, 1 (bad) to 4.5 (best), estimated from `r_factor`
+/// (ITU-T G.107 annex B).
+pub fn mos(r_factor: f64) -> f64 {
+    if r_factor <= 0.0 {
+        1.0
+    } else if r_factor >= 100.0 {
+        4.5
+    } else {
+        1.0 + 0.035 * r_factor + 7e-6 * r_factor * (r_factor - 60.0) * (100.0 - r_factor)
+    }
+}
+
+/// Exponentially weighted moving average over time: a sample's weight halves
+/// with every `half_life` after it.
+#[derive(Clone, Copy, Debug)]
+pub struct Ewma {
+    half_life: Duration,
+    value: Option<f64>,
+}
+
+impl Ewma {
+    pub fn new(half_life: Duration) -> Self {
+        Self {
+            half_life,
+            value: None,
+        }
+    }
+
+    /// Fold in `sample`, taken over `elapsed` since the previous one, and
+    /// return the new average.
+    pub fn update(&mut self, sample: f64, elapsed: Duration) -> f64 {
+        let weight = 1.0 - 0.5f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64());
+        let value = match self.value {
+            None => sample,
+            Some(value) => value + weight * (sample - value),
+        };
+        self.value = Some(value);
+        value
+    }
+
+    /// The average, `None` before the first sample.
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+/// Durations counted in buckets of microseconds that widen with the
+/// value, as in HdrHistogram: precise to 1/64 from a microsecond to hours
+/// in a few KiB.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: Duration) {
+        let i = bucket(value.as_micros().min(u64::MAX.into()) as u64);
+        if i >= self.counts.len() {
+            self.counts.resize(i + 1, 0);
+        }
+        self.counts[i] += 1;
+        self.total += 1;
+        self.max = self.max.max(value);
+    }
+
+    /// Values recorded
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Largest value recorded, exactly
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The value `percentile` percent of recorded ones are at most, rounded
+    /// up to the end of its bucket; zero if there are none.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((percentile / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(bucket_end(i)).min(self.max);
+            }
+        }
+        Duration::ZERO
+    }
+
+    /// Values recorded of at least about `value`, to within a bucket.
+    pub fn count_at_least(&self, value: Duration) -> u64 {
+        let first = bucket(value.as_micros().min(u64::MAX.into()) as u64);
+        self.counts.iter().skip(first).sum()
+    }
+
+    /// Add the values recorded in `other`.
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    /// The values recorded since `earlier`, a previous clone of this
+    /// histogram. The maximum is only known to within a bucket.
+    pub fn since(&self, earlier: &Histogram) -> Histogram {
+        let counts: Vec<u64> = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| count.saturating_sub(earlier.counts.get(i).copied().unwrap_or(0)))
+            .collect();
+        let max = match counts.iter().rposition(|&count| count > 0) {
+            Some(i) => Duration::from_micros(bucket_end(i)).min(self.max),
+            None => Duration::ZERO,
+        };
+        Histogram {
+            total: counts.iter().sum(),
+            counts,
+            max,
+        }
+    }
+
+    /// The reported percentiles and the maximum in milliseconds.
+    pub fn to_json(&self) -> String {
+        PERCENTILES
+            .iter()
+            .fold(json::Object::new(), |object, &(percentile, key)| {
+                object.f64(key, self.percentile(percentile).as_secs_f64() * 1000.0)
+            })
+            .f64("max_ms", self.max.as_secs_f64() * 1000.0)
+            .u64("count", self.total)
+            .finish()
+    }
+}
+
+impl fmt::Display for Histogram {
+    /// The reported percentiles and the maximum, e.g. `p50 1.2ms, ...`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (percentile, _) in PERCENTILES {
+            write!(
+                f,
+                "p{percentile} {:.1}ms, ",
+                self.percentile(percentile).as_secs_f64() * 1000.0
+            )?;
+        }
+        write!(f, "max {:.1}ms", self.max.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Index of the bucket `micros` is counted in: the first `2 *
+/// SUB_BUCKET_HALF` values have one each, then every doubling of the value
+/// has `SUB_BUCKET_HALF` buckets twice as wide as the previous doubling's.
+fn bucket(micros: u64) -> usize {
+    let shift = (u64::BITS - micros.leading_zeros()).saturating_sub(SUB_BUCKET_BITS);
+    shift as usize * SUB_BUCKET_HALF + (micros >> shift) as usize
+}
+
+/// Largest value counted in bucket `i`.
+fn bucket_end(i: usize) -> u64 {
+    let shift = (i / SUB_BUCKET_HALF).saturating_sub(1);
+    let start = ((i - shift * SUB_BUCKET_HALF) as u64) << shift;
+    start + (1 << shift) - 1
+}
+
+/// A period without any ACKs of at least [`OUTAGE_THRESHOLD`].
+#[derive(Clone, Copy, Debug)]
+pub struct Outage {
+    /// Time since client start at which the last ACK before the gap arrived
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// Round-trip counters for one probe size of a size mix.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeStats {
+    /// UDP payload bytes
+    pub size: u32,
+    pub sent: u64,
+    /// Distinct probes of this size acknowledged back to the client
+    pub received: u64,
+}
+
+impl SizeStats {
+    /// Percentage of probes of this size that were lost in either direction.
+    pub fn loss(&self) -> f64 {
+        100.0 * (1.0 - (self.received as f64 / self.sent as f64))
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("size", self.size.into())
+            .u64("sent", self.sent)
+            .u64("received", self.received)
+            .f64("loss", self.loss())
+            .finish()
+    }
+}
+
+/// Results for one source port while hopping ports.
+#[derive(Clone, Debug)]
+pub struct PathStats {
+    /// Local port of the socket
+    pub port: u16,
+    pub sent: u64,
+    /// Distinct probes from this port acknowledged back to the client
+    pub received: u64,
+    pub mean_rtt: Duration,
+    pub max_rtt: Duration,
+}
+
+impl PathStats {
+    /// Percentage of probes from this port that were lost in either
+    /// direction.
+    pub fn loss(&self) -> f64 {
+        100.0 * (1.0 - (self.received as f64 / self.sent as f64))
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("port", self.port.into())
+            .u64("sent", self.sent)
+            .u64("received", self.received)
+            .f64("loss", self.loss())
+            .f64("mean_rtt_ms", self.mean_rtt.as_secs_f64() * 1000.0)
+            .f64("max_rtt_ms", self.max_rtt.as_secs_f64() * 1000.0)
+            .finish()
+    }
+}
+
+/// Results for one TTL when tracing hops, see [`crate::ClientConfig::hops`].
+#[derive(Clone, Debug)]
+pub struct HopStats {
+    pub ttl: u8,
+    /// Router or server that last answered probes with this TTL
+    pub addr: Option<IpAddr>,
+    /// Probes answered or timed out
+    pub sent: u32,
+    pub received: u32,
+    pub mean_rtt: Duration,
+    pub max_rtt: Duration,
+}
+
+impl HopStats {
+    /// Percentage of probes with this TTL that got no answer.
+    pub fn loss(&self) -> f64 {
+        100.0 * (1.0 - (self.received as f64 / self.sent as f64))
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("ttl", self.ttl.into())
+            .raw(
+                "addr",
+                &self.addr.map_or_else(
+                    || "null".to_string(),
+                    |addr| json::string(&addr.to_string()),
+                ),
+            )
+            .u64("sent", self.sent.into())
+            .u64("received", self.received.into())
+            .f64("loss", self.loss())
+            .f64("mean_rtt_ms", self.mean_rtt.as_secs_f64() * 1000.0)
+            .f64("max_rtt_ms", self.max_rtt.as_secs_f64() * 1000.0)
+            .finish()
+    }
+}
+
+/// ECN field of probes as they reached the server, from its ACKs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EcnStats {
+    /// Arrived ECN-capable and unmarked
+    pub ect: u64,
+    /// Marked congestion experienced on the way
+    pub ce: u64,
+    /// Arrived with the ECN field cleared, or the server doesn't report it
+    pub not_ect: u64,
+}
+
+impl EcnStats {
+    /// Percentage of acknowledged probes that were CE marked.
+    pub fn ce_rate(&self) -> f64 {
+        100.0 * (self.ce as f64 / (self.ect + self.ce + self.not_ect) as f64)
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("ect", self.ect)
+            .u64("ce", self.ce)
+            .u64("not_ect", self.not_ect)
+            .f64("ce_rate", self.ce_rate())
+            .finish()
+    }
+}
+
+/// Round-trip loss over the latest probes, those sent within `window` before
+/// the ones still awaited.
+#[derive(Clone, Copy, Debug)]
+pub struct RecentLoss {
+    pub window: Duration,
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl RecentLoss {
+    pub fn loss(&self) -> f64 {
+        100.0 * (1.0 - (self.received as f64 / self.sent as f64))
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .f64("window_secs", self.window.as_secs_f64())
+            .u64("sent", self.sent)
+            .u64("received", self.received)
+            .f64("loss", self.loss())
+            .finish()
+    }
+}
+
+/// The server's own probe stream in symmetric mode, measuring downstream
+/// loss and jitter apart from ACKs, see [`crate::ClientConfig::symmetric`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SymmetricStats {
+    /// Probes the server sent, as of the latest that arrived
+    pub server_sent: u64,
+    /// Distinct server probes received by the client
+    pub client_received: u64,
+    /// Interarrival jitter of server probes (RFC 3550)
+    pub jitter: Duration,
+}
+
+impl SymmetricStats {
+    /// Percentage of server probes that did not reach the client.
+    pub fn loss(&self) -> f64 {
+        100.0 * (1.0 - (self.client_received as f64 / self.server_sent as f64))
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("server_sent", self.server_sent)
+            .u64("client_received", self.client_received)
+            .f64("loss", self.loss())
+            .f64("jitter_ms", self.jitter.as_secs_f64() * 1000.0)
+            .finish()
+    }
+}
+
+/// Snapshot of a running client's counters.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Probes sent by the client, except those left unanswered by servers
+    /// failed over from
+    pub client_sent: u64,
+    /// Probes left unanswered by servers failed over from, see
+    /// [`ClientConfig::failover`](crate::ClientConfig::failover)
+    pub failover_unanswered: u64,
+    /// Highest receive counter reported by the server
+    pub server_received: u64,
+    /// Distinct probes acknowledged back to the client
+    pub client_received: u64,
+    /// Time since the client started
+    pub elapsed: Duration,
+    /// How much longer than the wait between the probes each ACK took after
+    /// the previous one
+    pub lags: Histogram,
+    /// Largest gap between ACKs since the previous snapshot
+    pub max_gap: Duration,
+    /// Mean and largest round-trip time of acknowledged probes
+    pub mean_rtt: Duration,
+    pub max_rtt: Duration,
+    /// Round-trip times of acknowledged probes
+    pub rtts: Histogram,
+    /// How much the round-trip times of consecutive probes differ, either
+    /// way: the IP packet delay variation of RFC 3393
+    pub ipdv: Histogram,
+    /// Round-trip loss percentage and round-trip time averaged with
+    /// [`ClientConfig::half_life`](crate::ClientConfig::half_life), which
+    /// unlike `recent_loss` keeps up during outages; loss is NaN until the
+    /// first second is over
+    pub smoothed_loss: f64,
+    pub smoothed_rtt: Duration,
+    /// Round-trip loss over each of
+    /// [`ClientConfig::loss_windows`](crate::ClientConfig::loss_windows)
+    pub recent_loss: Vec<RecentLoss>,
+    /// Periods loss recurred with, most pronounced first; only searched for
+    /// in the final stats, see [`crate::periodicity`]
+    pub loss_periods: Vec<LossPeriod>,
+    /// Mean difference between the round-trip times of consecutive ACKs,
+    /// smoothed like the interarrival jitter of RFC 3550
+    pub jitter: Duration,
+    /// Outages that have ended so far, oldest first
+    pub outages: Vec<Outage>,
+    /// UDP payload bytes per probe, averaged over a size mix
+    pub probe_size: u32,
+    /// UDP payload bytes per ACK, averaged over a size mix
+    pub ack_size: u32,
+    /// Round-trip loss per probe size when cycling through a size mix
+    pub sizes: Vec<SizeStats>,
+    /// Upstream bottleneck capacity estimated from packet trains, in Mbit/s
+    pub capacity_mbps: Option<f64>,
+    /// Results per source port when hopping ports
+    pub paths: Vec<PathStats>,
+    /// Congestion marks when probing with ECN enabled
+    pub ecn: Option<EcnStats>,
+    /// Probes the server received with a wrong checksum when checking
+    /// them, as of its latest ACK; they count as lost upstream too
+    pub corrupted: Option<u32>,
+    /// Downstream loss and jitter of the server's own probes in symmetric
+    /// mode
+    pub symmetric: Option<SymmetricStats>,
+    /// Loss and latency per hop when tracing hops
+    pub hops: Vec<HopStats>,
+    /// The far end doesn't count the probes it receives, e.g. an echo
+    /// service, so loss is only known for the round trip: upstream and
+    /// downstream loss are NaN, and `server_received` counts echoes
+    pub round_trip_only: bool,
+}
+
+impl Stats {
+    /// Percentage of probes that did not reach the server.
+    pub fn upstream_loss(&self) -> f64 {
+        if self.round_trip_only {
+            return f64::NAN;
+        }
+        100.0 * (1.0 - (self.server_received as f64 / self.client_sent as f64))
+    }
+
+    /// Percentage of probes seen by the server whose ACK did not come back.
+    pub fn downstream_loss(&self) -> f64 {
+        if self.round_trip_only {
+            return f64::NAN;
+        }
+        100.0 * (1.0 - (self.client_received as f64 / self.server_received as f64))
+    }
+
+    /// Totals over flows measured side by side: counters add up, while gaps
+    /// and elapsed time are the largest of any flow.
+    pub fn combine<'a>(flows: impl IntoIterator<Item = &'a Stats>) -> Stats {
+        let mut total = Stats::default();
+        for (i, flow) in flows.into_iter().enumerate() {
+            if i == 0 {
+                total.probe_size = flow.probe_size;
+                total.ack_size = flow.ack_size;
+                total.sizes = flow.sizes.clone();
+                total.capacity_mbps = flow.capacity_mbps;
+                total.hops = flow.hops.clone();
+                total.recent_loss = flow.recent_loss.clone();
+            } else {
+                for (recent, flow_recent) in total.recent_loss.iter_mut().zip(&flow.recent_loss) {
+                    recent.sent += flow_recent.sent;
+                    recent.received += flow_recent.received;
+                }
+                for (size, flow_size) in total.sizes.iter_mut().zip(&flow.sizes) {
+                    size.sent += flow_size.sent;
+                    size.received += flow_size.received;
+                }
+                // the flows share the bottleneck, so any estimate will do
+                total.capacity_mbps = total.capacity_mbps.or(flow.capacity_mbps);
+            }
+            total.client_sent += flow.client_sent;
+            total.failover_unanswered += flow.failover_unanswered;
+            total.server_received += flow.server_received;
+            total.client_received += flow.client_received;
+            total.round_trip_only |= flow.round_trip_only;
+            total.elapsed = total.elapsed.max(flow.elapsed);
+            total.lags.merge(&flow.lags);
+            total.max_gap = total.max_gap.max(flow.max_gap);
+            // weighted by ACKs, with client_received already including this
+            // flow's
+            if total.client_received > 0 {
+                let weight = flow.client_received as f64 / total.client_received as f64;
+                total.mean_rtt =
+                    total.mean_rtt.mul_f64(1.0 - weight) + flow.mean_rtt.mul_f64(weight);
+            }
+            total.max_rtt = total.max_rtt.max(flow.max_rtt);
+            total.rtts.merge(&flow.rtts);
+            total.ipdv.merge(&flow.ipdv);
+            total.jitter = total.jitter.max(flow.jitter);
+            // like the flows' loss and round-trip times taken together
+            total.smoothed_loss = if i == 0 {
+                flow.smoothed_loss
+            } else {
+                (total.smoothed_loss * i as f64 + flow.smoothed_loss) / (i + 1) as f64
+            };
+            total.smoothed_rtt = total.smoothed_rtt.max(flow.smoothed_rtt);
+            total.outages.extend_from_slice(&flow.outages);
+            total.loss_periods.extend_from_slice(&flow.loss_periods);
+            total.paths.extend_from_slice(&flow.paths);
+            if let Some(ecn) = flow.ecn {
+                let total = total.ecn.get_or_insert_with(EcnStats::default);
+                total.ect += ecn.ect;
+                total.ce += ecn.ce;
+                total.not_ect += ecn.not_ect;
+            }
+            if let Some(corrupted) = flow.corrupted {
+                *total.corrupted.get_or_insert(0) += corrupted;
+            }
+            if let Some(symmetric) = flow.symmetric {
+                let total = total.symmetric.get_or_insert_with(SymmetricStats::default);
+                total.server_sent += symmetric.server_sent;
+                total.client_received += symmetric.client_received;
+                total.jitter = total.jitter.max(symmetric.jitter);
+            }
+        }
+        total.outages.sort_by_key(|outage| outage.start);
+        total
+            .loss_periods
+            .sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        total
+    }
+
+    /// Percentage of probes whose ACK did not come back, in either direction.
+    pub fn round_trip_loss(&self) -> f64 {
+        100.0 * (1.0 - (self.client_received as f64 / self.client_sent as f64))
+    }
+
+    /// E-model rating of calls over the path so far, see [`r_factor`].
+    pub fn r_factor(&self) -> f64 {
+        r_factor(self.round_trip_loss(), self.mean_rtt, self.jitter)
+    }
+
+    /// Estimated call quality over the path so far, see [`mos`].
+    pub fn mos(&self) -> f64 {
+        mos(self.r_factor())
+    }
+
+    /// Estimated bandwidth used by probes and ACKs, including IP/UDP headers.
+    pub fn traffic_kib_per_sec(&self) -> f64 {
+        let bytes = self.client_sent * (self.probe_size as u64 + HEADER_OVERHEAD)
+            + self.server_received * (self.ack_size as u64 + HEADER_OVERHEAD);
+        (bytes as f64 / (1 << 10) as f64) / self.elapsed.as_secs_f64()
+    }
+
+    /// Rate of lags of at least `threshold_ms`, for each 100ms threshold.
+    pub fn lags_per_hour(&self) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let elapsed = self.elapsed.as_secs_f64();
+        (1..LAG_BUCKETS).map(move |i| {
+            let lags = self
+                .lags
+                .count_at_least(Duration::from_millis(i as u64 * 100));
+            (i * 100, lags as f64 / elapsed * 3600.0)
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .u64("client_sent", self.client_sent)
+            .u64("failover_unanswered", self.failover_unanswered)
+            .u64("server_received", self.server_received)
+            .u64("client_received", self.client_received)
+            .f64("elapsed_secs", self.elapsed.as_secs_f64())
+            .f64("upstream_loss", self.upstream_loss())
+            .f64("downstream_loss", self.downstream_loss())
+            .f64("round_trip_loss", self.round_trip_loss())
+            .raw(
+                "round_trip_only",
+                if self.round_trip_only {
+                    "true"
+                } else {
+                    "false"
+                },
+            )
+            .f64("traffic_kib_per_sec", self.traffic_kib_per_sec())
+            .u64("probe_size", self.probe_size.into())
+            .u64("ack_size", self.ack_size.into())
+            .f64("max_gap_ms", self.max_gap.as_secs_f64() * 1000.0)
+            .f64("mean_rtt_ms", self.mean_rtt.as_secs_f64() * 1000.0)
+            .f64("max_rtt_ms", self.max_rtt.as_secs_f64() * 1000.0)
+            .raw("rtt_percentiles", &self.rtts.to_json())
+            .raw("ipdv_percentiles", &self.ipdv.to_json())
+            .f64("smoothed_loss", self.smoothed_loss)
+            .f64("smoothed_rtt_ms", self.smoothed_rtt.as_secs_f64() * 1000.0)
+            .f64("jitter_ms", self.jitter.as_secs_f64() * 1000.0)
+            .f64("r_factor", self.r_factor())
+            .f64("mos", self.mos())
+            .raw(
+                "lags_per_hour",
+                &json::array(self.lags_per_hour().map(|(threshold_ms, rate)| {
+                    json::Object::new()
+                        .u64("threshold_ms", threshold_ms as u64)
+                        .f64("rate", rate)
+                        .finish()
+                })),
+            )
+            .raw("lag_percentiles", &self.lags.to_json())
+            .raw(
+                "recent_loss",
+                &json::array(self.recent_loss.iter().map(RecentLoss::to_json)),
+            )
+            .raw(
+                "loss_periods",
+                &json::array(self.loss_periods.iter().map(LossPeriod::to_json)),
+            )
+            .raw(
+                "outages",
+                &json::array(self.outages.iter().map(Outage::to_json)),
+            )
+            .raw(
+                "sizes",
+                &json::array(self.sizes.iter().map(SizeStats::to_json)),
+            )
+            .raw(
+                "paths",
+                &json::array(self.paths.iter().map(PathStats::to_json)),
+            )
+            .raw(
+                "hops",
+                &json::array(self.hops.iter().map(HopStats::to_json)),
+            )
+            .raw(
+                "ecn",
+                &self
+                    .ecn
+                    .as_ref()
+                    .map_or_else(|| "null".to_string(), EcnStats::to_json),
+            )
+            .raw(
+                "corrupted",
+                &self
+                    .corrupted
+                    .map_or_else(|| "null".to_string(), |corrupted| corrupted.to_string()),
+            )
+            .raw(
+                "symmetric",
+                &self
+                    .symmetric
+                    .as_ref()
+                    .map_or_else(|| "null".to_string(), SymmetricStats::to_json),
+            )
+            .raw(
+                "capacity_mbps",
+                &self
+                    .capacity_mbps
+                    .map_or_else(|| "null".to_string(), json::number),
+            )
+            .finish()
+    }
+}
+
+impl Outage {
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .f64("start_secs", self.start.as_secs_f64())
+            .f64("duration_secs", self.duration.as_secs_f64())
+            .finish()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Estimated traffic: {:.02} KiB/s",
+            self.traffic_kib_per_sec()
+        )?;
+        writeln!(f, "Client sent    : {}", self.client_sent)?;
+        if self.failover_unanswered > 0 {
+            writeln!(
+                f,
+                "Failed over    : {} probes to silent servers not counted",
+                self.failover_unanswered
+            )?;
+        }
+        if self.round_trip_only {
+            writeln!(f, "Client received: {}", self.client_received)?;
+            writeln!(
+                f,
+                "Round-trip loss: {:.2}% (the server doesn't tell the directions apart)",
+                self.round_trip_loss()
+            )?;
+        } else {
+            writeln!(f, "Server received: {}", self.server_received)?;
+            writeln!(f, "Client received: {}", self.client_received)?;
+            writeln!(f, "Client   upstream loss: {:.2}%", self.upstream_loss())?;
+            writeln!(f, "Client downstream loss: {:.2}%", self.downstream_loss())?;
+        }
+        if !self.smoothed_loss.is_nan() {
+            writeln!(
+                f,
+                "Smoothed       : {:.2}% round-trip loss, {:.1}ms round trip",
+                self.smoothed_loss,
+                self.smoothed_rtt.as_secs_f64() * 1000.0
+            )?;
+        }
+        // nothing to tell until the first probes are past the late window
+        if self.recent_loss.iter().any(|recent| recent.sent > 0) {
+            write!(f, "Recent loss    : ")?;
+            for recent in &self.recent_loss {
+                write!(
+                    f,
+                    "{:.2}% (last {}s), ",
+                    recent.loss(),
+                    recent.window.as_secs_f64()
+                )?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(corrupted) = self.corrupted {
+            writeln!(
+                f,
+                "Corrupted upstream: {:.2}% ({corrupted})",
+                100.0 * corrupted as f64 / self.client_sent as f64
+            )?;
+        }
+        if let Some(symmetric) = self.symmetric {
+            writeln!(
+                f,
+                "Server probes  : {} of {} received, {:.2}% loss, {:.1}ms jitter",
+                symmetric.client_received,
+                symmetric.server_sent,
+                symmetric.loss(),
+                symmetric.jitter.as_secs_f64() * 1000.0
+            )?;
+        }
+        writeln!(
+            f,
+            "Round trip: {:.1}ms avg, {:.1}ms max, {:.1}ms jitter",
+            self.mean_rtt.as_secs_f64() * 1000.0,
+            self.max_rtt.as_secs_f64() * 1000.0,
+            self.jitter.as_secs_f64() * 1000.0
+        )?;
+        writeln!(f, "Round-trip percentiles: {}", self.rtts)?;
+        writeln!(f, "Delay variation (IPDV): {}", self.ipdv)?;
+        writeln!(
+            f,
+            "Call quality: MOS {:.2} (R-factor {:.1})",
+            self.mos(),
+            self.r_factor()
+        )?;
+        if let Some(ecn) = self.ecn {
+            write!(f, "ECN CE marked: {:.2}% ({})", ecn.ce_rate(), ecn.ce)?;
+            if ecn.not_ect > 0 {
+                write!(f, ", {} arrived without ECN", ecn.not_ect)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "Lags per hour: ")?;
+        for (threshold_ms, rate) in self.lags_per_hour() {
+            write!(f, "{rate:.02} (>={threshold_ms}ms), ")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Lag percentiles: {}", self.lags)?;
+        if !self.loss_periods.is_empty() {
+            write!(f, "Loss recurs every: ")?;
+            for period in &self.loss_periods {
+                write!(
+                    f,
+                    "{:.1}s (confidence {:.2}), ",
+                    period.period.as_secs_f64(),
+                    period.confidence
+                )?;
+            }
+            writeln!(f)?;
+        }
+        if !self.sizes.is_empty() {
+            write!(f, "Loss by size: ")?;
+            for size in &self.sizes {
+                write!(f, "{:.2}% ({}B), ", size.loss(), size.size)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.paths.is_empty() {
+            write!(f, "Loss by port: ")?;
+            for path in &self.paths {
+                write!(
+                    f,
+                    "{:.2}% (:{}, rtt {:.1}ms avg {:.1}ms max), ",
+                    path.loss(),
+                    path.port,
+                    path.mean_rtt.as_secs_f64() * 1000.0,
+                    path.max_rtt.as_secs_f64() * 1000.0
+                )?;
+            }
+            writeln!(f)?;
+        }
+        if !self.hops.is_empty() {
+            writeln!(
+                f,
+                "Hop  Address                                  Loss    Avg    Max"
+            )?;
+            for hop in &self.hops {
+                writeln!(
+                    f,
+                    "{:>3}  {:<39} {:>5.1}% {:>5.1}ms {:>5.1}ms",
+                    hop.ttl,
+                    hop.addr
+                        .map_or_else(|| "???".to_string(), |addr| addr.to_string()),
+                    hop.loss(),
+                    hop.mean_rtt.as_secs_f64() * 1000.0,
+                    hop.max_rtt.as_secs_f64() * 1000.0
+                )?;
+            }
+        }
+        if let Some(capacity) = self.capacity_mbps {
+            writeln!(f, "Upstream capacity: ~{capacity:.1} Mbit/s")?;
+        }
+        writeln!(f, "Time elapsed: {:.2} seconds", self.elapsed.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentiles_within_a_bucket() {
+        let mut histogram = Histogram::default();
+        for ms in 1..=1000 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        for (percentile, expected) in [(50.0, 500.0), (99.0, 990.0), (99.9, 999.0)] {
+            let ms = histogram.percentile(percentile).as_secs_f64() * 1000.0;
+            assert!(
+                (expected..=expected * (1.0 + 1.0 / 64.0)).contains(&ms),
+                "p{percentile} {ms}"
+            );
+        }
+        assert_eq!(histogram.percentile(100.0), Duration::from_secs(1));
+        assert_eq!(histogram.count_at_least(Duration::from_millis(100)), 901);
+        let mut merged = Histogram::default();
+        merged.merge(&histogram);
+        assert_eq!(merged, histogram);
+        for i in 0..4096 {
+            assert!(bucket_end(bucket(i)) >= i && bucket(bucket_end(bucket(i))) == bucket(i));
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! External address and NAT mapping detection with STUN binding requests,
+//! RFC 5389, sent from the probe socket before probing starts. NAT behavior
+//! explains many asymmetric results, e.g. ACKs lost to a mapping timing out.
+//!
+//! Asking several servers tells how the NAT maps the socket, see RFC 4787:
+//! the same external address for all of them means it maps independently of
+//! the destination, different ones that it picks a new port per destination.
+//! A `server --allow-stun` answers binding requests itself, so it can be one
+//! of them.
+
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{json, BUF_SIZE};
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_SIZE: usize = 20;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// Wait for an answer this long at first, doubling with every retry
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(500);
+const ATTEMPTS: u32 = 4;
+
+/// How the NAT in front of the client maps its socket, as far as the
+/// servers asked can tell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mapping {
+    /// The servers saw the socket's own address
+    NoNat,
+    /// Every server saw the same external address, so other hosts, like
+    /// peers punching through, reach the socket there too
+    EndpointIndependent,
+    /// Servers saw different external addresses, as behind NATs picking a
+    /// new port for every destination
+    AddressDependent,
+    /// Behind a NAT, but a single server can't tell how it maps
+    Unknown,
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mapping::NoNat => "no NAT",
+            Mapping::EndpointIndependent => "endpoint-independent",
+            Mapping::AddressDependent => "address-dependent",
+            Mapping::Unknown => "unknown",
+        })
+    }
+}
+
+/// What the STUN servers saw of the probe socket.
+#[derive(Clone, Debug)]
+pub struct NatReport {
+    /// The socket's own address
+    pub local: SocketAddr,
+    /// Each server asked, with the address it saw the socket at
+    pub external: Vec<(SocketAddr, SocketAddr)>,
+}
+
+impl NatReport {
+    pub fn mapping(&self) -> Mapping {
+        let first = self.external[0].1;
+        if self
+            .external
+            .iter()
+            .all(|&(_, external)| external == self.local)
+        {
+            Mapping::NoNat
+        } else if self.external.iter().any(|&(_, external)| external != first) {
+            Mapping::AddressDependent
+        } else if self.external.len() > 1 {
+            Mapping::EndpointIndependent
+        } else {
+            Mapping::Unknown
+        }
+    }
+
+    /// Address the first server saw the socket at.
+    pub fn external_addr(&self) -> SocketAddr {
+        self.external[0].1
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .str("local", &self.local.to_string())
+            .raw(
+                "external",
+                &json::array(self.external.iter().map(|(server, external)| {
+                    json::Object::new()
+                        .str("server", &server.to_string())
+                        .str("addr", &external.to_string())
+                        .finish()
+                })),
+            )
+            .str("mapping", &self.mapping().to_string())
+            .finish()
+    }
+}
+
+/// Ask each of `servers` for the address it sees the not yet connected
+/// `socket` at.
+pub(crate) fn query(socket: &UdpSocket, servers: &[SocketAddr]) -> eyre::Result<NatReport> {
+    let mut external = Vec::new();
+    for &server in servers {
+        let addr = binding(socket, server)?
+            .ok_or_else(|| eyre::eyre!("no answer from the STUN server {server}"))?;
+        external.push((server, addr));
+    }
+    Ok(NatReport {
+        local: local_addr(socket, servers[0])?,
+        external,
+    })
+}
+
+/// The socket's address with the IP it sends to `server` from, where it's
+/// bound to any.
+fn local_addr(socket: &UdpSocket, server: SocketAddr) -> eyre::Result<SocketAddr> {
+    let local = socket.local_addr()?;
+    if !local.ip().is_unspecified() {
+        return Ok(local);
+    }
+    // connecting picks the source address the route to the server takes
+    let route = UdpSocket::bind(SocketAddr::new(local.ip(), 0))?;
+    route.connect(server)?;
+    Ok(SocketAddr::new(route.local_addr()?.ip(), local.port()))
+}
+
+/// The address `server` answers a binding request with, retrying a few
+/// times; `None` if it doesn't.
+fn binding(socket: &UdpSocket, server: SocketAddr) -> eyre::Result<Option<SocketAddr>> {
+    let transaction: [u8; 12] = rand::random();
+    let mut request = [0u8; HEADER_SIZE];
+    request[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..20].copy_from_slice(&transaction);
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&request, server)?;
+        let deadline = Instant::now() + timeout;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock
+                            | ErrorKind::TimedOut
+                            | ErrorKind::Interrupted
+                            | ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if from != server {
+                continue;
+            }
+            if let Some(addr) = mapped_address(&buf[..n], &transaction) {
+                return Ok(Some(addr));
+            }
+        }
+        timeout *= 2;
+    }
+    Ok(None)
+}
+
+/// The mapped address in a binding response to `transaction`.
+fn mapped_address(response: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    let (header, attributes) = response.split_at_checked(HEADER_SIZE)?;
+    if u16::from_be_bytes([header[0], header[1]]) != BINDING_RESPONSE
+        || header[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || header[8..20] != transaction[..]
+    {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    let mut attributes = attributes.get(..len)?;
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        let value = attributes.get(4..4 + len)?;
+        match kind {
+            // preferred, as NATs rewriting addresses in payloads miss it
+            XOR_MAPPED_ADDRESS => return decode_address(value, Some(transaction)),
+            MAPPED_ADDRESS => mapped = decode_address(value, None),
+            _ => {}
+        }
+        // values are padded to multiples of 4 bytes
+        attributes = attributes
+            .get((4 + len).next_multiple_of(4)..)
+            .unwrap_or(&[]);
+    }
+    mapped
+}
+
+/// A (XOR-)MAPPED-ADDRESS value, XORed with the magic cookie and
+/// `transaction` if given.
+fn decode_address(value: &[u8], transaction: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let mut mask = [0u8; 16];
+    if let Some(transaction) = transaction {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+    let port = u16::from_be_bytes([value.get(2)? ^ mask[0], value.get(3)? ^ mask[1]]);
+    let ip = match value[1] {
+        1 => {
+            let octets: [u8; 4] = value.get(4..8)?.try_into().unwrap();
+            IpAddr::V4(Ipv4Addr::from(xor(octets, &mask)))
+        }
+        2 => {
+            let octets: [u8; 16] = value.get(4..20)?.try_into().unwrap();
+            IpAddr::V6(Ipv6Addr::from(xor(octets, &mask)))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn xor<const N: usize>(mut octets: [u8; N], mask: &[u8; 16]) -> [u8; N] {
+    for (octet, mask) in octets.iter_mut().zip(mask) {
+        *octet ^= mask;
+    }
+    octets
+}
+
+/// Whether `packet` is a binding request, which no loss_lens packet kind
+/// looks like.
+pub(crate) fn is_request(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_SIZE
+        && u16::from_be_bytes([packet[0], packet[1]]) == BINDING_REQUEST
+        && packet[4..8] == MAGIC_COOKIE.to_be_bytes()
+        && usize::from(u16::from_be_bytes([packet[2], packet[3]])) == packet.len() - HEADER_SIZE
+}
+
+/// The server's answer to a binding `request` from `addr`, at most 24 bytes
+/// larger than the request.
+pub(crate) fn answer(request: &[u8], addr: SocketAddr) -> Vec<u8> {
+    let mut mask = [0u8; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(&request[8..20]);
+    // clients of a dual-stack server reaching it over IPv4
+    let (family, ip) = match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => (1, xor(ip.octets(), &mask).to_vec()),
+        IpAddr::V6(ip) => (2, xor(ip.octets(), &mask).to_vec()),
+    };
+    let value_len = 4 + ip.len();
+    let mut response = Vec::with_capacity(HEADER_SIZE + 4 + value_len);
+    response.extend_from_slice(&BINDING_RESPONSE.to_be_bytes());
+    response.extend_from_slice(&((4 + value_len) as u16).to_be_bytes());
+    response.extend_from_slice(&request[4..20]);
+    response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+    response.extend_from_slice(&(value_len as u16).to_be_bytes());
+    response.extend_from_slice(&[0, family]);
+    response.extend_from_slice(&(addr.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+    response.extend_from_slice(&ip);
+    response
+}
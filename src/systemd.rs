@@ -0,0 +1,116 @@
+//! Integration with systemd service management: socket activation
+//! (`LISTEN_FDS`), readiness notifications (`NOTIFY_SOCKET`) and the service
+//! watchdog (`WATCHDOG_USEC`). All are no-ops when not running under systemd.
+
+use std::{
+    env,
+    net::UdpSocket,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    time::{Duration, Instant},
+};
+
+/// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the UDP socket passed by systemd socket activation, if any. Only a
+/// single datagram socket is supported.
+///
+/// The activation variables are removed from the environment so the socket
+/// isn't claimed twice; call this before spawning threads.
+pub fn listen_udp_socket() -> eyre::Result<Option<UdpSocket>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    // the variables are inherited by children that weren't meant to use them
+    if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let Some(fds) = fds.and_then(|fds| fds.parse::<RawFd>().ok()) else {
+        return Ok(None);
+    };
+    eyre::ensure!(fds == 1, "expected one socket from systemd, got {fds}");
+
+    let fd = LISTEN_FDS_START;
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&kind) as libc::socklen_t;
+    let rv = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            (&mut kind as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if rv == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    eyre::ensure!(
+        kind == libc::SOCK_DGRAM,
+        "socket from systemd is not a datagram socket"
+    );
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(Some(unsafe { UdpSocket::from_raw_fd(fd) }))
+}
+
+/// Send a state update such as `READY=1` to the service manager. Returns
+/// whether it was delivered, i.e. `false` when not running under systemd.
+pub fn notify(state: &str) -> eyre::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.as_encoded_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => eyre::bail!("abstract notify sockets are only supported on Linux"),
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Keep-alive for `WatchdogSec=`. Call [`Watchdog::ping`] from a main loop;
+/// if the loop hangs, the pings stop and systemd restarts the service.
+pub(crate) struct Watchdog {
+    /// Half the watchdog timeout, as recommended by `sd_watchdog_enabled(3)`
+    interval: Option<Duration>,
+    last: Instant,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Self {
+        let for_us =
+            env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let interval = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|&usec| for_us && usec > 0)
+            .map(|usec| Duration::from_micros(usec) / 2);
+        Self {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// Notify systemd if the last notification is older than the interval.
+    pub fn ping(&mut self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        if self.last.elapsed() >= interval {
+            self.last = Instant::now();
+            let _ = notify("WATCHDOG=1");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! TCP connect probing, for paths whose firewalls drop UDP altogether.
+//!
+//! Every probe is a connect to the target port, acknowledged once the
+//! handshake completes; refused and timed out connects count as lost, and
+//! the round-trip time is the time the handshake took. Like with an echo
+//! service, loss is only known for the round trip. Connects run on threads
+//! of their own so ones hanging until the timeout don't hold up the send
+//! loop, at most [`MAX_PENDING`] at once; probes beyond that count as lost
+//! too. Connections are closed right away, leaving them in TIME_WAIT on this
+//! side, so keep the rate to what the local port range can take.
+
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{protocol::Reply, LATE_WINDOW_SECS};
+
+/// Connects in flight at once
+pub const MAX_PENDING: usize = 256;
+/// Connects taking longer would count as lost anyway
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(LATE_WINDOW_SECS as u64);
+
+/// Runs the connects and passes those that succeeded on to the receive loop
+/// as the sequence numbers of their probes.
+pub(crate) struct Connector {
+    connected: Sender<Vec<u8>>,
+    acks: Mutex<Option<Receiver<Vec<u8>>>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Connector {
+    pub(crate) fn new() -> Self {
+        let (connected, acks) = mpsc::channel();
+        Self {
+            connected,
+            acks: Mutex::new(Some(acks)),
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Connect to `addr` as probe `seq`, in the background.
+    pub(crate) fn connect(&self, addr: SocketAddr, seq: u32) {
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        let connected = self.connected.clone();
+        let pending = Arc::clone(&self.pending);
+        thread::spawn(move || {
+            if TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok() {
+                let _ = connected.send(seq.to_be_bytes().to_vec());
+            }
+            pending.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    /// The sequence numbers of completed connects, taken by the receive
+    /// loop.
+    pub(crate) fn acks(&self) -> Option<Receiver<Vec<u8>>> {
+        self.acks.lock().unwrap().take()
+    }
+}
+
+/// A completed connect as an ACK, without a count of the target's.
+pub(crate) fn reply(packet: &[u8]) -> Option<Reply> {
+    Some(Reply::Ack {
+        seq: u32::from_be_bytes(packet.try_into().ok()?),
+        received: 0,
+        ecn: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn completed_connect_acks_the_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = Connector::new();
+        let acks = connector.acks().unwrap();
+        connector.connect(listener.local_addr().unwrap(), 42);
+        let packet = acks.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            reply(&packet),
+            Some(Reply::Ack {
+                seq: 42,
+                received: 0,
+                ecn: None
+            })
+        );
+        assert!(connector.acks().is_none());
+    }
+}
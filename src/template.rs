@@ -0,0 +1,223 @@
+//! Just enough of Mustache for report templates, rendering a parsed JSON
+//! value without pulling in a template engine:
+//!
+//! - `{{key}}`, `{{key.member}}`: a value, looked up from the innermost
+//!   section outwards; `{{.}}` is the current item
+//! - `{{key:.2}}`: a number with that many decimals
+//! - `{{#key}}...{{/key}}`: repeated for each item of an array, or once with
+//!   an object or any other value but `false`, `null`, `0` and `""`
+//! - `{{^key}}...{{/key}}`: once if the section would be skipped
+//! - `{{! comment}}`
+//!
+//! Unlike Mustache nothing is HTML-escaped, as reports are as often Markdown
+//! or plain text. Tags alone on their line don't leave an empty one.
+
+use std::fmt::Write;
+
+use crate::json::Value;
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Value {
+        path: String,
+        decimals: Option<usize>,
+    },
+    Section {
+        path: String,
+        inverted: bool,
+        children: Vec<Node>,
+    },
+}
+
+/// A parsed template.
+#[derive(Debug)]
+pub(crate) struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> eyre::Result<Self> {
+        // the open sections, innermost last, with their nodes so far
+        let mut open: Vec<(String, bool, Vec<Node>)> = Vec::new();
+        let mut nodes = Vec::new();
+        let mut rest = template;
+        let mut line = 1;
+        // whether `rest` starts a line
+        let mut at_line_start = true;
+        while let Some(start) = rest.find("{{") {
+            let mut text = &rest[..start];
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| eyre::eyre!("line {line}: unclosed tag"))?
+                + start;
+            let tag = rest[start + 2..end].trim();
+            let mut after = &rest[end + 2..];
+            line += rest[..end].matches('\n').count();
+            let sigil = tag.chars().next().unwrap_or(' ');
+            let began_line = std::mem::replace(&mut at_line_start, false);
+            if matches!(sigil, '#' | '^' | '/' | '!') {
+                // a tag alone on its line takes the line with it
+                let indent = text.len() - text.trim_end_matches([' ', '\t']).len();
+                let line_start = text.len() - indent;
+                let alone_before = if line_start == 0 {
+                    began_line
+                } else {
+                    text[..line_start].ends_with('\n')
+                };
+                let trailing = after.len() - after.trim_start_matches([' ', '\t']).len();
+                let alone_after = after[trailing..].starts_with('\n')
+                    || after[trailing..].starts_with("\r\n")
+                    || after.len() == trailing;
+                if alone_before && alone_after {
+                    text = &text[..line_start];
+                    after = after[trailing..]
+                        .strip_prefix("\r\n")
+                        .or_else(|| after[trailing..].strip_prefix('\n'))
+                        .unwrap_or(&after[trailing..]);
+                    line += 1;
+                    at_line_start = true;
+                }
+            }
+            let current = open.last_mut().map_or(&mut nodes, |(.., nodes)| nodes);
+            if !text.is_empty() {
+                current.push(Node::Text(text.to_string()));
+            }
+            match sigil {
+                '!' => {}
+                '#' | '^' => open.push((tag[1..].trim().to_string(), sigil == '^', Vec::new())),
+                '/' => {
+                    let (path, inverted, children) = open
+                        .pop()
+                        .ok_or_else(|| eyre::eyre!("line {line}: {{{{{tag}}}}} closes nothing"))?;
+                    eyre::ensure!(
+                        path == tag[1..].trim(),
+                        "line {line}: {{{{{tag}}}}} closes {{{{#{path}}}}}"
+                    );
+                    let current = open.last_mut().map_or(&mut nodes, |(.., nodes)| nodes);
+                    current.push(Node::Section {
+                        path,
+                        inverted,
+                        children,
+                    });
+                }
+                _ => {
+                    let (path, decimals) = match tag.split_once(":.") {
+                        Some((path, decimals)) => (
+                            path,
+                            Some(decimals.parse().map_err(|_| {
+                                eyre::eyre!("line {line}: bad decimals in {{{{{tag}}}}}")
+                            })?),
+                        ),
+                        None => (tag, None),
+                    };
+                    current.push(Node::Value {
+                        path: path.trim().to_string(),
+                        decimals,
+                    });
+                }
+            }
+            rest = after;
+        }
+        if let Some((path, ..)) = open.last() {
+            eyre::bail!("{{{{#{path}}}}} is never closed");
+        }
+        if !rest.is_empty() {
+            nodes.push(Node::Text(rest.to_string()));
+        }
+        Ok(Self { nodes })
+    }
+
+    pub fn render(&self, data: &Value) -> String {
+        let mut out = String::new();
+        render(&self.nodes, &mut vec![data], &mut out);
+        out
+    }
+}
+
+fn render(nodes: &[Node], stack: &mut Vec<&Value>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Value { path, decimals } => match lookup(stack, path) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(s)) => out.push_str(s),
+                Some(Value::Bool(b)) => {
+                    let _ = write!(out, "{b}");
+                }
+                Some(Value::Number(n)) => match decimals {
+                    Some(decimals) => {
+                        let _ = write!(out, "{n:.decimals$}");
+                    }
+                    None => {
+                        let _ = write!(out, "{n}");
+                    }
+                },
+                Some(value) => out.push_str(&value.to_json()),
+            },
+            Node::Section {
+                path,
+                inverted,
+                children,
+            } => {
+                let value = lookup(stack, path);
+                let items: Vec<&Value> = match value {
+                    None | Some(Value::Null | Value::Bool(false)) => Vec::new(),
+                    Some(Value::Number(n)) if *n == 0.0 => Vec::new(),
+                    Some(Value::String(s)) if s.is_empty() => Vec::new(),
+                    Some(Value::Array(items)) => items.iter().collect(),
+                    Some(value) => vec![value],
+                };
+                if *inverted {
+                    if items.is_empty() {
+                        render(children, stack, out);
+                    }
+                    continue;
+                }
+                for item in items {
+                    stack.push(item);
+                    render(children, stack, out);
+                    stack.pop();
+                }
+            }
+        }
+    }
+}
+
+/// `path` looked up from the innermost context outwards.
+fn lookup<'a>(stack: &[&'a Value], path: &str) -> Option<&'a Value> {
+    if path == "." {
+        return stack.last().copied();
+    }
+    let mut keys = path.split('.');
+    let first = keys.next()?;
+    let mut value = stack.iter().rev().find_map(|context| context.get(first))?;
+    for key in keys {
+        value = value.get(key)?;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn renders_sections_and_drops_their_lines() {
+        let data = json::parse(
+            r#"{"host":"a","loss":0.8531,"outages":[{"secs":2},{"secs":1.5}],"labels":{"site":"x"},"empty":[]}"#,
+        )
+        .unwrap();
+        let template = Template::parse(
+            "{{! report }}\n# {{host}} {{labels.site}}\nLoss {{loss:.2}}%\n{{#outages}}\n- {{secs}} s on {{host}}\n{{/outages}}\n{{^empty}}none{{/empty}}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&data),
+            "# a x\nLoss 0.85%\n- 2 s on a\n- 1.5 s on a\nnone\n"
+        );
+        assert!(Template::parse("{{#a}}{{/b}}").is_err());
+        assert!(Template::parse("{{#a}}").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Parser for the small TOML subset used by scenario files and the state
+//! file of resumed sessions: comments,
+//! `key = value` pairs, `[table]` and `[[array.of.tables]]` headers, and
+//! string, integer, float and boolean values.
+
+/// A parsed value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+/// Key/value pairs under one header; `name` is empty for the top level.
+#[derive(Debug, Default)]
+pub(crate) struct Table {
+    pub name: String,
+    /// Whether this came from a `[[name]]` header
+    pub array: bool,
+    /// 1-based line of the header, for error messages
+    pub line: usize,
+    pub entries: Vec<(String, Value, usize)>,
+}
+
+/// Tables in document order, starting with the top level.
+pub(crate) fn parse(input: &str) -> eyre::Result<Vec<Table>> {
+    let mut tables = vec![Table::default()];
+    for (i, raw) in input.lines().enumerate() {
+        let line_no = i + 1;
+        let line = strip_comment(raw).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let (name, array) = match header.strip_prefix('[') {
+                Some(name) => (name.strip_suffix("]]"), true),
+                None => (header.strip_suffix(']'), false),
+            };
+            let name = name
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| eyre::eyre!("line {line_no}: malformed table header"))?;
+            tables.push(Table {
+                name: name.to_string(),
+                array,
+                line: line_no,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| eyre::eyre!("line {line_no}: expected `key = value`"))?;
+        let key = key.trim();
+        eyre::ensure!(
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+            "line {line_no}: invalid key {key:?}"
+        );
+        let value = parse_value(value.trim())
+            .ok_or_else(|| eyre::eyre!("line {line_no}: invalid value for {key}"))?;
+        let table = tables.last_mut().unwrap();
+        eyre::ensure!(
+            table.entries.iter().all(|(k, _, _)| k != key),
+            "line {line_no}: duplicate key {key}"
+        );
+        table.entries.push((key.to_string(), value, line_no));
+    }
+    Ok(tables)
+}
+
+/// Cut off a `#` comment that isn't inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    if let Some(s) = value.strip_prefix('"') {
+        return parse_string(s.strip_suffix('"')?).map(Value::String);
+    }
+    match value {
+        "true" => return Some(Value::Boolean(true)),
+        "false" => return Some(Value::Boolean(false)),
+        _ => {}
+    }
+    let number = value.replace('_', "");
+    if let Ok(n) = number.parse() {
+        return Some(Value::Integer(n));
+    }
+    number.parse().ok().map(Value::Float)
+}
+
+fn parse_string(s: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return None,
+            '\\' => out.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '"' => '"',
+                '\\' => '\\',
+                _ => return None,
+            }),
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_parse_into_tables() {
+        let tables = parse(
+            r#"
+            name = "a # not a comment" # a comment
+            rate = 10_000
+            loss = 1.5
+            on = true
+
+            [[step]]
+            path = "C:\\probes\t\"quoted\""
+            [[step]]
+            path = "second"
+            "#,
+        )
+        .unwrap();
+        let names: Vec<_> = tables.iter().map(|t| (t.name.as_str(), t.array)).collect();
+        assert_eq!(names, [("", false), ("step", true), ("step", true)]);
+        let values: Vec<_> = tables[0]
+            .entries
+            .iter()
+            .map(|(k, v, _)| (k.as_str(), v))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("name", &Value::String("a # not a comment".to_string())),
+                ("rate", &Value::Integer(10_000)),
+                ("loss", &Value::Float(1.5)),
+                ("on", &Value::Boolean(true)),
+            ]
+        );
+        assert_eq!(
+            tables[1].entries[0].1,
+            Value::String("C:\\probes\t\"quoted\"".to_string())
+        );
+        assert_eq!(tables[1].line, 7);
+        // an escaped quote doesn't end the string
+        let tables = parse(r#"key = "x \" # y" # z"#).unwrap();
+        assert_eq!(
+            tables[0].entries[0].1,
+            Value::String("x \" # y".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_documents_are_rejected() {
+        for input in [
+            "[]",
+            "[table",
+            "[[table]",
+            "key",
+            "key = \"unterminated",
+            "key = \"bad \\q escape\"",
+            "key = \"stray \" quote\"",
+            "key = 1\nkey = 2",
+            "bad key = 1",
+            "key = 1x",
+        ] {
+            assert!(parse(input).is_err(), "{input:?}");
+        }
+        // the same key in different tables is fine
+        assert!(parse("key = 1\n[t]\nkey = 2\n[[a]]\nkey = 3\n[[a]]\nkey = 4").is_ok());
+    }
+}
@@ -0,0 +1,295 @@
+//! Full-screen live dashboard for `client --tui`, drawn with plain ANSI
+//! escapes on the terminal's alternate screen, and the sparklines of the
+//! periodic output on a terminal.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{self, Write},
+    time::Duration,
+};
+
+use loss_lens::Stats;
+
+const SPARK: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+/// Outages listed at the bottom of the screen
+const OUTAGE_LOG_LINES: usize = 8;
+/// Time the sparklines of the periodic output span
+const TREND_WINDOW: Duration = Duration::from_secs(300);
+
+/// Per-snapshot values derived from consecutive [`Stats`].
+struct Interval {
+    upstream_loss: f64,
+    downstream_loss: f64,
+    round_trip_loss: f64,
+    max_gap: Duration,
+    mos: f64,
+}
+
+impl Interval {
+    fn new(prev: Option<&Stats>, stats: &Stats) -> Self {
+        let (prev_sent, prev_server, prev_client) = prev.map_or((0, 0, 0), |p| {
+            (p.client_sent, p.server_received, p.client_received)
+        });
+        let sent = stats.client_sent.saturating_sub(prev_sent);
+        let server = stats.server_received.saturating_sub(prev_server);
+        let client = stats.client_received.saturating_sub(prev_client);
+        let round_trip_loss = loss(client, sent);
+        Self {
+            upstream_loss: loss(server, sent),
+            downstream_loss: loss(client, server),
+            round_trip_loss,
+            max_gap: stats.max_gap,
+            mos: loss_lens::stats::mos(loss_lens::stats::r_factor(
+                round_trip_loss,
+                stats.mean_rtt,
+                stats.jitter,
+            )),
+        }
+    }
+}
+
+pub struct Tui {
+    host: String,
+    prev: Option<Stats>,
+    history: VecDeque<Interval>,
+}
+
+impl Tui {
+    pub fn new(host: String) -> io::Result<Self> {
+        // alternate screen, hide cursor
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(Self {
+            host,
+            prev: None,
+            history: VecDeque::new(),
+        })
+    }
+
+    pub fn update(&mut self, stats: &Stats) -> io::Result<()> {
+        self.history
+            .push_back(Interval::new(self.prev.as_ref(), stats));
+        let width = terminal_width().saturating_sub(16).max(10);
+        while self.history.len() > width {
+            self.history.pop_front();
+        }
+        self.prev = Some(stats.clone());
+
+        let mut out = String::new();
+        let last = self.history.back().unwrap();
+        let elapsed = stats.elapsed.as_secs();
+        // home and clear
+        out.push_str("\x1b[H\x1b[2J");
+        let _ = writeln!(
+            out,
+            "\x1b[1mLoss Lens\x1b[0m → {}    elapsed {:02}:{:02}:{:02}",
+            self.host,
+            elapsed / 3600,
+            elapsed / 60 % 60,
+            elapsed % 60
+        );
+        let _ = writeln!(out);
+        let _ = writeln!(
+            out,
+            "Sent {}   Server received {}   Client received {}   Traffic {:.02} KiB/s",
+            stats.client_sent,
+            stats.server_received,
+            stats.client_received,
+            stats.traffic_kib_per_sec()
+        );
+        if stats.round_trip_only {
+            // echoes count as received by the server, so it's all upstream
+            let _ = writeln!(
+                out,
+                "Round-trip loss {:6.2}% total  {:6.2}% now",
+                stats.round_trip_loss(),
+                last.upstream_loss
+            );
+        } else {
+            let _ = writeln!(
+                out,
+                "Upstream loss   {:6.2}% total  {:6.2}% now",
+                stats.upstream_loss(),
+                last.upstream_loss
+            );
+            let _ = writeln!(
+                out,
+                "Downstream loss {:6.2}% total  {:6.2}% now",
+                stats.downstream_loss(),
+                last.downstream_loss
+            );
+        }
+        if !stats.smoothed_loss.is_nan() {
+            let _ = writeln!(
+                out,
+                "Smoothed loss   {:6.2}%        round trip {:.1} ms",
+                stats.smoothed_loss,
+                stats.smoothed_rtt.as_secs_f64() * 1000.0
+            );
+        }
+        if stats.recent_loss.iter().any(|recent| recent.sent > 0) {
+            let _ = write!(out, "Recent loss    ");
+            for recent in &stats.recent_loss {
+                let _ = write!(
+                    out,
+                    " {:6.2}% last {}s ",
+                    recent.loss(),
+                    recent.window.as_secs_f64()
+                );
+            }
+            let _ = writeln!(out);
+        }
+        let _ = writeln!(
+            out,
+            "Call quality    MOS {:4.2} total  MOS {:4.2} now",
+            stats.mos(),
+            last.mos
+        );
+        let _ = writeln!(out, "Round trip      {}", stats.rtts);
+        let _ = writeln!(out);
+        let up: Vec<f64> = self.history.iter().map(|i| i.upstream_loss).collect();
+        let down: Vec<f64> = self.history.iter().map(|i| i.downstream_loss).collect();
+        let gaps: Vec<f64> = self
+            .history
+            .iter()
+            .map(|i| i.max_gap.as_secs_f64() * 1000.0)
+            .collect();
+        if stats.round_trip_only {
+            let _ = writeln!(out, "Round trip {}", sparkline(&up, 1.0));
+        } else {
+            let _ = writeln!(out, "Upstream   {}", sparkline(&up, 1.0));
+            let _ = writeln!(out, "Downstream {}", sparkline(&down, 1.0));
+        }
+        let _ = writeln!(out, "ACK gap    {}", sparkline(&gaps, 100.0));
+        let _ = writeln!(
+            out,
+            "           worst gap this window: {:.0} ms",
+            gaps.iter().cloned().fold(0.0, f64::max)
+        );
+        let _ = writeln!(out);
+        let _ = write!(out, "Lags per hour:");
+        for (threshold_ms, rate) in stats.lags_per_hour().step_by(2) {
+            let _ = write!(out, "  >={threshold_ms}ms {rate:.1}");
+        }
+        let _ = writeln!(out);
+        let _ = writeln!(out);
+        let _ = writeln!(out, "Outages ({} total):", stats.outages.len());
+        for outage in stats.outages.iter().rev().take(OUTAGE_LOG_LINES) {
+            let start = outage.start.as_secs();
+            let _ = writeln!(
+                out,
+                "  at {:02}:{:02}:{:02} for {:.1} s",
+                start / 3600,
+                start / 60 % 60,
+                start % 60,
+                outage.duration.as_secs_f64()
+            );
+        }
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Sparklines of the round-trip loss and the ACK gaps of the last
+/// [`TREND_WINDOW`], for the periodic output on a terminal.
+pub struct Trends {
+    prev: Option<Stats>,
+    history: VecDeque<Interval>,
+    every: Duration,
+}
+
+impl Trends {
+    /// Trends of snapshots taken `every` so long.
+    pub fn new(every: Duration) -> Self {
+        Self {
+            prev: None,
+            history: VecDeque::new(),
+            every,
+        }
+    }
+
+    /// Add `stats` and draw the sparklines, one line each.
+    pub fn update(&mut self, stats: &Stats) -> String {
+        self.history
+            .push_back(Interval::new(self.prev.as_ref(), stats));
+        let width = ((TREND_WINDOW.as_secs_f64() / self.every.as_secs_f64()).ceil() as usize)
+            .min(terminal_width().saturating_sub(40))
+            .max(10);
+        while self.history.len() > width {
+            self.history.pop_front();
+        }
+        self.prev = Some(stats.clone());
+
+        let loss: Vec<f64> = self.history.iter().map(|i| i.round_trip_loss).collect();
+        let gaps: Vec<f64> = self
+            .history
+            .iter()
+            .map(|i| i.max_gap.as_secs_f64() * 1000.0)
+            .collect();
+        let span = self.every.mul_f64(self.history.len() as f64).as_secs();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "Loss trend {} max {:.1}% over {}m{:02}s",
+            sparkline(&loss, 1.0),
+            loss.iter().cloned().fold(0.0, f64::max),
+            span / 60,
+            span % 60
+        );
+        let _ = writeln!(
+            out,
+            "Lag trend  {} max {:.0} ms",
+            sparkline(&gaps, 100.0),
+            gaps.iter().cloned().fold(0.0, f64::max)
+        );
+        out
+    }
+}
+
+fn loss(received: u64, sent: u64) -> f64 {
+    if sent == 0 {
+        0.0
+    } else {
+        100.0 * (1.0 - (received as f64 / sent as f64)).max(0.0)
+    }
+}
+
+/// Scale to the larger of the window maximum and `min_scale` so a quiet
+/// link shows a flat line instead of amplified noise.
+fn sparkline(values: &[f64], min_scale: f64) -> String {
+    let scale = values.iter().cloned().fold(min_scale, f64::max);
+    values
+        .iter()
+        .map(|v| {
+            let idx = (v / scale * (SPARK.len() - 1) as f64).round() as usize;
+            SPARK[idx.min(SPARK.len() - 1)]
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0
+        && size.ws_col > 0
+    {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
+
+#[cfg(not(unix))]
+fn terminal_width() -> usize {
+    80
+}
@@ -0,0 +1,172 @@
+//! TWAMP-Light (RFC 5357 appendix I, unauthenticated mode), so loss_lens
+//! can probe the reflectors carriers already run and their senders can
+//! probe a loss_lens server.
+//!
+//! Test packets carry no kind to tell them from loss_lens packets, so the
+//! server reflects them on a port of its own, 862 by convention. The
+//! reflector is stateful: its sequence number counts the packets it
+//! reflected to each sender, which the client takes as the server's receive
+//! count to tell upstream from downstream loss. Stateless reflectors copy
+//! the sender's sequence number instead, which makes all loss look like
+//! downstream loss.
+//!
+//! Reflected packets are 27 bytes longer than unpadded test packets. As
+//! RFC 6038 suggests for symmetric sizes, test packets are expected to be
+//! padded by as much, and shorter ones are ignored so the server can't be
+//! used for amplification.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{protocol::Reply, StopHandle, BUF_SIZE};
+
+/// Well-known port of TWAMP reflectors
+pub const PORT: u16 = 862;
+/// Unpadded test packet: `[seq, timestamp, error estimate]`
+pub const TEST_SIZE: usize = 4 + 8 + 2;
+/// Unpadded reflected packet: `[seq, timestamp, error estimate, MBZ,
+/// receive timestamp, sender seq, sender timestamp, sender error estimate,
+/// MBZ, sender TTL]`, and the smallest test packet answered
+pub const REFLECTED_SIZE: usize = 4 + 8 + 2 + 2 + 8 + 4 + 8 + 2 + 2 + 1;
+
+/// Clock not synchronized to UTC, with the smallest valid error estimate,
+/// as the multiplier must not be 0
+const ERROR_ESTIMATE: u16 = 1;
+/// Seconds from the NTP epoch, 1900, to the Unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// Senders counted for at once; beyond that they're reflected statelessly
+const MAX_SENDERS: usize = 4096;
+/// Silence after which a sender's count is forgotten
+const SENDER_IDLE: Duration = Duration::from_secs(60);
+
+/// `time` as an NTP timestamp: seconds since 1900 and their fraction in
+/// units of 2^-32 s.
+fn timestamp(time: SystemTime) -> [u8; 8] {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = (since.as_secs() + NTP_UNIX_OFFSET) as u32;
+    let fraction = ((u64::from(since.subsec_nanos()) << 32) / 1_000_000_000) as u32;
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&secs.to_be_bytes());
+    bytes[4..].copy_from_slice(&fraction.to_be_bytes());
+    bytes
+}
+
+/// Write the header of test packet `seq` into `buf`, leaving the padding
+/// after it as it is.
+pub(crate) fn write_test(buf: &mut [u8], seq: u32) {
+    buf[..4].copy_from_slice(&seq.to_be_bytes());
+    buf[4..12].copy_from_slice(&timestamp(SystemTime::now()));
+    buf[12..14].copy_from_slice(&ERROR_ESTIMATE.to_be_bytes());
+}
+
+/// A reflected packet as an ACK of the probe sent as test packet `seq - 1`,
+/// as TWAMP counts from 0 and probes from 1.
+pub(crate) fn reply(packet: &[u8]) -> Option<Reply> {
+    if packet.len() < REFLECTED_SIZE {
+        return None;
+    }
+    let reflector_seq = u32::from_be_bytes(packet[..4].try_into().unwrap());
+    let sender_seq = u32::from_be_bytes(packet[24..28].try_into().unwrap());
+    Some(Reply::Ack {
+        seq: sender_seq.wrapping_add(1),
+        received: reflector_seq.wrapping_add(1),
+        ecn: None,
+    })
+}
+
+/// Write the reflection of `test`, received at `received`, into `reply`,
+/// which is as long as `test`, keeping what of its padding fits.
+fn reflect(test: &[u8], seq: u32, received: SystemTime, reply: &mut [u8]) {
+    reply[..4].copy_from_slice(&seq.to_be_bytes());
+    reply[12..14].copy_from_slice(&ERROR_ESTIMATE.to_be_bytes());
+    reply[14..16].fill(0);
+    reply[16..24].copy_from_slice(&timestamp(received));
+    reply[24..38].copy_from_slice(&test[..TEST_SIZE]);
+    reply[38..40].fill(0);
+    // not read from the IP header; 0 as it's unknown
+    reply[40] = 0;
+    reply[REFLECTED_SIZE..].copy_from_slice(&test[REFLECTED_SIZE..]);
+    reply[4..12].copy_from_slice(&timestamp(SystemTime::now()));
+}
+
+/// Reflect test packets arriving at `socket` until `done`.
+pub(crate) fn spawn_reflector(
+    socket: UdpSocket,
+    done: StopHandle,
+) -> eyre::Result<JoinHandle<eyre::Result<()>>> {
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    Ok(thread::spawn(move || {
+        let mut buf = vec![0u8; BUF_SIZE];
+        let mut reply = vec![0u8; BUF_SIZE];
+        // packets reflected to each sender so far, and when the latest was
+        let mut senders = HashMap::<SocketAddr, (u32, Instant)>::new();
+        while !done.is_stopped() {
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                // e.g. ICMP unreachable from an earlier answer
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let received = SystemTime::now();
+            if n < REFLECTED_SIZE {
+                continue;
+            }
+            let now = Instant::now();
+            if senders.len() >= MAX_SENDERS && !senders.contains_key(&from) {
+                senders.retain(|_, (_, last)| now - *last < SENDER_IDLE);
+            }
+            let seq = if senders.len() < MAX_SENDERS || senders.contains_key(&from) {
+                let (count, last) = senders.entry(from).or_insert((0, now));
+                if now - *last >= SENDER_IDLE {
+                    *count = 0;
+                }
+                *last = now;
+                *count = count.wrapping_add(1);
+                count.wrapping_sub(1)
+            } else {
+                u32::from_be_bytes(buf[..4].try_into().unwrap())
+            };
+            reflect(&buf[..n], seq, received, &mut reply[..n]);
+            // unreachable senders aren't the reflector's problem
+            let _ = socket.send_to(&reply[..n], from);
+        }
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reflected_test_packet_acks_the_probe() {
+        let mut test = [0xaau8; 64];
+        write_test(&mut test, 41);
+        let mut reflected = [0u8; 64];
+        reflect(&test, 6, SystemTime::now(), &mut reflected);
+        assert_eq!(&reflected[24..38], &test[..TEST_SIZE]);
+        assert_eq!(&reflected[REFLECTED_SIZE..], &test[REFLECTED_SIZE..]);
+        assert_eq!(
+            reply(&reflected),
+            Some(Reply::Ack {
+                seq: 42,
+                received: 7,
+                ecn: None
+            })
+        );
+        assert_eq!(reply(&reflected[..REFLECTED_SIZE - 1]), None);
+    }
+}
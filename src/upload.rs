@@ -0,0 +1,167 @@
+//! Uploading results to an HTTP endpoint of one's own, for backends that
+//! want them without running a [`Collector`](crate::Collector): the
+//! end-of-run summary and, optionally, periodic snapshots are POSTed as
+//!
+//! ```text
+//! {"kind": "summary" or "snapshot", "host": HOST, "labels": {KEY: VALUE},
+//!  "unix_secs": SECS, "stats": STATS}
+//! ```
+//!
+//! with `STATS` as serialized by [`Stats::to_json`]. Failed uploads are
+//! retried with exponential backoff, snapshots only until the next one is
+//! due. Plain `http://` only.
+
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    client::labels_json,
+    http::{self, Url},
+    json,
+    snapshots::Snapshots,
+    Stats,
+};
+
+/// Attempts at uploading the summary before giving up
+const SUMMARY_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubling with every further one
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where to upload results to, and what to send along.
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    /// Endpoint to POST to, e.g. `http://backend.example/loss`
+    pub url: String,
+    /// Host being probed
+    pub host: String,
+    /// See [`ClientConfig::labels`](crate::ClientConfig::labels)
+    pub labels: Vec<(String, String)>,
+    /// Sent as `Authorization: Bearer TOKEN`
+    pub token: Option<String>,
+    /// Also upload a snapshot this often while running
+    pub every: Option<Duration>,
+}
+
+/// Uploads a client's stats, periodic snapshots from a thread of its own so
+/// a slow endpoint doesn't hold up probing. Cloning shares the thread, so
+/// one clone can be moved into the stats callback.
+#[derive(Clone)]
+pub struct Uploader {
+    /// Only with an interval to upload snapshots at
+    snapshots: Option<Snapshots>,
+    sender: Arc<UploadSender>,
+}
+
+struct UploadSender {
+    url: Url,
+    host: String,
+    labels: String,
+    authorization: Option<String>,
+}
+
+impl UploadSender {
+    fn body(&self, kind: &str, stats: &Stats) -> String {
+        json::Object::new()
+            .str("kind", kind)
+            .str("host", &self.host)
+            .raw("labels", &self.labels)
+            .f64(
+                "unix_secs",
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+            )
+            .raw("stats", &stats.to_json())
+            .finish()
+    }
+
+    /// POST `body`, retrying with backoff until it's accepted, a retry
+    /// wouldn't help, `attempts` are used up or `give_up` says so.
+    fn post(&self, body: &str, attempts: u32, give_up: impl Fn() -> bool) -> eyre::Result<()> {
+        let mut headers = vec![("Content-Type", "application/json")];
+        if let Some(authorization) = &self.authorization {
+            headers.push(("Authorization", authorization));
+        }
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let error = match http::request(&self.url, "POST", &headers, body) {
+                Ok((200..=299, _)) => return Ok(()),
+                // the endpoint won't take it however often it's asked
+                Ok((status @ 400..=499, answer)) if status != 408 && status != 429 => {
+                    eyre::bail!(
+                        "{} refused the upload with {status}: {}",
+                        self.url,
+                        answer.trim()
+                    )
+                }
+                Ok((status, answer)) => {
+                    eyre::eyre!("{} answered {status}: {}", self.url, answer.trim())
+                }
+                Err(e) => e,
+            };
+            if attempt >= attempts || give_up() {
+                return Err(error.wrap_err(format!("uploading failed {attempt} times")));
+            }
+            let retry_at = Instant::now() + backoff;
+            while Instant::now() < retry_at {
+                if give_up() {
+                    return Err(error.wrap_err(format!("uploading failed {attempt} times")));
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+}
+
+impl Uploader {
+    pub fn start(config: UploadConfig) -> eyre::Result<Self> {
+        let url = Url::parse(&config.url)
+            .ok_or_else(|| eyre::eyre!("upload URL must start with http://"))?;
+        eyre::ensure!(
+            config.every.is_none_or(|every| !every.is_zero()),
+            "upload interval must be positive"
+        );
+        let sender = Arc::new(UploadSender {
+            url,
+            host: config.host,
+            labels: labels_json(&config.labels),
+            authorization: config.token.map(|token| format!("Bearer {token}")),
+        });
+        let snapshots = config.every.map(|every| {
+            let sender = Arc::clone(&sender);
+            Snapshots::start(every, move |stats, superseded| {
+                let body = sender.body("snapshot", stats);
+                // a newer snapshot replaces this one once due
+                if let Err(e) = sender.post(&body, u32::MAX, superseded) {
+                    crate::log::warn!("Uploading a snapshot failed: {e:#}");
+                }
+            })
+        });
+        Ok(Self { snapshots, sender })
+    }
+
+    /// Keep `stats` to upload when the next snapshot is due.
+    pub fn update(&self, stats: &Stats) {
+        if let Some(snapshots) = &self.snapshots {
+            snapshots.update(stats);
+        }
+    }
+
+    /// Stop uploading snapshots and upload `stats` as the summary, retrying
+    /// a few times.
+    pub fn finish(&self, stats: &Stats) -> eyre::Result<()> {
+        if let Some(snapshots) = &self.snapshots {
+            snapshots.stop();
+        }
+        let body = self.sender.body("summary", stats);
+        self.sender.post(&body, SUMMARY_ATTEMPTS, || false)
+    }
+}
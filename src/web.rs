@@ -0,0 +1,202 @@
+//! Embedded web dashboard with live charts, backed by the client's in-memory
+//! stats, plus a WebSocket stream of stats and events at `/ws` and a small
+//! REST API:
+//!
+//! - `GET /stats`: latest [`Stats`] snapshot, `null` before the first one
+//! - `GET /events?since=ID`: recent events with an id greater than `ID`
+//! - `GET /config`: the client's configuration
+
+use std::{
+    collections::VecDeque,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    client::labels_json,
+    http::{self, Reply, Response},
+    json, websocket, ClientConfig, Event, Stats,
+};
+
+/// Snapshots kept for the charts; at one per second this is ten minutes.
+const HISTORY: usize = 600;
+/// Events kept for `GET /events`
+const EVENT_HISTORY: usize = 1000;
+
+const INDEX_HTML: &str = include_str!("web/index.html");
+
+#[derive(Default)]
+struct DashboardState {
+    latest: Option<Stats>,
+    /// `[elapsed_secs, client_sent, server_received, client_received, max_gap_ms]`
+    history: VecDeque<[f64; 5]>,
+    /// Connected WebSocket clients, pruned when a send fails
+    subscribers: Vec<Sender<String>>,
+    /// `(id, json)` of recent events
+    events: VecDeque<(u64, String)>,
+    next_event_id: u64,
+    config: Option<String>,
+    /// The client's labels as JSON, sent along with stats
+    labels: Option<String>,
+}
+
+impl DashboardState {
+    fn broadcast(&mut self, message: String) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(message.clone()).is_ok());
+    }
+}
+
+/// HTTP server for the dashboard. Cloning shares the underlying state, so one
+/// clone can be moved into the stats callback.
+#[derive(Clone)]
+pub struct Dashboard {
+    state: Arc<Mutex<DashboardState>>,
+    local_addr: SocketAddr,
+}
+
+impl Dashboard {
+    /// Start serving on `addr` in the background.
+    pub fn serve(addr: impl ToSocketAddrs) -> eyre::Result<Self> {
+        let state = Arc::new(Mutex::new(DashboardState::default()));
+        let local_addr = http::serve(addr, http::MAX_BODY, {
+            let state = Arc::clone(&state);
+            move |request, stream| match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/") => Response::ok("text/html; charset=utf-8", INDEX_HTML).into(),
+                ("GET", "/api/dashboard") => {
+                    Response::json(dashboard_json(&state.lock().unwrap())).into()
+                }
+                ("GET", "/ws") if websocket::is_upgrade(request) => {
+                    if websocket::accept(request, stream).is_ok() {
+                        let (tx, rx) = mpsc::channel();
+                        state.lock().unwrap().subscribers.push(tx);
+                        for message in rx {
+                            if websocket::send_text(stream, &message).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Reply::Handled
+                }
+                ("GET", "/stats") => {
+                    let state = state.lock().unwrap();
+                    Response::json(
+                        state
+                            .latest
+                            .as_ref()
+                            .map_or_else(|| "null".to_string(), Stats::to_json),
+                    )
+                    .into()
+                }
+                ("GET", "/events") => {
+                    let since = request
+                        .query_param("since")
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(0);
+                    Response::json(events_json(&state.lock().unwrap(), since)).into()
+                }
+                ("GET", "/config") => Response::json(
+                    state
+                        .lock()
+                        .unwrap()
+                        .config
+                        .clone()
+                        .unwrap_or_else(|| "null".to_string()),
+                )
+                .into(),
+                (_, "/" | "/api/dashboard" | "/ws" | "/stats" | "/events" | "/config") => {
+                    Response::method_not_allowed().into()
+                }
+                _ => Response::not_found().into(),
+            }
+        })?;
+        Ok(Self { state, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn update(&self, stats: &Stats) {
+        let mut state = self.state.lock().unwrap();
+        state.history.push_back([
+            stats.elapsed.as_secs_f64(),
+            stats.client_sent as f64,
+            stats.server_received as f64,
+            stats.client_received as f64,
+            stats.max_gap.as_secs_f64() * 1000.0,
+        ]);
+        while state.history.len() > HISTORY {
+            state.history.pop_front();
+        }
+        state.latest = Some(stats.clone());
+        let message = json::Object::new()
+            .str("type", "stats")
+            .raw("labels", state.labels.as_deref().unwrap_or("{}"))
+            .raw("stats", &stats.to_json())
+            .finish();
+        state.broadcast(message);
+    }
+
+    /// Record `event` for `GET /events` and forward it to WebSocket
+    /// subscribers.
+    pub fn event(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap();
+        let json = event.to_json();
+        state.next_event_id += 1;
+        let id = state.next_event_id;
+        state.events.push_back((id, json.clone()));
+        while state.events.len() > EVENT_HISTORY {
+            state.events.pop_front();
+        }
+        state.broadcast(json);
+    }
+
+    /// Configuration reported by `GET /config`, and labels sent along
+    /// with stats.
+    pub fn set_config(&self, config: &ClientConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.config = Some(config.to_json());
+        state.labels = Some(labels_json(&config.labels));
+    }
+}
+
+fn events_json(state: &DashboardState, since: u64) -> String {
+    json::array(
+        state
+            .events
+            .iter()
+            .filter(|(id, _)| *id > since)
+            .map(|(id, event)| {
+                json::Object::new()
+                    .u64("id", *id)
+                    .raw("event", event)
+                    .finish()
+            }),
+    )
+}
+
+fn dashboard_json(state: &DashboardState) -> String {
+    json::Object::new()
+        .raw("labels", state.labels.as_deref().unwrap_or("{}"))
+        .raw(
+            "latest",
+            &state
+                .latest
+                .as_ref()
+                .map_or_else(|| "null".to_string(), Stats::to_json),
+        )
+        .raw(
+            "history",
+            &json::array(
+                state
+                    .history
+                    .iter()
+                    .map(|point| json::array(point.iter().map(|v| json::number(*v)))),
+            ),
+        )
+        .finish()
+}
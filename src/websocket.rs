@@ -0,0 +1,70 @@
+//! Server side of RFC 6455, limited to what a push-only event stream needs:
+//! the upgrade handshake and unmasked text frames.
+
+use std::{
+    io::{self, Write},
+    net::TcpStream,
+};
+
+use crate::{hash::sha1, http::Request};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub(crate) fn is_upgrade(request: &Request) -> bool {
+    request
+        .header("Upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Complete the opening handshake for `request` on `stream`.
+pub(crate) fn accept(request: &Request, stream: &mut TcpStream) -> io::Result<()> {
+    let Some(key) = request.header("Sec-WebSocket-Key") else {
+        stream.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "missing key"));
+    };
+    let accept = base64(&sha1(format!("{key}{ACCEPT_GUID}").as_bytes()));
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+    )?;
+    stream.flush()
+}
+
+pub(crate) fn send_text(stream: &mut TcpStream, text: &str) -> io::Result<()> {
+    let len = text.len();
+    let mut frame = Vec::with_capacity(len + 10);
+    // FIN + text opcode
+    frame.push(0x81);
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(text.as_bytes());
+    stream.write_all(&frame)
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,355 @@
+//! Pushing a client's metrics to a Zabbix server or proxy with the Zabbix
+//! sender protocol, as `zabbix_sender` does, for trapper items of a host
+//! configured there. Every interval a value is sent per metric, over the
+//! probes of that interval:
+//!
+//! | metric            | value                                     |
+//! |-------------------|-------------------------------------------|
+//! | `loss`            | round-trip loss in percent                |
+//! | `upstream_loss`   | loss on the way to the server, in percent |
+//! | `downstream_loss` | loss on the way back, in percent          |
+//! | `rtt_p50`         | median round-trip time in milliseconds    |
+//! | `rtt_p99`         | 99th percentile of it                     |
+//! | `jitter`          | jitter at the end, in milliseconds        |
+//! | `max_gap`         | largest gap between ACKs in milliseconds  |
+//! | `outages`         | outages that ended                        |
+//! | `sent`            | probes sent                               |
+//! | `received`        | probes acknowledged                       |
+//!
+//! Items are keyed `loss_lens.METRIC` unless mapped to other keys. Values
+//! not known, like upstream loss against an echo server or round-trip
+//! times without ACKs, are left out. Plain TCP only, without TLS.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{json, Histogram, Stats, StopHandle};
+
+pub const DEFAULT_PORT: u16 = 10051;
+pub const METRICS: [&str; 10] = [
+    "loss",
+    "upstream_loss",
+    "downstream_loss",
+    "rtt_p50",
+    "rtt_p99",
+    "jitter",
+    "max_gap",
+    "outages",
+    "sent",
+    "received",
+];
+const KEY_PREFIX: &str = "loss_lens.";
+const HEADER: &[u8; 5] = b"ZBXD\x01";
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest response accepted
+const MAX_RESPONSE: u64 = 1 << 20;
+
+/// Where to push metrics to, and as what.
+#[derive(Clone, Debug)]
+pub struct ZabbixConfig {
+    /// Zabbix server or proxy, `host[:port]` with port 10051 by default
+    pub server: String,
+    /// Name of the host the items belong to in Zabbix
+    pub host: String,
+    /// `(metric, key)` pairs overriding the items' keys, see [`METRICS`];
+    /// an empty key leaves the metric out
+    pub keys: Vec<(String, String)>,
+    /// How often to push
+    pub every: Duration,
+}
+
+/// Cumulative counts at the start of an interval.
+#[derive(Default)]
+struct IntervalStart {
+    sent: u64,
+    server_received: u64,
+    received: u64,
+    outages: usize,
+    rtts: Histogram,
+}
+
+/// What the sender thread needs of the stats since the last push.
+#[derive(Default)]
+struct Latest {
+    stats: Option<Stats>,
+    /// Largest `max_gap` of the stats since
+    max_gap: Duration,
+}
+
+/// Pushes a client's stats to Zabbix from a thread of its own, so an
+/// unreachable server doesn't hold up probing. Cloning shares the thread,
+/// so one clone can be moved into the stats callback.
+#[derive(Clone)]
+pub struct ZabbixSender {
+    latest: Arc<Mutex<Latest>>,
+    sender: Arc<Mutex<Sender>>,
+    stop: StopHandle,
+}
+
+struct Sender {
+    server: String,
+    host: String,
+    /// By metric, in the order of [`METRICS`]
+    keys: Vec<Option<String>>,
+    start: IntervalStart,
+}
+
+impl Sender {
+    /// The metrics' values since the previous call, as `(key, value)`.
+    fn values(&mut self, stats: &Stats, max_gap: Duration) -> Vec<(&str, String)> {
+        let start = std::mem::replace(
+            &mut self.start,
+            IntervalStart {
+                sent: stats.client_sent,
+                server_received: stats.server_received,
+                received: stats.client_received,
+                outages: stats.outages.len(),
+                rtts: stats.rtts.clone(),
+            },
+        );
+        let sent = stats.client_sent.saturating_sub(start.sent);
+        let server_received = stats.server_received.saturating_sub(start.server_received);
+        let received = stats.client_received.saturating_sub(start.received);
+        let rtts = stats.rtts.since(&start.rtts);
+        let percent = |part: u64, whole: u64| {
+            (whole > 0).then(|| 100.0 * whole.saturating_sub(part) as f64 / whole as f64)
+        };
+        let ms = |duration: Duration| Some(duration.as_secs_f64() * 1000.0);
+        let known_directions = !stats.round_trip_only;
+        let values = [
+            percent(received, sent),
+            percent(server_received, sent).filter(|_| known_directions),
+            percent(received, server_received).filter(|_| known_directions),
+            (rtts.count() > 0)
+                .then(|| rtts.percentile(50.0))
+                .and_then(ms),
+            (rtts.count() > 0)
+                .then(|| rtts.percentile(99.0))
+                .and_then(ms),
+            ms(stats.jitter),
+            ms(max_gap),
+            Some(stats.outages.len().saturating_sub(start.outages) as f64),
+            Some(sent as f64),
+            Some(received as f64),
+        ];
+        self.keys
+            .iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key.as_deref()?, json::number(value?))))
+            .collect()
+    }
+
+    fn send(&mut self, stats: &Stats, max_gap: Duration) -> eyre::Result<()> {
+        let clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let host = self.host.clone();
+        let server = self.server.clone();
+        let values = self.values(stats, max_gap);
+        if values.is_empty() {
+            return Ok(());
+        }
+        let body = request(&host, &values, clock);
+        let answer = exchange(&server, &body)?;
+        let answer = json::parse(&answer)
+            .map_err(|e| eyre::eyre!("{server} answered with bad JSON: {e}"))?;
+        let info = answer
+            .get("info")
+            .and_then(json::Value::as_str)
+            .unwrap_or_default();
+        match answer.get("response").and_then(json::Value::as_str) {
+            Some("success") => {}
+            _ => eyre::bail!("{server} refused the values: {info}"),
+        }
+        // e.g. "processed: 8; failed: 2; total: 10; seconds spent: 0.000101"
+        if let Some(failed) = info
+            .split(';')
+            .find_map(|part| part.trim().strip_prefix("failed: "))
+            .filter(|failed| *failed != "0")
+        {
+            crate::log::warn!(
+                "Zabbix refused {failed} of {} values for host {host}, are there trapper items with their keys?",
+                values.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A `sender data` request with `values` of `host`.
+fn request(host: &str, values: &[(&str, String)], clock: u64) -> String {
+    json::Object::new()
+        .str("request", "sender data")
+        .raw(
+            "data",
+            &json::array(values.iter().map(|(key, value)| {
+                json::Object::new()
+                    .str("host", host)
+                    .str("key", key)
+                    .str("value", value)
+                    .u64("clock", clock)
+                    .finish()
+            })),
+        )
+        .u64("clock", clock)
+        .finish()
+}
+
+/// `payload` with the protocol's header: `ZBXD`, flags, then its length
+/// and 4 reserved bytes, little-endian.
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut out = HEADER.to_vec();
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Send `body` to `server` and read its answer.
+fn exchange(server: &str, body: &str) -> eyre::Result<String> {
+    let addr = match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() && !server.ends_with(']') => {
+            server.to_string()
+        }
+        _ => format!("{server}:{DEFAULT_PORT}"),
+    };
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| eyre::eyre!("{server} has no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(&frame(body.as_bytes()))?;
+    let mut header = [0; 13];
+    stream.read_exact(&mut header)?;
+    eyre::ensure!(
+        header[..4] == HEADER[..4],
+        "{server} doesn't speak the Zabbix protocol"
+    );
+    let len = u32::from_le_bytes(header[5..9].try_into().unwrap());
+    let mut answer = String::new();
+    stream
+        .take(u64::from(len).min(MAX_RESPONSE))
+        .read_to_string(&mut answer)?;
+    Ok(answer)
+}
+
+impl ZabbixSender {
+    pub fn start(config: ZabbixConfig) -> eyre::Result<Self> {
+        eyre::ensure!(!config.every.is_zero(), "Zabbix interval must be positive");
+        let mut keys: Vec<Option<String>> = METRICS
+            .iter()
+            .map(|metric| Some(format!("{KEY_PREFIX}{metric}")))
+            .collect();
+        for (metric, key) in &config.keys {
+            let i = METRICS.iter().position(|m| m == metric).ok_or_else(|| {
+                eyre::eyre!(
+                    "no metric {metric:?} to map to a Zabbix key, there are {}",
+                    METRICS.join(", ")
+                )
+            })?;
+            keys[i] = (!key.is_empty()).then(|| key.clone());
+        }
+        let sender = Arc::new(Mutex::new(Sender {
+            server: config.server,
+            host: config.host,
+            keys,
+            start: IntervalStart::default(),
+        }));
+        let latest = Arc::new(Mutex::new(Latest::default()));
+        let stop = StopHandle::default();
+        thread::spawn({
+            let latest = Arc::clone(&latest);
+            let sender = Arc::clone(&sender);
+            let stop = stop.clone();
+            let every = config.every;
+            move || {
+                let mut last = Instant::now();
+                while !stop.is_stopped() {
+                    thread::sleep(Duration::from_millis(100));
+                    if last.elapsed() < every {
+                        continue;
+                    }
+                    last = Instant::now();
+                    let Latest { stats, max_gap } = std::mem::take(&mut *latest.lock().unwrap());
+                    let Some(stats) = stats else { continue };
+                    if let Err(e) = sender.lock().unwrap().send(&stats, max_gap) {
+                        crate::log::warn!("Sending to Zabbix failed: {e:#}");
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            latest,
+            sender,
+            stop,
+        })
+    }
+
+    /// Keep `stats` to send when the next push is due.
+    pub fn update(&self, stats: &Stats) {
+        let mut latest = self.latest.lock().unwrap();
+        latest.max_gap = latest.max_gap.max(stats.max_gap);
+        latest.stats = Some(stats.clone());
+    }
+
+    /// Stop pushing periodically and push what's left up to `stats`.
+    pub fn finish(&self, stats: &Stats) -> eyre::Result<()> {
+        self.stop.stop();
+        let max_gap = std::mem::take(&mut *self.latest.lock().unwrap())
+            .max_gap
+            .max(stats.max_gap);
+        self.sender.lock().unwrap().send(stats, max_gap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_cover_the_interval_and_keys_can_be_mapped() {
+        let mut sender = Sender {
+            server: String::new(),
+            host: "h".to_string(),
+            keys: METRICS
+                .iter()
+                .map(|metric| match *metric {
+                    "loss" => Some("net.loss[wan]".to_string()),
+                    "sent" | "received" => Some(format!("{KEY_PREFIX}{metric}")),
+                    _ => None,
+                })
+                .collect(),
+            start: IntervalStart::default(),
+        };
+        let stats = |sent, received| Stats {
+            client_sent: sent,
+            server_received: sent,
+            client_received: received,
+            ..Stats::default()
+        };
+        sender.values(&stats(100, 90), Duration::ZERO);
+        let values = sender.values(&stats(300, 270), Duration::ZERO);
+        assert_eq!(
+            values,
+            [
+                ("net.loss[wan]", "10".to_string()),
+                ("loss_lens.sent", "200".to_string()),
+                ("loss_lens.received", "180".to_string()),
+            ]
+        );
+        let request = request("h", &values[..1], 7);
+        assert_eq!(
+            request,
+            r#"{"request":"sender data","data":[{"host":"h","key":"net.loss[wan]","value":"10","clock":7}],"clock":7}"#
+        );
+        assert_eq!(&frame(b"{}")[..13], b"ZBXD\x01\x02\0\0\0\0\0\0\0");
+    }
+}
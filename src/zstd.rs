@@ -0,0 +1,226 @@
+//! In-process zstd writer for when the `zstd` tool isn't available, e.g. on
+//! Windows. It only emits raw and RLE blocks, which any zstd decoder reads.
+//!
+//! Limitation: there are no compressed blocks, so neither Huffman-coded
+//! literals nor sequences. Only runs of at least [`MIN_RUN`] equal bytes
+//! shrink, e.g. those of fully acknowledged slots; everything else is stored
+//! as is plus 3 bytes per block, so recordings of lossy links come out about
+//! as large as the raw slots. Recompress them with `zstd` where size
+//! matters.
+
+use std::io::{self, Write};
+
+const MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// First of the 16 magic numbers of skippable frames, which decoders skip
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+/// No content size, checksum or dictionary; a window descriptor follows.
+const FRAME_HEADER_DESCRIPTOR: u8 = 0;
+/// 128 KiB window, which also bounds the block size
+const WINDOW_DESCRIPTOR: u8 = 7 << 3;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+
+/// Bytes buffered before they are written out as blocks
+const PENDING_LIMIT: usize = 1024;
+/// Shorter runs are cheaper to store raw than as a separate block
+const MIN_RUN: usize = 8;
+
+/// Writes one zstd frame. Data only becomes decodable once the frame is
+/// ended with [`FrameWriter::finish`]; further frames may be appended to the
+/// same file.
+pub(crate) struct FrameWriter<W: Write> {
+    out: W,
+    pending: Vec<u8>,
+    header_written: bool,
+}
+
+/// Write `data` as a skippable frame, which zstd decoders pass over, to keep
+/// metadata in a compressed file without affecting its decompressed content.
+pub(crate) fn write_skippable_frame(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    out.write_all(&SKIPPABLE_MAGIC.to_le_bytes())?;
+    out.write_all(&(data.len() as u32).to_le_bytes())?;
+    out.write_all(data)?;
+    out.flush()
+}
+
+/// Length of the longest prefix of `data` made of complete frames, zstd or
+/// skippable, so a frame a crash cut short can be dropped before appending.
+/// Frames are walked by their block headers, without decompressing.
+pub(crate) fn complete_len(data: &[u8]) -> usize {
+    let mut len = 0;
+    while let Some(frame) = frame_len(&data[len..]) {
+        len += frame;
+    }
+    len
+}
+
+fn frame_len(data: &[u8]) -> Option<usize> {
+    let magic = u32::from_le_bytes(data.get(..4)?.try_into().unwrap());
+    if magic & !0xf == SKIPPABLE_MAGIC {
+        let size = u32::from_le_bytes(data.get(4..8)?.try_into().unwrap());
+        let len = 8 + size as usize;
+        return (data.len() >= len).then_some(len);
+    }
+    if magic != u32::from_le_bytes(MAGIC) {
+        return None;
+    }
+    let descriptor = *data.get(4)?;
+    let single_segment = descriptor & 0x20 != 0;
+    let checksum = descriptor & 0x04 != 0;
+    let dictionary_id = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+    let content_size = match descriptor >> 6 {
+        0 => usize::from(single_segment),
+        flag => 1 << flag,
+    };
+    let mut pos = 5 + usize::from(!single_segment) + dictionary_id + content_size;
+    loop {
+        let header = data.get(pos..pos + 3)?;
+        let header = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+        let size = (header >> 3) as usize;
+        pos += 3 + match header >> 1 & 0b11 {
+            BLOCK_RAW | BLOCK_COMPRESSED => size,
+            BLOCK_RLE => 1,
+            _ => return None,
+        };
+        if header & 1 != 0 {
+            break;
+        }
+    }
+    if checksum {
+        pos += 4;
+    }
+    (data.len() >= pos).then_some(pos)
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            pending: Vec::with_capacity(PENDING_LIMIT),
+            header_written: false,
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        if self.pending.len() >= PENDING_LIMIT {
+            self.write_blocks(false)?;
+        }
+        Ok(())
+    }
+
+    /// End the frame and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_blocks(true)?;
+        Ok(self.out)
+    }
+
+    fn write_blocks(&mut self, last: bool) -> io::Result<()> {
+        if !self.header_written {
+            self.out.write_all(&MAGIC)?;
+            self.out
+                .write_all(&[FRAME_HEADER_DESCRIPTOR, WINDOW_DESCRIPTOR])?;
+            self.header_written = true;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let mut blocks = Vec::new();
+        let mut raw_start = 0;
+        let mut i = 0;
+        while i < pending.len() {
+            let run = pending[i..]
+                .iter()
+                .take_while(|&&b| b == pending[i])
+                .count();
+            if run >= MIN_RUN {
+                if raw_start < i {
+                    blocks.push((BLOCK_RAW, &pending[raw_start..i], i - raw_start));
+                }
+                blocks.push((BLOCK_RLE, &pending[i..i + 1], run));
+                raw_start = i + run;
+            }
+            i += run;
+        }
+        if raw_start < pending.len() || (last && blocks.is_empty()) {
+            blocks.push((BLOCK_RAW, &pending[raw_start..], pending.len() - raw_start));
+        }
+        let count = blocks.len();
+        for (n, (kind, content, size)) in blocks.into_iter().enumerate() {
+            let last_block = u32::from(last && n + 1 == count);
+            let header = last_block | kind << 1 | (size as u32) << 3;
+            self.out.write_all(&header.to_le_bytes()[..3])?;
+            self.out.write_all(content)?;
+        }
+        self.pending = pending;
+        self.pending.clear();
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use super::*;
+
+    /// Slots with runs long enough for RLE blocks and stretches that aren't,
+    /// past `PENDING_LIMIT`
+    fn slots() -> Vec<u8> {
+        let mut slots = vec![64; 3000];
+        slots.extend((0..2000).map(|i| (i * 7 % 65) as u8));
+        slots.extend([0; 20]);
+        slots
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.write(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn only_complete_frames_count() {
+        let mut file = Vec::new();
+        write_skippable_frame(&mut file, b"{\"slot\":0}").unwrap();
+        let metadata = file.len();
+        file.extend(frame(&slots()));
+        let first = file.len();
+        assert_eq!(complete_len(&file), first);
+        file.extend(frame(&[]));
+        let second = file.len();
+        write_skippable_frame(&mut file, b"{}").unwrap();
+        file.extend(frame(&slots()));
+        assert_eq!(complete_len(&file), file.len());
+        for cut in 0..file.len() {
+            let expected = [0, metadata, first, second, second + 10]
+                .into_iter()
+                .filter(|&len| len <= cut)
+                .max()
+                .unwrap();
+            assert_eq!(complete_len(&file[..cut]), expected, "cut at {cut}");
+        }
+    }
+
+    #[test]
+    fn frames_decompress_with_the_zstd_tool() {
+        let mut file = frame(&slots());
+        write_skippable_frame(&mut file, b"{}").unwrap();
+        file.extend(frame(&[1, 2, 3]));
+        let spawned = Command::new("zstd")
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+        // skipped where the tool isn't installed
+        let Ok(mut zstd) = spawned else {
+            return;
+        };
+        zstd.stdin.take().unwrap().write_all(&file).unwrap();
+        let out = zstd.wait_with_output().unwrap();
+        assert!(out.status.success());
+        let mut expected = slots();
+        expected.extend([1, 2, 3]);
+        assert_eq!(out.stdout, expected);
+    }
+}